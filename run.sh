@@ -1,6 +1,7 @@
 qemu-system-aarch64 \
     -machine virt,gic-version=2 \
     -cpu cortex-a57 \
+    -smp 2 \
     -m 1024 \
     -nographic \
     -d guest_errors \
@@ -225,6 +225,15 @@ pub fn r_ttbr1_el1() -> u64 {
     res.cast_unsigned()
 }
 
+#[inline]
+pub fn r_mpidr_el1() -> u64 {
+    let mut res = 0i64;
+    unsafe {
+        asm!("mrs {}, mpidr_el1", out(reg) res);
+    }
+    res.cast_unsigned()
+}
+
 #[inline]
 pub fn r_far_el1() -> u64 {
     let mut res = 0i64;
@@ -266,6 +275,20 @@ pub fn tlbi_vaee1(v: u64) {
     }
 }
 
+#[inline]
+pub fn tlbi_aside1is(asid: u64) {
+    unsafe {
+        asm!("tlbi aside1is , {}", in(reg) asid << 48);
+    }
+}
+
+#[inline]
+pub fn tlbi_vaee1is(v: u64) {
+    unsafe {
+        asm!("tlbi vaae1is , {}", in(reg) v>>12);
+    }
+}
+
 #[macro_export]
 macro_rules! dsb {
     () => {
@@ -273,6 +296,20 @@ macro_rules! dsb {
     };
 }
 
+#[macro_export]
+macro_rules! dsb_ish {
+    () => {
+        unsafe { asm!("dsb ish") }
+    };
+}
+
+#[macro_export]
+macro_rules! dsb_ishst {
+    () => {
+        unsafe { asm!("dsb ishst") }
+    };
+}
+
 #[macro_export]
 macro_rules! dmb {
     () => {
@@ -294,6 +331,13 @@ macro_rules! tlbi_vmalle1 {
     };
 }
 
+#[macro_export]
+macro_rules! tlbi_vmalle1is {
+    () => {
+        unsafe { asm!("tlbi VMALLE1IS") }
+    };
+}
+
 #[macro_export]
 macro_rules! wfi {
     () => {
@@ -1,16 +1,32 @@
 use crate::{
     dsb, print,
+    sched::{sleep, wakeup},
     spin::Lock,
+    trap::gic_enable_intr,
     virtio::{self, Q, Regs, Status, init_dev_common},
     vm,
 };
-use core::{arch::asm, ptr::NonNull};
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
 const QSIZE: usize = 4;
 
 struct VirtioBlk {
     regs: NonNull<Regs>,
     vq: Q<QSIZE>,
+    // negotiated, bit numbers from Features
+    features: u64,
+}
+
+impl VirtioBlk {
+    // where requests wait for descriptors
+    fn desc_chan(&self) -> u64 {
+        &self.vq as *const Q<QSIZE> as u64
+    }
 }
 
 // static REGS: StaticMut<Option<&mut Regs>> = StaticMut::new(None);
@@ -20,6 +36,7 @@ static BLK: Lock<VirtioBlk> = Lock::new(
     VirtioBlk {
         regs: NonNull::dangling(),
         vq: Q::new(),
+        features: 0,
     },
 );
 
@@ -140,7 +157,18 @@ fn get_config(reg: &mut Regs) -> &Config {
     unsafe { (((reg as *mut Regs as usize) + Regs::CONFIG) as *mut Config).as_ref() }.unwrap()
 }
 
-pub fn init(reg: &mut Regs) {
+// 0 when no device was found
+static IRQ: AtomicU32 = AtomicU32::new(0);
+
+pub fn present() -> bool {
+    IRQ.load(Ordering::Relaxed) != 0
+}
+
+pub fn irq() -> u32 {
+    IRQ.load(Ordering::Relaxed)
+}
+
+pub fn init(reg: &mut Regs, irq: u32) {
     let lock = BLK.acquire();
     let blk = lock.as_mut();
 
@@ -151,16 +179,20 @@ pub fn init(reg: &mut Regs) {
 
     blk.regs = NonNull::new(reg as *mut Regs).unwrap();
 
-    init_dev_common(reg, 0);
-    let status: u32 = reg.read(Regs::STATUS);
-    reg.write(Regs::STATUS, status | Status::DRIVER_OK);
-    dsb!();
+    blk.features = init_dev_common(reg, (1 << Features::FLUSH) | (1 << Features::RO), 0);
 
     virtio::set_q_len(reg, 0, blk.vq.len());
     virtio::set_used_area(reg, blk.vq.used_area_paddr());
     virtio::set_avail_area(reg, blk.vq.avail_area_paddr());
     virtio::set_desc_area(reg, blk.vq.desc_area_paddr());
     dsb!();
+
+    let status: u32 = reg.read(Regs::STATUS);
+    reg.write(Regs::STATUS, status | Status::DRIVER_OK);
+    dsb!();
+
+    IRQ.store(irq, Ordering::Relaxed);
+    gic_enable_intr(irq as usize);
 }
 
 fn rw(sect: u64, buf: *const u8, len: usize, r: bool, sync: bool) -> Result<(), ()> {
@@ -172,44 +204,66 @@ fn rw(sect: u64, buf: *const u8, len: usize, r: bool, sync: bool) -> Result<(),
         return Err(());
     }
 
-    let lock = BLK.acquire();
-    let blk = lock.as_mut();
-    assert!(blk.regs != NonNull::dangling());
-
     let kind = if r { ReqKind::IN } else { ReqKind::OUT };
-    let req = Req::new(kind, sect);
-
-    // print!("first clr.....\n");
-    let d1_idx = blk.vq.alloc_desc().unwrap();
-    let d2_idx = blk.vq.alloc_desc().unwrap();
-    let d3_idx = blk.vq.alloc_desc().unwrap();
-
-    let d1 = blk.vq.get_desc_mut(d1_idx as usize);
-    // let k = Box::new(0u8);
+    submit(kind, sect, Some((buf, len)), sync)
+}
 
-    d1.set_next(d2_idx).set_len(16).set_data(req.paddr() as u64);
+// header, data if any, then the status byte the device writes back
+fn submit(kind: u32, sect: u64, data: Option<(*const u8, usize)>, sync: bool) -> Result<(), ()> {
+    let mut lock = BLK.acquire();
+    assert!(lock.as_ref().regs != NonNull::dangling());
 
-    let d2 = blk.vq.get_desc_mut(d2_idx as usize);
-    d2.set_next(d3_idx)
-        .set_len(len as u32)
-        .set_data(vm::v2p(buf as *const u8 as usize).unwrap() as u64);
-    if r {
-        d2.set_writable();
+    let req = Req::new(kind, sect);
+    let ndesc = if data.is_some() { 3 } else { 2 };
+
+    // sync callers may run before there is anything to schedule, they
+    // reap the used ring themselves until the chain fits
+    while lock.as_ref().vq.n_free() < ndesc {
+        if sync {
+            drop(lock);
+            spin_loop();
+            irq_handle();
+            lock = BLK.acquire();
+        } else {
+            sleep(lock.as_ref().desc_chan(), lock.get_lock());
+        }
     }
+    let blk = lock.as_mut();
 
-    let d3 = blk.vq.get_desc_mut(d3_idx as usize);
+    // none of these fail once n_free covers the chain
+    let head = blk.vq.alloc_desc().ok_or(())?;
+    blk.vq
+        .get_desc_mut(head as usize)
+        .set_len(16)
+        .set_data(req.paddr() as u64);
+
+    let mut tail = head;
+    if let Some((buf, len)) = data {
+        let d = blk.vq.alloc_desc().ok_or(())?;
+        blk.vq.get_desc_mut(tail as usize).set_next(d);
+        let desc = blk.vq.get_desc_mut(d as usize);
+        desc.set_len(len as u32)
+            .set_data(vm::v2p(buf as usize).unwrap() as u64);
+        if kind == ReqKind::IN {
+            desc.set_writable();
+        }
+        tail = d;
+    }
 
-    d3.set_writable()
+    let st = blk.vq.alloc_desc().ok_or(())?;
+    blk.vq.get_desc_mut(tail as usize).set_next(st);
+    blk.vq
+        .get_desc_mut(st as usize)
+        .set_writable()
         .set_len(1)
         .set_data(req.status_paddr() as u64);
 
-    // print!("====> req before: {:?}\n", req)
     let req_ptr = &req as *const Req as u64;
-    blk.vq.desc_data[d1_idx as usize] = if sync { 0 } else { req_ptr };
+    blk.vq.desc_data[head as usize] = if sync { 0 } else { req_ptr };
 
     let regs = unsafe { blk.regs.as_mut() };
 
-    let old = blk.vq.add_avail(d1_idx);
+    let old = blk.vq.add_avail(head);
     virtio::set_ready(regs, 0);
     virtio::notify_q(regs, 0);
 
@@ -218,16 +272,33 @@ fn rw(sect: u64, buf: *const u8, len: usize, r: bool, sync: bool) -> Result<(),
         drop(lock);
         irq_handle();
     } else {
-        //TODO sleep on req_ptr here
+        // irq_handle wakes us once the device has written status
+        sleep(req_ptr, lock.get_lock());
     }
 
-    if req.status == ReqStatus::OK {
+    // written by the device behind the compiler's back
+    let status = unsafe { (&raw const req.status).read_volatile() };
+    if status == ReqStatus::OK {
         Ok(())
     } else {
         Err(())
     }
 }
 
+// write back the device's cache, nothing to do without FLUSH
+pub fn flush() -> Result<(), ()> {
+    let features = BLK.acquire().as_ref().features;
+    if features & (1 << Features::FLUSH) == 0 {
+        return Ok(());
+    }
+    submit(ReqKind::FLUSH, 0, None, false)
+}
+
+pub fn read_only() -> bool {
+    let lock = BLK.acquire();
+    lock.as_ref().features & (1 << Features::RO) != 0
+}
+
 pub fn read(sect: u64, buf: &mut [u8]) -> Result<(), ()> {
     let ptr = (&buf[0]) as *const u8;
     let len = buf.len();
@@ -235,6 +306,9 @@ pub fn read(sect: u64, buf: &mut [u8]) -> Result<(), ()> {
 }
 
 pub fn write(sect: u64, buf: &[u8]) -> Result<(), ()> {
+    if read_only() {
+        return Err(());
+    }
     let ptr = (&buf[0]) as *const u8;
     let len = buf.len();
     rw(sect, ptr, len, false, false)
@@ -247,11 +321,26 @@ pub fn read_sync(sect: u64, buf: &mut [u8]) -> Result<(), ()> {
 }
 
 pub fn write_sync(sect: u64, buf: &[u8]) -> Result<(), ()> {
+    if read_only() {
+        return Err(());
+    }
     let ptr = (&buf[0]) as *const u8;
     let len = buf.len();
     rw(sect, ptr, len, false, true)
 }
 
+pub const SECTOR: usize = 512;
+
+// in sectors
+pub fn capacity() -> u64 {
+    let lock = BLK.acquire();
+    let blk = lock.as_mut();
+    assert!(blk.regs != NonNull::dangling());
+    let regs = unsafe { blk.regs.as_mut() };
+    let cfg = get_config(regs);
+    unsafe { (&raw const cfg.capacity).read_volatile() }
+}
+
 pub fn pending_irq() -> bool {
     let lock = BLK.acquire();
     let blk = lock.as_mut();
@@ -262,7 +351,12 @@ pub fn pending_irq() -> bool {
 }
 
 pub fn irq_handle() {
-    let lock = BLK.acquire();
+    // the status stays unacked while someone holds the lock, the device
+    // keeps the line up and the irq comes back
+    let lock = match BLK.try_acquire() {
+        Some(l) => l,
+        _ => return,
+    };
     let blk = lock.as_mut();
     assert!(blk.regs != NonNull::dangling());
     let regs = unsafe { blk.regs.as_mut() };
@@ -272,11 +366,16 @@ pub fn irq_handle() {
         panic!("device config changed.");
     }
 
+    let mut freed = false;
     while let Some((_, data)) = blk.vq.peek_used() {
         if data != 0 {
-            //TODO wake on data here
+            wakeup(data);
         }
         blk.vq.pop_used();
+        freed = true;
+    }
+    if freed {
+        wakeup(blk.desc_chan());
     }
 
     virtio::irq_ack(regs, irq_status);
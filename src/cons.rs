@@ -1,21 +1,26 @@
-use alloc::collections::vec_deque::VecDeque;
+use core::cmp::min;
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
 
 use crate::{
     elf::PT_LOOS,
     fs,
     heap::SyncUnsafeCell,
-    print,
-    sched::{Task, Wq, mycpu, sleep, wakeup},
+    log,
+    sched::{self, sleep, sleep_intr, wakeup},
     spin::Lock,
-    tty,
-    uart::{self, putc},
+    timer,
+    tty::{self, V},
+    uart::{self},
+    virtconsole,
 };
 
 pub struct File {}
 
 impl File {
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
-        Ok(read_line(buf))
+        tty::claim_fg(sched::getpid() as usize);
+        read_line(buf)
     }
 
     pub fn readable(&self) -> bool {
@@ -23,19 +28,15 @@ impl File {
         !lock.as_ref().buf.is_empty()
     }
 
-    pub fn wait4readable(&self) {
-        let task = mycpu().get_task().unwrap();
-        let lock = BUF.acquire();
-        lock.as_mut().wq.add(task as *mut Task);
-    }
-
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, ()> {
+        let mut out = Vec::with_capacity(buf.len());
         for &c in buf {
             if tty::opost() && tty::onlcr() && c == b'\n' {
-                putc(b'\r');
+                out.push(b'\r');
             }
-            putc(c);
+            out.push(c);
         }
+        emit(&out);
         Ok(buf.len())
     }
 
@@ -60,17 +61,32 @@ pub fn open() -> &'static mut File {
 
 struct C {
     buf: VecDeque<u8>,
-    wq: Wq,
 }
 
+// readers sleep on its address, the raw mode VTIME timer wakes them there too
 static BUF: Lock<C> = Lock::new(
     "cons buf",
     C {
         buf: VecDeque::new(),
-        wq: Wq::new("console"),
     },
 );
 
+fn chan() -> u64 {
+    &BUF as *const Lock<C> as u64
+}
+
+// virtio-console when present, pl011 otherwise
+fn emit(buf: &[u8]) {
+    if virtconsole::present() && virtconsole::write(buf).is_ok() {
+        return;
+    }
+    uart::write(buf);
+}
+
+fn putc(c: u8) {
+    emit(&[c]);
+}
+
 fn put_backspace() {
     putc(8);
     putc(32);
@@ -80,12 +96,27 @@ fn put_backspace() {
 pub fn push_char(c: u8) {
     let lock = BUF.acquire();
     let buf = lock.as_mut();
-    print!("P: {} {}\n", c, buf.wq.count);
+    log!(Trace, "P: {}\n", c);
+
+    // goes to the foreground instead of the buffer, along with whatever
+    // was typed before it
+    if let Some(sig) = tty::sig_char(c) {
+        if !tty::noflsh() {
+            buf.buf.clear();
+        }
+        // kill_pid takes locks of its own, the buffer isn't needed for it
+        let fg = tty::fg();
+        drop(lock);
+        if let Some(pid) = fg {
+            sched::kill_pid(pid as i64, sig as u64);
+        }
+        return;
+    }
 
     if !tty::icanon() {
         buf.buf.push_back(c);
-        // wakeup(&BUF as *const Lock<VecDeque<u8>> as u64);
-        buf.wq.wake_all();
+        wakeup(chan());
+        fs::poll_wake();
         return;
     }
 
@@ -106,32 +137,106 @@ pub fn push_char(c: u8) {
             }
             buf.buf.push_back(c);
             if c == 10 {
-                // wakeup(&BUF as *const Lock<VecDeque<u8>> as u64);
-                buf.wq.wake_all();
+                wakeup(chan());
+                fs::poll_wake();
             }
         }
     }
 }
 
-pub fn read_line(buf: &mut [u8]) -> usize {
-    let lock = BUF.acquire();
+pub fn read_line(buf: &mut [u8]) -> Result<usize, ()> {
     if buf.len() == 0 {
-        return 0;
+        return Ok(0);
     }
+    if !tty::icanon() {
+        return read_raw(buf);
+    }
+
+    let lock = BUF.acquire();
     let mut i = 0;
 
     'outer: loop {
         while let Some(c) = lock.as_mut().buf.pop_front() {
             buf[i] = c;
             i += 1;
-            if c == 10 || i == buf.len() || !tty::icanon() {
+            if c == 10 || i == buf.len() {
                 break 'outer;
             }
         }
 
-        // sleep(&BUF as *const Lock<VecDeque<u8>> as u64, lock.get_lock());
-        lock.as_mut().wq.sleep(lock.get_lock());
+        if sleep_intr(chan(), lock.get_lock()).is_err() {
+            // a signal with nothing read yet is EINTR
+            if i == 0 {
+                return Err(());
+            }
+            break;
+        }
     }
 
-    return i;
+    Ok(i)
+}
+
+// non canonical: done at VMIN bytes or VTIME deciseconds. with a VMIN the
+// timer only starts at the first byte, without one it starts right away
+fn read_raw(buf: &mut [u8]) -> Result<usize, ()> {
+    let vmin = tty::cc(V::MIN) as usize;
+    let vtime = tty::cc(V::TIME) as u64;
+    let want = min(vmin, buf.len());
+    let ns = vtime * 100_000_000;
+
+    let lock = BUF.acquire();
+    let mut deadline = if vmin == 0 && vtime > 0 {
+        Some(timer::deadline(ns))
+    } else {
+        None
+    };
+    let mut armed = None;
+    let mut i = 0;
+    let mut seen = 0;
+
+    let res = loop {
+        while i < buf.len() {
+            match lock.as_mut().buf.pop_front() {
+                Some(c) => {
+                    buf[i] = c;
+                    i += 1;
+                }
+                _ => break,
+            }
+        }
+
+        // VMIN 0 VTIME 0 is a plain poll, VMIN 0 with a VTIME waits for one byte
+        if i >= want && (i > 0 || vtime == 0) {
+            break Ok(i);
+        }
+        // with a VMIN VTIME is an inter-byte timer, each byte restarts it
+        if vtime > 0 && vmin > 0 && i > seen {
+            seen = i;
+            deadline = Some(timer::deadline(ns));
+            if let Some(slot) = armed.take() {
+                timer::disarm(slot);
+            }
+        }
+        if deadline.is_some_and(timer::passed) {
+            break Ok(i);
+        }
+        if armed.is_none() {
+            armed = match deadline.map(|d| timer::arm(d, chan())).transpose() {
+                Ok(slot) => slot,
+                // no timer to wait on, as if VTIME ran out
+                _ => break Ok(i),
+            };
+        }
+
+        if sleep_intr(chan(), lock.get_lock()).is_err() {
+            // a signal with nothing read yet is EINTR
+            break if i == 0 { Err(()) } else { Ok(i) };
+        }
+    };
+    drop(lock);
+
+    if let Some(slot) = armed {
+        timer::disarm(slot);
+    }
+    res
 }
@@ -0,0 +1,407 @@
+use crate::{
+    pm::{GB, align_f},
+    spin::Lock,
+};
+
+// flattened device tree, only what the kernel needs is kept. parsed
+// once at boot before vm::init, the blob itself is not kept around
+
+const MAGIC: u32 = 0xd00d_feed;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+const END: u32 = 9;
+
+// the boot tables map the first GB of ram here until vm::init
+const BOOT_RAM: usize = 0x4000_0000;
+const BOOT_VOFFT: usize = 0xffff_0000_0000_0000;
+
+pub const MAX_VIRTIO: usize = 32;
+
+// gicv2 flavours, gic-v3 has a different cpu interface
+const GIC_COMPAT: [&[u8]; 3] = [b"arm,cortex-a15-gic", b"arm,cortex-a9-gic", b"arm,gic-400"];
+
+// psci 0.1 has its own function ids
+const PSCI_COMPAT: [&[u8]; 2] = [b"arm,psci-1.0", b"arm,psci-0.2"];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    pub base: usize,
+    pub irq: u32,
+}
+
+struct Dt {
+    gic_dist: usize,
+    gic_cpu: usize,
+    virtio: [Mmio; MAX_VIRTIO],
+    n_virtio: usize,
+    // (base, size) of the first /memory range
+    mem: Option<(usize, usize)>,
+    // psci conduit, smc when false
+    psci_hvc: bool,
+}
+
+#[derive(Default)]
+struct Node<'a> {
+    compat: &'a [u8],
+    reg: &'a [u8],
+    intr: &'a [u8],
+    dtype: &'a [u8],
+    method: &'a [u8],
+}
+
+impl Dt {
+    const fn new() -> Dt {
+        Dt {
+            gic_dist: 0,
+            gic_cpu: 0,
+            virtio: [Mmio { base: 0, irq: 0 }; MAX_VIRTIO],
+            n_virtio: 0,
+            mem: None,
+            // qemu without el2 or el3
+            psci_hvc: true,
+        }
+    }
+
+    // qemu virt with gic-version=2
+    fn fallback() -> Dt {
+        let mut dt = Dt::new();
+        dt.gic_dist = 0x800_0000;
+        dt.gic_cpu = 0x801_0000;
+        for i in 0..MAX_VIRTIO {
+            dt.virtio[i] = Mmio {
+                base: 0xa00_0000 + 0x200 * i,
+                irq: 0x10 + 32 + i as u32,
+            };
+        }
+        dt.n_virtio = MAX_VIRTIO;
+        dt
+    }
+
+    fn parse(&mut self, blob: &[u8]) -> Option<()> {
+        if be32(blob, 0)? != MAGIC {
+            return None;
+        }
+        let strings = blob.get(be32(blob, 12)? as usize..)?;
+        let mut pos = be32(blob, 8)? as usize;
+        let mut depth = 0;
+        // spec defaults, the root overrides them for its children
+        let (mut acells, mut scells) = (2, 1);
+        let mut node = Node::default();
+
+        loop {
+            let tok = be32(blob, pos)?;
+            pos += 4;
+            match tok {
+                BEGIN_NODE => {
+                    let name = cstr(blob, pos)?;
+                    pos = align_f(pos + name.len() + 1, 4);
+                    depth += 1;
+                    if depth == 2 {
+                        node = Node::default();
+                    }
+                }
+                END_NODE => {
+                    if depth == 0 {
+                        return None;
+                    }
+                    if depth == 2 {
+                        self.add(&node, acells, scells)?;
+                    }
+                    depth -= 1;
+                }
+                PROP => {
+                    let len = be32(blob, pos)? as usize;
+                    let name = cstr(strings, be32(blob, pos + 4)? as usize)?;
+                    let val = blob.get(pos + 8..pos + 8 + len)?;
+                    pos = align_f(pos + 8 + len, 4);
+                    match (depth, name) {
+                        (1, b"#address-cells") => acells = be32(val, 0)? as usize,
+                        (1, b"#size-cells") => scells = be32(val, 0)? as usize,
+                        (2, b"compatible") => node.compat = val,
+                        (2, b"reg") => node.reg = val,
+                        (2, b"interrupts") => node.intr = val,
+                        (2, b"device_type") => node.dtype = val,
+                        (2, b"method") => node.method = val,
+                        _ => {}
+                    }
+                }
+                NOP => {}
+                END => return Some(()),
+                _ => return None,
+            }
+        }
+    }
+
+    // a direct child of the root is done
+    fn add(&mut self, node: &Node, acells: usize, scells: usize) -> Option<()> {
+        if node.dtype == b"memory\0" {
+            if self.mem.is_none() {
+                let base = cells(node.reg, 0, acells)? as usize;
+                let size = cells(node.reg, 4 * acells, scells)? as usize;
+                self.mem = Some((base, size));
+            }
+        } else if has_compat(node.compat, b"virtio,mmio") {
+            if self.n_virtio == MAX_VIRTIO {
+                return Some(());
+            }
+            // <type number flags>, spis start at 32 and ppis at 16
+            let kind = be32(node.intr, 0)?;
+            let irq = be32(node.intr, 4)? + if kind == 1 { 16 } else { 32 };
+            self.virtio[self.n_virtio] = Mmio {
+                base: cells(node.reg, 0, acells)? as usize,
+                irq,
+            };
+            self.n_virtio += 1;
+        } else if GIC_COMPAT.iter().any(|c| has_compat(node.compat, c)) {
+            // distributor first then the cpu interface
+            self.gic_dist = cells(node.reg, 0, acells)? as usize;
+            self.gic_cpu = cells(node.reg, 4 * (acells + scells), acells)? as usize;
+        } else if PSCI_COMPAT.iter().any(|c| has_compat(node.compat, c)) {
+            self.psci_hvc = node.method != b"smc\0";
+        }
+        Some(())
+    }
+
+    // qemu lists the slots top down
+    fn sort(&mut self) {
+        let v = &mut self.virtio[..self.n_virtio];
+        for i in 1..v.len() {
+            let mut j = i;
+            while j > 0 && v[j - 1].base > v[j].base {
+                v.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+}
+
+fn be32(b: &[u8], off: usize) -> Option<u32> {
+    let w = b.get(off..off + 4)?;
+    Some(u32::from_be_bytes([w[0], w[1], w[2], w[3]]))
+}
+
+fn cells(b: &[u8], off: usize, n: usize) -> Option<u64> {
+    let mut v = 0u64;
+    for i in 0..n {
+        v = (v << 32) | be32(b, off + 4 * i)? as u64;
+    }
+    Some(v)
+}
+
+fn cstr(b: &[u8], off: usize) -> Option<&[u8]> {
+    let s = b.get(off..)?;
+    let n = s.iter().position(|&c| c == 0)?;
+    Some(&s[..n])
+}
+
+// compatible is a list of nul terminated strings
+fn has_compat(v: &[u8], want: &[u8]) -> bool {
+    v.split(|&c| c == 0).any(|s| s == want)
+}
+
+fn blob(pa: usize) -> Option<&'static [u8]> {
+    if pa < BOOT_RAM || pa % 8 != 0 || pa + 8 > BOOT_RAM + GB {
+        return None;
+    }
+    let hdr = unsafe { core::slice::from_raw_parts((pa + BOOT_VOFFT) as *const u8, 8) };
+    if be32(hdr, 0)? != MAGIC {
+        return None;
+    }
+    let size = be32(hdr, 4)? as usize;
+    if pa + size > BOOT_RAM + GB {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts((pa + BOOT_VOFFT) as *const u8, size) })
+}
+
+static DT: Lock<Dt> = Lock::new("dt", Dt::new());
+
+// pa is x0 from boot. qemu leaves it zero for elf images and puts the
+// blob at the start of ram instead
+pub fn init(pa: usize) {
+    let lock = DT.acquire();
+    for pa in [pa, BOOT_RAM] {
+        let mut dt = Dt::new();
+        let ok = match blob(pa) {
+            Some(b) => dt.parse(b).is_some() && dt.gic_dist != 0,
+            _ => false,
+        };
+        if ok {
+            dt.sort();
+            *lock.as_mut() = dt;
+            return;
+        }
+    }
+    *lock.as_mut() = Dt::fallback();
+}
+
+// (distributor, cpu interface)
+pub fn gic() -> (usize, usize) {
+    let lock = DT.acquire();
+    let dt = lock.as_ref();
+    (dt.gic_dist, dt.gic_cpu)
+}
+
+// none when there was no usable blob
+pub fn memory() -> Option<(usize, usize)> {
+    let lock = DT.acquire();
+    lock.as_ref().mem
+}
+
+pub fn psci_hvc() -> bool {
+    let lock = DT.acquire();
+    lock.as_ref().psci_hvc
+}
+
+// by base address
+pub fn virtio_mmio(i: usize) -> Option<Mmio> {
+    let lock = DT.acquire();
+    let dt = lock.as_ref();
+    if i < dt.n_virtio {
+        Some(dt.virtio[i])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // just enough of dtc: a header, the struct block and the strings
+    struct Fdt {
+        st: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Fdt {
+        fn new() -> Fdt {
+            Fdt {
+                st: Vec::new(),
+                strings: Vec::new(),
+            }
+        }
+
+        fn tok(&mut self, t: u32) -> &mut Fdt {
+            self.st.extend_from_slice(&t.to_be_bytes());
+            self
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Fdt {
+            self.tok(BEGIN_NODE);
+            self.st.extend_from_slice(name.as_bytes());
+            self.st.push(0);
+            self.st.resize(align_f(self.st.len(), 4), 0);
+            self
+        }
+
+        fn end(&mut self) -> &mut Fdt {
+            self.tok(END_NODE)
+        }
+
+        fn prop(&mut self, name: &str, val: &[u8]) -> &mut Fdt {
+            let off = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.tok(PROP).tok(val.len() as u32).tok(off);
+            self.st.extend_from_slice(val);
+            self.st.resize(align_f(self.st.len(), 4), 0);
+            self
+        }
+
+        fn cells(&mut self, name: &str, v: &[u32]) -> &mut Fdt {
+            let val: Vec<u8> = v.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &val)
+        }
+
+        fn blob(&mut self) -> Vec<u8> {
+            self.tok(END);
+            let off_st = 40;
+            let off_str = off_st + self.st.len();
+            let mut b = Vec::new();
+            for w in [
+                MAGIC,
+                (off_str + self.strings.len()) as u32,
+                off_st as u32,
+                off_str as u32,
+            ] {
+                b.extend_from_slice(&w.to_be_bytes());
+            }
+            b.resize(off_st, 0);
+            b.extend_from_slice(&self.st);
+            b.extend_from_slice(&self.strings);
+            b
+        }
+    }
+
+    fn virt(mem_hi: u32, mem_lo: u32) -> Vec<u8> {
+        let mut f = Fdt::new();
+        f.begin("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .begin("psci")
+            .prop("compatible", b"arm,psci-1.0\0arm,psci-0.2\0arm,psci\0")
+            .prop("method", b"smc\0")
+            .end()
+            .begin("memory@40000000")
+            .prop("device_type", b"memory\0")
+            .cells("reg", &[0, 0x4000_0000, mem_hi, mem_lo])
+            .end()
+            .begin("virtio_mmio@a000200")
+            .prop("compatible", b"virtio,mmio\0")
+            .cells("reg", &[0, 0xa00_0200, 0, 0x200])
+            .cells("interrupts", &[0, 0x11, 1])
+            .end()
+            .begin("virtio_mmio@a000000")
+            .prop("compatible", b"virtio,mmio\0")
+            .cells("reg", &[0, 0xa00_0000, 0, 0x200])
+            .cells("interrupts", &[0, 0x10, 1])
+            .end()
+            .begin("intc@8000000")
+            .prop("compatible", b"arm,cortex-a15-gic\0")
+            .cells(
+                "reg",
+                &[0, 0x800_0000, 0, 0x1_0000, 0, 0x801_0000, 0, 0x1_0000],
+            )
+            // grandchildren don't count as devices
+            .begin("v2m@8020000")
+            .prop("compatible", b"virtio,mmio\0")
+            .end()
+            .end()
+            .end();
+        f.blob()
+    }
+
+    #[test]
+    fn parses_qemu_virt() {
+        let mut dt = Dt::new();
+        assert!(dt.parse(&virt(0, 0x8000_0000)).is_some());
+        dt.sort();
+        assert_eq!((dt.gic_dist, dt.gic_cpu), (0x800_0000, 0x801_0000));
+        assert_eq!(dt.n_virtio, 2);
+        assert_eq!((dt.virtio[0].base, dt.virtio[0].irq), (0xa00_0000, 48));
+        assert_eq!((dt.virtio[1].base, dt.virtio[1].irq), (0xa00_0200, 49));
+        assert_eq!(dt.mem, Some((0x4000_0000, 2 * GB)));
+        assert!(!dt.psci_hvc);
+    }
+
+    #[test]
+    fn memory_above_4g() {
+        let mut dt = Dt::new();
+        assert!(dt.parse(&virt(1, 0)).is_some());
+        assert_eq!(dt.mem, Some((0x4000_0000, 4 * GB)));
+    }
+
+    #[test]
+    fn rejects_bad_blobs() {
+        let mut b = virt(0, 0x8000_0000);
+        assert!(Dt::new().parse(&b[..b.len() / 2]).is_none());
+        b[0] = 0;
+        assert!(Dt::new().parse(&b).is_none());
+        assert!(Dt::new().parse(&[]).is_none());
+    }
+}
@@ -3,6 +3,7 @@ use core::{cmp, mem::MaybeUninit};
 use alloc::boxed::Box;
 
 use crate::{
+    errno::ENOEXEC,
     fs::{self, File, open},
     p9,
     stuff::as_slice_mut,
@@ -68,12 +69,12 @@ pub struct Elf64Hdr {
     machine: Elf64Half,
     version: Elf64Word,
     pub entry: Elf64Addr, /* Entry point virtual address */
-    phoff: Elf64Off,      /* Program header table file offset */
+    pub phoff: Elf64Off,  /* Program header table file offset */
     shoff: Elf64Off,      /* Section header table file offset */
     flags: Elf64Word,
     ehsize: Elf64Half,
-    phentsize: Elf64Half,
-    phnum: Elf64Half,
+    pub phentsize: Elf64Half,
+    pub phnum: Elf64Half,
     shentsize: Elf64Half,
     shnum: Elf64Half,
     shstrndx: Elf64Half,
@@ -134,6 +135,9 @@ impl Elf64Phdr {
     }
 }
 
+// where position independent executables go, between brk and the stack
+const PIE_BASE: u64 = 0x1_0000_0000;
+
 const ELFCLASSNONE: u8 = 0; /* EI_CLASS */
 const ELFCLASS32: u8 = 1;
 const ELFCLASS64: u8 = 2;
@@ -146,45 +150,53 @@ pub struct Elf {
 }
 
 impl Elf {
-    pub fn new(path: &str) -> Result<Elf, ()> {
-        if let Ok(file) = open(path, fs::O::RDONLY as u32, 0) {
-            let mut elf = Elf {
-                header: Elf64Hdr::zeroed(),
-                file,
-                idx: 0,
-            };
-            let buf = as_slice_mut(
-                (&mut elf.header) as *mut Elf64Hdr as *mut u8,
-                size_of::<Elf64Hdr>(),
-            );
-            if let Ok(n) = elf.file.read(buf) {
-                if n != buf.len() {
-                    return Err(());
-                }
-
-                if &elf.header.ident[0..4] != &[0x7fu8, 0x45, 0x4c, 0x46] {
-                    return Err(());
-                }
-
-                if elf.header.ident[EI_VERSION] != 1 {
-                    return Err(());
-                }
-
-                if elf.header.ident[EI_CLASS] != ELFCLASS64 {
-                    return Err(());
-                }
-
-                if elf.header.machine != 183 {
-                    return Err(());
-                }
-
-                if elf.header.kind != ET_EXEC {
-                    return Err(());
-                }
-            }
-            Ok(elf)
+    // what open or read failed with, ENOEXEC for anything that isn't an
+    // aarch64 executable
+    pub fn new(path: &str) -> Result<Elf, i32> {
+        let file = open(path, fs::O::RDONLY, 0)?;
+        let mut elf = Elf {
+            header: Elf64Hdr::zeroed(),
+            file,
+            idx: 0,
+        };
+        let buf = as_slice_mut(
+            (&mut elf.header) as *mut Elf64Hdr as *mut u8,
+            size_of::<Elf64Hdr>(),
+        );
+        if elf.file.read(buf)? != buf.len() {
+            return Err(ENOEXEC);
+        }
+
+        if elf.header.ident[0..4] != [0x7fu8, 0x45, 0x4c, 0x46] {
+            return Err(ENOEXEC);
+        }
+
+        if elf.header.ident[EI_VERSION] != 1 {
+            return Err(ENOEXEC);
+        }
+
+        if elf.header.ident[EI_CLASS] != ELFCLASS64 {
+            return Err(ENOEXEC);
+        }
+
+        if elf.header.machine != 183 {
+            return Err(ENOEXEC);
+        }
+
+        if elf.header.kind != ET_EXEC && elf.header.kind != ET_DYN {
+            return Err(ENOEXEC);
+        }
+        Ok(elf)
+    }
+}
+
+impl Elf {
+    // added to every vaddr in the file, entry included
+    pub fn bias(&self) -> u64 {
+        if self.header.kind == ET_DYN {
+            PIE_BASE
         } else {
-            return Err(());
+            0
         }
     }
 }
@@ -10,14 +10,20 @@ use core::{
 use alloc::{str, string::String, vec::Vec};
 
 use crate::{
+    blk,
     cons::{self},
+    errno::{
+        EAGAIN, EBADF, EEXIST, EFAULT, EINTR, EINVAL, EIO, EISDIR, EMFILE, ENFILE, ENOENT, ENOMEM,
+        ENOSYS, ENOTDIR, ENXIO, EPIPE, ERANGE, EROFS, ESPIPE, err,
+    },
     heap::SyncUnsafeCell,
-    p9, print, ptr2mut, ptr2ref, ptr2ref_op, rtc,
-    sched::{Task, mycpu, sleep_if},
+    log, p9, pipe, ptr2mut, ptr2ref, ptr2ref_op, rng, rtc,
+    sched::{self, RLIMIT, Task, mycpu, sleep_intr, wakeup},
+    shm,
     spin::Lock,
     stuff::{as_slice, as_slice_mut, cstr_as_slice},
     timer,
-    tty::{self, Termios, Winsize},
+    tty::{self, Termios, Termios2, Winsize},
 };
 
 pub enum FileKind {
@@ -25,6 +31,88 @@ pub enum FileKind {
     Used,
     P9(&'static mut p9::File),
     Cons(&'static mut cons::File),
+    Shm(&'static mut shm::File),
+    Pipe(pipe::File),
+    Dev(DevKind),
+    Blk,
+}
+
+const BLK_PATH: &str = "/dev/vda";
+
+// user buffers aren't device visible, go one sector at a time through this
+#[repr(C, align(512))]
+struct Sector([u8; blk::SECTOR]);
+
+// first sector and sector count within the disk, both must be aligned
+fn blk_span(offt: u64, len: usize) -> Result<(u64, usize), i32> {
+    let ss = blk::SECTOR;
+    if offt as usize % ss != 0 || len % ss != 0 {
+        return Err(EINVAL);
+    }
+    let first = offt / ss as u64;
+    let left = blk::capacity().saturating_sub(first);
+    Ok((first, min((len / ss) as u64, left) as usize))
+}
+
+fn blk_read(offt: u64, buf: &mut [u8]) -> Result<usize, i32> {
+    let (first, n) = blk_span(offt, buf.len())?;
+    let mut sector = Sector([0; blk::SECTOR]);
+    for (i, chunk) in buf.chunks_mut(blk::SECTOR).take(n).enumerate() {
+        blk::read(first + i as u64, &mut sector.0).map_err(|_| EIO)?;
+        chunk.copy_from_slice(&sector.0);
+    }
+    Ok(n * blk::SECTOR)
+}
+
+fn blk_write(offt: u64, buf: &[u8]) -> Result<usize, i32> {
+    if blk::read_only() {
+        return Err(EROFS);
+    }
+    let (first, n) = blk_span(offt, buf.len())?;
+    let mut sector = Sector([0; blk::SECTOR]);
+    for (i, chunk) in buf.chunks(blk::SECTOR).take(n).enumerate() {
+        sector.0.copy_from_slice(chunk);
+        blk::write(first + i as u64, &sector.0).map_err(|_| EIO)?;
+    }
+    Ok(n * blk::SECTOR)
+}
+
+// character devices that need no backing state
+#[derive(Debug, Clone, Copy)]
+pub enum DevKind {
+    Null,
+    Zero,
+}
+
+impl DevKind {
+    fn from_path(path: &str) -> Option<DevKind> {
+        match path {
+            "/dev/null" => Some(DevKind::Null),
+            "/dev/zero" => Some(DevKind::Zero),
+            _ => None,
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> usize {
+        match self {
+            DevKind::Null => 0,
+            DevKind::Zero => {
+                buf.fill(0);
+                buf.len()
+            }
+        }
+    }
+
+    fn stat(&self, stat: &mut Stat) {
+        *stat = Stat::default();
+        stat.st_nlink = 1;
+        stat.st_mode = 0o020666;
+        // major 1, minor 3 and 5 as on linux
+        stat.st_rdev = match self {
+            DevKind::Null => 0x103,
+            DevKind::Zero => 0x105,
+        };
+    }
 }
 
 pub struct File {
@@ -32,6 +120,8 @@ pub struct File {
     rc: AtomicU16,
     offt: u64,
     path: Option<String>,
+    // O_* it was opened with, F_SETFL changes the SETFL subset
+    flags: u32,
 }
 
 pub struct Seek;
@@ -48,12 +138,14 @@ impl File {
             rc: AtomicU16::new(0),
             offt: 0,
             path: None,
+            flags: 0,
         }
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+    // Err is a positive errno
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, i32> {
         if self.rc.load(Ordering::Acquire) == 0 {
-            return Err(());
+            return Err(EBADF);
         }
         match &mut self.kind {
             FileKind::P9(p9f) => {
@@ -61,30 +153,56 @@ impl File {
                     self.offt = self.offt.wrapping_add(n as u64);
                     Ok(n)
                 } else {
-                    Err(())
+                    Err(EIO)
                 }
             }
-            FileKind::Cons(c) => c.read(buf),
+            FileKind::Cons(c) => c.read(buf).map_err(|_| EINTR),
+            FileKind::Pipe(p) => p.read(buf),
+            FileKind::Dev(dev) => Ok(dev.read(buf)),
+            FileKind::Blk => {
+                let n = blk_read(self.offt, buf)?;
+                self.offt += n as u64;
+                Ok(n)
+            }
+            FileKind::Shm(shm) => {
+                let n = shm.read(buf, self.offt as usize).map_err(|_| EIO)?;
+                self.offt = self.offt.wrapping_add(n as u64);
+                Ok(n)
+            }
             _ => {
                 panic!("read: unhandled file kind.")
             }
         }
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize, ()> {
+    // Err is a positive errno
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, i32> {
         if self.rc.load(Ordering::Acquire) == 0 {
-            return Err(());
+            return Err(EBADF);
+        }
+        // every write lands at the end, whatever the offset was
+        if self.flags & O::APPEND != 0 {
+            self.offt = self.get_size();
         }
         match &mut self.kind {
             FileKind::P9(p9f) => {
-                if let Ok(n) = p9f.write(buf, self.offt as usize) {
-                    self.offt = self.offt.wrapping_add(n as u64);
-                    Ok(n)
-                } else {
-                    Err(())
-                }
+                let n = p9f.write(buf, self.offt as usize)?;
+                self.offt = self.offt.wrapping_add(n as u64);
+                Ok(n)
+            }
+            FileKind::Cons(c) => c.write(buf).map_err(|_| EIO),
+            FileKind::Pipe(p) => p.write(buf),
+            FileKind::Dev(_) => Ok(buf.len()),
+            FileKind::Blk => {
+                let n = blk_write(self.offt, buf)?;
+                self.offt += n as u64;
+                Ok(n)
+            }
+            FileKind::Shm(shm) => {
+                let n = shm.write(buf, self.offt as usize).map_err(|_| EIO)?;
+                self.offt = self.offt.wrapping_add(n as u64);
+                Ok(n)
             }
-            FileKind::Cons(c) => c.write(buf),
             _ => {
                 panic!("write: unhandled file kind.")
             }
@@ -103,14 +221,35 @@ impl File {
             Ordering::Relaxed,
         ) {
             match &mut self.kind {
+                // the fid is clunked even when it fails, nothing to retry
                 FileKind::P9(p9f) => {
-                    return if let Ok(_) = p9f.close() {
-                        print!(
-                            "CLOSE: {} {:?} {}\n",
-                            self.rc.load(Ordering::Acquire),
-                            self.path,
-                            p9f.fid
-                        );
+                    let res = p9f.close();
+                    log!(
+                        Debug,
+                        "CLOSE: {} {:?} {} {:?}\n",
+                        self.rc.load(Ordering::Acquire),
+                        self.path,
+                        p9f.fid,
+                        res
+                    );
+                    self.kind = FileKind::None;
+                    self.path = None;
+                    return res.map_err(|_| ());
+                }
+                FileKind::Cons(cons) => {}
+                FileKind::Pipe(p) => {
+                    p.close();
+                    self.kind = FileKind::None;
+                    self.path = None;
+                    return Ok(());
+                }
+                FileKind::Dev(_) | FileKind::Blk => {
+                    self.kind = FileKind::None;
+                    self.path = None;
+                    return Ok(());
+                }
+                FileKind::Shm(shm) => {
+                    return if let Ok(_) = shm.close() {
                         self.kind = FileKind::None;
                         self.path = None;
                         Ok(())
@@ -119,7 +258,6 @@ impl File {
                         Err(())
                     };
                 }
-                FileKind::Cons(cons) => {}
                 _ => panic!("write: unhandled file kind."),
             }
         } else {
@@ -133,12 +271,8 @@ impl File {
         self.offt = offt as u64;
     }
 
-    pub fn seek_by(&mut self, offt: i32) {
-        self.offt = if offt > 0 {
-            self.offt.wrapping_add(offt as u64)
-        } else {
-            self.offt.wrapping_sub(offt as u64)
-        };
+    pub fn seek_by(&mut self, offt: i64) {
+        self.offt = self.offt.wrapping_add_signed(offt);
     }
 
     pub fn get_size(&mut self) -> u64 {
@@ -147,26 +281,74 @@ impl File {
             FileKind::Used => 0,
             FileKind::P9(file) => file.get_size(),
             FileKind::Cons(file) => file.get_size(),
+            FileKind::Shm(file) => file.get_size(),
+            FileKind::Pipe(file) => file.get_size(),
+            FileKind::Dev(_) => 0,
+            FileKind::Blk => blk::capacity() * blk::SECTOR as u64,
         }
     }
 
-    pub fn lseek(&mut self, offt: i64, whence: u64) -> Result<u64, ()> {
-        match whence {
-            Seek::SET => self.seek_to(offt as usize),
+    // push buffered writes out to the backing file
+    pub fn flush(&mut self) -> Result<(), ()> {
+        match &mut self.kind {
+            FileKind::P9(p9f) => p9f.flush().map_err(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    // flush and have the server commit it, a no-op for anything but 9p.
+    // Err is a positive errno
+    pub fn fsync(&mut self, datasync: bool) -> Result<(), i32> {
+        match &mut self.kind {
+            FileKind::P9(p9f) => p9f.fsync(datasync),
+            FileKind::Blk => blk::flush().map_err(|_| EIO),
+            _ => Ok(()),
+        }
+    }
+
+    // Err is a positive errno
+    pub fn lseek(&mut self, offt: i64, whence: u64) -> Result<u64, i32> {
+        if let FileKind::Cons(_) | FileKind::Pipe(_) = self.kind {
+            return Err(ESPIPE);
+        }
+        // a failure waits for the next write or fsync
+        let _ = self.flush();
+        let base = match whence {
+            Seek::SET => 0,
+            Seek::CUR => self.offt as i64,
             Seek::END => {
-                let offt = self.get_size().wrapping_sub(offt as u64) as usize;
-                self.seek_to(offt)
+                // ask the server, someone else may have grown it
+                let mut st = Stat::default();
+                match self.fstat(&mut st) {
+                    Ok(_) => st.st_size,
+                    Err(_) => self.get_size() as i64,
+                }
             }
-            Seek::CUR => self.seek_by(offt as i32),
-            _ => return Err(()),
+            _ => return Err(EINVAL),
         };
 
-        Ok(self.offt)
+        match base.checked_add(offt) {
+            Some(pos) if pos >= 0 => {
+                self.seek_to(pos as usize);
+                Ok(self.offt)
+            }
+            _ => Err(EINVAL),
+        }
+    }
+
+    // the status flags F_SETFL may change
+    pub fn set_flags(&mut self, flags: u32) {
+        let keep = self.flags & !O::SETFL;
+        self.flags = keep | (flags & O::SETFL);
+        if let FileKind::Pipe(p) = &mut self.kind {
+            p.set_nonblock(flags & O::NONBLOCK != 0);
+        }
     }
 
     pub fn dup(&mut self) -> Option<&'static mut Self> {
         self.rc.fetch_add(1, Ordering::Release);
-        print!(
+        log!(
+            Debug,
             "DUP: {:?} rc {}\n",
             self.path,
             self.rc.load(Ordering::Relaxed)
@@ -197,6 +379,22 @@ impl File {
         match &self.kind {
             FileKind::P9(p9) => p9.stat(stat),
             FileKind::Cons(c) => c.stat(stat),
+            FileKind::Shm(shm) => shm.stat(stat),
+            FileKind::Pipe(p) => p.stat(stat),
+            FileKind::Dev(dev) => {
+                dev.stat(stat);
+                Ok(())
+            }
+            FileKind::Blk => {
+                *stat = Stat::default();
+                stat.st_nlink = 1;
+                stat.st_mode = 0o060660;
+                // major 254 is virtblk
+                stat.st_rdev = 254 << 8;
+                stat.st_size = (blk::capacity() * blk::SECTOR as u64) as i64;
+                stat.st_blksize = blk::SECTOR as _;
+                Ok(())
+            }
             FileKind::None => panic!("fstat: none"),
             FileKind::Used => panic!("fstat: used"),
             _ => panic!("fstat: unhandled file kind."),
@@ -206,14 +404,11 @@ impl File {
     pub fn getdents64(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
         match &self.kind {
             FileKind::P9(p9) => {
-                if let Ok((n, offt)) = p9.getdents64(buf, self.offt) {
-                    self.offt = offt as u64;
-                    Ok(n)
-                } else {
-                    Err(())
-                }
+                let (n, offt) = p9.getdents64(buf, self.offt)?;
+                self.offt = offt;
+                Ok(n)
             }
-            _ => panic!("fstat: unhandled file kind."),
+            _ => Err(()),
         }
     }
 
@@ -241,15 +436,21 @@ impl File {
             FileKind::Used => false,
             FileKind::P9(file) => true,
             FileKind::Cons(file) => file.readable(),
+            FileKind::Shm(_) => true,
+            FileKind::Pipe(file) => file.readable(),
+            FileKind::Dev(_) | FileKind::Blk => true,
         }
     }
 
     pub fn writeable(&self) -> bool {
-        match self.kind {
+        match &self.kind {
             FileKind::None => false,
             FileKind::Used => false,
             FileKind::P9(_) => true,
             FileKind::Cons(_) => true,
+            FileKind::Shm(_) => true,
+            FileKind::Pipe(file) => file.writeable(),
+            FileKind::Dev(_) | FileKind::Blk => true,
         }
     }
 
@@ -259,18 +460,24 @@ impl File {
             FileKind::Used => false,
             FileKind::P9(_) => true,
             FileKind::Cons(_) => true,
+            FileKind::Shm(_) => true,
+            FileKind::Pipe(_) => true,
+            FileKind::Dev(_) | FileKind::Blk => true,
         }
     }
 
     pub fn hanged_up(&self) -> bool {
-        false
+        match &self.kind {
+            FileKind::Pipe(file) => file.hanged_up(),
+            _ => false,
+        }
     }
 
-    pub fn wait4readable(&self) {
+    // backing page of a shared memory object, for mmap
+    pub fn shm_page(&self, idx: usize) -> Option<usize> {
         match &self.kind {
-            FileKind::P9(file) => {}
-            FileKind::Cons(file) => file.wait4readable(),
-            x => panic!("unhandled file kind."),
+            FileKind::Shm(shm) => shm.page(idx),
+            _ => None,
         }
     }
 }
@@ -281,11 +488,60 @@ struct Fs {
     files: [File; NFILES],
 }
 
-pub fn open(path: &str, flags: u32, _: u32) -> Result<&'static mut File, ()> {
+// Err is a positive errno
+pub fn open(path: &str, flags: u32, mode: u32) -> Result<&'static mut File, i32> {
+    if shm::is_shm(path) {
+        return open_shm(path, flags).map_err(|_| ENOENT);
+    }
+
+    if path == BLK_PATH {
+        if !blk::present() {
+            return Err(ENXIO);
+        }
+        if flags & (O::WRONLY | O::RDWR) != 0 && blk::read_only() {
+            return Err(EROFS);
+        }
+        let (_, file) = alloc_file().ok_or(ENFILE)?;
+        file.kind = FileKind::Blk;
+        file.rc = AtomicU16::new(1);
+        file.path = Some(String::from(path));
+        file.offt = 0;
+        return Ok(file);
+    }
+
+    if let Some(dev) = DevKind::from_path(path) {
+        let (_, file) = alloc_file().ok_or(ENFILE)?;
+        file.kind = FileKind::Dev(dev);
+        file.rc = AtomicU16::new(1);
+        file.path = Some(String::from(path));
+        file.offt = 0;
+        return Ok(file);
+    }
+
+    if let Some((idx, file)) = alloc_file() {
+        return match p9::open(path, flags, mode) {
+            Ok(p9file) => {
+                log!(Debug, "OPEN: path {} fid = {}\n", path, p9file.fid);
+                file.kind = FileKind::P9(p9file);
+                file.rc = AtomicU16::new(1);
+                file.path = Some(String::from(path));
+                file.offt = 0;
+                Ok(file)
+            }
+            Err(e) => {
+                free_file(idx);
+                Err(e)
+            }
+        };
+    }
+
+    Err(ENFILE)
+}
+
+fn open_shm(path: &str, flags: u32) -> Result<&'static mut File, ()> {
     if let Some((idx, file)) = alloc_file() {
-        return if let Ok(p9file) = p9::open(path, flags) {
-            print!("OPEN: path {} fid = {}\n", path, p9file.fid);
-            file.kind = FileKind::P9(p9file);
+        return if let Ok(shmf) = shm::open(path, flags) {
+            file.kind = FileKind::Shm(shmf);
             file.rc = AtomicU16::new(1);
             file.path = Some(String::from(path));
             file.offt = 0;
@@ -303,38 +559,56 @@ pub fn open_cons() -> Result<&'static mut File, ()> {
     if let Some((_, file)) = alloc_file() {
         file.kind = FileKind::Cons(cons::open());
         file.rc = AtomicU16::new(1);
+        file.flags = O::RDWR;
         Ok(file)
     } else {
         Err(())
     }
 }
 
+// linux's MAX_RW_COUNT
+const MAX_RW: u64 = 0x7fff_f000;
+// ttbr0 covers the low 48 bits
+const USER_END: u64 = 1 << 48;
+
+// every read/write length from userspace goes through here
+fn rw_len(ptr: u64, len: u64) -> Result<usize, u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+    if ptr == 0 {
+        return Err(err(EFAULT));
+    }
+    match ptr.checked_add(len) {
+        Some(end) if end <= USER_END => Ok(min(len, MAX_RW) as usize),
+        _ => Err(err(EINVAL)),
+    }
+}
+
 pub fn sys_write() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
     let fd = tf.regs[0] as usize;
-    if fd >= task.files.len() {
-        return !0;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
-    if task.files[fd].is_none() {
-        return !0;
-    }
-
-    let len = tf.regs[2] as usize;
     let ptr = tf.regs[1];
+    let len = match rw_len(ptr, tf.regs[2]) {
+        Ok(0) => return 0,
+        Ok(len) => len,
+        Err(e) => return e,
+    };
 
-    let file = task.files[fd].as_mut().unwrap();
+    let file = fdt.files[fd].as_mut().unwrap();
 
-    if ptr == 0 {
-        return !0;
-    }
     // i trust you user
     let buf = as_slice(ptr as *const u8, len);
-    if let Ok(n) = file.write(buf) {
-        n as u64
-    } else {
-        !0
+    match file.write(buf) {
+        Ok(n) => n as u64,
+        Err(EPIPE) => sched::sigpipe(),
+        Err(e) => err(e),
     }
 }
 
@@ -346,22 +620,19 @@ struct IOvec {
 pub fn sys_writev() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
     let fd = tf.regs[0] as usize;
-    if fd >= task.files.len() {
-        return !0;
-    }
-
-    if task.files[fd].is_none() {
-        return !0;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
     let iovec_len = tf.regs[2] as usize;
     let ptr = tf.regs[1];
 
-    let file = task.files[fd].as_mut().unwrap();
+    let file = fdt.files[fd].as_mut().unwrap();
 
     if ptr == 0 {
-        return !0;
+        return err(EFAULT);
     }
 
     let iovec_buf = as_slice(ptr as *const IOvec, iovec_len);
@@ -369,11 +640,17 @@ pub fn sys_writev() -> u64 {
     let mut written = 0;
     for i in 0..iovec_len {
         let iovec = &iovec_buf[i];
-        let buf = as_slice(iovec.ptr, iovec.len);
-        if let Ok(n) = file.write(buf) {
-            written += n as u64
-        } else {
-            return !0;
+        let len = match rw_len(iovec.ptr as u64, iovec.len as u64) {
+            Ok(0) => continue,
+            Ok(len) => len,
+            Err(e) => return e,
+        };
+        let buf = as_slice(iovec.ptr, len);
+        match file.write(buf) {
+            Ok(n) => written += n as u64,
+            Err(_) if written > 0 => break,
+            Err(EPIPE) => return sched::sigpipe(),
+            Err(e) => return err(e),
         }
     }
 
@@ -383,17 +660,26 @@ pub fn sys_writev() -> u64 {
 pub fn getcwd() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
-    let cwd = task.cwd.as_ref().unwrap();
-    let len = min(tf.regs[1] as usize, cwd.as_bytes().len());
-    let buf = as_slice_mut(tf.regs[0] as *mut u8, len + 1);
-    print!("CWD = {}\n", cwd);
-    buf[0..cwd.as_bytes().len()].copy_from_slice(&cwd.as_bytes());
-    if tf.regs[1] as usize > cwd.len() {
-        buf[cwd.len()] = 0;
+    let cwd = task.cwd.as_deref().unwrap_or("/");
+    log!(Debug, "CWD = {}\n", cwd);
+    // chdir keeps a trailing slash
+    let cwd = if cwd.len() > 1 {
+        cwd.trim_end_matches('/')
     } else {
-        return -78i64 as u64;
-    }
-    0
+        cwd
+    };
+    // with the nul
+    let len = cwd.len() + 1;
+    if (tf.regs[1] as usize) < len {
+        return err(ERANGE);
+    }
+    if tf.regs[0] == 0 {
+        return err(EFAULT);
+    }
+    let buf = as_slice_mut(tf.regs[0] as *mut u8, len);
+    buf[..cwd.len()].copy_from_slice(cwd.as_bytes());
+    buf[cwd.len()] = 0;
+    len as u64
 }
 
 pub fn chdir() -> u64 {
@@ -403,12 +689,24 @@ pub fn chdir() -> u64 {
     let path = cstr_as_slice(tf.regs[0] as *const u8);
     let path_str = String::from(str::from_utf8(path).unwrap());
 
-    if exists(&path_str) {
-        task.cwd = Some(path_str);
-        0
-    } else {
-        -2i64 as u64
+    let path_str = match at_path(AT_FDCWD as u64, path_str, task) {
+        Ok(p) => p,
+        _ => return err(ENOENT),
+    };
+
+    match is_dir(&path_str) {
+        Ok(true) => {}
+        Ok(false) => return err(ENOTDIR),
+        Err(e) => return err(e),
     }
+
+    // at_path joins onto it as is
+    let mut cwd = path_str;
+    if !cwd.ends_with('/') {
+        cwd.push('/');
+    }
+    task.cwd = Some(cwd);
+    0
 }
 
 pub fn umask() -> u64 {
@@ -422,58 +720,53 @@ pub fn umask() -> u64 {
 pub fn getdents64() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
     let fd = tf.regs[0] as usize;
-    if fd >= task.files.len() {
-        return !0;
-    }
-
-    if task.files[fd].is_none() {
-        return !0;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
     let len = tf.regs[2] as usize;
     let ptr = tf.regs[1];
 
-    let file = task.files[fd].as_mut().unwrap();
+    let file = fdt.files[fd].as_mut().unwrap();
 
     if ptr == 0 {
-        return !0;
+        return err(EFAULT);
     }
 
     let buf = as_slice_mut(ptr as *mut u8, len);
     if let Ok(n) = file.getdents64(buf) {
         n as u64
     } else {
-        !0
+        // buffer too small for the next entry
+        err(EINVAL)
     }
 }
 
 pub fn sys_read() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
     let fd = tf.regs[0] as usize;
-    if fd >= task.files.len() {
-        return !0;
-    }
-
-    if task.files[fd].is_none() {
-        return !0;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
-    let len = tf.regs[2] as usize;
     let ptr = tf.regs[1];
+    let len = match rw_len(ptr, tf.regs[2]) {
+        Ok(0) => return 0,
+        Ok(len) => len,
+        Err(e) => return e,
+    };
 
-    let file = task.files[fd].as_mut().unwrap();
+    let file = fdt.files[fd].as_mut().unwrap();
 
-    if ptr == 0 {
-        return !0;
-    }
     // i trust you user
     let buf = as_slice_mut(ptr as *mut u8, len);
-    if let Ok(n) = file.read(buf) {
-        n as u64
-    } else {
-        !0
+    match file.read(buf) {
+        Ok(n) => n as u64,
+        Err(e) => err(e),
     }
 }
 
@@ -486,25 +779,29 @@ pub fn readlinkat() -> u64 {
     let path = cstr_as_slice(tf.regs[1] as *const u8);
     let path_str = String::from(str::from_utf8(path).unwrap());
 
-    let buf = as_slice_mut(tf.regs[2] as *mut u8, tf.regs[3] as usize);
+    let bufsiz = tf.regs[3] as usize;
+    if bufsiz == 0 || bufsiz > i32::MAX as usize {
+        return err(EINVAL);
+    }
+    let buf = as_slice_mut(tf.regs[2] as *mut u8, bufsiz);
 
     let real_path = if let Ok(path) = at_path(fd, path_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    print!("READ LINK AT: {}\n", real_path);
+    log!(Debug, "READ LINK AT: {}\n", real_path);
 
-    if let Ok(n) = readlink(&real_path, buf) {
-        n as u64
-    } else {
-        -2i64 as u64
+    match readlink(&real_path, buf) {
+        Ok(Some(n)) => n as u64,
+        Ok(None) => err(EINVAL),
+        Err(e) => err(e),
     }
 }
 
-pub fn symlink(linkname: &str, path: &str) -> Result<(), ()> {
-    p9::symlink(linkname, path)
+pub fn symlink(linkname: &str, path: &str) -> Result<(), i32> {
+    p9::symlink(linkname, path).map(|_| ())
 }
 
 fn cpystr(buf: &mut [u8], s: &str) -> usize {
@@ -514,16 +811,13 @@ fn cpystr(buf: &mut [u8], s: &str) -> usize {
     n
 }
 
-pub fn readlink(path: &str, buf: &mut [u8]) -> Result<usize, ()> {
+// truncated to buf and not nul terminated, None if path isn't a link
+pub fn readlink(path: &str, buf: &mut [u8]) -> Result<Option<usize>, i32> {
     if path == "/proc/self/fd/0" {
-        return Ok(cpystr(buf, "/dev/tty"));
+        return Ok(Some(cpystr(buf, "/dev/tty")));
     }
 
-    if let Ok(str) = p9::readlink(path) {
-        Ok(cpystr(buf, &str))
-    } else {
-        Err(())
-    }
+    Ok(p9::readlink(path)?.map(|target| cpystr(buf, &target)))
 }
 
 pub fn symlinkat() -> u64 {
@@ -541,25 +835,60 @@ pub fn symlinkat() -> u64 {
     let real_path = if let Ok(path) = at_path(fd, path_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    print!("SYM LINK AT: old {} path {}\n", oldname_str, real_path);
+    log!(
+        Debug,
+        "SYM LINK AT: old {} path {}\n",
+        oldname_str,
+        real_path
+    );
 
-    if let Ok(_) = symlink(&oldname_str, &real_path) {
-        0
-    } else {
-        !0
+    if oldname_str.is_empty() {
+        return err(ENOENT);
+    }
+
+    if exists(&real_path) {
+        return err(EEXIST);
+    }
+
+    match symlink(&oldname_str, &real_path) {
+        Ok(()) => 0,
+        Err(e) => err(e),
     }
 }
 
-pub fn rename(from: &str, to: &str) -> Result<(), ()> {
+// Err is a positive errno
+pub fn rename(from: &str, to: &str) -> Result<(), i32> {
     p9::rename(from, to)
 }
 
+pub struct RENAME;
+impl RENAME {
+    pub const NOREPLACE: u64 = 1 << 0;
+    pub const EXCHANGE: u64 = 1 << 1;
+    pub const WHITEOUT: u64 = 1 << 2;
+}
+
 pub fn renameat() -> u64 {
+    renameat_inner(0)
+}
+
+pub fn renameat2() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    renameat_inner(tf.regs[4])
+}
+
+fn renameat_inner(flags: u64) -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    // Trenameat has no way to say these
+    if flags & !RENAME::NOREPLACE != 0 {
+        return err(EINVAL);
+    }
 
     let oldfd = tf.regs[0];
     let newfd = tf.regs[2];
@@ -573,25 +902,33 @@ pub fn renameat() -> u64 {
     let real_oldpath = if let Ok(path) = at_path(oldfd, oldpath_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
     let real_newpath = if let Ok(path) = at_path(newfd, newpath_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    print!("RENAMEAT: old {} new {}\n", real_oldpath, real_newpath);
+    log!(
+        Debug,
+        "RENAMEAT: old {} new {}\n",
+        real_oldpath,
+        real_newpath
+    );
 
-    if rename(&real_oldpath, &real_newpath).is_ok() {
-        0
-    } else {
-        -2i64 as u64
+    if flags & RENAME::NOREPLACE != 0 && exists(&real_newpath) {
+        return err(EEXIST);
+    }
+
+    match rename(&real_oldpath, &real_newpath) {
+        Ok(()) => 0,
+        Err(e) => err(e),
     }
 }
 
-pub fn link(from: &str, to: &str, follow: bool) -> Result<(), ()> {
+pub fn link(from: &str, to: &str, follow: bool) -> Result<(), i32> {
     p9::link(from, to, follow)
 }
 
@@ -611,56 +948,94 @@ pub fn linkat() -> u64 {
     let real_oldpath = if let Ok(path) = at_path(oldfd, oldpath_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
     let real_newpath = if let Ok(path) = at_path(newfd, newpath_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    print!("LINKAT: old {} new {}\n", real_oldpath, real_newpath);
+    log!(Debug, "LINKAT: old {} new {}\n", real_oldpath, real_newpath);
 
-    if link(
+    if exists(&real_newpath) {
+        return err(EEXIST);
+    }
+
+    match link(
         &real_oldpath,
         &real_newpath,
         tf.regs[4] & SYMLINK_FOLLOW != 0,
-    )
-    .is_ok()
-    {
-        0
-    } else {
-        -2i64 as u64
+    ) {
+        Ok(()) => 0,
+        Err(e) => err(e),
     }
 }
 
+pub struct GRND;
+impl GRND {
+    pub const NONBLOCK: u64 = 1;
+    pub const RANDOM: u64 = 2;
+    pub const INSECURE: u64 = 4;
+}
+
 pub fn getrandom() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
-    tf.regs[1]
+
+    let ptr = tf.regs[0];
+    let flags = tf.regs[2];
+    if flags & !(GRND::NONBLOCK | GRND::RANDOM | GRND::INSECURE) != 0 {
+        return err(EINVAL);
+    }
+
+    let len = match rw_len(ptr, tf.regs[1]) {
+        Ok(0) => return 0,
+        Ok(len) => len,
+        Err(e) => return e,
+    };
+
+    // without the device all there is is counter noise
+    if !rng::present() && flags & GRND::NONBLOCK != 0 {
+        return err(EAGAIN);
+    }
+
+    // the device writes physical memory, bounce through the kernel stack
+    let buf = as_slice_mut(ptr as *mut u8, len);
+    let mut chunk = [0u8; 256];
+    for out in buf.chunks_mut(chunk.len()) {
+        let tmp = &mut chunk[0..out.len()];
+        rng::fill(tmp);
+        out.copy_from_slice(tmp);
+    }
+
+    len as u64
 }
 
 pub fn lseek() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
 
     let fd = tf.regs[0] as usize;
-    print!(
+    log!(
+        Trace,
         "LSEEK FD {} offt {} whence {}\n",
-        fd, tf.regs[1] as i64, tf.regs[2]
+        fd,
+        tf.regs[1] as i64,
+        tf.regs[2]
     );
 
-    if task.files[fd].is_none() {
-        return -2i64 as u64;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
-    let file = task.files[fd].as_mut().unwrap();
+    let file = fdt.files[fd].as_mut().unwrap();
 
-    if let Ok(offt) = file.lseek(tf.regs[1] as i64, tf.regs[2]) {
-        offt
-    } else {
-        return -29i64 as u64;
+    match file.lseek(tf.regs[1] as i64, tf.regs[2]) {
+        Ok(offt) => offt,
+        Err(e) => err(e),
     }
 }
 
@@ -707,23 +1082,44 @@ impl T {
     pub const IOCSBRK: u64 = 0x5427;
     pub const IOCCBRK: u64 = 0x5428;
     pub const IOCGSID: u64 = 0x5429;
+    pub const CGETS2: u64 = 0x802c_542a;
+    pub const CSETS2: u64 = 0x402c_542b;
 }
 
 pub fn ioctl() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
 
-    print!("IOCTL {:x} 0x{:x}\n", tf.regs[0], tf.regs[1]);
+    log!(Debug, "IOCTL {:x} 0x{:x}\n", tf.regs[0], tf.regs[1]);
 
     match (tf.regs[1]) {
+        T::CGETS | T::CSETS
+            if !sched::user_range_ok(task, tf.regs[2] as usize, size_of::<Termios>()) =>
+        {
+            err(EFAULT)
+        }
+        T::CGETS2 | T::CSETS2
+            if !sched::user_range_ok(task, tf.regs[2] as usize, size_of::<Termios2>()) =>
+        {
+            err(EFAULT)
+        }
         T::CGETS => tty::get_termios(tf.regs[2] as *mut Termios),
         T::CSETS => tty::set_termios(tf.regs[2] as *const Termios),
+        T::CGETS2 => tty::get_termios2(tf.regs[2] as *mut Termios2),
+        T::CSETS2 => tty::set_termios2(tf.regs[2] as *const Termios2),
         T::IOCGWINSZ => tty::get_winsz(tf.regs[2] as *mut Winsize),
+        T::IOCGPGRP | T::IOCSPGRP if !sched::user_range_ok(task, tf.regs[2] as usize, 4) => {
+            err(EFAULT)
+        }
         T::IOCGPGRP => {
-            unsafe { *(tf.regs[2] as *mut u32) = task.pid as u32 };
+            let pid = tty::fg().unwrap_or(task.pid as usize);
+            unsafe { *(tf.regs[2] as *mut u32) = pid as u32 };
+            0
+        }
+        T::IOCSPGRP => {
+            tty::set_fg(unsafe { *(tf.regs[2] as *const u32) } as usize);
             0
         }
-        T::IOCSPGRP => 0,
         x => panic!("unimplemented ioctl 0x{:x}", x),
     }
 }
@@ -749,6 +1145,9 @@ impl O {
     pub const CLOEXEC: u32 = 1 << 19;
     pub const SYNC: u32 = 1 << 20;
     pub const PATH: u32 = 1 << 21;
+    // only meaningful at open, not kept on the file
+    pub const OPEN_ONLY: u32 = O::CREAT | O::EXCL | O::NOCTTY | O::TRUNC | O::CLOEXEC;
+    pub const SETFL: u32 = O::APPEND | O::NONBLOCK;
     pub const TMPFILE: u32 = 1 << 22;
 }
 
@@ -756,7 +1155,14 @@ fn exists(path: &str) -> bool {
     p9::exists(path)
 }
 
-fn remove(path: &str) -> Result<(), ()> {
+fn is_dir(path: &str) -> Result<bool, i32> {
+    p9::is_dir(path)
+}
+
+fn remove(path: &str) -> Result<(), i32> {
+    if shm::is_shm(path) {
+        return shm::unlink(path).map_err(|_| ENOENT);
+    }
     p9::remove(path)
 }
 
@@ -772,17 +1178,16 @@ pub fn unlinkat() -> u64 {
     let real_path = if let Ok(path) = at_path(fd, path_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    if let Ok(_) = remove(&real_path) {
-        0
-    } else {
-        -2i64 as u64
+    match remove(&real_path) {
+        Ok(_) => 0,
+        Err(e) => err(e),
     }
 }
 
-pub fn mkdir(path: &str, mode: u32) -> Result<(), ()> {
+pub fn mkdir(path: &str, mode: u32) -> Result<(), i32> {
     p9::mkdir(path, mode)
 }
 
@@ -796,24 +1201,48 @@ pub fn mkdirat() -> u64 {
     let path_str = String::from(str::from_utf8(path).unwrap());
 
     if let Ok(path) = at_path(fd, path_str, task) {
-        print!("MKDIRAT {}\n", path);
-        if mkdir(&path, tf.regs[2] as u32).is_ok() {
-            0
-        } else {
-            !0
+        log!(Debug, "MKDIRAT {}\n", path);
+        match mkdir(&path, tf.regs[2] as u32) {
+            Ok(()) => 0,
+            Err(e) => err(e),
         }
     } else {
-        -2i64 as u64
+        err(ENOENT)
+    }
+}
+
+// collapse "." and ".." components, ".." never climbs above root
+pub fn normalize_path(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+
+    let mut res = String::from("/");
+    for i in 0..parts.len() {
+        if i > 0 {
+            res.push('/');
+        }
+        res.push_str(parts[i]);
     }
+    res
 }
 
+// every *at syscall resolves through here, exactly one '/' goes
+// between the directory and path before normalizing
 fn at_path(fd: u64, path: String, task: &Task) -> Result<String, ()> {
     if path.starts_with("/") {
-        return Ok(path);
+        return Ok(normalize_path(&path));
     }
 
-    let mut dir_path = if fd == AT_FDCWD as u64 {
-        task.cwd.as_ref().unwrap().clone()
+    let dir_path = if fd == AT_FDCWD as u64 {
+        String::from(task.cwd.as_deref().unwrap_or("/"))
     } else {
         if let Some(dir) = task.get_file(fd as usize) {
             if let Some(p) = &dir.path {
@@ -825,13 +1254,32 @@ fn at_path(fd: u64, path: String, task: &Task) -> Result<String, ()> {
             return Err(());
         }
     };
+    Ok(join_path(dir_path, &path))
+}
 
-    if !dir_path.ends_with("/") {
-        dir_path.push('/');
+fn join_path(mut dir: String, path: &str) -> String {
+    if !dir.ends_with("/") {
+        dir.push('/');
     }
+    dir.push_str(path);
+    normalize_path(&dir)
+}
+
+const UTIME_NOW: u64 = (1 << 30) - 1;
+const UTIME_OMIT: u64 = (1 << 30) - 2;
 
-    dir_path.push_str(&path);
-    Ok(dir_path)
+#[repr(C)]
+struct Timespec {
+    sec: u64,
+    nsec: u64,
+}
+
+fn utime(ts: &Timespec) -> p9::Utime {
+    match ts.nsec {
+        UTIME_NOW => p9::Utime::Now,
+        UTIME_OMIT => p9::Utime::Omit,
+        nsec => p9::Utime::Set(ts.sec, nsec),
+    }
 }
 
 pub fn utimensat() -> u64 {
@@ -840,25 +1288,66 @@ pub fn utimensat() -> u64 {
 
     let fd = tf.regs[0];
 
+    let (atime, mtime) = if tf.regs[2] == 0 {
+        (p9::Utime::Now, p9::Utime::Now)
+    } else {
+        let times = as_slice(tf.regs[2] as *const Timespec, 2);
+        (utime(&times[0]), utime(&times[1]))
+    };
+
+    // futimens
+    if tf.regs[1] == 0 {
+        let file = match task.get_file(fd as usize) {
+            Some(f) => f,
+            _ => return err(EBADF),
+        };
+        return match &file.kind {
+            FileKind::P9(p9) => match p9.utimes(atime, mtime) {
+                Ok(()) => 0,
+                Err(e) => err(e),
+            },
+            _ => 0,
+        };
+    }
+
     let path = cstr_as_slice(tf.regs[1] as *const u8);
     let path_str = String::from(str::from_utf8(path).unwrap());
 
     let real_path = if let Ok(path) = at_path(fd, path_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    if exists(&real_path) { 0 } else { -2i64 as u64 }
+    let follow = tf.regs[3] as u32 & AT_SYMLINK_NOFOLLOW == 0;
+    match p9::utimes(&real_path, atime, mtime, follow) {
+        Ok(()) => 0,
+        Err(e) => err(e),
+    }
 }
 
 pub fn faccessat() -> u64 {
-    utimensat()
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let fd = tf.regs[0];
+
+    let path = cstr_as_slice(tf.regs[1] as *const u8);
+    let path_str = String::from(str::from_utf8(path).unwrap());
+
+    let real_path = if let Ok(path) = at_path(fd, path_str, task) {
+        path
+    } else {
+        return err(ENOENT);
+    };
+
+    if exists(&real_path) { 0 } else { err(ENOENT) }
 }
 
 pub fn openat() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
 
     let fd = tf.regs[0];
 
@@ -872,33 +1361,186 @@ pub fn openat() -> u64 {
     let real_path = if let Ok(path) = at_path(fd, path_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    print!("OPEN: path {} by {}\n", real_path, task.pid);
+    log!(Debug, "OPEN: path {} by {}\n", real_path, task.pid);
 
+    let nofile = min(task.rlimit(RLIMIT::NOFILE), fdt.files.len() as u64) as usize;
     let mut idx = None;
-    for i in 0..task.files.len() {
-        if (task.files[i].is_none()) {
+    for i in 0..nofile {
+        if (fdt.files[i].is_none()) {
             idx = Some(i);
             break;
         }
     }
 
+    if idx.is_none() {
+        return err(EMFILE);
+    }
+
     if let Some(idx) = idx {
-        if let Ok(f) = open(&real_path, tf.regs[2] as u32, tf.regs[3] as u32) {
-            task.files[idx] = Some(f);
-            return idx as u64;
-        } else {
-            print!("FAILED TO OPEN: {}\n", real_path);
+        match open(&real_path, tf.regs[2] as u32, tf.regs[3] as u32) {
+            Ok(f) => {
+                f.flags = tf.regs[2] as u32 & !O::OPEN_ONLY;
+                fdt.files[idx] = Some(f);
+                task.set_cloexec(idx, tf.regs[2] as u32 & O::CLOEXEC != 0);
+                return idx as u64;
+            }
+            Err(e) => {
+                log!(Debug, "FAILED TO OPEN: {} {}\n", real_path, e);
+                return err(e);
+            }
         }
     }
 
     !0
 }
 
+pub struct F;
+impl F {
+    pub const DUPFD: u64 = 0;
+    pub const GETFD: u64 = 1;
+    pub const SETFD: u64 = 2;
+    pub const GETFL: u64 = 3;
+    pub const SETFL: u64 = 4;
+    pub const GETLK: u64 = 5;
+    pub const SETLK: u64 = 6;
+    pub const SETLKW: u64 = 7;
+    pub const DUPFD_CLOEXEC: u64 = 1030;
+}
+
+pub const FD_CLOEXEC: u64 = 1;
+
+#[repr(C)]
+pub struct Flock {
+    l_type: i16,
+    l_whence: i16,
+    l_start: i64,
+    l_len: i64,
+    l_pid: i32,
+}
+
 pub fn fcntl() -> u64 {
-    0
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
+    let fd = tf.regs[0] as usize;
+    let cmd = tf.regs[1];
+
+    let file = match task.get_file(fd) {
+        Some(f) => f,
+        _ => return err(EBADF),
+    };
+
+    match cmd {
+        F::DUPFD | F::DUPFD_CLOEXEC => dupfd(task, file, tf.regs[2], cmd == F::DUPFD_CLOEXEC),
+        F::GETFD => (fdt.cloexec >> fd) & FD_CLOEXEC,
+        F::SETFD => {
+            task.set_cloexec(fd, tf.regs[2] & FD_CLOEXEC != 0);
+            0
+        }
+        F::GETFL => file.flags as u64,
+        F::SETFL => {
+            file.set_flags(tf.regs[2] as u32);
+            0
+        }
+        F::GETLK | F::SETLK | F::SETLKW => {
+            if tf.regs[2] == 0 {
+                return err(EFAULT);
+            }
+            fcntl_lock(file, cmd, ptr2mut!(tf.regs[2], Flock), task.pid as u32)
+        }
+        _ => err(EINVAL),
+    }
+}
+
+// lowest free fd at or above min, sharing the file and its offset
+fn dupfd(task: &mut Task, file: &mut File, min_fd: u64, cloexec: bool) -> u64 {
+    let fdt = task.fdt();
+    let nofile = min(task.rlimit(RLIMIT::NOFILE), fdt.files.len() as u64);
+    if min_fd >= nofile {
+        return err(EINVAL);
+    }
+    let fd = match (min_fd as usize..nofile as usize).find(|&i| fdt.files[i].is_none()) {
+        Some(fd) => fd,
+        _ => return err(EMFILE),
+    };
+    fdt.files[fd] = file.dup();
+    task.set_cloexec(fd, cloexec);
+    fd as u64
+}
+
+fn fcntl_lock(file: &mut File, cmd: u64, fl: &mut Flock, pid: u32) -> u64 {
+    let base = match fl.l_whence as u64 {
+        Seek::SET => 0,
+        Seek::CUR => file.offt as i64,
+        Seek::END => file.get_size() as i64,
+        _ => return err(EINVAL),
+    };
+
+    // a negative length covers the bytes before start
+    let (start, len) = if fl.l_len < 0 {
+        (base + fl.l_start + fl.l_len, -fl.l_len)
+    } else {
+        (base + fl.l_start, fl.l_len)
+    };
+    if start < 0 || fl.l_type < 0 || fl.l_type as u8 > p9::LOCK::UNLCK {
+        return err(EINVAL);
+    }
+
+    let req = p9::Flock {
+        kind: fl.l_type as u8,
+        start: start as u64,
+        len: len as u64,
+        proc_id: pid,
+    };
+
+    // nothing else can be locked, it never conflicts
+    let p9f = match &file.kind {
+        FileKind::P9(p9f) => p9f,
+        _ => {
+            if cmd == F::GETLK {
+                fl.l_type = p9::LOCK::UNLCK as i16;
+            }
+            return 0;
+        }
+    };
+
+    if cmd == F::GETLK {
+        let res = match p9f.getlock(&req) {
+            Ok(res) => res,
+            Err(e) => return err(e),
+        };
+        fl.l_type = res.kind as i16;
+        if res.kind != p9::LOCK::UNLCK {
+            fl.l_whence = Seek::SET as i16;
+            fl.l_start = res.start as i64;
+            fl.l_len = res.len as i64;
+            fl.l_pid = res.proc_id as i32;
+        }
+        return 0;
+    }
+
+    // the server answers BLOCKED rather than holding the reply, so
+    // SETLKW polls with a growing delay
+    let mut backoff = 1;
+    loop {
+        match p9f.lock(&req, 0) {
+            Ok(p9::LOCK::SUCCESS) => return 0,
+            Ok(p9::LOCK::BLOCKED) if cmd == F::SETLKW => {}
+            Ok(p9::LOCK::BLOCKED) => return err(EAGAIN),
+            Ok(_) => return err(EIO),
+            Err(e) => return err(e),
+        }
+
+        let task = mycpu().get_task().unwrap();
+        if task.signal_pending() {
+            return err(EINTR);
+        }
+        timer::sleep(backoff);
+        backoff = min(backoff * 2, 100);
+    }
 }
 
 #[repr(C)]
@@ -918,137 +1560,178 @@ impl POLL {
     pub const NVAL: i16 = 0x020;
     pub const RDNORM: i16 = 0x040;
     pub const RDBAND: i16 = 0x080;
+    pub const WRNORM: i16 = 0x100;
 }
 
-// done with int disabled
-fn check_events(pfds: &mut [Pollfd], task: &Task, timer_wait: bool) -> usize {
-    let mut n_events = 0;
-    for i in 0..pfds.len() {
-        let pfd = &mut pfds[i];
-        let events = pfd.events;
-        let fd = pfd.fd;
-        print!("CHECK EVENT fd: {} events: {}\n", fd, events);
+// bumped on every readiness change, pollers sleep on its address
+static POLL_GEN: Lock<u64> = Lock::new("poll", 0);
 
-        pfd.revents = 0;
+// console input and pipe traffic, anything a poller may be waiting for
+pub fn poll_wake() {
+    let lock = POLL_GEN.acquire();
+    *lock.as_mut() += 1;
+    wakeup(&POLL_GEN as *const Lock<u64> as u64);
+}
 
-        if let Some(file) = task.get_file(fd as usize) {
-            if !file.is_ok() {
-                pfd.revents |= POLL::ERR;
-            }
+// fills in revents, the number of entries with any set
+fn check_events(pfds: &mut [Pollfd], task: &Task) -> usize {
+    let mut n_events = 0;
+    for pfd in pfds.iter_mut() {
+        pfd.revents = 0;
+        // negative fds are ignored
+        if pfd.fd < 0 {
+            continue;
+        }
 
-            if file.hanged_up() {
-                pfd.revents |= POLL::HUP;
+        match task.get_file(pfd.fd as usize) {
+            Some(file) => {
+                if !file.is_ok() {
+                    pfd.revents |= POLL::ERR;
+                }
+                if file.hanged_up() {
+                    pfd.revents |= POLL::HUP;
+                }
+                if file.readable() {
+                    pfd.revents |= pfd.events & (POLL::IN | POLL::RDNORM);
+                }
+                if file.writeable() {
+                    pfd.revents |= pfd.events & (POLL::OUT | POLL::WRNORM);
+                }
             }
-        } else {
-            print!("POLL NO FILE: {}\n", fd);
-            pfd.revents |= POLL::NVAL;
+            _ => pfd.revents = POLL::NVAL,
         }
 
         if pfd.revents != 0 {
             n_events += 1;
-            continue;
-        }
-
-        match events {
-            POLL::IN => {
-                if let Some(file) = task.get_file(fd as usize) {
-                    if file.readable() {
-                        print!("POLLIN DETECTED.\n");
-                        pfd.revents |= POLL::IN;
-                        n_events += 1;
-                    } else {
-                        print!("ADDING POLLIN TO WQ\n");
-                        file.wait4readable();
-                        if timer_wait {
-                            timer::add2wait();
-                        }
-                    }
-                }
-            }
-            x => panic!("unhandled poll: {}\n", x),
         }
     }
-    print!("CHECK EVENT RES = {}\n", n_events);
+    log!(Trace, "CHECK EVENT RES = {}\n", n_events);
     n_events
 }
 
 pub fn ppoll() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let nfds = tf.regs[1] as usize;
 
-    if tf.regs[1] == 0 {
-        return 0;
+    if nfds as u64 > task.rlimit(RLIMIT::NOFILE) {
+        return err(EINVAL);
     }
+    if !sched::user_range_ok(task, tf.regs[0] as usize, nfds * size_of::<Pollfd>()) {
+        return err(EFAULT);
+    }
+    let timeout = match tf.regs[2] {
+        0 => None,
+        ts => match rtc::timespec_ns(ts) {
+            Ok(ns) => Some(ns),
+            Err(e) => return e,
+        },
+    };
+    let mask = match tf.regs[3] {
+        0 => None,
+        _ if tf.regs[4] != 8 => return err(EINVAL),
+        m if !sched::user_range_ok(task, m as usize, 8) => return err(EFAULT),
+        m => Some(*ptr2ref!(m, u64)),
+    };
+    if let Some(mask) = mask {
+        task.swap_sigmask(mask);
+    }
+
+    let pfds = as_slice_mut(tf.regs[0] as *mut Pollfd, nfds);
+    let chan = &POLL_GEN as *const Lock<u64> as u64;
+    let deadline = timeout.map(timer::deadline);
+    let mut armed = None;
+
+    let ret = loop {
+        // wakers take the task lock under the file locks, so the check runs
+        // with nothing held and a wake after it shows up as a new generation
+        let generation = *POLL_GEN.acquire().as_ref();
+        let n = check_events(pfds, task);
+        if n > 0 || timeout == Some(0) {
+            break n as u64;
+        }
+        if armed.is_none() {
+            armed = match deadline.map(|d| timer::arm(d, chan)).transpose() {
+                Ok(slot) => slot,
+                _ => break err(EAGAIN),
+            };
+        }
 
-    let pfds = as_slice_mut(tf.regs[0] as *mut Pollfd, tf.regs[1] as usize);
-    let ts = ptr2ref_op!(tf.regs[2], rtc::KernelTimespec);
-
-    //TODO signal
-    print!("POLL tmeout = {:?}\n", ts);
-    let start = timer::read_tick();
-
-    let mut timed_out = false;
-    let mut n = 0;
-
-    while !timed_out {
-        n = 0;
-
-        sleep_if(&mut || {
-            n = check_events(pfds, task, ts.is_some());
-
-            if let Some(ts) = ts {
-                print!("WAITING FOR {}ms\n", ts.millis());
-                if timer::read_tick() - start > ts.millis() {
-                    timed_out = true
-                }
-            } else {
-                print!("WAITING FOR EVER\n");
-            }
-
-            n == 0 && !timed_out
-        });
-
-        if n > 0 {
-            break;
+        let lock = POLL_GEN.acquire();
+        if *lock.as_ref() != generation {
+            continue;
         }
-    }
+        if deadline.is_some_and(timer::passed) {
+            break 0;
+        }
+        if sleep_intr(chan, lock.get_lock()).is_err() {
+            break err(EINTR);
+        }
+    };
 
-    print!("POLL WAKE n: {} timed_out: {} ts: {:?}\n", n, timed_out, ts);
-    n as u64
+    if let Some(slot) = armed {
+        timer::disarm(slot);
+    }
+    // an interrupting signal is delivered under the ppoll mask, do_signals
+    // puts the old one back
+    if ret != err(EINTR) {
+        task.restore_sigmask();
+    }
+    ret
 }
 
 pub fn close() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
 
     let fd = tf.regs[0] as usize;
-    print!("CLOSE FD {}\n", fd);
+    log!(Trace, "CLOSE FD {}\n", fd);
 
-    if task.files[fd].is_none() {
-        return !0;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
-    let file = task.files[fd].as_mut().unwrap();
-    print!("CLOSING {:?} fd: {} BY {}\n", file.path, fd, task.pid);
-    if let Ok(_) = file.close() {
-        task.files[fd] = None;
-        0
-    } else {
-        !0
+    let file = fdt.files[fd].as_mut().unwrap();
+    log!(
+        Debug,
+        "CLOSING {:?} fd: {} BY {}\n",
+        file.path,
+        fd,
+        task.pid
+    );
+    // a p9 close that failed is still closed, like linux the fd is gone
+    let res = file.close();
+    if res.is_ok() || matches!(file.kind, FileKind::None) {
+        fdt.files[fd] = None;
+        task.set_cloexec(fd, false);
+    }
+    match res {
+        Ok(_) => 0,
+        _ => err(EIO),
     }
 }
 
 pub fn dup3() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
 
     let old_fd = tf.regs[0] as usize;
     let new_fd = tf.regs[1] as usize;
+    let flags = tf.regs[2] as u32;
 
-    print!("DUP3 old {} new {}\n", old_fd, new_fd);
+    log!(Debug, "DUP3 old {} new {}\n", old_fd, new_fd);
 
-    if task.files[old_fd].is_none() {
-        return !0;
+    if old_fd >= fdt.files.len() || fdt.files[old_fd].is_none() {
+        return err(EBADF);
+    }
+    let nofile = min(task.rlimit(RLIMIT::NOFILE), fdt.files.len() as u64) as usize;
+    if new_fd >= nofile {
+        return err(EBADF);
+    }
+    if flags & !O::CLOEXEC != 0 {
+        return err(EINVAL);
     }
 
     if old_fd == new_fd {
@@ -1058,58 +1741,166 @@ pub fn dup3() -> u64 {
     let mut replaced = task.get_file(new_fd);
 
     let file = task.get_file(old_fd).unwrap();
-    task.files[new_fd] = Some(file.dup().unwrap());
+    fdt.files[new_fd] = Some(file.dup().unwrap());
+    task.set_cloexec(new_fd, flags & O::CLOEXEC != 0);
 
+    // errors closing the replaced file are dropped, as dup2 does
     if let Some(f) = &mut replaced {
-        f.close().unwrap();
+        let _ = f.close();
     }
 
-    print!("DUP3 {} to {}\n", old_fd, new_fd);
+    log!(Debug, "DUP3 {} to {}\n", old_fd, new_fd);
 
     new_fd as u64
 }
 
+pub fn pipe2() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
+
+    let fds = tf.regs[0] as *mut i32;
+    let flags = tf.regs[1] as u32;
+
+    if fds.is_null() {
+        return err(EFAULT);
+    }
+    if flags & !(O::CLOEXEC | O::NONBLOCK) != 0 {
+        return err(EINVAL);
+    }
+
+    let nofile = min(task.rlimit(RLIMIT::NOFILE), fdt.files.len() as u64) as usize;
+    let mut free = (0..nofile).filter(|&i| fdt.files[i].is_none());
+    let (rfd, wfd) = match (free.next(), free.next()) {
+        (Some(r), Some(w)) => (r, w),
+        _ => return err(EMFILE),
+    };
+
+    let (ridx, rfile) = match alloc_file() {
+        Some(f) => f,
+        None => return err(ENFILE),
+    };
+    let (widx, wfile) = match alloc_file() {
+        Some(f) => f,
+        None => {
+            free_file(ridx);
+            return err(ENFILE);
+        }
+    };
+    let (r, w) = match pipe::alloc(flags & O::NONBLOCK != 0) {
+        Some(ends) => ends,
+        None => {
+            free_file(ridx);
+            free_file(widx);
+            return err(ENFILE);
+        }
+    };
+
+    for (file, end, mode) in [(&mut *rfile, r, O::RDONLY), (&mut *wfile, w, O::WRONLY)] {
+        file.kind = FileKind::Pipe(end);
+        file.rc = AtomicU16::new(1);
+        file.path = None;
+        file.offt = 0;
+        file.flags = mode | (flags & O::NONBLOCK);
+    }
+
+    fdt.files[rfd] = Some(rfile);
+    fdt.files[wfd] = Some(wfile);
+    task.set_cloexec(rfd, flags & O::CLOEXEC != 0);
+    task.set_cloexec(wfd, flags & O::CLOEXEC != 0);
+    unsafe {
+        fds.write(rfd as i32);
+        fds.add(1).write(wfd as i32);
+    }
+
+    log!(Debug, "PIPE2: {} {} by {}\n", rfd, wfd, task.pid);
+    0
+}
+
 pub fn ftruncate() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
 
     let fd = tf.regs[0] as usize;
 
-    if task.files[fd].is_none() {
-        return !0;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
     let file = task.get_file(fd).unwrap();
 
-    if file.path.is_none() {
-        return !0;
+    if (tf.regs[1] as i64) < 0 {
+        return err(EINVAL);
     }
 
-    if truncate(file.path.as_ref().unwrap(), tf.regs[1]).is_ok() {
-        0
-    } else {
-        !0
+    if let FileKind::Shm(shm) = &mut file.kind {
+        // growing is all that can fail
+        return match shm.truncate(tf.regs[1]) {
+            Ok(()) => 0,
+            Err(()) => err(ENOMEM),
+        };
+    }
+
+    if let FileKind::P9(p9) = &mut file.kind {
+        if p9.qid.kind.is_dir() {
+            return err(EISDIR);
+        }
+        return match p9.truncate(tf.regs[1]) {
+            Ok(()) => 0,
+            Err(e) => err(e),
+        };
+    }
+
+    err(EINVAL)
+}
+
+pub fn fsync() -> u64 {
+    fsync_inner(false)
+}
+
+pub fn fdatasync() -> u64 {
+    fsync_inner(true)
+}
+
+fn fsync_inner(datasync: bool) -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
+
+    let fd = tf.regs[0] as usize;
+
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
+    }
+
+    let file = task.get_file(fd).unwrap();
+
+    match file.fsync(datasync) {
+        Ok(()) => 0,
+        Err(e) => err(e),
     }
 }
 
 pub fn sendfile64() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
 
     let out_fd = tf.regs[0] as usize;
     let in_fd = tf.regs[1] as usize;
     let offt = tf.regs[2] as *mut u64;
     let cnt = tf.regs[3] as usize;
 
-    if task.files[in_fd].is_none() {
-        return !0;
+    if in_fd >= fdt.files.len() || fdt.files[in_fd].is_none() {
+        return err(EBADF);
     }
 
-    if task.files[out_fd].is_none() {
-        return !0;
+    if out_fd >= fdt.files.len() || fdt.files[out_fd].is_none() {
+        return err(EBADF);
     }
 
-    print!("SENDFILE: {} {} {:?} {}\n", in_fd, out_fd, offt, cnt);
+    log!(Debug, "SENDFILE: {} {} {:?} {}\n", in_fd, out_fd, offt, cnt);
 
     let ifile = task.get_file(in_fd).unwrap();
     let ofile = task.get_file(out_fd).unwrap();
@@ -1131,11 +1922,15 @@ pub fn sendfile64() -> u64 {
 pub const AT_SYMLINK_NOFOLLOW: u32 = 256;
 pub const SYMLINK_FOLLOW: u64 = 0x400;
 
-pub fn fstat(path: &str, stat: &mut Stat, follow: bool) -> Result<(), ()> {
+pub fn fstat(path: &str, stat: &mut Stat, follow: bool) -> Result<(), i32> {
+    if let Some(dev) = DevKind::from_path(path) {
+        dev.stat(stat);
+        return Ok(());
+    }
     p9::stat(&path, stat, follow)
 }
 
-pub fn truncate(path: &str, size: u64) -> Result<(), ()> {
+pub fn truncate(path: &str, size: u64) -> Result<(), i32> {
     p9::truncate(path, size)
 }
 
@@ -1151,36 +1946,36 @@ pub fn newfsstatat() -> u64 {
     let real_path = if let Ok(path) = at_path(fd, path_str, task) {
         path
     } else {
-        return -2i64 as u64;
+        return err(ENOENT);
     };
 
-    print!("NEWFSTAT: {}\n", real_path);
+    log!(Debug, "NEWFSTAT: {}\n", real_path);
 
     let stat = unsafe { (tf.regs[2] as *mut Stat).as_mut() }.unwrap();
-    if fstat(
+    match fstat(
         &real_path,
         stat,
         tf.regs[3] as u32 & AT_SYMLINK_NOFOLLOW == 0,
-    )
-    .is_ok()
-    {
-        return 0;
+    ) {
+        Ok(_) => 0,
+        Err(e) => {
+            log!(Debug, "NEWFSTAT FAIL: {} {}\n", real_path, e);
+            err(e)
+        }
     }
-
-    print!("NEWFSTAT FAIL: {}\n", real_path);
-    return -2i64 as u64;
 }
 
 pub fn newfstat() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let fdt = task.fdt();
     let fd = tf.regs[0] as usize;
 
-    if task.files[fd].is_none() {
-        return !0;
+    if fd >= fdt.files.len() || fdt.files[fd].is_none() {
+        return err(EBADF);
     }
 
-    let file = task.files[fd].as_ref().unwrap();
+    let file = fdt.files[fd].as_ref().unwrap();
 
     if let Ok(_) = file.fstat(unsafe { (tf.regs[1] as *mut Stat).as_mut() }.unwrap()) {
         return 0;
@@ -1190,6 +1985,102 @@ pub fn newfstat() -> u64 {
 }
 pub const AT_FDCWD: i32 = -100;
 
+// asm-generic statfs, every word is 64 bit on aarch64
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Statfs {
+    pub f_type: u64,
+    pub f_bsize: u64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: u64,
+    pub f_namelen: u64,
+    pub f_frsize: u64,
+    pub f_flags: u64,
+    pub f_spare: [u64; 4],
+}
+
+// what linux reports for the filesystems behind the files that aren't 9p
+const TMPFS_MAGIC: u64 = 0x0102_1994;
+const PIPEFS_MAGIC: u64 = 0x5049_5045;
+const DEVPTS_MAGIC: u64 = 0x1cd1;
+
+// no blocks to count, only the type and the sizes
+fn synth_statfs(kind: u64, st: &mut Statfs) {
+    st.f_type = kind;
+    st.f_bsize = 4096;
+    st.f_frsize = 4096;
+    st.f_namelen = 255;
+}
+
+pub fn statfs() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    if tf.regs[0] == 0 || tf.regs[1] == 0 {
+        return err(EFAULT);
+    }
+
+    let path = cstr_as_slice(tf.regs[0] as *const u8);
+    let path_str = String::from(str::from_utf8(path).unwrap());
+
+    let real_path = if let Ok(path) = at_path(AT_FDCWD as u64, path_str, task) {
+        path
+    } else {
+        return err(ENOENT);
+    };
+
+    let st = unsafe { (tf.regs[1] as *mut Statfs).as_mut() }.unwrap();
+    *st = Statfs::default();
+    if shm::is_shm(&real_path) {
+        synth_statfs(TMPFS_MAGIC, st);
+        return 0;
+    }
+    match p9::statfs(&real_path, st) {
+        Ok(()) => 0,
+        Err(e) => err(e),
+    }
+}
+
+pub fn fstatfs() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let file = match task.get_file(tf.regs[0] as usize) {
+        Some(f) => f,
+        _ => return err(EBADF),
+    };
+
+    if tf.regs[1] == 0 {
+        return err(EFAULT);
+    }
+
+    let st = unsafe { (tf.regs[1] as *mut Statfs).as_mut() }.unwrap();
+    *st = Statfs::default();
+    match &file.kind {
+        FileKind::P9(p9) => match p9.statfs(st) {
+            Ok(()) => 0,
+            Err(e) => err(e),
+        },
+        FileKind::Pipe(_) => {
+            synth_statfs(PIPEFS_MAGIC, st);
+            0
+        }
+        FileKind::Cons(_) => {
+            synth_statfs(DEVPTS_MAGIC, st);
+            0
+        }
+        FileKind::Shm(_) | FileKind::Dev(_) | FileKind::Blk => {
+            synth_statfs(TMPFS_MAGIC, st);
+            0
+        }
+        FileKind::None | FileKind::Used => err(EBADF),
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Stat {
@@ -1359,6 +2250,7 @@ fn alloc_file() -> Option<(usize, &'static mut File)> {
         let file = &mut fs.files[i];
         if let FileKind::None = file.kind {
             file.kind = FileKind::Used;
+            file.flags = 0;
             let steal = unsafe { (file as *mut File).as_mut() }.unwrap();
             return Some((i, steal));
         }
@@ -1372,3 +2264,27 @@ fn free_file(idx: usize) {
     let fs = lock.as_mut();
     fs.files[idx].kind = FileKind::None;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_with_one_separator() {
+        let j = |d: &str, p: &str| join_path(String::from(d), p);
+        assert_eq!(j("/foo", "bar"), "/foo/bar");
+        assert_eq!(j("/foo/", "./baz"), "/foo/baz");
+        assert_eq!(j("/a/b", "../c"), "/a/c");
+        assert_eq!(j("/", "x//y/"), "/x/y");
+        assert_eq!(j("/a", "../../.."), "/");
+    }
+
+    #[test]
+    fn normalizes() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("//usr///bin/./"), "/usr/bin");
+        assert_eq!(normalize_path("/usr/../etc/./passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/../.."), "/");
+    }
+}
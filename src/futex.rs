@@ -0,0 +1,187 @@
+use crate::{
+    errno::{EAGAIN, EFAULT, EINTR, EINVAL, ENOSYS, ETIMEDOUT, err},
+    rtc,
+    sched::{self, Task, mycpu, sleep_intr, wakeup},
+    spin::Lock,
+    timer,
+    vm::{self, PmWrap},
+};
+
+pub struct FUTEX;
+impl FUTEX {
+    pub const WAIT: u64 = 0;
+    pub const WAKE: u64 = 1;
+    pub const WAIT_BITSET: u64 = 9;
+    pub const WAKE_BITSET: u64 = 10;
+    pub const PRIVATE_FLAG: u64 = 128;
+    pub const CLOCK_REALTIME: u64 = 256;
+}
+
+// one per task at most
+const NWAITERS: usize = 32;
+
+// private words by address space and virtual address, a cow break
+// moves the page under them. shared ones by the physical address of
+// the word so they work across tasks
+#[derive(Clone, Copy, PartialEq)]
+enum Key {
+    Private(usize, usize),
+    Shared(usize),
+}
+
+#[derive(Clone, Copy)]
+struct Waiter {
+    key: Key,
+    used: bool,
+    woken: bool,
+}
+
+// waiters sleep on the address of their slot
+static WAITERS: Lock<[Waiter; NWAITERS]> = Lock::new(
+    "futex",
+    [Waiter {
+        key: Key::Shared(0),
+        used: false,
+        woken: false,
+    }; NWAITERS],
+);
+
+pub fn futex() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let uaddr = tf.regs[0] as usize;
+    let private = tf.regs[1] & FUTEX::PRIVATE_FLAG != 0;
+    let realtime = tf.regs[1] & FUTEX::CLOCK_REALTIME != 0;
+    let op = tf.regs[1] & !(FUTEX::PRIVATE_FLAG | FUTEX::CLOCK_REALTIME);
+    let val = tf.regs[2] as u32;
+
+    if uaddr % 4 != 0 {
+        return err(EINVAL);
+    }
+    if !sched::user_ok(task, uaddr) {
+        return err(EFAULT);
+    }
+
+    // what libc uses the bitset ops for matches any bit, which is plain
+    // WAIT and WAKE. other masks aren't kept per waiter
+    if op == FUTEX::WAIT_BITSET || op == FUTEX::WAKE_BITSET {
+        match tf.regs[5] as u32 {
+            0 => return err(EINVAL),
+            u32::MAX => {}
+            _ => return err(ENOSYS),
+        }
+    }
+
+    match op {
+        FUTEX::WAIT | FUTEX::WAIT_BITSET => {
+            let timeout = match tf.regs[3] {
+                0 => None,
+                ts => match rtc::timespec_ns(ts) {
+                    Ok(ns) if op == FUTEX::WAIT => Some(ns),
+                    // absolute, against the clock the flag picks
+                    Ok(ns) if realtime => Some(ns.saturating_sub(rtc::realtime_ns())),
+                    Ok(ns) => Some(ns.saturating_sub(timer::uptime_ns())),
+                    Err(e) => return e,
+                },
+            };
+            wait(task, uaddr, val, timeout, private)
+        }
+        FUTEX::WAKE | FUTEX::WAKE_BITSET => wake_addr(task, uaddr, val as usize, private) as u64,
+        _ => err(ENOSYS),
+    }
+}
+
+fn key(task: &mut Task, uaddr: usize, private: bool) -> Option<Key> {
+    if private {
+        return Some(Key::Private(task.mm_id(), uaddr));
+    }
+    sched::user_v2p(task, uaddr).map(Key::Shared)
+}
+
+// through the physical page, a user access could fault
+fn read_word(task: &mut Task, uaddr: usize) -> Option<u32> {
+    let p = sched::user_v2p(task, uaddr)?;
+    let page = PmWrap::new(p, vm::PR, false).ok()?;
+    Some(page.as_slice::<u32>()[(p % 4096) / 4])
+}
+
+// sleeps while the word holds val, timeout in ns
+fn wait(task: &mut Task, uaddr: usize, val: u32, timeout: Option<u64>, private: bool) -> u64 {
+    // faults the page in before any lock is held
+    let _ = unsafe { (uaddr as *const u32).read_volatile() };
+    let key = match key(task, uaddr, private) {
+        Some(k) => k,
+        _ => return err(EFAULT),
+    };
+
+    // wakers take the lock too, nothing slips in between the check and
+    // the sleep
+    let lock = WAITERS.acquire();
+    match read_word(task, uaddr) {
+        Some(v) if v == val => {}
+        Some(_) => return err(EAGAIN),
+        _ => return err(EFAULT),
+    }
+
+    // the caller checks the word again and retries
+    let slot = match lock.as_ref().iter().position(|w| !w.used) {
+        Some(slot) => slot,
+        _ => return err(EAGAIN),
+    };
+    lock.as_mut()[slot] = Waiter {
+        key,
+        used: true,
+        woken: false,
+    };
+    let chan = &lock.as_ref()[slot] as *const Waiter as u64;
+    let deadline = timeout.map(timer::deadline);
+    let armed = match deadline.map(|d| timer::arm(d, chan)).transpose() {
+        Ok(slot) => slot,
+        _ => {
+            lock.as_mut()[slot].used = false;
+            return err(EAGAIN);
+        }
+    };
+
+    let mut ret = 0;
+    while !lock.as_ref()[slot].woken {
+        if deadline.is_some_and(timer::passed) {
+            ret = err(ETIMEDOUT);
+            break;
+        }
+        if sleep_intr(chan, lock.get_lock()).is_err() {
+            ret = err(EINTR);
+            break;
+        }
+    }
+    lock.as_mut()[slot].used = false;
+    drop(lock);
+
+    if let Some(slot) = armed {
+        timer::disarm(slot);
+    }
+    ret
+}
+
+// up to n waiters on the word at uaddr, the number woken
+pub fn wake_addr(task: &mut Task, uaddr: usize, n: usize, private: bool) -> usize {
+    // nothing can wait on a page that was never backed
+    let key = match key(task, uaddr, private) {
+        Some(k) => k,
+        _ => return 0,
+    };
+
+    let lock = WAITERS.acquire();
+    let mut woken = 0;
+    for w in lock.as_mut().iter_mut() {
+        if woken == n {
+            break;
+        }
+        if w.used && !w.woken && w.key == key {
+            w.woken = true;
+            wakeup(w as *const Waiter as u64);
+            woken += 1;
+        }
+    }
+    woken
+}
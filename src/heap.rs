@@ -1,11 +1,79 @@
-use core::cell::UnsafeCell;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr::{NonNull, null_mut},
+};
 
 use linked_list_allocator::LockedHeap;
 
-use crate::pm::MB;
+use crate::{
+    pm::{self, GB, KB, MB, align_f},
+    spin::Lock,
+    vm,
+};
 
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+// own window so growing can map right at the top
+const HEAP_BASE: usize = 0xffff_0100_0000_0000;
+const HEAP_MAX: usize = GB;
+
+struct Grow(LockedHeap);
+
+unsafe impl GlobalAlloc for Grow {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let top = {
+                let mut heap = self.0.lock();
+                if let Ok(p) = heap.allocate_first_fit(layout) {
+                    return p.as_ptr();
+                }
+                heap.top() as usize
+            };
+            if grow(&self.0, top).is_err() {
+                return null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            self.0
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        };
+    }
+}
+
+#[cfg_attr(not(test), global_allocator)]
+static ALLOCATOR: Grow = Grow(LockedHeap::empty());
+
+// growers take turns, only they move the top
+static GROW: Lock<()> = Lock::new("heap grow", ());
+
+// one more pm block mapped at the top, big requests just loop. the
+// block is allocated and mapped with the heap unlocked, frees and
+// allocations that fit go on meanwhile. Ok and nothing to do when
+// someone else grew it past top already
+fn grow(heap: &LockedHeap, top: usize) -> Result<(), ()> {
+    let lock = GROW.acquire();
+    if heap.lock().top() as usize != top {
+        return Ok(());
+    }
+    if top + MB > HEAP_BASE + HEAP_MAX {
+        return Err(());
+    }
+    map_chunk(top)?;
+    unsafe { heap.lock().extend(MB) };
+    Ok(())
+}
+
+fn map_chunk(v: usize) -> Result<(), ()> {
+    let p = pm::alloc(MB)?;
+    if vm::map_at(v, p, MB / (4 * KB), vm::PR_PW).is_err() {
+        pm::free(p, MB);
+        return Err(());
+    }
+    Ok(())
+}
 
 #[repr(transparent)]
 pub struct SyncUnsafeCell<T>(pub UnsafeCell<T>);
@@ -25,12 +93,19 @@ impl<T> SyncUnsafeCell<T> {
 
 unsafe impl<T: Sync> Sync for SyncUnsafeCell<T> {}
 
-#[unsafe(link_section = ".bss.heap")]
-static HEAP_MEMORY: SyncUnsafeCell<[u8; MB]> = SyncUnsafeCell(UnsafeCell::new([0u8; MB]));
-
-pub fn init() {
+pub fn init() -> Result<(), ()> {
+    map_chunk(HEAP_BASE)?;
     unsafe {
-        let ptr = HEAP_MEMORY.0.get() as *mut u8;
-        ALLOCATOR.lock().init(ptr, MB);
+        ALLOCATOR.0.lock().init(HEAP_BASE as *mut u8, MB);
     }
+    Ok(())
+}
+
+pub fn used() -> usize {
+    ALLOCATOR.0.lock().used()
+}
+
+// what's left before the next grow
+pub fn free() -> usize {
+    ALLOCATOR.0.lock().free()
 }
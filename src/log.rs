@@ -0,0 +1,50 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn from_u8(v: u8) -> Option<Level> {
+        match v {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+// messages above this are dropped before formatting
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed)).unwrap()
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// log!(Debug, "OPEN: {}\n", path), print! stays the raw writer
+#[macro_export]
+macro_rules! log {
+    ($lvl:ident, $($arg:tt)*) => {{
+        if $crate::log::enabled($crate::log::Level::$lvl) {
+            $crate::print!($($arg)*);
+        }
+    }};
+}
@@ -1,5 +1,5 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![allow(unused)]
 
 extern crate alloc;
@@ -14,14 +14,21 @@ use crate::{arch::enable_fp, heap::SyncUnsafeCell};
 mod arch;
 mod blk;
 mod cons;
+mod dt;
 mod elf;
+mod errno;
 mod fs;
+mod futex;
 mod heap;
+mod log;
 mod p9;
+mod pipe;
 mod pm;
 mod rng;
 mod rtc;
 mod sched;
+mod shm;
+mod smp;
 mod spin;
 mod stuff;
 mod svc;
@@ -29,15 +36,19 @@ mod timer;
 mod trap;
 mod tty;
 mod uart;
+mod virtconsole;
+mod virtinput;
 mod virtio;
 mod vm;
 
-#[unsafe(no_mangle)]
-fn main(b: usize, e: usize) {
-    pm::init(b, e);
+#[cfg_attr(not(test), unsafe(no_mangle))]
+fn main(b: usize, e: usize, dtb: usize) {
+    dt::init(dtb);
+    // the page array grows the kernel
+    let e = pm::init(b, e);
     vm::init(b, e);
     uart::init_tx();
-    heap::init();
+    heap::init().expect("no memory for the kernel heap");
     trap::init();
     uart::init_rx();
     timer::init();
@@ -45,6 +56,7 @@ fn main(b: usize, e: usize) {
     virtio::init();
     enable_fp();
     sched::create_task(0);
+    smp::init();
     sched::scheduler();
     loop {
         wfi!();
@@ -65,7 +77,7 @@ unsafe extern "C" {
     pub static _user_end: u64;
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[unsafe(link_section = ".boot.data")]
 #[unsafe(naked)]
 pub extern "C" fn _data() {
@@ -83,11 +95,12 @@ pub extern "C" fn _data() {
     )
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[unsafe(link_section = ".boot.text")]
 #[unsafe(naked)]
 pub extern "C" fn _start() {
     naked_asm!(
+       "mov x19, x0", // dtb
        "ldr x0, =0x5b0103210",
        "msr tcr_el1, x0",
        "ldr x0, =l0_id",
@@ -117,6 +130,7 @@ pub extern "C" fn _start() {
        "msr vbar_el1, x0",
        "ldr x0, ={begin}",
        "ldr x1, ={end}",
+       "mov x2, x19",
        "bl main",
        "1:",
        "wfi",
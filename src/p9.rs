@@ -4,22 +4,25 @@ use core::{
     hint::spin_loop,
     ops::BitOr,
     ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
 };
 
 use alloc::{collections::btree_set::SymmetricDifference, str, string::String, vec::Vec};
 use hashbrown::HashMap;
 
 use crate::{
-    dsb, fs,
+    dsb,
+    errno::{EEXIST, EINVAL, EIO, ENOENT},
+    fs,
     heap::SyncUnsafeCell,
-    memcpy,
+    log, memcpy,
     pm::align_f,
-    print, ptr2mut,
-    sched::wakeup,
+    ptr2mut,
+    sched::{wakeup, yild},
     spin::Lock,
-    stuff::{BitSet128, defer, print_slice_chars},
+    stuff::{BitSet, Defer, defer, print_slice_chars},
     trap::gic_enable_intr,
-    virtio::{self, Q, Regs, Status, get_irq_status, init_dev_common, irq_ack},
+    virtio::{self, Feature, Q, Regs, Status, get_irq_status, init_dev_common, irq_ack},
 };
 
 struct Msg {
@@ -86,18 +89,45 @@ impl Msg {
         Some(q)
     }
 
-    pub fn read_str(&mut self) -> Option<&str> {
-        let len = self.read_u16().unwrap() as usize;
-        let buf = self.get_buf();
-        if self.pos + len > buf.len() {
+    pub fn read_slice(&mut self, len: usize) -> Option<&[u8]> {
+        let pos = self.pos;
+        if pos + len > self.get_buf().len() {
             return None;
         }
-        match str::from_utf8(&buf[self.pos..self.pos + len as usize]) {
+        self.pos += len;
+        Some(&self.get_buf()[pos..pos + len])
+    }
+
+    pub fn read_str(&mut self) -> Option<&str> {
+        let len = self.read_u16()? as usize;
+        match str::from_utf8(self.read_slice(len)?) {
             Ok(s) => Some(s),
             Err(_) => None,
         }
     }
 
+    // Err carries the server's errno when it answered with Rlerror
+    fn check(&mut self, kind: Op) -> Result<(), i32> {
+        self.seek(4);
+        let resp_kind = self.read_u8().ok_or(EIO)?;
+        if resp_kind == kind as u8 {
+            return Ok(());
+        }
+        if resp_kind == Op::RLERROR as u8 {
+            self.seek(7);
+            return Err(self.read_u32().ok_or(EIO)? as i32);
+        }
+        Err(EIO)
+    }
+
+    pub fn read_qid(&mut self) -> Option<QID> {
+        Some(QID {
+            kind: self.read_u8()?.into(),
+            version: self.read_u32()?,
+            path: self.read_u64()?,
+        })
+    }
+
     pub fn write_slice(&mut self, slice: &[u8]) {
         let pos = self.pos;
         let vec = &mut self.buf;
@@ -150,38 +180,51 @@ impl Msg {
 }
 
 const QSIZE: usize = 32;
+// fids in use at once, each open file holds one
+const NFIDS: usize = 256;
 
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, Default)]
-pub enum QIDKind {
-    #[default]
-    DIR = 0x80,
-    APPEND = 0x40,
-    EXCL = 0x20,
-    MOUNT = 0x10,
-    AUTH = 0x08,
-    TMP = 0x04,
-    SYMLINK = 0x02,
-    LINK = 0x01,
-    FILE = 0x00,
-    DONT = 0xff,
+// qid type is a bitmask, a plain file has no bits set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QIDKind(pub u8);
+
+impl QIDKind {
+    pub const DIR: QIDKind = QIDKind(0x80);
+    pub const APPEND: QIDKind = QIDKind(0x40);
+    pub const EXCL: QIDKind = QIDKind(0x20);
+    pub const MOUNT: QIDKind = QIDKind(0x10);
+    pub const AUTH: QIDKind = QIDKind(0x08);
+    pub const TMP: QIDKind = QIDKind(0x04);
+    pub const SYMLINK: QIDKind = QIDKind(0x02);
+    pub const LINK: QIDKind = QIDKind(0x01);
+    pub const FILE: QIDKind = QIDKind(0x00);
+    pub const DONT: QIDKind = QIDKind(0xff);
+
+    pub fn has(&self, other: QIDKind) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.has(QIDKind::DIR)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.has(QIDKind::SYMLINK)
+    }
+
+    pub fn is_append(&self) -> bool {
+        self.has(QIDKind::APPEND)
+    }
 }
 
-impl TryFrom<u8> for QIDKind {
-    type Error = ();
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0x0 => Ok(QIDKind::FILE),
-            0x01 => Ok(QIDKind::LINK),
-            0x02 => Ok(QIDKind::SYMLINK),
-            0x04 => Ok(QIDKind::TMP),
-            0x08 => Ok(QIDKind::AUTH),
-            0x10 => Ok(QIDKind::MOUNT),
-            0x20 => Ok(QIDKind::EXCL),
-            0x40 => Ok(QIDKind::APPEND),
-            0x80 => Ok(QIDKind::DIR),
-            _ => Err(()),
-        }
+impl Default for QIDKind {
+    fn default() -> Self {
+        QIDKind::DIR
+    }
+}
+
+impl From<u8> for QIDKind {
+    fn from(value: u8) -> Self {
+        QIDKind(value)
     }
 }
 
@@ -204,10 +247,13 @@ impl QID {
 
 pub struct P9 {
     q: Q<QSIZE>,
-    fid_bs: BitSet128,
+    fid_bs: BitSet<{ NFIDS / 64 }>,
     tag: u16,
     qid: QID,
     regs: Option<NonNull<Regs>>,
+    msize: u32,
+    // VIRTIO_F_INDIRECT_DESC was negotiated
+    indirect: bool,
 }
 
 impl P9 {
@@ -215,7 +261,7 @@ impl P9 {
         match self.fid_bs.first_clr() {
             Some(i) => {
                 self.fid_bs.set(i);
-                print!("ALLOC fid: {}\n", i);
+                log!(Trace, "ALLOC fid: {}\n", i);
                 Some(i as u32)
             }
             _ => None,
@@ -224,7 +270,12 @@ impl P9 {
 
     fn free_fid(&mut self, fid: u32) {
         assert!(self.fid_is_ok(fid));
-        self.fid_bs.clr(fid as u8);
+        self.fid_bs.clr(fid as usize);
+    }
+
+    // ops sleep here when the queue runs out of descriptors
+    fn desc_chan(&self) -> u64 {
+        &self.q as *const Q<QSIZE> as u64
     }
 
     fn next_tag(&mut self) -> u16 {
@@ -234,7 +285,7 @@ impl P9 {
     }
 
     fn fid_is_ok(&self, fid: u32) -> bool {
-        fid < self.fid_bs.len() as u32 && self.fid_bs.tst(fid as u8)
+        (fid as usize) < self.fid_bs.len() && self.fid_bs.tst(fid as usize)
     }
 }
 
@@ -377,17 +428,31 @@ impl BitOr for O {
 
 const VERSION: &'static str = "9P2000.L";
 
+// size[4] Rlerror tag[2] ecode[4]
+const RLERROR_LEN: usize = 4 + 1 + 2 + 4;
+
 static P9L: Lock<P9> = Lock::new(
     "9p",
     P9 {
         q: Q::new(),
-        fid_bs: BitSet128::new(128),
+        fid_bs: BitSet::new(NFIDS),
         tag: 0,
         qid: QID::new(),
         regs: None,
+        msize: u16::MAX as u32,
+        indirect: false,
     },
 );
 
+// size[4] Tread/Twrite tag[2] fid[4] offset[8] count[4]
+const IOHDRSZ: usize = 4 + 1 + 2 + 4 + 8 + 4;
+
+// largest Tread/Twrite payload that fits in one message
+fn max_io() -> usize {
+    let lock = P9L.acquire();
+    lock.as_ref().msize as usize - IOHDRSZ
+}
+
 #[derive(Default, Debug)]
 pub struct Stat {
     pub kind: u16,
@@ -453,20 +518,242 @@ impl Stat {
     }
 }
 
+pub struct GETATTR;
+impl GETATTR {
+    pub const MODE: u64 = 0x0000_0001;
+    pub const NLINK: u64 = 0x0000_0002;
+    pub const UID: u64 = 0x0000_0004;
+    pub const GID: u64 = 0x0000_0008;
+    pub const RDEV: u64 = 0x0000_0010;
+    pub const ATIME: u64 = 0x0000_0020;
+    pub const MTIME: u64 = 0x0000_0040;
+    pub const CTIME: u64 = 0x0000_0080;
+    pub const INO: u64 = 0x0000_0100;
+    pub const SIZE: u64 = 0x0000_0200;
+    pub const BLOCKS: u64 = 0x0000_0400;
+    // everything struct stat has
+    pub const BASIC: u64 = 0x0000_07ff;
+}
+
+pub struct SETATTR;
+impl SETATTR {
+    pub const MODE: u32 = 0x0000_0001;
+    pub const UID: u32 = 0x0000_0002;
+    pub const GID: u32 = 0x0000_0004;
+    pub const SIZE: u32 = 0x0000_0008;
+    // without the _SET bit the server stamps its own clock
+    pub const ATIME: u32 = 0x0000_0010;
+    pub const MTIME: u32 = 0x0000_0020;
+    pub const CTIME: u32 = 0x0000_0040;
+    pub const ATIME_SET: u32 = 0x0000_0080;
+    pub const MTIME_SET: u32 = 0x0000_0100;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Utime {
+    Omit,
+    Now,
+    Set(u64, u64),
+}
+
+impl Utime {
+    fn valid(&self, now: u32, set: u32) -> u32 {
+        match self {
+            Utime::Omit => 0,
+            Utime::Now => now,
+            Utime::Set(..) => now | set,
+        }
+    }
+
+    fn time(&self) -> (u64, u64) {
+        match self {
+            Utime::Set(sec, nsec) => (*sec, *nsec),
+            _ => (0, 0),
+        }
+    }
+}
+
+// Rgetattr, mode carries the S_IF* type bits already
+#[derive(Default, Debug)]
+pub struct Attr {
+    pub valid: u64,
+    pub qid: QID,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub rdev: u64,
+    pub size: u64,
+    pub blksize: u64,
+    pub blocks: u64,
+    pub atime: (u64, u64),
+    pub mtime: (u64, u64),
+    pub ctime: (u64, u64),
+}
+
+impl Attr {
+    fn fill(&self, stat: &mut fs::Stat) {
+        stat.st_dev = 0;
+        stat.st_ino = self.qid.path;
+        stat.st_mode = self.mode;
+        stat.st_nlink = self.nlink as u32;
+        stat.st_uid = self.uid;
+        stat.st_gid = self.gid;
+        stat.st_rdev = self.rdev;
+        stat.st_size = self.size as i64;
+        stat.st_blksize = self.blksize as i32;
+        stat.st_blocks = self.blocks as i64;
+        stat.st_atime = self.atime.0 as i64;
+        stat.st_atime_nsec = self.atime.1;
+        stat.st_mtime = self.mtime.0 as i64;
+        stat.st_mtime_nsec = self.mtime.1;
+        stat.st_ctime = self.ctime.0 as i64;
+        stat.st_ctime_nsec = self.ctime.1;
+    }
+}
+
+pub struct LOCK;
+impl LOCK {
+    // same values as F_RDLCK, F_WRLCK and F_UNLCK
+    pub const RDLCK: u8 = 0;
+    pub const WRLCK: u8 = 1;
+    pub const UNLCK: u8 = 2;
+
+    pub const FLAGS_BLOCK: u32 = 1;
+
+    pub const SUCCESS: u8 = 0;
+    pub const BLOCKED: u8 = 1;
+    pub const ERROR: u8 = 2;
+    pub const GRACE: u8 = 3;
+}
+
+// who the server sees holding our locks, proc_id tells tasks apart
+const CLIENT_ID: &'static str = "xyz";
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Flock {
+    pub kind: u8,
+    pub start: u64,
+    // 0 runs to the end of the file
+    pub len: u64,
+    pub proc_id: u32,
+}
+
+#[derive(Default, Debug)]
+pub struct FsStat {
+    pub kind: u32,
+    pub bsize: u32,
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub fsid: u64,
+    pub namelen: u32,
+}
+
+impl FsStat {
+    fn fill(&self, st: &mut fs::Statfs) {
+        st.f_type = self.kind as u64;
+        st.f_bsize = self.bsize as u64;
+        st.f_blocks = self.blocks;
+        st.f_bfree = self.bfree;
+        st.f_bavail = self.bavail;
+        st.f_files = self.files;
+        st.f_ffree = self.ffree;
+        st.f_fsid = self.fsid;
+        st.f_namelen = self.namelen as u64;
+        st.f_frsize = self.bsize as u64;
+    }
+}
+
 mod ops {
-    use core::{cmp::max, hint::spin_loop, mem::forget};
+    use core::{
+        cmp::{max, min},
+        hint::spin_loop,
+        mem::forget,
+    };
 
     use alloc::{string::String, vec::Vec};
 
     use crate::{
-        p9::{Msg, Op, P9, P9L, QID, QIDKind, Stat, VERSION},
+        errno::{EINTR, EIO, ENAMETOOLONG, ENOENT},
+        log,
+        p9::{
+            Attr, CLIENT_ID, Flock, FsStat, IOHDRSZ, Msg, Op, P9, P9L, QID, QIDKind, QSIZE,
+            RLERROR_LEN, Stat, VERSION,
+        },
         print,
-        sched::{mycpu, sleep},
+        sched::{mycpu, sleep, sleep_intr},
         spin::LockGuard,
         stuff::defer,
-        virtio::{self, get_irq_status, irq_ack},
+        virtio::{self, Q, VqDesc, get_irq_status, irq_ack},
     };
 
+    // msg as descriptors, tlen bytes out then rlen back, one per
+    // physically contiguous run of the buffer
+    fn segs(msg: &Msg, tlen: usize, rlen: usize) -> Vec<VqDesc> {
+        let mut table = Vec::new();
+        virtio::push_segs(&mut table, msg.get_buf_ptr() as u64, tlen, false);
+        virtio::push_segs(&mut table, msg.get_buf_ptr() as u64, rlen, true);
+        table
+    }
+
+    // copy table into ring descriptors linked in order, the caller made
+    // sure enough are free. returns the head
+    fn chain(q: &mut Q<QSIZE>, table: &[VqDesc]) -> u16 {
+        let ids: Vec<u16> = table.iter().map(|_| q.alloc_desc().unwrap()).collect();
+        for i in 0..ids.len() {
+            let d = q.get_desc_mut(ids[i] as usize);
+            *d = table[i];
+            if i + 1 < ids.len() {
+                d.set_next(ids[i + 1]);
+            }
+        }
+        ids[0]
+    }
+
+    // queue msg, tlen bytes out, with room for an rlen reply and never
+    // less than an Rlerror. the table may be what the device reads
+    // through an indirect slot, keep it until the reply is in
+    fn post(lock: &LockGuard<P9>, msg: &mut Msg, tlen: usize, rlen: usize) -> Vec<VqDesc> {
+        let p9 = lock.as_mut();
+        let rlen = max(rlen, RLERROR_LEN);
+        if msg.buf.len() < rlen {
+            msg.buf.resize(rlen, 0);
+        }
+
+        let mut table = segs(msg, tlen, rlen);
+        let indirect = p9.indirect
+            && table.len() > 2
+            && virtio::is_contiguous(table.as_ptr() as u64, table.len() * size_of::<VqDesc>());
+        let need = if indirect { 1 } else { table.len() };
+        while p9.q.n_free() < need {
+            sleep(p9.desc_chan(), lock.get_lock());
+        }
+
+        let head = if indirect {
+            p9.q.set_indirect(table.as_mut_slice()).unwrap()
+        } else {
+            chain(&mut p9.q, &table)
+        };
+
+        p9.q.set_desc_data(head as usize, msg.get_self_ptr());
+        let _ = p9.q.add_avail(head);
+
+        let regs = unsafe { p9.regs.unwrap().as_mut() };
+        virtio::set_ready(regs, 0);
+        p9.q.notify(regs, 0);
+        table
+    }
+
+    // post and wait for the reply
+    fn submit(lock: &LockGuard<P9>, msg: &mut Msg, tlen: usize, rlen: usize) {
+        let table = post(lock, msg, tlen, rlen);
+        sleep(msg.get_self_ptr(), lock.get_lock());
+        drop(table);
+    }
+
     pub fn set_version(p9: &mut P9) {
         //size[4] Tversion tag[2] msize[4] version[s]
         let msg_len = 4 + 1 + 2 + 4 + 2 + VERSION.len();
@@ -479,26 +766,13 @@ mod ops {
         let vpos = msg.tell();
         msg.write_str(VERSION);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(msg_len as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(msg_len as u32)
-            .set_data(msg.get_buf_ptr() as u64);
+        let table = segs(&msg, msg_len, msg_len);
+        let head = chain(&mut p9.q, &table);
 
         let regs = unsafe { p9.regs.unwrap().as_mut() };
-        let old = p9.q.add_avail(d1);
+        let old = p9.q.add_avail(head);
         virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
+        p9.q.notify(regs, 0);
 
         p9.q.wait_use(old);
         p9.q.pop_used();
@@ -507,9 +781,13 @@ mod ops {
 
         msg.seek(4);
         let resp_kind = msg.read_u8().unwrap();
+        msg.seek(7);
+        let msize = msg.read_u32().unwrap();
         msg.seek(vpos);
         let rv = msg.read_str().unwrap();
         assert!(resp_kind == Op::RVERSION as u8 && rv == VERSION);
+        // the server may only lower it
+        p9.msize = min(msize, p9.msize);
     }
 
     pub fn attach(p9: &mut P9) {
@@ -528,27 +806,14 @@ mod ops {
         msg.seek(0);
         msg.write_u32(len as u32);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(len as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
+        let table = segs(&msg, len, 20);
+        let head = chain(&mut p9.q, &table);
 
-        desc2
-            .set_writable()
-            .set_len(20)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        let old = p9.q.add_avail(d1);
+        let old = p9.q.add_avail(head);
 
         let regs = unsafe { p9.regs.unwrap().as_mut() };
         virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
+        p9.q.notify(regs, 0);
 
         p9.q.wait_use(old);
         p9.q.pop_used();
@@ -559,18 +824,36 @@ mod ops {
         let resp_kind = msg.read_u8().unwrap();
         assert!(resp_kind == Op::RATTACH as u8);
         msg.seek(7);
-        p9.qid.kind = msg.read_u8().unwrap().try_into().unwrap();
-        p9.qid.version = msg.read_u32().unwrap();
-        p9.qid.path = msg.read_u64().unwrap();
+        p9.qid = msg.read_qid().unwrap();
     }
 
     fn path_to_wnames(path: &str) -> Vec<&str> {
         path.split('/').filter(|s| !s.is_empty()).collect()
     }
 
-    pub fn walk_inner(lock: &LockGuard<P9>, wnames: &[&str]) -> Result<(u32, QID), ()> {
+    // most names a single Twalk may carry
+    const MAXWELEM: usize = 16;
+
+    // deeper paths go MAXWELEM names at a time, each hop walks off the
+    // previous fid which is clunked right after
+    pub fn walk_inner(lock: &LockGuard<P9>, wnames: &[&str]) -> Result<(u32, QID), i32> {
+        if wnames.len() <= MAXWELEM {
+            return walk_from(lock, 0, wnames);
+        }
+
+        let mut chunks = wnames.chunks(MAXWELEM);
+        let (mut fid, mut qid) = walk_from(lock, 0, chunks.next().unwrap())?;
+        for chunk in chunks {
+            let res = walk_from(lock, fid, chunk);
+            let _ = clunk_inner(lock, fid);
+            (fid, qid) = res?;
+        }
+        Ok((fid, qid))
+    }
+
+    fn walk_from(lock: &LockGuard<P9>, from: u32, wnames: &[&str]) -> Result<(u32, QID), i32> {
         let p9 = lock.as_mut();
-        print!("WALK wnames {:?}\n", wnames);
+        log!(Trace, "WALK from {} wnames {:?}\n", from, wnames);
 
         // size[4] Twalk tag[2] fid[4] newfid[4] nwname[2] nwname*(wname[s])
         // size[4] Rwalk tag[2] nwqid[2] nwqid*(wqid[13])
@@ -587,8 +870,8 @@ mod ops {
         msg.write_u32(tlen as u32);
         msg.write_u8(Op::TWALK as u8);
         msg.write_u16(p9.next_tag());
-        msg.write_u32(0);
-        let fid = p9.alloc_fid().unwrap();
+        msg.write_u32(from);
+        let fid = p9.alloc_fid().ok_or(EIO)?;
         let def = defer(|| lock.as_mut().free_fid(fid));
         msg.write_u32(fid);
         msg.write_u16(wnames.len() as u16);
@@ -597,48 +880,28 @@ mod ops {
             msg.write_str(wnames[i]);
         }
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let _ = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        submit(lock, &mut msg, tlen, rlen);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RWALK as u8 {
-            print!("RWALK ERROR\n");
-            return Err(());
+        if let Err(e) = msg.check(Op::RWALK) {
+            log!(Debug, "RWALK ERROR {}\n", e);
+            return Err(e);
         }
         msg.seek(7);
-        let qid_len = msg.read_u16().unwrap() as usize;
+        let qid_len = msg.read_u16().ok_or(EIO)? as usize;
         if qid_len != wnames.len() {
-            print!(
+            // a partial walk means a component is missing
+            log!(
+                Debug,
                 "WARN: qid_len({}) != wnames.len({})\n",
                 qid_len,
                 wnames.len()
             );
-            return Err(());
+            return Err(ENOENT);
         }
 
+        // the server holds newfid from here on
+        forget(def);
+
         let mut qid = super::QID::new();
 
         if qid_len > 0 {
@@ -648,18 +911,21 @@ mod ops {
         }
 
         if qid_len > 0 {
-            qid.kind = msg.read_u8().unwrap().try_into().unwrap();
-            qid.version = msg.read_u32().unwrap();
-            qid.path = msg.read_u64().unwrap();
+            qid = match msg.read_qid() {
+                Some(qid) => qid,
+                None => {
+                    let _ = clunk_inner(lock, fid);
+                    return Err(EIO);
+                }
+            };
         }
 
-        forget(def);
         Ok((fid, qid))
     }
 
-    pub fn walk(path: &str) -> Result<(u32, QID), ()> {
+    pub fn walk(path: &str) -> Result<(u32, QID), i32> {
         if path.is_empty() {
-            return Err(());
+            return Err(ENOENT);
         }
 
         let lock = P9L.acquire();
@@ -667,18 +933,18 @@ mod ops {
 
         let wnames = path_to_wnames(path);
         if wnames.len() > u16::MAX as usize {
-            return Err(());
+            return Err(ENAMETOOLONG);
         }
 
         walk_inner(&lock, &wnames)
     }
 
-    pub fn open(fid: u32, mode: u32) -> Result<(QID, u32), ()> {
+    pub fn open(fid: u32, mode: u32) -> Result<(QID, u32), i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
         // size[4] Topen tag[2] fid[4] mode[4]
@@ -694,98 +960,39 @@ mod ops {
         msg.seek(0);
         msg.write_u32(len as u32);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(len as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(resp_len as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let _ = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        submit(&lock, &mut msg, len, resp_len);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::ROPEN as u8 {
-            return Err(());
-        }
+        msg.check(Op::ROPEN)?;
 
         msg.seek(7);
-        let mut qid = QID::new();
-
-        qid.kind = msg.read_u8().unwrap().try_into().unwrap();
-        qid.version = msg.read_u32().unwrap();
-        qid.path = msg.read_u64().unwrap();
+        let qid = msg.read_qid().ok_or(EIO)?;
 
-        Ok((qid, msg.read_u32().unwrap()))
+        Ok((qid, msg.read_u32().ok_or(EIO)?))
     }
 
-    pub fn remove(fid: u32) -> Result<(), ()> {
+    pub fn remove(fid: u32) -> Result<(), i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
         // size[4] Tremove tag[2] fid[4]
         // size[4] Rremove tag[2]
-        let resp_len = 4 + 1 + 2;
-        let mut msg = Msg::new(resp_len + 4);
-        msg.write_u32(resp_len as u32 + 4);
+        let tlen = 4 + 1 + 2 + 4;
+        let rlen = 4 + 1 + 2;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
         msg.write_u8(Op::TREMOVE as u8);
         msg.write_u16(p9.next_tag());
         msg.write_u32(fid);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(resp_len as u32 + 4);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(resp_len as u32 + 4)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        submit(&lock, &mut msg, tlen, rlen);
 
         p9.free_fid(fid);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        msg.seek(resp_len + 4);
-        if resp_kind != Op::RREMOVE as u8 {
-            return Err(());
-        }
-        Ok(())
+        msg.check(Op::RREMOVE)
     }
 
     enum RWBuf<'a> {
@@ -823,16 +1030,16 @@ mod ops {
         }
     }
 
-    fn rw(fid: u32, mut buf: RWBuf, offt: usize) -> Result<usize, ()> {
+    fn rw(fid: u32, mut buf: RWBuf, offt: usize) -> Result<usize, i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
-        if buf.len() > u16::MAX as usize {
-            return Err(());
+        if buf.len() + IOHDRSZ > p9.msize as usize {
+            return Err(EIO);
         }
 
         let r = buf.is_r();
@@ -843,13 +1050,14 @@ mod ops {
         // size[4] Twrite tag[2] fid[4] offset[8] count[4] data[count]
         // size[4] Rwrite tag[2] count[4]
 
-        let resp_len = 4 + 1 + 2 + 4 + 8 + 4 + buf.len();
+        let resp_len = IOHDRSZ + buf.len();
 
         let mut msg = Msg::new(resp_len);
 
         msg.write_u32(0);
         msg.write_u8(if r { Op::TREAD } else { Op::TWRITE } as u8);
-        msg.write_u16(p9.next_tag());
+        let tag = p9.next_tag();
+        msg.write_u16(tag);
         msg.write_u32(fid);
         msg.write_u64(offt as u64);
         msg.write_u32(buf.len() as u32);
@@ -860,119 +1068,109 @@ mod ops {
         msg.seek(0);
         msg.write_u32(len as u32);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(len as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(resp_len as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        let table = post(&lock, &mut msg, len, resp_len);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != if r { Op::RREAD } else { Op::RWRITE } as u8 {
-            return Err(());
+        // a read of a host fifo can block for good, let signals in. the
+        // reply overwrites the request, if the op byte changed the server
+        // answered before the flush and the op took effect
+        if sleep_intr(msg.get_self_ptr(), lock.get_lock()).is_err() {
+            let op = msg.buf[4];
+            flush_inner(&lock, tag);
+            if msg.buf[4] == op {
+                return Err(EINTR);
+            }
         }
+        drop(table);
+
+        msg.check(if r { Op::RREAD } else { Op::RWRITE })?;
 
         msg.seek(7);
-        let n = msg.read_u32().unwrap() as usize;
+        let n = msg.read_u32().ok_or(EIO)? as usize;
         if r {
-            buf.buf_mut()[0..n].copy_from_slice(&msg.get_buf()[msg.pos..][0..n]);
+            let data = msg.read_slice(n).ok_or(EIO)?;
+            let out = buf.buf_mut();
+            if n > out.len() {
+                return Err(EIO);
+            }
+            out[0..n].copy_from_slice(data);
         }
         Ok(n)
     }
 
-    pub fn read(fid: u32, buf: &mut [u8], offt: usize) -> Result<usize, ()> {
+    pub fn read(fid: u32, buf: &mut [u8], offt: usize) -> Result<usize, i32> {
         rw(fid, RWBuf::R(buf), offt)
     }
 
-    pub fn write(fid: u32, buf: &[u8], offt: usize) -> Result<usize, ()> {
+    pub fn write(fid: u32, buf: &[u8], offt: usize) -> Result<usize, i32> {
         rw(fid, RWBuf::W(buf), offt)
     }
 
-    pub fn clunk_inner(lock: &LockGuard<P9>, fid: u32) -> Result<(), ()> {
+    pub fn clunk_inner(lock: &LockGuard<P9>, fid: u32) -> Result<(), i32> {
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
-        let mut msg = Msg::new(11);
-        msg.write_u32(11);
+        // size[4] Tclunk tag[2] fid[4]
+        // size[4] Rclunk tag[2]
+        let tlen = 4 + 1 + 2 + 4;
+        let rlen = 4 + 1 + 2;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
         msg.write_u8(Op::TCLUNK as u8);
         msg.write_u16(p9.next_tag());
         msg.write_u32(fid);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(11);
+        submit(lock, &mut msg, tlen, rlen);
 
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(7)
-            .set_data(msg.get_buf_ptr() as u64);
+        p9.free_fid(fid);
 
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
+        log!(Trace, "CLOSED: fid {} {:?}\n", fid, p9.fid_bs.first_clr());
 
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
+        msg.check(Op::RCLUNK)
+    }
 
-        sleep(msg.get_self_ptr(), lock.get_lock());
+    // abandon the request tagged oldtag. the server answers it, if at
+    // all, before Rflush so once that is in neither the old Msg nor its
+    // descriptors are touched again and the caller may drop them. only
+    // rw sleeps interruptibly and it flushes before returning, so no
+    // request outlives the task that sent it and teardown has nothing
+    // to cancel
+    pub fn flush_inner(lock: &LockGuard<P9>, oldtag: u16) {
+        let p9 = lock.as_mut();
 
-        p9.free_fid(fid);
+        // size[4] Tflush tag[2] oldtag[2]
+        // size[4] Rflush tag[2]
+        let tlen = 4 + 1 + 2 + 2;
+        let rlen = 4 + 1 + 2;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
+        msg.write_u8(Op::TFLUSH as u8);
+        msg.write_u16(p9.next_tag());
+        msg.write_u16(oldtag);
 
-        print!("CLOSED: fid {} {:?}\n", fid, p9.fid_bs.first_clr());
+        // Tflush can't fail and mustn't be interrupted itself
+        submit(lock, &mut msg, tlen, rlen);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RCLUNK as u8 {
-            return Err(());
+        if msg.check(Op::RFLUSH).is_err() {
+            log!(Warn, "TFLUSH: unexpected reply for tag {}\n", oldtag);
         }
-
-        Ok(())
     }
 
-    pub fn clunk(fid: u32) -> Result<(), ()> {
-        // size[4] Tclunk tag[2] fid[4]
-        // size[4] Rclunk tag[2]
+    pub fn clunk(fid: u32) -> Result<(), i32> {
         let lock = P9L.acquire();
         clunk_inner(&lock, fid)
     }
 
-    pub fn create(path: &str, perm: u32, mode: u32, gid: u32) -> Result<(u32, QID, u32), ()> {
+    pub fn create(path: &str, perm: u32, mode: u32, gid: u32) -> Result<(u32, QID, u32), i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         let wnames = path_to_wnames(path);
 
         if wnames.len() < 1 {
-            return Err(());
+            return Err(ENOENT);
         }
 
         let dir_wnames = if wnames.len() > 1 {
@@ -981,19 +1179,23 @@ mod ops {
             &["."][0..]
         };
 
-        print!("CREATE WNAMES: {:?}\n", wnames);
-        print!("CREATE DIRNAMES: {:?}\n", dir_wnames);
+        log!(Trace, "CREATE WNAMES: {:?}\n", wnames);
+        log!(Trace, "CREATE DIRNAMES: {:?}\n", dir_wnames);
 
-        let (dir_fid, _) = walk_inner(&lock, dir_wnames).map_err(|_| ())?;
+        let (dir_fid, _) = walk_inner(&lock, dir_wnames)?;
 
         let def = defer(|| {
             clunk_inner(&lock, dir_fid);
         });
 
         let name = wnames[wnames.len() - 1];
-        print!(
+        log!(
+            Trace,
             "CREATE NAME: {:?} fid {:?} mode {} perm {}\n",
-            name, dir_fid, mode, perm
+            name,
+            dir_fid,
+            mode,
+            perm
         );
 
         // size[4] Tcreate tag[2] fid[4] name[s] perm[4] mode[1] xt s
@@ -1007,59 +1209,29 @@ mod ops {
 
         msg.write_u32(dir_fid);
         msg.write_str(name);
-        msg.write_u32(0o0777);
+        msg.write_u32(perm & 0o7777);
         msg.write_u8(mode as u8);
         msg.write_u16(0);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
+        submit(&lock, &mut msg, tlen, rlen);
 
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
-
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RCREATE as u8 {
-            return Err(());
-        }
+        msg.check(Op::RCREATE)?;
 
         msg.seek(7);
-        let mut qid = QID::new();
-
-        qid.kind = msg.read_u8().unwrap().try_into().unwrap();
-        qid.version = msg.read_u32().unwrap();
-        qid.path = msg.read_u64().unwrap();
+        let qid = msg.read_qid().ok_or(EIO)?;
+        let iou = msg.read_u32().ok_or(EIO)?;
 
         forget(def);
-        Ok((dir_fid, qid, msg.read_u32().unwrap()))
+        Ok((dir_fid, qid, iou))
     }
 
-    pub fn mkdir(path: &str, mut mode: u32, gid: u32) -> Result<QID, ()> {
+    pub fn mkdir(path: &str, mut mode: u32, gid: u32) -> Result<QID, i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
         let wnames = path_to_wnames(path);
 
         if wnames.len() < 1 {
-            return Err(());
+            return Err(ENOENT);
         }
 
         let dir_wnames = if wnames.len() > 1 {
@@ -1068,17 +1240,17 @@ mod ops {
             &["."][0..]
         };
 
-        print!("MKDIR WNAMES: {:?}\n", wnames);
-        print!("MKDIR DIRNAMES: {:?}\n", dir_wnames);
+        log!(Trace, "MKDIR WNAMES: {:?}\n", wnames);
+        log!(Trace, "MKDIR DIRNAMES: {:?}\n", dir_wnames);
 
-        let (dir_fid, _) = walk_inner(&lock, dir_wnames).map_err(|_| ())?;
+        let (dir_fid, _) = walk_inner(&lock, dir_wnames)?;
 
         let def = defer(|| {
             clunk_inner(&lock, dir_fid);
         });
 
         let name = wnames[wnames.len() - 1];
-        print!("MKDIR NAME: {:?} fid {:?}\n", name, dir_fid);
+        log!(Trace, "MKDIR NAME: {:?} fid {:?}\n", name, dir_fid);
 
         // mode = 0777;
 
@@ -1095,57 +1267,26 @@ mod ops {
         msg.write_u32(mode as u32);
         msg.write_u32(gid);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        submit(&lock, &mut msg, tlen, rlen);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RMKDIR as u8 {
-            return Err(());
-        }
+        msg.check(Op::RMKDIR)?;
 
         msg.seek(7);
-        let mut qid = QID::new();
-
-        qid.kind = msg.read_u8().unwrap().try_into().unwrap();
-        qid.version = msg.read_u32().unwrap();
-        qid.path = msg.read_u64().unwrap();
+        let qid = msg.read_qid().ok_or(EIO)?;
 
         Ok(qid)
     }
 
-    pub fn readdir(fid: u32, buf: &mut [u8], offt: u64) -> Result<u32, ()> {
+    pub fn readdir(fid: u32, buf: &mut [u8], offt: u64) -> Result<u32, i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
         if buf.len() > u16::MAX as usize {
-            return Err(());
+            return Err(EIO);
         }
 
         // size[4] Treaddir tag[2] fid[4] offt [8] count [4]
@@ -1160,48 +1301,24 @@ mod ops {
         msg.write_u64(offt);
         msg.write_u32(buf.len() as u32);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        submit(&lock, &mut msg, tlen, rlen);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RREADDIR as u8 {
-            return Err(());
-        }
+        msg.check(Op::RREADDIR)?;
         msg.seek(7);
-        let count = msg.read_u32().unwrap();
-        buf[0..count as usize].copy_from_slice(&msg.get_buf()[msg.pos..msg.pos + count as usize]);
+        let count = msg.read_u32().ok_or(EIO)?;
+        if count as usize > buf.len() {
+            return Err(EIO);
+        }
+        buf[0..count as usize].copy_from_slice(msg.read_slice(count as usize).ok_or(EIO)?);
         Ok(count)
     }
 
-    pub fn stat(fid: u32) -> Result<Stat, ()> {
+    pub fn stat(fid: u32) -> Result<Stat, i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
         // size[4] Tstat tag[2] fid[4]
@@ -1228,73 +1345,261 @@ mod ops {
         msg.write_u16(p9.next_tag());
         msg.write_u32(fid);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
+        submit(&lock, &mut msg, tlen, rlen);
 
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
+        msg.check(Op::RSTAT)?;
+        msg.seek(11);
+        let mut stat = Stat::default();
+        stat.kind = msg.read_u16().ok_or(EIO)?;
+        stat.dev = msg.read_u32().ok_or(EIO)?;
 
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
+        stat.qid = msg.read_qid().ok_or(EIO)?;
 
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
+        stat.mode = msg.read_u32().ok_or(EIO)?;
+        stat.mode |= if stat.qid.kind.is_dir() {
+            0x4000
+        } else if stat.qid.kind.is_symlink() {
+            0xA000
+        } else {
+            0x8000
+        };
+        stat.atime = msg.read_u32().ok_or(EIO)?;
+        stat.mtime = msg.read_u32().ok_or(EIO)?;
+        stat.len = msg.read_u64().ok_or(EIO)?;
+        Ok(stat)
+    }
 
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
+    pub fn getattr(fid: u32, mask: u64) -> Result<Attr, i32> {
+        let lock = P9L.acquire();
+        let p9 = lock.as_mut();
 
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-        let _ = old;
+        if !p9.fid_is_ok(fid) {
+            return Err(EIO);
+        }
 
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        // size[4] Tgetattr tag[2] fid[4] request_mask[8]
+        // size[4] Rgetattr tag[2] valid[8] qid[13] mode[4] uid[4] gid[4]
+        // nlink[8] rdev[8] size[8] blksize[8] blocks[8]
+        // atime_sec[8] atime_nsec[8] mtime_sec[8] mtime_nsec[8]
+        // ctime_sec[8] ctime_nsec[8] btime_sec[8] btime_nsec[8]
+        // gen[8] data_version[8]
+        let tlen = 4 + 1 + 2 + 4 + 8;
+        let rlen = 4 + 1 + 2 + 8 + 13 + 4 + 4 + 4 + 8 * 15;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
+        msg.write_u8(Op::TGETATTR as u8);
+        msg.write_u16(p9.next_tag());
+        msg.write_u32(fid);
+        msg.write_u64(mask);
 
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RSTAT as u8 {
-            return Err(());
-        }
-        msg.seek(11);
-        let mut stat = Stat::default();
-        stat.kind = msg.read_u16().unwrap();
-        stat.dev = msg.read_u32().unwrap();
-
-        stat.qid.kind = msg.read_u8().unwrap().try_into().unwrap();
-        stat.qid.version = msg.read_u32().unwrap();
-        stat.qid.path = msg.read_u64().unwrap();
-
-        stat.mode = msg.read_u32().unwrap();
-        stat.mode |= match stat.qid.kind {
-            QIDKind::DIR => 0x4000,
-            QIDKind::APPEND => todo!(),
-            QIDKind::EXCL => todo!(),
-            QIDKind::MOUNT => todo!(),
-            QIDKind::AUTH => todo!(),
-            QIDKind::TMP => todo!(),
-            QIDKind::DONT => todo!(),
-            QIDKind::SYMLINK => 0xA000,
-            QIDKind::LINK | QIDKind::FILE => 0x8000,
-        };
-        stat.atime = msg.read_u32().unwrap();
-        stat.mtime = msg.read_u32().unwrap();
-        stat.len = msg.read_u64().unwrap();
-        Ok(stat)
-    }
+        submit(&lock, &mut msg, tlen, rlen);
 
-    pub fn wstat(fid: u32, stat: &Stat) -> Result<(), ()> {
+        msg.check(Op::RGETATTR)?;
+        msg.seek(7);
+        let mut attr = Attr::default();
+        attr.valid = msg.read_u64().ok_or(EIO)?;
+        attr.qid = msg.read_qid().ok_or(EIO)?;
+        attr.mode = msg.read_u32().ok_or(EIO)?;
+        attr.uid = msg.read_u32().ok_or(EIO)?;
+        attr.gid = msg.read_u32().ok_or(EIO)?;
+        attr.nlink = msg.read_u64().ok_or(EIO)?;
+        attr.rdev = msg.read_u64().ok_or(EIO)?;
+        attr.size = msg.read_u64().ok_or(EIO)?;
+        attr.blksize = msg.read_u64().ok_or(EIO)?;
+        attr.blocks = msg.read_u64().ok_or(EIO)?;
+        attr.atime = (msg.read_u64().ok_or(EIO)?, msg.read_u64().ok_or(EIO)?);
+        attr.mtime = (msg.read_u64().ok_or(EIO)?, msg.read_u64().ok_or(EIO)?);
+        attr.ctime = (msg.read_u64().ok_or(EIO)?, msg.read_u64().ok_or(EIO)?);
+        Ok(attr)
+    }
+
+    // the status byte, LOCK::SUCCESS, BLOCKED, ...
+    pub fn lock(fid: u32, fl: &Flock, flags: u32) -> Result<u8, i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
-        // size[4] Tstat tag[2] fid[4]
+        // size[4] Tlock tag[2] fid[4] type[1] flags[4] start[8] length[8]
+        // proc_id[4] client_id[s]
+        // size[4] Rlock tag[2] status[1]
+        let tlen = 4 + 1 + 2 + 4 + 1 + 4 + 8 + 8 + 4 + 2 + CLIENT_ID.len();
+        let rlen = 4 + 1 + 2 + 1;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
+        msg.write_u8(Op::TLOCK as u8);
+        msg.write_u16(p9.next_tag());
+        msg.write_u32(fid);
+        msg.write_u8(fl.kind);
+        msg.write_u32(flags);
+        msg.write_u64(fl.start);
+        msg.write_u64(fl.len);
+        msg.write_u32(fl.proc_id);
+        msg.write_str(CLIENT_ID);
+
+        submit(&lock, &mut msg, tlen, rlen);
+
+        msg.check(Op::RLOCK)?;
+        msg.seek(7);
+        msg.read_u8().ok_or(EIO)
+    }
+
+    // the first lock that would conflict with fl, kind is LOCK::UNLCK
+    // when there is none
+    pub fn getlock(fid: u32, fl: &Flock) -> Result<Flock, i32> {
+        let lock = P9L.acquire();
+        let p9 = lock.as_mut();
+
+        if !p9.fid_is_ok(fid) {
+            return Err(EIO);
+        }
+
+        // size[4] Tgetlock tag[2] fid[4] type[1] start[8] length[8]
+        // proc_id[4] client_id[s]
+        // size[4] Rgetlock tag[2] type[1] start[8] length[8] proc_id[4]
+        // client_id[s]
+        let tlen = 4 + 1 + 2 + 4 + 1 + 8 + 8 + 4 + 2 + CLIENT_ID.len();
+        let rlen = 4 + 1 + 2 + 1 + 8 + 8 + 4 + 2 + 256;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
+        msg.write_u8(Op::TGETLOCK as u8);
+        msg.write_u16(p9.next_tag());
+        msg.write_u32(fid);
+        msg.write_u8(fl.kind);
+        msg.write_u64(fl.start);
+        msg.write_u64(fl.len);
+        msg.write_u32(fl.proc_id);
+        msg.write_str(CLIENT_ID);
+
+        submit(&lock, &mut msg, tlen, rlen);
+
+        msg.check(Op::RGETLOCK)?;
+        msg.seek(7);
+        let mut res = Flock::default();
+        res.kind = msg.read_u8().ok_or(EIO)?;
+        res.start = msg.read_u64().ok_or(EIO)?;
+        res.len = msg.read_u64().ok_or(EIO)?;
+        res.proc_id = msg.read_u32().ok_or(EIO)?;
+        Ok(res)
+    }
+
+    pub fn fsync(fid: u32, datasync: bool) -> Result<(), i32> {
+        let lock = P9L.acquire();
+        let p9 = lock.as_mut();
+
+        if !p9.fid_is_ok(fid) {
+            return Err(EIO);
+        }
+
+        // size[4] Tfsync tag[2] fid[4] datasync[4]
+        // size[4] Rfsync tag[2]
+        let tlen = 4 + 1 + 2 + 4 + 4;
+        let rlen = 4 + 1 + 2;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
+        msg.write_u8(Op::TFSYNC as u8);
+        msg.write_u16(p9.next_tag());
+        msg.write_u32(fid);
+        msg.write_u32(datasync as u32);
+
+        submit(&lock, &mut msg, tlen, rlen);
+
+        msg.check(Op::RFSYNC)
+    }
+
+    pub fn statfs(fid: u32) -> Result<FsStat, i32> {
+        let lock = P9L.acquire();
+        let p9 = lock.as_mut();
+
+        if !p9.fid_is_ok(fid) {
+            return Err(EIO);
+        }
+
+        // size[4] Tstatfs tag[2] fid[4]
+        // size[4] Rstatfs tag[2] type[4] bsize[4] blocks[8] bfree[8]
+        // bavail[8] files[8] ffree[8] fsid[8] namelen[4]
+        let tlen = 4 + 1 + 2 + 4;
+        let rlen = 4 + 1 + 2 + 4 + 4 + 8 * 6 + 4;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
+        msg.write_u8(Op::TSTATFS as u8);
+        msg.write_u16(p9.next_tag());
+        msg.write_u32(fid);
+
+        submit(&lock, &mut msg, tlen, rlen);
+
+        msg.check(Op::RSTATFS)?;
+        msg.seek(7);
+        let mut st = FsStat::default();
+        st.kind = msg.read_u32().ok_or(EIO)?;
+        st.bsize = msg.read_u32().ok_or(EIO)?;
+        st.blocks = msg.read_u64().ok_or(EIO)?;
+        st.bfree = msg.read_u64().ok_or(EIO)?;
+        st.bavail = msg.read_u64().ok_or(EIO)?;
+        st.files = msg.read_u64().ok_or(EIO)?;
+        st.ffree = msg.read_u64().ok_or(EIO)?;
+        st.fsid = msg.read_u64().ok_or(EIO)?;
+        st.namelen = msg.read_u32().ok_or(EIO)?;
+        Ok(st)
+    }
+
+    pub fn setattr(
+        fid: u32,
+        valid: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        size: u64,
+        atime: (u64, u64),
+        mtime: (u64, u64),
+    ) -> Result<(), i32> {
+        if valid == 0 {
+            return Ok(());
+        }
+
+        let lock = P9L.acquire();
+        let p9 = lock.as_mut();
+
+        if !p9.fid_is_ok(fid) {
+            return Err(EIO);
+        }
+
+        // size[4] Tsetattr tag[2] fid[4] valid[4] mode[4] uid[4] gid[4]
+        // size[8] atime_sec[8] atime_nsec[8] mtime_sec[8] mtime_nsec[8]
+        // size[4] Rsetattr tag[2]
+        let tlen = 4 + 1 + 2 + 4 + 4 + 4 + 4 + 4 + 8 * 5;
+        let rlen = 4 + 1 + 2 + 4;
+        let mut msg = Msg::new(max(tlen, rlen));
+        msg.write_u32(tlen as u32);
+        msg.write_u8(Op::TSETATTR as u8);
+        msg.write_u16(p9.next_tag());
+        msg.write_u32(fid);
+        msg.write_u32(valid);
+        msg.write_u32(mode);
+        msg.write_u32(uid);
+        msg.write_u32(gid);
+        msg.write_u64(size);
+        msg.write_u64(atime.0);
+        msg.write_u64(atime.1);
+        msg.write_u64(mtime.0);
+        msg.write_u64(mtime.1);
+
+        submit(&lock, &mut msg, tlen, rlen);
+
+        msg.check(Op::RSETATTR)
+    }
+
+    pub fn wstat(fid: u32, stat: &Stat) -> Result<(), i32> {
+        let lock = P9L.acquire();
+        let p9 = lock.as_mut();
+
+        if !p9.fid_is_ok(fid) {
+            return Err(EIO);
+        }
+
+        // size[4] Tstat tag[2] fid[4]
         //
         // size[4]
         // Rwstat
@@ -1339,7 +1644,7 @@ mod ops {
         // [4] dev
         msg.write_u32(stat.dev);
         // [13] qid
-        msg.write_u8(stat.qid.kind as u8);
+        msg.write_u8(stat.qid.kind.0);
         msg.write_u32(stat.qid.version);
         msg.write_u64(stat.qid.path);
         // [4] mode
@@ -1367,47 +1672,17 @@ mod ops {
         // [4] muid
         msg.write_u32(!0);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
+        submit(&lock, &mut msg, tlen, rlen);
 
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-        let _ = old;
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
-
-        msg.seek(4);
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RWSTAT as u8 {
-            return Err(());
-        }
-
-        Ok(())
+        msg.check(Op::RWSTAT)
     }
 
-    pub fn readlink(fid: u32) -> Result<String, ()> {
+    pub fn readlink(fid: u32) -> Result<String, i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         if !p9.fid_is_ok(fid) {
-            return Err(());
+            return Err(EIO);
         }
 
         // size[4] Treadlink tag[2] fid[4]
@@ -1423,51 +1698,23 @@ mod ops {
         msg.write_u16(p9.next_tag());
         msg.write_u32(fid);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
+        submit(&lock, &mut msg, tlen, rlen);
 
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
-
-        msg.seek(4);
-
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RREADLINK as u8 {
-            return Err(());
-        }
+        msg.check(Op::RREADLINK)?;
 
         msg.seek(7);
-        let str = msg.read_str().unwrap();
+        let str = msg.read_str().ok_or(EIO)?;
         Ok(String::from(str))
     }
 
-    pub fn symlink(linkname: &str, path: &str) -> Result<(), ()> {
+    pub fn symlink(linkname: &str, path: &str) -> Result<QID, i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
         let wnames = path_to_wnames(path);
 
         if wnames.len() < 1 {
-            return Err(());
+            return Err(ENOENT);
         }
 
         let dir_wnames = if wnames.len() > 1 {
@@ -1476,17 +1723,17 @@ mod ops {
             &["."][0..]
         };
 
-        print!("SYMLINK WNAMES: {:?}\n", wnames);
-        print!("SYMLINK DIRNAMES: {:?}\n", dir_wnames);
+        log!(Trace, "SYMLINK WNAMES: {:?}\n", wnames);
+        log!(Trace, "SYMLINK DIRNAMES: {:?}\n", dir_wnames);
 
-        let (dir_fid, _) = walk_inner(&lock, dir_wnames).map_err(|_| ())?;
+        let (dir_fid, _) = walk_inner(&lock, dir_wnames)?;
 
         let def = defer(|| {
             clunk_inner(&lock, dir_fid);
         });
 
         let name = wnames[wnames.len() - 1];
-        print!("SYMLINK NAME: {:?} fid {:?}\n", name, dir_fid);
+        log!(Trace, "SYMLINK NAME: {:?} fid {:?}\n", name, dir_fid);
 
         // size[4] Tsymlink tag[2] fid[4] name s symname s gid[4]
         // size[4] Rsymlink tag[2] qid[13]
@@ -1504,148 +1751,63 @@ mod ops {
         msg.write_str(linkname);
         msg.write_u32(0);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
-
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
-
-        msg.seek(4);
+        submit(&lock, &mut msg, tlen, rlen);
 
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RSYMLINK as u8 {
-            return Err(());
-        }
+        msg.check(Op::RSYMLINK)?;
 
-        Ok(())
+        msg.seek(7);
+        msg.read_qid().ok_or(EIO)
     }
 
-    pub fn rename(fid: u32, path: &str) -> Result<(), ()> {
+    pub fn renameat(olddfid: u32, oldname: &str, newdfid: u32, newname: &str) -> Result<(), i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
-        let wnames = path_to_wnames(path);
-
-        if wnames.len() < 1 {
-            return Err(());
+        if !p9.fid_is_ok(olddfid) || !p9.fid_is_ok(newdfid) {
+            return Err(EIO);
         }
 
-        let dir_wnames = if wnames.len() > 1 {
-            &wnames[0..wnames.len() - 1]
-        } else {
-            &["."][0..]
-        };
-
-        print!("RENAME WNAMES: {:?}\n", wnames);
-        print!("RENAME DIRNAMES: {:?}\n", dir_wnames);
-
-        let (dir_fid, _) = walk_inner(&lock, dir_wnames).map_err(|_| ())?;
-
-        let def = defer(|| {
-            clunk_inner(&lock, dir_fid);
-        });
-
-        let name = wnames[wnames.len() - 1];
-        print!("RENAME NAME: {:?} fid {:?}\n", name, dir_fid);
+        log!(
+            Trace,
+            "RENAMEAT: {} {:?} -> {} {:?}\n",
+            olddfid,
+            oldname,
+            newdfid,
+            newname
+        );
 
-        // size[4] Trename tag[2] fid[4] dirfid [4] name s
-        // size[4] Rrename tag[2]
+        // size[4] Trenameat tag[2] olddirfid[4] oldname[s] newdirfid[4] newname[s]
+        // size[4] Rrenameat tag[2]
 
-        let tlen = 4 + 1 + 2 + 4 + 4 + 2 + name.len();
+        let tlen = 4 + 1 + 2 + 4 + 2 + oldname.len() + 4 + 2 + newname.len();
         let rlen = 4 + 1 + 2;
 
         let mut msg = Msg::new(max(tlen, rlen));
 
         msg.write_u32(tlen as u32);
-        msg.write_u8(Op::TRENAME as u8);
+        msg.write_u8(Op::TRENAMEAT as u8);
         msg.write_u16(p9.next_tag());
-        msg.write_u32(fid);
-        msg.write_u32(dir_fid);
-        msg.write_str(name);
-
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
+        msg.write_u32(olddfid);
+        msg.write_str(oldname);
+        msg.write_u32(newdfid);
+        msg.write_str(newname);
 
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
+        submit(&lock, &mut msg, tlen, rlen);
 
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
-
-        msg.seek(4);
-
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RRENAME as u8 {
-            return Err(());
-        }
-
-        Ok(())
+        msg.check(Op::RRENAMEAT)
     }
 
-    pub fn link(fid: u32, path: &str) -> Result<(), ()> {
+    pub fn link(dir_fid: u32, fid: u32, name: &str) -> Result<(), i32> {
         let lock = P9L.acquire();
         let p9 = lock.as_mut();
 
-        let wnames = path_to_wnames(path);
-
-        if wnames.len() < 1 {
-            return Err(());
+        if !p9.fid_is_ok(dir_fid) || !p9.fid_is_ok(fid) {
+            return Err(EIO);
         }
 
-        let dir_wnames = if wnames.len() > 1 {
-            &wnames[0..wnames.len() - 1]
-        } else {
-            &["."][0..]
-        };
-
-        print!("LINK WNAMES: {:?}\n", wnames);
-        print!("LINK DIRNAMES: {:?}\n", dir_wnames);
+        log!(Trace, "LINK NAME: {:?} dir {} fid {}\n", name, dir_fid, fid);
 
-        let (dir_fid, _) = walk_inner(&lock, dir_wnames).map_err(|_| ())?;
-
-        let def = defer(|| {
-            clunk_inner(&lock, dir_fid);
-        });
-
-        let name = wnames[wnames.len() - 1];
-        print!("LINK NAME: {:?} fid {:?}\n", name, dir_fid);
-
-        // size[4] Tlink tag[2] olddirfid[4] newdirfid [4] name s
+        // size[4] Tlink tag[2] dfid[4] fid[4] name[s]
         // size[4] Rlink tag[2]
 
         let tlen = 4 + 1 + 2 + 4 + 4 + 2 + name.len();
@@ -1660,45 +1822,29 @@ mod ops {
         msg.write_u32(fid);
         msg.write_str(name);
 
-        let d1 = p9.q.alloc_desc().unwrap();
-        let d2 = p9.q.alloc_desc().unwrap();
-
-        let desc1 = p9.q.get_desc_mut(d1 as usize);
-        desc1
-            .set_next(d2)
-            .set_data(msg.get_buf_ptr() as u64)
-            .set_len(tlen as u32);
-
-        let desc2 = p9.q.get_desc_mut(d2 as usize);
-
-        desc2
-            .set_writable()
-            .set_len(rlen as u32)
-            .set_data(msg.get_buf_ptr() as u64);
-
-        p9.q.set_desc_data(d1 as usize, msg.get_self_ptr());
-        let old = p9.q.add_avail(d1);
+        submit(&lock, &mut msg, tlen, rlen);
 
-        let regs = unsafe { p9.regs.unwrap().as_mut() };
-        virtio::set_ready(regs, 0);
-        virtio::notify_q(regs, 0);
-
-        sleep(msg.get_self_ptr(), lock.get_lock());
+        msg.check(Op::RLINK)
+    }
+}
 
-        msg.seek(4);
+static IRQ: AtomicU32 = AtomicU32::new(0);
 
-        let resp_kind = msg.read_u8().unwrap();
-        if resp_kind != Op::RLINK as u8 {
-            print!("RLINK FAIL: {}\n", resp_kind);
-            return Err(());
-        }
+pub fn present() -> bool {
+    IRQ.load(Ordering::Relaxed) != 0
+}
 
-        Ok(())
-    }
+pub fn irq() -> u32 {
+    IRQ.load(Ordering::Relaxed)
 }
 
 pub fn irq_handle() {
-    let lock = P9L.acquire();
+    // the status stays unacked while someone holds the lock, the device
+    // keeps the line up and the irq comes back
+    let lock = match P9L.try_acquire() {
+        Some(l) => l,
+        _ => return,
+    };
     let p9 = lock.as_mut();
     assert!(p9.regs.is_some());
     let regs = unsafe { p9.regs.unwrap().as_mut() };
@@ -1708,11 +1854,17 @@ pub fn irq_handle() {
         panic!("device config changed.");
     }
 
+    // replies wake whoever sleeps on their Msg, an Rflush the flusher
+    let mut freed = false;
     while let Some((_, data)) = p9.q.peek_used() {
         if data != 0 {
             wakeup(data);
         }
         p9.q.pop_used();
+        freed = true;
+    }
+    if freed {
+        wakeup(p9.desc_chan());
     }
     virtio::irq_ack(regs, irq_status);
 }
@@ -1729,7 +1881,9 @@ pub fn init(regs: &mut Regs, irq: u32) {
     p9.regs = NonNull::new(regs as *mut Regs);
     p9.alloc_fid().unwrap(); // waste fid 0
 
-    init_dev_common(regs, 0);
+    let features = init_dev_common(regs, Feature::INDIRECT_DESC | Feature::EVENT_IDX, 0);
+    p9.indirect = features & Feature::INDIRECT_DESC != 0;
+    p9.q.set_event_idx(features & Feature::EVENT_IDX != 0);
 
     virtio::set_q_len(regs, 0, p9.q.len());
     virtio::set_used_area(regs, p9.q.used_area_paddr());
@@ -1748,14 +1902,27 @@ pub fn init(regs: &mut Regs, irq: u32) {
     // root.qid = p9.qid;
     // root.iou = u16::MAX as u32;
 
+    IRQ.store(irq, Ordering::Relaxed);
     gic_enable_intr(irq as usize);
 }
 
+// small writes are held back and sent as one Twrite
+const WBUF: usize = 4096;
+
 pub struct File {
     pub fid: u32,
     pub iou: u32,
     pub qid: QID,
     st: Option<Stat>,
+    wbuf: Vec<u8>,
+    woff: usize,
+    // a flush nobody could report to failed, the next write or fsync
+    // returns it
+    werr: Option<i32>,
+    // wbuf has one user at a time, a stat or open by path flushes it from
+    // whatever task asked. Twrite sleeps, so waiters yield
+    wbusy: AtomicBool,
+    sync: bool,
 }
 
 impl File {
@@ -1765,421 +1932,599 @@ impl File {
             iou: 0,
             qid: QID::new(),
             st: None,
+            wbuf: Vec::new(),
+            woff: 0,
+            werr: None,
+            wbusy: AtomicBool::new(false),
+            sync: false,
         }
     }
+
+    fn reset(&mut self, fid: u32, iou: u32, qid: QID, flags: u32) {
+        self.fid = fid;
+        // 0 leaves it to msize
+        self.iou = if iou == 0 {
+            max_io() as u32
+        } else {
+            min(iou as usize, max_io()) as u32
+        };
+        self.qid = qid;
+        self.st = if let Ok(st) = ops::stat(fid) {
+            Some(st)
+        } else {
+            None
+        };
+        self.wbuf.clear();
+        self.woff = 0;
+        self.werr = None;
+        self.sync = flags & (fs::O::DSYNC | fs::O::SYNC) != 0;
+    }
 }
 
 impl File {
-    pub fn read(&self, buf: &mut [u8], offt: usize) -> Result<usize, ()> {
-        let len = min(self.iou as usize, buf.len());
-        ops::read(self.fid, &mut buf[0..len], offt)
+    // one Tread per iounit until the buffer is full or the server comes
+    // up short
+    pub fn read(&mut self, buf: &mut [u8], offt: usize) -> Result<usize, ()> {
+        let _ = self.flush();
+        let mut done = 0;
+        while done < buf.len() {
+            let len = min(self.iou as usize, buf.len() - done);
+            let n = match ops::read(self.fid, &mut buf[done..done + len], offt + done) {
+                Ok(n) => n,
+                Err(_) if done > 0 => break,
+                Err(_) => return Err(()),
+            };
+            done += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write_through(&mut self, buf: &[u8], offt: usize) -> Result<usize, i32> {
+        let mut done = 0;
+        while done < buf.len() {
+            let len = min(self.iou as usize, buf.len() - done);
+            let n = match ops::write(self.fid, &buf[done..done + len], offt + done) {
+                Ok(n) => n,
+                Err(_) if done > 0 => break,
+                Err(e) => return Err(e),
+            };
+            done += n;
+            if n < len {
+                break;
+            }
+        }
+        self.grow(offt + done);
+        Ok(done)
+    }
+
+    pub fn write(&mut self, buf: &[u8], offt: usize) -> Result<usize, i32> {
+        let _held = self.hold_wbuf();
+        self.take_werr()?;
+        if self.sync || buf.len() >= WBUF {
+            self.flush_held()?;
+            return self.write_through(buf, offt);
+        }
+
+        if self.wbuf.len() > 0
+            && (offt != self.woff + self.wbuf.len() || self.wbuf.len() + buf.len() > WBUF)
+        {
+            self.flush_held()?;
+        }
+
+        if self.wbuf.len() == 0 {
+            self.woff = offt;
+        }
+        self.wbuf.extend_from_slice(buf);
+        self.grow(offt + buf.len());
+        Ok(buf.len())
     }
 
-    pub fn write(&self, buf: &[u8], offt: usize) -> Result<usize, ()> {
-        let len = min(self.iou as usize, buf.len());
-        ops::write(self.fid, &buf[0..len], offt)
+    fn hold_wbuf(&self) -> Defer<impl FnOnce() + use<>> {
+        while self.wbusy.swap(true, Ordering::Acquire) {
+            yild();
+        }
+        let busy = &self.wbusy as *const AtomicBool;
+        defer(move || unsafe { &*busy }.store(false, Ordering::Release))
     }
 
-    pub fn close(&mut self) -> Result<(), ()> {
-        print!("CLOSE CLUNK {}\n", self.fid);
-        ops::clunk(self.fid).unwrap();
-        self.st = None;
+    fn take_werr(&mut self) -> Result<(), i32> {
+        match self.werr.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // for callers that have nobody to report to, a failure is kept for
+    // the next write or fsync
+    pub fn flush(&mut self) -> Result<(), i32> {
+        let _held = self.hold_wbuf();
+        let res = self.flush_held();
+        if let Err(e) = res {
+            self.werr = Some(e);
+        }
+        res
+    }
+
+    // what made it out is dropped from wbuf even when a later Twrite
+    // fails, a retry must not write it twice
+    fn flush_held(&mut self) -> Result<(), i32> {
+        let mut done = 0;
+        let mut res = Ok(());
+        while done < self.wbuf.len() {
+            let len = min(self.iou as usize, self.wbuf.len() - done);
+            match ops::write(self.fid, &self.wbuf[done..done + len], self.woff + done) {
+                Ok(0) => res = Err(EIO),
+                Ok(n) => done += n,
+                Err(e) => res = Err(e),
+            }
+            if res.is_err() {
+                break;
+            }
+        }
+        self.wbuf.drain(0..done);
+        self.woff += done;
+        res
+    }
+
+    pub fn lock(&self, fl: &Flock, flags: u32) -> Result<u8, i32> {
+        ops::lock(self.fid, fl, flags)
+    }
+
+    pub fn getlock(&self, fl: &Flock) -> Result<Flock, i32> {
+        ops::getlock(self.fid, fl)
+    }
+
+    pub fn fsync(&mut self, datasync: bool) -> Result<(), i32> {
+        let held = self.hold_wbuf();
+        self.take_werr()?;
+        self.flush_held()?;
+        drop(held);
+        ops::fsync(self.fid, datasync)
+    }
+
+    pub fn truncate(&mut self, len: u64) -> Result<(), i32> {
+        let held = self.hold_wbuf();
+        self.take_werr()?;
+        self.flush_held()?;
+        drop(held);
+        ops::setattr(self.fid, SETATTR::SIZE, 0, 0, 0, len, (0, 0), (0, 0))?;
+        if let Some(st) = &mut self.st {
+            st.len = len;
+        }
         Ok(())
     }
 
+    pub fn statfs(&self, st: &mut fs::Statfs) -> Result<(), i32> {
+        ops::statfs(self.fid)?.fill(st);
+        Ok(())
+    }
+
+    pub fn utimes(&self, atime: Utime, mtime: Utime) -> Result<(), i32> {
+        utimes_fid(self.fid, atime, mtime)
+    }
+
+    // keep the cached size in step with writes that are still buffered
+    fn grow(&mut self, end: usize) {
+        if let Some(st) = &mut self.st {
+            if end as u64 > st.len {
+                st.len = end as u64;
+            }
+        }
+    }
+
+    // the fid is gone either way, a write that never made it is
+    // reported here
+    pub fn close(&mut self) -> Result<(), i32> {
+        let held = self.hold_wbuf();
+        let flushed = self.flush_held();
+        if flushed.is_err() {
+            log!(Warn, "CLOSE: dropped {} buffered bytes\n", self.wbuf.len());
+        }
+        let flushed = self.take_werr().and(flushed);
+        log!(Trace, "CLOSE CLUNK {}\n", self.fid);
+        let clunked = ops::clunk(self.fid);
+        self.st = None;
+        self.wbuf = Vec::new();
+        drop(held);
+        flushed?;
+        clunked
+    }
+
     pub fn get_size(&self) -> u64 {
         if let Some(st) = &self.st { st.len } else { 0 }
     }
 
     pub fn stat(&self, stat: &mut fs::Stat) -> Result<(), ()> {
-        if let Some(s) = &self.st {
-            stat.st_dev = s.dev as u64;
-            stat.st_size = s.len as i64;
-            stat.st_atime = s.atime as i64;
-            stat.st_mtime = s.mtime as i64;
-            stat.st_mode = s.mode;
-            stat.st_uid = 1000;
-            stat.st_gid = 1000;
-            stat.st_nlink = if let QIDKind::DIR = s.qid.kind { 2 } else { 1 };
-            stat.st_blocks = align_f(s.len as usize, 4096) as i64 / 4096;
-            stat.st_blksize = 4096;
-            stat.st_ctime = 0;
-            return Ok(());
-        } else {
-            Err(())
-        }
-    }
-
-    pub fn getdents64(&self, buf: &mut [u8], offt: u64) -> Result<(usize, usize), ()> {
-        // dent: Qqbs = 13 + 8 + 1 + 2 = 24
-        let mut p9buf = Vec::with_capacity(buf.len());
-        p9buf.resize(buf.len(), 0);
-        let mut rbuf = p9buf.as_mut_slice();
-        let mut wbuf = buf;
-        let mut dent_off = 0u64;
-        let mut ino = 0u64;
-        let mut name_len = 0u16;
-        if let Ok(n) = ops::readdir(self.fid, rbuf, offt) {
-            rbuf = &mut rbuf[0..n as usize];
-            let mut reclens = 0usize;
-            while rbuf.len() > 0 {
-                rbuf = &mut rbuf[5..]; // ty, version
-                memcpy!((&mut ino) as *mut u64, rbuf.as_ptr(), 8);
-                rbuf = &mut rbuf[8..]; // ino
-                memcpy!((&mut dent_off) as *mut u64, rbuf.as_ptr(), 8);
-                rbuf = &mut rbuf[8..]; // offt
-                let dt = rbuf[0];
-                rbuf = &mut rbuf[1..]; // dt
-                memcpy!((&mut name_len) as *mut u16, rbuf.as_ptr(), 2);
-                rbuf = &mut rbuf[2..]; // name_len
-
-                let reclen = 8 + 8 + 3 + name_len + 1;
-                memcpy!(wbuf.as_mut_ptr(), (&ino) as *const u64, 8);
-                wbuf = &mut wbuf[8..]; //d_ino
-                memcpy!(wbuf.as_mut_ptr(), (&offt) as *const u64, 8);
-                wbuf = &mut wbuf[8..]; //d_off
-                memcpy!(wbuf.as_mut_ptr(), (&reclen) as *const u16, 2);
-                wbuf = &mut wbuf[2..]; //d_reclen
-                wbuf[0] = dt;
-                wbuf = &mut wbuf[1..]; //d_type
-                memcpy!(wbuf.as_mut_ptr(), rbuf[0..].as_ptr(), name_len as usize);
-                wbuf = &mut wbuf[name_len as usize..]; //d_name
-                wbuf[0] = 0;
-                wbuf = &mut wbuf[1..]; //null
-
-                rbuf = &mut rbuf[name_len as usize..]; // name
-
-                reclens += reclen as usize;
-            }
-            Ok((reclens, dent_off as usize))
-        } else {
-            Err(())
+        let attr = ops::getattr(self.fid, GETATTR::BASIC).map_err(|_| ())?;
+        attr.fill(stat);
+        // the server hasn't seen what's still in wbuf
+        if !self.wbuf.is_empty() {
+            stat.st_size = max(stat.st_size, self.get_size() as i64);
         }
+        Ok(())
+    }
+
+    // returns bytes written and the offset to resume at
+    pub fn getdents64(&self, buf: &mut [u8], offt: u64) -> Result<(usize, u64), ()> {
+        let mut p9buf = Vec::new();
+        p9buf.resize(min(buf.len(), max_io()), 0);
+        let n = ops::readdir(self.fid, p9buf.as_mut_slice(), offt).map_err(|_| ())? as usize;
+        repack_dirents(&p9buf[..n], buf, offt)
     }
 }
 
-pub fn truncate(path: &str, size: u64) -> Result<(), ()> {
-    if let Ok((fid, qid)) = ops::walk(path) {
-        let mut s = Stat::dont_touch();
-        s.len = size;
-        let res = ops::wstat(fid, &s);
-        print!("FTRUNCATE: {} {:?}\n", path, res);
-        ops::clunk(fid).unwrap();
-        res
-    } else {
-        Err(())
+// Rreaddir entries qid[13] offset[8] type[1] name[s] into linux_dirent64s
+fn repack_dirents(p9buf: &[u8], buf: &mut [u8], offt: u64) -> Result<(usize, u64), ()> {
+    let n = p9buf.len();
+    let mut rpos = 0;
+    let mut wpos = 0;
+    let mut next = offt;
+    while rpos + 24 <= n {
+        let ent = &p9buf[rpos..n];
+        let ino = u64::from_le_bytes(ent[5..13].try_into().unwrap());
+        let off = u64::from_le_bytes(ent[13..21].try_into().unwrap());
+        let dt = ent[21];
+        let name_len = u16::from_le_bytes(ent[22..24].try_into().unwrap()) as usize;
+        if 24 + name_len > ent.len() {
+            break;
+        }
+
+        // d_ino[8] d_off[8] d_reclen[2] d_type[1] d_name[] nul, 8 aligned
+        let reclen = (19 + name_len + 1 + 7) & !7;
+        if wpos + reclen > buf.len() {
+            break;
+        }
+        let rec = &mut buf[wpos..wpos + reclen];
+        rec[0..8].copy_from_slice(&ino.to_le_bytes());
+        rec[8..16].copy_from_slice(&off.to_le_bytes());
+        rec[16..18].copy_from_slice(&(reclen as u16).to_le_bytes());
+        rec[18] = dt;
+        rec[19..19 + name_len].copy_from_slice(&ent[24..24 + name_len]);
+        rec[19 + name_len..].fill(0);
+
+        rpos += 24 + name_len;
+        wpos += reclen;
+        next = off;
     }
+
+    // not even one entry fit
+    if wpos == 0 && n > 0 {
+        return Err(());
+    }
+    Ok((wpos, next))
 }
 
-pub fn link(from: &str, to: &str, follow: bool) -> Result<(), ()> {
-    if let Ok((fid, qid)) = ops::walk(from) {
-        let fid = if follow {
-            let real = follow_fid(fid, &qid).map_err(|_| ())?;
-            real
-        } else {
-            fid
-        };
-        print!("9p LINK: fid {}\n", fid);
-        let res = ops::link(fid, to);
-        print!("9p LINK: {} {} {:?}\n", from, to, res);
-        ops::clunk(fid).unwrap();
-        return res;
-    };
+pub fn truncate(path: &str, size: u64) -> Result<(), i32> {
+    let (fid, qid) = ops::walk(path)?;
+    // buffered writes would land after the truncate otherwise
+    flush_qid(qid.path);
+    let res = ops::setattr(fid, SETATTR::SIZE, 0, 0, 0, size, (0, 0), (0, 0));
+    log!(Debug, "FTRUNCATE: {} {:?}\n", path, res);
+    let _ = ops::clunk(fid);
+    res
+}
 
-    Err(())
+pub fn statfs(path: &str, st: &mut fs::Statfs) -> Result<(), i32> {
+    let (fid, _) = ops::walk(path)?;
+    let res = ops::statfs(fid).map(|s| s.fill(st));
+    let _ = ops::clunk(fid);
+    res
 }
 
-pub fn mkdir(path: &str, mode: u32) -> Result<(), ()> {
-    if ops::mkdir(path, mode, 0).is_ok() {
-        Ok(())
+fn utimes_fid(fid: u32, atime: Utime, mtime: Utime) -> Result<(), i32> {
+    let valid = atime.valid(SETATTR::ATIME, SETATTR::ATIME_SET)
+        | mtime.valid(SETATTR::MTIME, SETATTR::MTIME_SET);
+    ops::setattr(fid, valid, 0, 0, 0, 0, atime.time(), mtime.time())
+}
+
+pub fn utimes(path: &str, atime: Utime, mtime: Utime, follow: bool) -> Result<(), i32> {
+    let (fid, qid) = ops::walk(path)?;
+    let fid = if follow {
+        follow_fid(fid, &qid)?.0
     } else {
-        Err(())
-    }
+        fid
+    };
+    let res = utimes_fid(fid, atime, mtime);
+    let _ = ops::clunk(fid);
+    res
+}
+
+pub fn link(from: &str, to: &str, follow: bool) -> Result<(), i32> {
+    let (dir, name) = split_path(to).ok_or(EINVAL)?;
+
+    let (fid, qid) = ops::walk(from)?;
+    let fid = if follow {
+        follow_fid(fid, &qid)?.0
+    } else {
+        fid
+    };
+
+    let dir_fid = match ops::walk(dir) {
+        Ok((dir_fid, _)) => dir_fid,
+        Err(e) => {
+            let _ = ops::clunk(fid);
+            return Err(e);
+        }
+    };
+
+    let res = ops::link(dir_fid, fid, name);
+    log!(Debug, "9p LINK: {} {} {:?}\n", from, to, res);
+    let _ = ops::clunk(fid);
+    let _ = ops::clunk(dir_fid);
+    res
+}
+
+pub fn mkdir(path: &str, mode: u32) -> Result<(), i32> {
+    ops::mkdir(path, mode, 0).map(|_| ())
 }
 
 pub fn exists(path: &str) -> bool {
     if let Ok((fid, _)) = ops::walk(path) {
-        ops::clunk(fid).unwrap();
+        let _ = ops::clunk(fid);
         true
     } else {
         false
     }
 }
 
-pub fn symlink(linkname: &str, path: &str) -> Result<(), ()> {
+// Err when the path doesn't resolve, symlinks are followed
+pub fn is_dir(path: &str) -> Result<bool, i32> {
+    let (fid, qid) = ops::walk(path)?;
+    let (fid, _) = follow_fid(fid, &qid)?;
+    let res = ops::stat(fid).map(|s| s.qid.kind.is_dir());
+    let _ = ops::clunk(fid);
+    res
+}
+
+pub fn symlink(linkname: &str, path: &str) -> Result<QID, i32> {
     ops::symlink(linkname, path)
 }
 
-pub fn rename(from: &str, to: &str) -> Result<(), ()> {
-    if let Ok((fid, qid)) = ops::walk(from) {
-        ops::rename(fid, to)
-    } else {
-        Err(())
+// ("/a/b", "c") for "/a/b/c", None when there is no last component
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    let (dir, name) = path.trim_end_matches('/').rsplit_once('/')?;
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
     }
+    Some((if dir.is_empty() { "/" } else { dir }, name))
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), i32> {
+    let (odir, oname) = split_path(from).ok_or(EINVAL)?;
+    let (ndir, nname) = split_path(to).ok_or(EINVAL)?;
+
+    let (ofid, _) = ops::walk(odir)?;
+    let nfid = match ops::walk(ndir) {
+        Ok((fid, _)) => fid,
+        Err(e) => {
+            let _ = ops::clunk(ofid);
+            return Err(e);
+        }
+    };
+
+    let res = ops::renameat(ofid, oname, nfid, nname);
+    let _ = ops::clunk(ofid);
+    let _ = ops::clunk(nfid);
+    res
 }
 
-pub fn readlink(path: &str) -> Result<String, ()> {
-    if let Ok((fid, qid)) = ops::walk(path) {
-        ops::readlink(fid)
+// Ok(None) when path is there but isn't a symlink
+pub fn readlink(path: &str) -> Result<Option<String>, i32> {
+    let (fid, qid) = ops::walk(path)?;
+    let res = if qid.kind.is_symlink() {
+        ops::readlink(fid).map(Some)
     } else {
-        Err(())
-    }
+        Ok(None)
+    };
+    let _ = ops::clunk(fid);
+    res
 }
 
-pub fn open(path: &str, flags: u32) -> Result<&'static mut File, ()> {
+// perm only matters when O_CREAT makes a new file
+pub fn open(path: &str, flags: u32, perm: u32) -> Result<&'static mut File, i32> {
     let mode = o2p9o(flags) as u32;
-    let follow = flags & fs::O::NOFOLLOW == 0;
-    print!("9P OPEN: {} FOLLOW: {}\n", path, follow);
-    let fid = if let Ok((fid, qid)) = ops::walk(path) {
-        if follow {
-            let real = follow_fid(fid, &qid).map_err(|_| ())?;
-            real
-        } else {
-            fid
+    let creat = flags & fs::O::CREAT != 0;
+    let excl = creat && flags & fs::O::EXCL != 0;
+    // O_EXCL never follows, a dangling link counts as existing
+    let follow = flags & fs::O::NOFOLLOW == 0 && !excl;
+    log!(Debug, "9P OPEN: {} FOLLOW: {}\n", path, follow);
+    let (fid, qid) = match ops::walk(path) {
+        Ok((fid, _)) if excl => {
+            let _ = ops::clunk(fid);
+            return Err(EEXIST);
         }
-    } else {
-        print!("FAILED TO WALK: {}\n", path);
-        if flags & fs::O::CREAT != 0 {
-            if let Ok((fid, qid, iou)) = ops::create(path, 0777, mode, 0) {
-                let file = &mut FILES.as_mut()[fid as usize];
-                file.fid = fid;
-                file.iou = iou;
-                file.qid = qid;
-                file.st = if let Ok(st) = ops::stat(fid) {
-                    Some(st)
-                } else {
-                    None
-                };
-                return Ok(file);
-            } else {
-                print!("FAILED TO TCREATE: {}\n", path);
-                return Err(());
-            }
-        } else {
-            print!("FAILED TO TOPEN: {}\n", path);
-            return Err(());
+        Ok((fid, qid)) if follow => follow_fid(fid, &qid)?,
+        Ok(found) => found,
+        Err(e) if e != ENOENT || !creat => {
+            log!(Debug, "FAILED TO WALK: {} {}\n", path, e);
+            return Err(e);
+        }
+        Err(_) => {
+            return match ops::create(path, perm, mode, 0) {
+                Ok((fid, qid, iou)) => {
+                    let file = &mut FILES.as_mut()[fid as usize];
+                    file.reset(fid, iou, qid, flags);
+                    Ok(file)
+                }
+                Err(e) => {
+                    log!(Debug, "FAILED TO TCREATE: {} {}\n", path, e);
+                    Err(e)
+                }
+            };
         }
     };
 
-    if let Ok((qid, iou)) = ops::open(fid, mode) {
-        let file = &mut FILES.as_mut()[fid as usize];
-        file.fid = fid;
-        file.iou = iou;
-        file.qid = qid;
-        file.st = if let Ok(st) = ops::stat(fid) {
-            Some(st)
-        } else {
-            None
-        };
-        Ok(file)
-    } else {
-        ops::clunk(fid).unwrap();
-        print!("FAILED TO TOPEN: {} fid {}\n", path, fid);
-        Err(())
+    flush_qid(qid.path);
+
+    // zero an existing file before handing it out
+    if flags & fs::O::TRUNC != 0 && flags & (fs::O::WRONLY | fs::O::RDWR) != 0 {
+        // the server's errno, EACCES or EISDIR say more than EIO
+        if let Err(e) = ops::setattr(fid, SETATTR::SIZE, 0, 0, 0, 0, (0, 0), (0, 0)) {
+            let _ = ops::clunk(fid);
+            log!(Debug, "FAILED TO TRUNCATE: {} {}\n", path, e);
+            return Err(e);
+        }
     }
-}
 
-pub fn stat_inner(fid: u32, stat: &mut fs::Stat) -> Result<(), ()> {
-    if let Ok(mut s) = ops::stat(fid) {
-        stat.st_dev = s.dev as u64;
-        stat.st_size = s.len as i64;
-        stat.st_atime = s.atime as i64;
-        stat.st_mtime = s.mtime as i64;
-        stat.st_mode = s.mode;
-        stat.st_uid = 1000;
-        stat.st_gid = 1000;
-        stat.st_nlink = if let QIDKind::DIR = s.qid.kind { 2 } else { 1 };
-        stat.st_blocks = align_f(s.len as usize, 4096) as i64 / 4096;
-        stat.st_blksize = 4096;
-        stat.st_ctime = 0;
-        return Ok(());
-    } else {
-        return Err(());
+    match ops::open(fid, mode) {
+        Ok((qid, iou)) => {
+            let file = &mut FILES.as_mut()[fid as usize];
+            file.reset(fid, iou, qid, flags);
+            Ok(file)
+        }
+        Err(e) => {
+            let _ = ops::clunk(fid);
+            log!(Debug, "FAILED TO TOPEN: {} fid {} {}\n", path, fid, e);
+            Err(e)
+        }
     }
 }
 
-fn follow_sym(mut fid: u32) -> Result<u32, ()> {
-    let mut target = ops::readlink(fid).map_err(|_| ())?;
+pub fn stat_inner(fid: u32, stat: &mut fs::Stat) -> Result<(), i32> {
+    let attr = ops::getattr(fid, GETATTR::BASIC)?;
+    attr.fill(stat);
+    Ok(())
+}
+
+// a dangling link is ENOENT
+fn follow_sym(mut fid: u32) -> Result<(u32, QID), i32> {
+    let mut target = ops::readlink(fid)?;
 
     while let Ok((fid, qid)) = ops::walk(&target) {
-        print!("WaLK link TARGET = {} {}\n", target, fid);
-        if let QIDKind::SYMLINK = qid.kind {
-            target = ops::readlink(fid).map_err(|_| ())?;
-            ops::clunk(fid).unwrap();
+        log!(Trace, "WaLK link TARGET = {} {}\n", target, fid);
+        if qid.kind.is_symlink() {
+            let res = ops::readlink(fid);
+            let _ = ops::clunk(fid);
+            target = res?;
         } else {
-            return Ok(fid);
+            return Ok((fid, qid));
         }
     }
 
-    return Err(());
+    return Err(ENOENT);
 }
 
-fn follow_fid(fid: u32, qid: &QID) -> Result<u32, ()> {
-    if let QIDKind::SYMLINK = qid.kind {
-        print!("FOLLOW SYMLINK\n");
-        let def = defer(|| ops::clunk(fid).unwrap());
-        let real_fid = follow_sym(fid).map_err(|_| ())?;
+// the fid and qid of what the link ends at
+fn follow_fid(fid: u32, qid: &QID) -> Result<(u32, QID), i32> {
+    if qid.kind.is_symlink() {
+        log!(Trace, "FOLLOW SYMLINK\n");
+        let def = defer(|| {
+            let _ = ops::clunk(fid);
+        });
+        let real = follow_sym(fid)?;
         drop(def);
-        Ok(real_fid)
+        Ok(real)
     } else {
-        print!("FOLLOW NOT SYMLINK: {:?}\n", qid.kind);
-        Ok(fid)
+        log!(Trace, "FOLLOW NOT SYMLINK: {:?}\n", qid.kind);
+        Ok((fid, *qid))
     }
 }
 
-pub fn stat(path: &str, s: &mut fs::Stat, follow: bool) -> Result<(), ()> {
-    if let Ok((fid, qid)) = ops::walk(path) {
-        let fid = if follow {
-            let real = follow_fid(fid, &qid).map_err(|_| ())?;
-            real
-        } else {
-            fid
-        };
-        print!("9p STAT: fid {}\n", fid);
-        let res = stat_inner(fid, s);
-        print!("9p STAT: {} {:?}\n", path, res);
-        ops::clunk(fid).unwrap();
-        return res;
+pub fn stat(path: &str, s: &mut fs::Stat, follow: bool) -> Result<(), i32> {
+    let (fid, qid) = ops::walk(path)?;
+    let (fid, qid) = if follow {
+        follow_fid(fid, &qid)?
+    } else {
+        (fid, qid)
     };
+    flush_qid(qid.path);
+    log!(Trace, "9p STAT: fid {}\n", fid);
+    let res = stat_inner(fid, s);
+    log!(Trace, "9p STAT: {} {:?}\n", path, res);
+    let _ = ops::clunk(fid);
+    res
+}
 
-    Err(())
+pub fn remove(path: &str) -> Result<(), i32> {
+    let (fid, _) = ops::walk(path)?;
+    ops::remove(fid)
 }
 
-pub fn remove(path: &str) -> Result<(), ()> {
-    if let Ok((fid, _)) = ops::walk(path) {
-        ops::remove(fid)
-    } else {
-        Err(())
+// writes another fid still holds back go out before a stat or open by
+// path asks the server, it has to see what write already returned for
+fn flush_qid(path: u64) {
+    for file in FILES.as_mut().iter_mut() {
+        if file.qid.path == path && !file.wbuf.is_empty() {
+            let _ = file.flush();
+        }
     }
 }
 
-static FILES: SyncUnsafeCell<[File; 128]> = SyncUnsafeCell::new([
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-    File::zeroed(),
-]);
+// indexed by fid
+static FILES: SyncUnsafeCell<[File; NFIDS]> =
+    SyncUnsafeCell::new([const { File::zeroed() }; NFIDS]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ent(v: &mut Vec<u8>, ino: u64, off: u64, dt: u8, name: &str) {
+        v.push(0);
+        v.extend_from_slice(&0u32.to_le_bytes());
+        v.extend_from_slice(&ino.to_le_bytes());
+        v.extend_from_slice(&off.to_le_bytes());
+        v.push(dt);
+        v.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        v.extend_from_slice(name.as_bytes());
+    }
+
+    fn dirents() -> Vec<u8> {
+        let mut v = Vec::new();
+        ent(&mut v, 7, 1, 4, ".");
+        ent(&mut v, 8, 2, 8, "busybox");
+        ent(&mut v, 9, 3, 4, "a-much-longer-directory-name");
+        v
+    }
+
+    #[test]
+    fn repacks_every_entry() {
+        let mut buf = [0xaau8; 256];
+        let (n, next) = repack_dirents(&dirents(), &mut buf, 0).unwrap();
+        assert_eq!(next, 3);
+
+        let mut names = Vec::new();
+        let mut pos = 0;
+        while pos < n {
+            let rec = &buf[pos..];
+            let reclen = u16::from_le_bytes([rec[16], rec[17]]) as usize;
+            assert_eq!(reclen % 8, 0);
+            let name = &rec[19..reclen];
+            let len = name.iter().position(|&c| c == 0).unwrap();
+            names.push((
+                u64::from_le_bytes(rec[0..8].try_into().unwrap()),
+                u64::from_le_bytes(rec[8..16].try_into().unwrap()),
+                rec[18],
+                String::from(str::from_utf8(&name[..len]).unwrap()),
+            ));
+            pos += reclen;
+        }
+        assert_eq!(pos, n);
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], (7, 1, 4, String::from(".")));
+        assert_eq!(names[1], (8, 2, 8, String::from("busybox")));
+        assert_eq!(names[2].3, "a-much-longer-directory-name");
+    }
+
+    #[test]
+    fn stops_at_a_full_buffer() {
+        // "." is 24 bytes, "busybox" 32
+        let mut buf = [0u8; 50];
+        assert_eq!(repack_dirents(&dirents(), &mut buf, 0), Ok((24, 1)));
+        let mut buf = [0u8; 56];
+        assert_eq!(repack_dirents(&dirents(), &mut buf, 0), Ok((56, 2)));
+        let mut buf = [0u8; 16];
+        assert_eq!(repack_dirents(&dirents(), &mut buf, 0), Err(()));
+    }
+
+    #[test]
+    fn end_of_directory() {
+        let mut buf = [0u8; 64];
+        assert_eq!(repack_dirents(&[], &mut buf, 5), Ok((0, 5)));
+        // a torn entry is left for the next call
+        let v = dirents();
+        assert_eq!(repack_dirents(&v[..30], &mut buf, 0), Ok((24, 1)));
+    }
+}
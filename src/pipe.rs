@@ -0,0 +1,247 @@
+use core::cmp::min;
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+
+use crate::{
+    errno::{EAGAIN, EBADF, EINTR, EPIPE},
+    fs, log,
+    sched::{sleep_intr, wakeup},
+    spin::Lock,
+};
+
+// writes up to this size never interleave with other writers
+pub const PIPE_BUF: usize = 4096;
+
+const NPIPES: usize = 16;
+
+struct Pipe {
+    buf: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+impl Pipe {
+    const fn zeroed() -> Pipe {
+        Pipe {
+            buf: VecDeque::new(),
+            readers: 0,
+            writers: 0,
+        }
+    }
+
+    fn chan(&self) -> u64 {
+        self as *const Pipe as u64
+    }
+
+    fn wake(&mut self) {
+        wakeup(self.chan());
+        fs::poll_wake();
+    }
+}
+
+// one end, lives inside fs::File so dups share it
+pub struct File {
+    idx: usize,
+    write: bool,
+    nonblock: bool,
+}
+
+impl File {
+    // blocks until there is something to read or no writer is left. user
+    // memory is only touched with the lock dropped, see write
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, i32> {
+        if self.write {
+            return Err(EBADF);
+        }
+
+        let lock = PIPES.acquire();
+        let data: Vec<u8> = loop {
+            let p = &mut lock.as_mut()[self.idx];
+            if !p.buf.is_empty() {
+                let n = min(buf.len(), p.buf.len());
+                let data = p.buf.drain(0..n).collect();
+                p.wake();
+                break data;
+            }
+            if p.writers == 0 {
+                return Ok(0);
+            }
+            if self.nonblock {
+                return Err(EAGAIN);
+            }
+            sleep_intr(p.chan(), lock.get_lock()).map_err(|_| EINTR)?;
+        };
+        drop(lock);
+
+        buf[0..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    // all of buf unless the readers go away or a signal comes in,
+    // PIPE_BUF or less goes in one piece. it is copied in PIPE_BUF at a
+    // time before taking the lock, a fault on a user page can't happen
+    // under it
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, i32> {
+        if !self.write {
+            return Err(EBADF);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let chunk = buf[done..min(buf.len(), done + PIPE_BUF)].to_vec();
+            let mut off = 0;
+            let lock = PIPES.acquire();
+            while off < chunk.len() {
+                let p = &mut lock.as_mut()[self.idx];
+                if p.readers == 0 {
+                    return if done + off > 0 {
+                        Ok(done + off)
+                    } else {
+                        Err(EPIPE)
+                    };
+                }
+
+                let room = PIPE_BUF - p.buf.len();
+                if room > 0 && (buf.len() > PIPE_BUF || room >= buf.len()) {
+                    let n = min(room, chunk.len() - off);
+                    p.buf.extend(&chunk[off..off + n]);
+                    off += n;
+                    p.wake();
+                    continue;
+                }
+
+                if self.nonblock {
+                    return if done + off > 0 {
+                        Ok(done + off)
+                    } else {
+                        Err(EAGAIN)
+                    };
+                }
+                if sleep_intr(p.chan(), lock.get_lock()).is_err() {
+                    return if done + off > 0 {
+                        Ok(done + off)
+                    } else {
+                        Err(EINTR)
+                    };
+                }
+            }
+            done += off;
+        }
+        Ok(done)
+    }
+
+    pub fn set_nonblock(&mut self, on: bool) {
+        self.nonblock = on;
+    }
+
+    pub fn close(&mut self) {
+        let lock = PIPES.acquire();
+        let p = &mut lock.as_mut()[self.idx];
+        if self.write {
+            p.writers -= 1;
+        } else {
+            p.readers -= 1;
+        }
+        if p.readers == 0 && p.writers == 0 {
+            p.buf = VecDeque::new();
+        }
+        p.wake();
+        log!(
+            Debug,
+            "PIPE CLOSE: {} readers {} writers {}\n",
+            self.idx,
+            p.readers,
+            p.writers
+        );
+    }
+
+    // eof counts as readable
+    pub fn readable(&self) -> bool {
+        let lock = PIPES.acquire();
+        let p = &lock.as_ref()[self.idx];
+        !self.write && (!p.buf.is_empty() || p.writers == 0)
+    }
+
+    pub fn writeable(&self) -> bool {
+        let lock = PIPES.acquire();
+        let p = &lock.as_ref()[self.idx];
+        self.write && p.buf.len() < PIPE_BUF
+    }
+
+    // the other end is gone for good
+    pub fn hanged_up(&self) -> bool {
+        let lock = PIPES.acquire();
+        let p = &lock.as_ref()[self.idx];
+        if self.write {
+            p.readers == 0
+        } else {
+            p.writers == 0 && p.buf.is_empty()
+        }
+    }
+
+    pub fn get_size(&self) -> u64 {
+        let lock = PIPES.acquire();
+        lock.as_ref()[self.idx].buf.len() as u64
+    }
+
+    pub fn stat(&self, stat: &mut fs::Stat) -> Result<(), ()> {
+        stat.st_ino = 0;
+        stat.st_size = 0;
+        stat.st_nlink = 1;
+        stat.st_mode = 0o010600;
+        stat.st_blksize = PIPE_BUF as _;
+        Ok(())
+    }
+}
+
+// (read end, write end)
+pub fn alloc(nonblock: bool) -> Option<(File, File)> {
+    let lock = PIPES.acquire();
+    let pipes = lock.as_mut();
+
+    for i in 0..pipes.len() {
+        let p = &mut pipes[i];
+        if p.readers == 0 && p.writers == 0 {
+            p.buf.clear();
+            p.readers = 1;
+            p.writers = 1;
+            log!(Debug, "PIPE ALLOC: {}\n", i);
+            return Some((
+                File {
+                    idx: i,
+                    write: false,
+                    nonblock,
+                },
+                File {
+                    idx: i,
+                    write: true,
+                    nonblock,
+                },
+            ));
+        }
+    }
+
+    None
+}
+
+static PIPES: Lock<[Pipe; NPIPES]> = Lock::new(
+    "pipe",
+    [
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+        Pipe::zeroed(),
+    ],
+);
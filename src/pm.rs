@@ -1,8 +1,8 @@
 // use std::{fs::File, io::Read, mem::size_of, ptr::NonNull};
 
-use core::{cell::UnsafeCell, ptr::NonNull};
+use core::{cell::UnsafeCell, cmp::min, ptr::NonNull};
 
-use crate::{print, spin::Lock};
+use crate::{dt, print, spin::Lock};
 
 pub const MB: usize = 1024 * 1024;
 pub const GB: usize = 1024 * 1024 * 1024;
@@ -33,10 +33,16 @@ pub struct Page {
     next: Option<NonNull<Page>>,
     magic: u32,
     pub flags: Flags,
+    // max order blocks in a contiguous allocation, 1 for buddy blocks
+    span: usize,
 }
 
 const MAGIC: u32 = 0xDEADBEEF;
 
+// ram the boot tables map at VOFFT + pa, used when there's no dtb
+const BOOT_RAM: usize = 0x4000_0000;
+const VOFFT: usize = 0xffff_0000_0000_0000;
+
 impl Page {
     pub const fn new(idx: usize, ord: usize, rc: usize) -> Page {
         Page {
@@ -46,6 +52,7 @@ impl Page {
             next: None,
             magic: MAGIC,
             flags: Flags::None,
+            span: 1,
         }
     }
 
@@ -113,7 +120,7 @@ impl Page {
     }
 
     pub fn len(&self) -> usize {
-        4096 << (8 - self.ord)
+        (4096 << (8 - self.ord)) * self.span
     }
 
     pub fn mark_mids(&mut self) {
@@ -162,6 +169,7 @@ impl Default for Page {
             next: None,
             magic: MAGIC,
             flags: Flags::None,
+            span: 1,
         }
     }
 }
@@ -241,29 +249,13 @@ pub struct Allocator {
     // meta_size: usize,
 }
 
-struct Pages {
-    p: UnsafeCell<[Page; GB / 4096]>,
-}
-
-impl Pages {
-    pub const fn new() -> Pages {
-        Pages {
-            p: UnsafeCell::new([Page::new(0, 0, 0); GB / 4096]),
-        }
-    }
-}
-
-unsafe impl Sync for Pages {}
 unsafe impl Sync for Allocator {}
 
-static PAGES: Pages = Pages::new();
 static ALLOC: Lock<Allocator> = Lock::new("pm", Allocator::new());
 
 impl Allocator {
     const ORDER: usize = 8;
 
-    // ptr = 0xffff_0000_4000_0000
-    // len = 1gb (hardcoded)
     pub const fn new() -> Allocator {
         Allocator {
             free_lists: [FL { head: None }; 9],
@@ -273,9 +265,19 @@ impl Allocator {
         }
     }
 
-    pub fn init(&mut self, k_begin: usize, k_end: usize) {
-        let npages = GB / 4096;
-        let page_ptr = PAGES.p.get() as *mut Page;
+    // the page array goes right after the kernel, returns its end
+    pub fn init(&mut self, k_begin: usize, k_end: usize) -> usize {
+        let (offt, size) = dt::memory()
+            .filter(|&(b, s)| b <= k_begin && k_end < b + s)
+            .unwrap_or((BOOT_RAM, GB));
+        // buddies are looked up without bounds checks
+        let size = align_b(size, MB);
+        let npages = size / 4096;
+
+        let pages = align_f(k_end, 4 * KB);
+        let end = align_f(pages + npages * size_of::<Page>(), 4 * KB);
+        assert!(end <= BOOT_RAM + GB);
+        let page_ptr = (pages + VOFFT) as *mut Page;
         for i in 0..npages {
             unsafe {
                 *page_ptr.add(i) = Page::new(i, 8, 1);
@@ -284,15 +286,17 @@ impl Allocator {
         *self = Allocator {
             free_lists: Default::default(),
             page_ptr,
-            offt: 0x4000_0000, /*hardcode*/
-            size: GB,
+            offt,
+            size,
         };
-        self.init_free_list(k_begin, k_end);
+        self.init_free_list(k_begin, end);
+        end
     }
 
+    // only what the boot tables map, the rest waits for init_free_list2
     fn init_free_list(&mut self, _k_begin: usize, k_end: usize) {
         let mut i = align_f(k_end, 4 * KB);
-        let ram_end = self.offt + GB;
+        let ram_end = min(self.offt + self.size, BOOT_RAM + GB);
         while i < ram_end {
             self.free(i, 4 * KB);
             i += 4 * KB;
@@ -306,6 +310,11 @@ impl Allocator {
             self.free(i, 4 * KB);
             i += 4 * KB;
         }
+        let mut i = BOOT_RAM + GB;
+        while i < self.offt + self.size {
+            self.free(i, 4 * KB);
+            i += 4 * KB;
+        }
     }
 
     fn get_ord(n: usize) -> usize {
@@ -374,8 +383,47 @@ impl Allocator {
         }
     }
 
+    // a run of free max order blocks, first fit from the bottom. the
+    // head remembers the span so free can hand the blocks back one by one
+    fn alloc_contig(&mut self, n: usize) -> Option<*mut u8> {
+        let blk = 1 << Allocator::ORDER;
+        let k = align_f(n, MB) / MB;
+        let nblks = self.size / MB;
+
+        let mut run = 0;
+        for b in 0..nblks {
+            let p = unsafe { self.page_ptr.add(b * blk).as_ref() }.unwrap();
+            if p.ord != 0 || !p.is_idle() || p.flags != Flags::None {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run < k {
+                continue;
+            }
+
+            let first = b + 1 - k;
+            for j in first..=b {
+                let p = unsafe { self.page_ptr.add(j * blk).as_mut() }.unwrap();
+                self.free_lists[0].remove(p);
+            }
+            let head = unsafe { self.page_ptr.add(first * blk).as_mut() }.unwrap();
+            head.assert_ok();
+            head.ref_cnt = 1;
+            head.span = k;
+            head.rm_links();
+            return Some((head.idx * 4096) as *mut u8);
+        }
+        None
+    }
+
     pub fn alloc(&mut self, n: usize) -> Option<usize> {
-        match self._alloc(n) {
+        let res = if n > MB && self.size != 0 {
+            self.alloc_contig(n)
+        } else {
+            self._alloc(n)
+        };
+        match res {
             Some(n) => {
                 let page = self.lookup(n as usize + self.offt).unwrap();
                 page.mark_mids();
@@ -422,6 +470,20 @@ impl Allocator {
         }
     }
 
+    // extra reference without cow, used for shared mappings
+    pub fn share(&self, addr: usize, len: usize) -> Result<(), ()> {
+        if let Some(p) = self.lookup(addr) {
+            if p.ref_cnt == 0 || p.len() != len {
+                return Err(());
+            }
+            p.assert_ok();
+            p.ref_cnt += 1;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
     fn free(&mut self, addr: usize, len: usize) {
         let page = self.lookup(addr).unwrap();
         page.assert_ok();
@@ -433,32 +495,50 @@ impl Allocator {
         }
         page.unmark_mids();
         page.flags = Flags::None;
+        if page.span > 1 {
+            let blk = 1 << Allocator::ORDER;
+            let (idx, k) = (page.idx, page.span);
+            page.span = 1;
+            for j in 0..k {
+                let p = unsafe { self.page_ptr.add(idx + j * blk).as_mut() }.unwrap();
+                p.ref_cnt = 0;
+                p.join(self);
+            }
+            return;
+        }
         page.join(self);
     }
 }
 
 pub enum CowAction {
     Remap,
-    Alloc,
+    // new block to copy into, the caller frees its ref on the old one
+    // once the copy is done
+    Copy(usize),
 }
 
-pub fn cow_action<F: FnMut(CowAction, &mut Allocator)>(addr: usize, len: usize, mut f: F) {
+// the whole block is resolved at once, len must be the block's len.
+// the old ref is kept across the copy: dropping it first would let the
+// last sharer remap the block writable and change it under the copy
+pub fn cow_action(addr: usize, len: usize) -> Option<CowAction> {
     let lock = ALLOC.acquire();
-    if let Some(page) = lock.as_ref().lookup(addr) {
-        if page.flags != Flags::Cow {
-            return;
-        }
-        if page.len() != len {
-            return;
-        }
-        if page.ref_cnt == 1 {
-            page.flags = Flags::Used;
-            f(CowAction::Remap, lock.as_mut());
-        } else {
-            page.ref_cnt -= 1;
-            f(CowAction::Alloc, lock.as_mut());
-        }
+    let al = lock.as_mut();
+    let page = al.lookup(addr)?;
+    if page.flags != Flags::Cow || page.len() != len {
+        return None;
+    }
+    if page.ref_cnt == 1 {
+        page.flags = Flags::Used;
+        return Some(CowAction::Remap);
     }
+    al.alloc(len).map(|p| CowAction::Copy(p))
+}
+
+// still shared with a fork, the first write has to copy it
+pub fn is_cow(addr: usize) -> bool {
+    let lock = ALLOC.acquire();
+    let al = lock.as_mut();
+    al.lookup(addr).is_some_and(|p| p.flags == Flags::Cow)
 }
 
 pub fn alloc(n: usize) -> Result<usize, ()> {
@@ -480,16 +560,22 @@ pub fn dup(addr: usize, len: usize) -> Result<(), ()> {
     lock.as_mut().dup(addr, len)
 }
 
+pub fn share(addr: usize, len: usize) -> Result<(), ()> {
+    let lock = ALLOC.acquire();
+    lock.as_mut().share(addr, len)
+}
+
 // pub fn lookup(addr: usize) -> Option<&'static mut Page> {
 //     let lock = ALLOC.acquire();
 //     lock.as_mut().lookup(addr)
 // }
 
-pub fn init(k_begin: usize, k_end: usize) {
+pub fn init(k_begin: usize, k_end: usize) -> usize {
     let lock = ALLOC.acquire();
-    lock.as_mut().init(k_begin, k_end);
+    lock.as_mut().init(k_begin, k_end)
 }
 
+// after vm::init, everything outside the kernel that init skipped
 pub fn free_low(k_begin: usize) {
     let lock = ALLOC.acquire();
     lock.as_mut().init_free_list2(k_begin);
@@ -1,9 +1,15 @@
 use crate::{
-    dsb, print,
+    dsb, log, print,
     spin::Lock,
+    timer,
     virtio::{self, Q, Regs, Status, get_irq_status, init_dev_common},
 };
-use core::{arch::asm, hint::spin_loop, ptr::NonNull};
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 const QSIZE: usize = 2;
 
@@ -30,22 +36,30 @@ pub fn init(reg: &mut Regs) {
 
     rng.regs = NonNull::new(reg as *mut Regs).unwrap();
 
-    init_dev_common(reg, 0);
-
-    let status: u32 = reg.read(Regs::STATUS);
-    reg.write(Regs::STATUS, status | Status::DRIVER_OK);
-    dsb!();
+    init_dev_common(reg, 0, 0);
 
     virtio::set_q_len(reg, 0, rng.vq.len());
     virtio::set_used_area(reg, rng.vq.used_area_paddr());
     virtio::set_avail_area(reg, rng.vq.avail_area_paddr());
     virtio::set_desc_area(reg, rng.vq.desc_area_paddr());
     dsb!();
+
+    let status: u32 = reg.read(Regs::STATUS);
+    reg.write(Regs::STATUS, status | Status::DRIVER_OK);
+    dsb!();
+}
+
+pub fn present() -> bool {
+    let lock = RNG.acquire();
+    lock.as_ref().regs != NonNull::dangling()
 }
 
 pub fn read_inner(buf: &mut [u8], sync: bool) -> Result<usize, ()> {
     let lock = RNG.acquire();
     let rng = lock.as_mut();
+    if rng.regs == NonNull::dangling() || buf.len() == 0 {
+        return Err(());
+    }
     let d = rng.vq.alloc_desc().unwrap();
     let desc = rng.vq.get_desc_mut(d as usize);
 
@@ -81,6 +95,28 @@ pub fn read_sync(buf: &mut [u8]) -> Result<usize, ()> {
     read_inner(buf, true)
 }
 
+static WEAK: AtomicBool = AtomicBool::new(false);
+
+// device bytes when there is one, counter noise otherwise.
+// buf is handed to the device, it must be identity mapped
+pub fn fill(buf: &mut [u8]) {
+    if read_sync(buf).is_ok() {
+        return;
+    }
+    // canaries and pointer guards from this are guessable
+    if !WEAK.swap(true, Ordering::Relaxed) {
+        log!(Warn, "no virtio-rng, random bytes are timer noise\n");
+    }
+    let mut x = timer::uptime_ns() ^ 0x9e37_79b9_7f4a_7c15;
+    for b in buf.iter_mut() {
+        x ^= timer::uptime_ns();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *b = (x >> 32) as u8;
+    }
+}
+
 pub fn irq_pending() -> bool {
     let lock = RNG.acquire();
     let rng = lock.as_mut();
@@ -1,7 +1,9 @@
 use crate::{
+    errno::{EAGAIN, EFAULT, EINTR, EINVAL, err},
     heap::{self, SyncUnsafeCell},
-    ptr2mut, ptr2ref,
+    ptr2mut, ptr2mut_op, ptr2ref, ptr2ref_op,
     sched::mycpu,
+    spin::Lock,
     timer, vm,
 };
 
@@ -19,6 +21,32 @@ pub fn read() -> u32 {
     unsafe { MAP.as_ref().0.read_volatile() }
 }
 
+// pl031 RTCLR, the counter continues from here
+fn load(sec: u32) {
+    unsafe { MAP.as_ref().0.add(2).write_volatile(sec) };
+}
+
+// the rtc only counts seconds. (second, counter when it was first seen)
+static SECOND: Lock<(u32, u64)> = Lock::new("rtc", (0, 0));
+
+// rtc seconds plus the time since that second was first seen, never
+// goes backwards while the rtc doesn't
+fn now_us() -> (u32, u64) {
+    let lock = SECOND.acquire();
+    let sec = read();
+    let now = timer::counter();
+    if sec != lock.as_ref().0 {
+        *lock.as_mut() = (sec, now);
+    }
+    let us = timer::cnt2ns(now - lock.as_ref().1) / 1000;
+    (sec, us.min(999_999))
+}
+
+pub fn realtime_ns() -> u64 {
+    let (sec, us) = now_us();
+    sec as u64 * 1_000_000_000 + us * 1000
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct KernelTimespec {
@@ -28,7 +56,14 @@ pub struct KernelTimespec {
 
 impl KernelTimespec {
     pub fn millis(&self) -> u64 {
-        ((self.sec * 100) + (self.nsec / 1000_000_0)) as u64
+        (self.sec * 1000 + self.nsec / 1_000_000) as u64
+    }
+
+    pub fn from_ns(ns: u64) -> KernelTimespec {
+        KernelTimespec {
+            sec: (ns / 1_000_000_000) as i64,
+            nsec: (ns % 1_000_000_000) as i64,
+        }
     }
 }
 
@@ -49,31 +84,113 @@ impl Clock {
 pub fn clock_gettime() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
-    let ts = (tf.regs[1] as *mut KernelTimespec);
-    match tf.regs[0] {
-        Clock::REALTIME_COARSE => unsafe {
-            ts.write(KernelTimespec {
-                sec: read() as i64,
-                nsec: 0,
-            })
-        },
-        x => panic!("unimplemented clock: {}\n", x),
+    let now = match tf.regs[0] {
+        Clock::REALTIME | Clock::REALTIME_COARSE => {
+            let (sec, us) = now_us();
+            KernelTimespec {
+                sec: sec as i64,
+                nsec: us as i64 * 1000,
+            }
+        }
+        // nothing suspends, so boottime is monotonic too
+        Clock::MONOTONIC | Clock::MONOTONIC_RAW | Clock::MONOTONIC_COARSE | Clock::BOOTTIME => {
+            KernelTimespec::from_ns(timer::uptime_ns())
+        }
+        _ => return err(EINVAL),
+    };
+    match ptr2mut_op!(tf.regs[1], KernelTimespec) {
+        Some(ts) => *ts = now,
+        _ => return err(EFAULT),
     }
     0
 }
 
-pub fn clock_nanosleep() -> u64 {
+#[repr(C)]
+pub struct Timeval {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+// the timezone is obsolete, tz is not touched
+pub fn gettimeofday() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
-    //TODO
-    // match tf.regs[0] {
-    //     x => panic!("unimplemented clock: {}\n", x),
-    // }
-    let ts = ptr2ref!(tf.regs[2], KernelTimespec);
+    if let Some(tv) = ptr2mut_op!(tf.regs[0], Timeval) {
+        let (sec, usec) = now_us();
+        tv.sec = sec as i64;
+        tv.usec = usec as i64;
+    }
+    0
+}
 
-    let millis = ts.millis();
+pub fn settimeofday() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let tv = match ptr2ref_op!(tf.regs[0], Timeval) {
+        Some(tv) => tv,
+        _ => return 0,
+    };
+    if tv.sec < 0 || tv.sec > u32::MAX as i64 || tv.usec < 0 || tv.usec >= 1_000_000 {
+        return err(EINVAL);
+    }
+    let lock = SECOND.acquire();
+    load(tv.sec as u32);
+    *lock.as_mut() = (tv.sec as u32, timer::counter());
+    0
+}
 
-    timer::sleep(millis as u64);
+const TIMER_ABSTIME: u64 = 1;
 
-    0
+fn do_nanosleep(ns: u64, rem: u64) -> u64 {
+    match timer::sleep_ns(ns) {
+        Ok(()) => 0,
+        Err(None) => err(EAGAIN),
+        Err(Some(left)) => {
+            if let Some(rem) = ptr2mut_op!(rem, KernelTimespec) {
+                *rem = KernelTimespec::from_ns(left);
+            }
+            err(EINTR)
+        }
+    }
+}
+
+pub fn timespec_ns(req: u64) -> Result<u64, u64> {
+    let ts = match ptr2ref_op!(req, KernelTimespec) {
+        Some(ts) => ts,
+        _ => return Err(err(EFAULT)),
+    };
+    if ts.sec < 0 || ts.nsec < 0 || ts.nsec >= 1_000_000_000 {
+        return Err(err(EINVAL));
+    }
+    Ok((ts.sec as u64)
+        .saturating_mul(1_000_000_000)
+        .saturating_add(ts.nsec as u64))
+}
+
+pub fn nanosleep() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    match timespec_ns(tf.regs[0]) {
+        Ok(ns) => do_nanosleep(ns, tf.regs[1]),
+        Err(e) => e,
+    }
+}
+
+pub fn clock_nanosleep() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let now = match tf.regs[0] {
+        Clock::REALTIME => realtime_ns(),
+        Clock::MONOTONIC | Clock::BOOTTIME => timer::uptime_ns(),
+        _ => return err(EINVAL),
+    };
+    let ns = match timespec_ns(tf.regs[2]) {
+        Ok(ns) => ns,
+        Err(e) => return e,
+    };
+    if tf.regs[1] & TIMER_ABSTIME != 0 {
+        // rem is left alone for absolute sleeps
+        return do_nanosleep(ns.saturating_sub(now), 0);
+    }
+    do_nanosleep(ns, tf.regs[3])
 }
@@ -1,27 +1,32 @@
 use core::{
     arch::{asm, naked_asm},
-    cmp::min,
+    cmp::{max, min},
     mem::forget,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use alloc::{collections::linked_list::LinkedList, string::String, vec::Vec};
 
 use crate::{
     arch::{
-        pstate_i_clr, pstate_i_set, r_far_el1, r_pstate_daif, r_tpidr_el0, r_tpidrro_el0,
-        tlbi_aside1, w_tpidr_el0, w_ttbr0_el1,
+        pstate_i_clr, pstate_i_set, r_esr_el1, r_far_el1, r_mpidr_el1, r_pstate_daif, r_tpidr_el0,
+        r_tpidrro_el0, w_tpidr_el0, w_ttbr0_el1,
     },
     dsb,
     elf::{self, Elf, Elf64Phdr, PhIter},
+    errno::{
+        E2BIG, EBADF, EBUSY, ECHILD, EFAULT, EINTR, EINVAL, ENAMETOOLONG, ENODEV, ENOENT, ENOMEM,
+        EPERM, EPIPE, ESRCH, err,
+    },
     fs::{self, File},
+    futex,
     heap::SyncUnsafeCell,
-    isb, p9,
+    isb, log, p9,
     pm::{self, GB, MB, align_b, align_f},
-    print, ptr2mut,
+    ptr2mut, ptr2mut_op, ptr2ref, ptr2ref_op, rng, smp,
     spin::Lock,
-    stuff::{as_slice, as_slice_mut, cstr_as_slice, cstr64_as_slice, defer},
-    tlbi_vmalle1, trap,
+    stuff::{as_slice, as_slice_mut, defer},
+    timer, tlbi_vmalle1, trap, tty,
     vm::{self, PmWrap, free_pt, map_v2p_4k_inner, unmap_4k_inner, v2p, v2p_pt},
     wfi,
 };
@@ -37,11 +42,15 @@ unsafe impl Sync for Cpu {}
 
 impl Cpu {
     pub fn disable_intr(&mut self) {
-        if self.int_disables == 0 {
-            self.int_enable = (r_pstate_daif() | 0b10) == 0;
-            pstate_i_set();
+        let daif = r_pstate_daif();
+        pstate_i_set();
+        // the caller may have been moved to another core between
+        // mycpu() and the mask, only now is the core fixed
+        let cpu = mycpu();
+        if cpu.int_disables == 0 {
+            cpu.int_enable = daif & 0b10 == 0;
         }
-        self.int_disables += 1;
+        cpu.int_disables += 1;
     }
 
     pub fn enable_intr(&mut self) {
@@ -53,33 +62,40 @@ impl Cpu {
 
     pub fn get_task(&mut self) -> Option<&'static mut Task> {
         self.disable_intr();
-        let task = match self.task_idx {
+        let cpu = mycpu();
+        let task = match cpu.task_idx {
             Some(idx) => Some(&mut TASKS.as_mut()[idx]),
             _ => None,
         };
-        self.enable_intr();
+        cpu.enable_intr();
         task
     }
 }
 
-pub const NCPU: usize = 1;
+pub const NCPU: usize = 4;
+const ALL_CPUS: u64 = (1 << NCPU) - 1;
 
-static CPUS: SyncUnsafeCell<[Cpu; NCPU]> = SyncUnsafeCell::new([Cpu {
+const CPU: Cpu = Cpu {
     int_enable: false,
     int_disables: 0,
     task_idx: None,
     shed_ctx: [0; 15],
-}]);
+};
+
+static CPUS: SyncUnsafeCell<[Cpu; NCPU]> = SyncUnsafeCell::new([CPU; NCPU]);
 
+// aff0, qemu virt numbers the cores from 0 within a cluster of 8
 pub fn cpuid() -> usize {
-    0
+    (r_mpidr_el1() & 0xff) as usize
 }
 
 pub fn mycpu() -> &'static mut Cpu {
     &mut CPUS.as_mut()[cpuid()]
 }
 
-static NTASKS: usize = 32;
+pub const NTASKS: usize = 32;
+// descriptors per task, RLIMIT_NOFILE can only lower it
+pub const NFDS: usize = 64;
 
 enum State {
     Free,
@@ -96,6 +112,8 @@ enum RegionType {
     Stack,
     Brk,
     Mmap,
+    // MAP_SHARED, the pages belong to the object and are never cow
+    Shared,
 }
 
 const REGION_MAX_SZ: usize = GB;
@@ -114,7 +132,7 @@ impl Region {
     pub fn calc_granule(block_size: usize) -> u8 {
         assert!(block_size.count_ones() == 1);
         assert!(block_size >= 4096 && block_size <= MB);
-        ((block_size / 4096) - 1) as u8
+        (block_size / 4096).trailing_zeros() as u8
     }
 
     pub fn blksize(&self) -> usize {
@@ -125,6 +143,16 @@ impl Region {
         4096 << granule as usize
     }
 
+    // never written or MAP_SHARED, forks map the same pages instead of
+    // cow. private mappings can be made writable later, they always cow
+    pub fn shared(&self) -> bool {
+        match self.ty {
+            RegionType::Shared => true,
+            RegionType::Mmap => false,
+            _ => self.flags & elf::PF_W == 0,
+        }
+    }
+
     pub fn has(&self, vaddr: usize) -> bool {
         vaddr >= self.vaddr && vaddr < (self.vaddr + self.len)
     }
@@ -149,23 +177,78 @@ pub type RTree = LinkedList<Region>;
 
 pub struct Task {
     parent: Option<*mut Task>,
+    // threads, reaped on exit with no zombie or SIGCHLD
+    autoreap: bool,
+    // wait status: code << 8 for exit, the signal for a kill
     exit_code: u64,
-    tid: Option<u64>,
+    // zeroed and futex woken on exit, what pthread_join waits on
+    clear_child_tid: Option<u64>,
     state: State,
     ctx: [u64; 15],
     lock: Lock<()>,
     pub trapframe: u64,
     user_pt: Option<u64>,
     user_sp: Option<u64>,
+    // slot whose address space this runs in, its own unless CLONE_VM.
+    // the owner's user_pt, regions and stack are the shared ones
+    mm: usize,
+    // tasks running in this slot's address space, the slot isn't
+    // handed out again before it drops to 0
+    mm_users: AtomicUsize,
+    // the owner's guards maps, brk and the page tables against the
+    // other threads of the mm
+    mm_lock: Lock<()>,
+    // the owner's, REGISTER_PRIVATE_EXPEDITED was done in this mm
+    membarrier_private: bool,
+    // of the address space, only valid in generation asid_gen
+    asid: u16,
+    asid_gen: u64,
     chan: Option<u64>,
     pub pid: u16,
-    pub files: [Option<&'static mut fs::File>; 8],
+    // thread group, the leader's pid. getpid reports it
+    tgid: u16,
+    // killed by a group exit, exit_code is already the group's
+    group_exit: bool,
+    // the parent sleeps in clone until this one execs or exits
+    vfork: bool,
+    pub files: [Option<&'static mut fs::File>; NFDS],
+    // bit per fd, closed on execve
+    pub cloexec: u64,
+    // slot whose files and cloexec this uses, its own unless CLONE_FILES
+    fdt: usize,
+    // tasks using this slot's fd table, kept like mm_users
+    fdt_users: AtomicUsize,
     program: RTree,
     mmap: Region,
+    // live mappings inside mmap sorted by address, the rw anonymous
+    // ones are backed on first touch like brk
+    maps: RTree,
     brk: Region,
     spel0: Region,
     pub umask: u32,
     pub cwd: Option<String>,
+    pub uid: u32,
+    pub euid: u32,
+    pub gid: u32,
+    pub egid: u32,
+    // bit n-1 for signal n
+    pub sigpending: u64,
+    pub sigmask: u64,
+    // what ppoll swapped out, back once the call or its signal is done
+    saved_sigmask: Option<u64>,
+    pub sigrestart: u64,
+    // index sig-1
+    pub sigactions: [SigAction; NSIG],
+    intr: bool,
+    eintr: bool,
+    pub rlimits: [Rlimit; RLIMIT::NLIMITS],
+    // address and si_code of the last SIGSEGV
+    pub fault: Option<(usize, u32)>,
+    // scratch pages standing in for bad user addresses the kernel
+    // touched during this trap, with the ptes they replaced
+    ufault: Vec<(usize, u64)>,
+    // bit n for cpu n
+    pub affinity: u64,
 }
 
 unsafe impl Sync for Task {}
@@ -174,17 +257,30 @@ impl Task {
     const fn zeroed() -> Task {
         Task {
             parent: None,
+            autoreap: false,
             exit_code: 0,
-            tid: None,
+            clear_child_tid: None,
             state: State::Free,
             ctx: [0; 15],
             lock: Lock::new("T", ()),
             trapframe: 0,
             user_pt: None,
             user_sp: None,
+            mm: 0,
+            mm_users: AtomicUsize::new(0),
+            mm_lock: Lock::new("mm", ()),
+            membarrier_private: false,
+            asid: 0,
+            asid_gen: 0,
             chan: None,
             pid: 0,
-            files: [None, None, None, None, None, None, None, None],
+            tgid: 0,
+            group_exit: false,
+            vfork: false,
+            files: [const { None }; NFDS],
+            cloexec: 0,
+            fdt: 0,
+            fdt_users: AtomicUsize::new(0),
             program: RTree::new(),
             mmap: Region {
                 ty: RegionType::Mmap,
@@ -194,6 +290,7 @@ impl Task {
                 flags: elf::PF_R | elf::PF_W,
                 granule: 0,
             },
+            maps: RTree::new(),
             brk: Region {
                 ty: RegionType::Brk,
                 vaddr: 2 * GB,
@@ -212,31 +309,122 @@ impl Task {
             },
             umask: 0777,
             cwd: None,
+            uid: 0,
+            euid: 0,
+            gid: 0,
+            egid: 0,
+            sigpending: 0,
+            sigmask: 0,
+            saved_sigmask: None,
+            sigrestart: 0,
+            sigactions: [SigAction::zeroed(); NSIG],
+            intr: false,
+            eintr: false,
+            rlimits: default_rlimits(),
+            fault: None,
+            ufault: Vec::new(),
+            affinity: ALL_CPUS,
+        }
+    }
+
+    pub fn rlimit(&self, res: usize) -> u64 {
+        self.rlimits[res].cur
+    }
+
+    pub fn signal_pending(&self) -> bool {
+        self.sigpending & !(self.sigmask & !UNBLOCKABLE) != 0
+    }
+
+    // the mask for the length of a syscall. a signal that cuts it short
+    // is handled under it and the handler returns to the old one
+    pub fn swap_sigmask(&mut self, mask: u64) {
+        if self.saved_sigmask.is_none() {
+            self.saved_sigmask = Some(self.sigmask);
+        }
+        self.sigmask = mask & !UNBLOCKABLE;
+    }
+
+    pub fn restore_sigmask(&mut self) {
+        if let Some(mask) = self.saved_sigmask.take() {
+            self.sigmask = mask;
+        }
+    }
+
+    // lowest deliverable signal, 0 if none
+    pub fn next_signal(&self) -> u32 {
+        let set = self.sigpending & !(self.sigmask & !UNBLOCKABLE);
+        if set == 0 {
+            0
+        } else {
+            set.trailing_zeros() + 1
         }
     }
 
+    // set by an interrupted sleep, consumed by the syscall layer
+    pub fn take_eintr(&mut self) -> bool {
+        let e = self.eintr;
+        self.eintr = false;
+        e
+    }
+
+    // owner of the address space
+    fn mm(&self) -> &'static mut Task {
+        &mut TASKS.as_mut()[self.mm]
+    }
+
     pub fn get_trap_frame(&self) -> Option<&'static mut trap::Frame> {
         unsafe { (self.trapframe as *mut trap::Frame).as_mut() }
     }
 
+    // the address space, what private futexes key on
+    pub fn mm_id(&self) -> usize {
+        self.mm
+    }
+
+    // owner of the fd table
+    pub fn fdt(&self) -> &'static mut Task {
+        &mut TASKS.as_mut()[self.fdt]
+    }
+
+    pub fn set_cloexec(&mut self, fd: usize, on: bool) {
+        let fdt = self.fdt();
+        if on {
+            fdt.cloexec |= 1 << fd;
+        } else {
+            fdt.cloexec &= !(1 << fd);
+        }
+    }
+
     pub fn get_file(&self, idx: usize) -> Option<&'static mut File> {
-        if idx > self.files.len() {
+        let fdt = self.fdt();
+        if idx >= fdt.files.len() {
             return None;
         }
 
-        if self.files[idx].is_none() {
+        if fdt.files[idx].is_none() {
             return None;
         }
 
-        let file = self.files[idx].as_ref().unwrap();
+        let file = fdt.files[idx].as_ref().unwrap();
         return Some(ptr2mut!((*file) as *const File, File));
     }
 
-    fn init_1(&mut self, pc: u64) {
-        let user_pt = pm::alloc(4096).unwrap() as u64;
-        self.user_pt = Some(user_pt);
-        let l0_pt = PmWrap::new(self.user_pt.unwrap() as usize, vm::PR_PW, true).unwrap();
-        let user_sp = pm::alloc(SPEL0_SIZE).unwrap();
+    // page tables and the user stack, nothing else is mapped yet
+    fn new_mm(&mut self) -> Result<(), ()> {
+        let user_pt = pm::alloc(4096)?;
+        let pt_defer = defer(|| free_pt(user_pt as u64));
+        let l0_pt = PmWrap::new(user_pt, vm::PR_PW, true).map_err(|_| ())?;
+
+        let user_sp = pm::alloc(SPEL0_SIZE)?;
+        // free_pt would free leaves 4k at a time, unmap before it runs
+        let sp_defer = defer(|| {
+            let _ = unmap(
+                l0_pt.as_slice_mut(),
+                self.spel0.vaddr,
+                self.spel0.len / 4096,
+            );
+            pm::free(user_sp, SPEL0_SIZE);
+        });
 
         map(
             l0_pt.as_slice_mut(),
@@ -245,14 +433,32 @@ impl Task {
             self.spel0.len / 4096,
             vm::PR_PW_UR_UW1,
         ) //
-        .unwrap();
+        .map_err(|_| ())?;
 
+        forget(pt_defer);
+        forget(sp_defer);
+        self.user_pt = Some(user_pt as u64);
         self.user_sp = Some(user_sp as u64);
+        self.membarrier_private = false;
+        Ok(())
+    }
+
+    fn init_1(&mut self, pc: u64) -> Result<(), ()> {
+        self.new_mm()?;
+        let mm_defer = defer(|| release_mm(self));
 
-        let sp_el1 = pm::alloc(4096 * 2).unwrap();
-        let sp_el1 = vm::map(sp_el1, 2, vm::PR_PW).unwrap();
+        let sp_el1 = pm::alloc(4096 * 2)?;
+        let sp_el1 = match vm::map(sp_el1, 2, vm::PR_PW) {
+            Ok(v) => v,
+            Err(_) => {
+                pm::free(sp_el1, 4096 * 2);
+                return Err(());
+            }
+        };
         let sp_el1 = sp_el1 + 4096 * 2;
 
+        forget(mm_defer);
+
         let tf_ptr = unsafe { (sp_el1 as *mut trap::Frame).sub(1) };
         let tf = unsafe { tf_ptr.as_mut().unwrap() };
 
@@ -263,6 +469,7 @@ impl Task {
         self.ctx[13] = forkret as *const fn() as u64;
 
         self.trapframe = tf_ptr as u64;
+        Ok(())
     }
 }
 
@@ -281,26 +488,21 @@ impl Wq {
         }
     }
 
-    pub fn add(&mut self, task: *mut Task) {
-        if task.is_null() {
-            return;
-        }
-        let lock = self.lock.acquire();
-        self.tasks[self.count] = Some(task);
-        self.count += 1;
-        drop(lock)
-    }
-
+    // a sleeping task is in here once, NTASKS of them always fit
     pub fn sleep<T>(&mut self, lock: &Lock<T>) {
         let task = mycpu().get_task().unwrap();
-        self.add(task as *mut Task);
+        let wq_lock = self.lock.acquire();
+        self.tasks[self.count] = Some(task as *mut Task);
+        self.count += 1;
+        drop(wq_lock);
         let task_lock = task.lock.acquire();
         lock.release();
         task.state = State::Sleeping;
         task.chan = None;
         sched();
-        forget(lock.acquire());
+        // task lock first, wakers hold lock and then take it
         drop(task_lock);
+        forget(lock.acquire());
     }
 
     pub fn wake_all(&mut self) {
@@ -358,13 +560,14 @@ fn map_ovwr(
             true,
             |_| {},
         ) {
-            Err(vm::Error::Exists(_)) => {}
-            e => return e,
+            Ok(_) | Err(vm::Error::Exists(_)) => {}
+            Err(e) => return Err(e),
         };
     }
     Ok(v)
 }
 
+// permissions only, the output address stays so no break before make
 fn map_chg_perms(l0_pt: &mut [u64], v: usize, n: usize, perms: u64) -> Result<usize, vm::Error> {
     for i in 0..n {
         v2p_pt(
@@ -375,57 +578,111 @@ fn map_chg_perms(l0_pt: &mut [u64], v: usize, n: usize, perms: u64) -> Result<us
             }),
         )
         .unwrap();
+        vm::tlb_shootdown(None, Some(v + (4096 * i)));
     }
     Ok(v)
 }
 
 const SPEL0_SIZE: usize = 4096 * 2;
 
+// auxv keys
+struct AT;
+impl AT {
+    const NULL: u64 = 0;
+    const PHDR: u64 = 3;
+    const PHENT: u64 = 4;
+    const PHNUM: u64 = 5;
+    const PAGESZ: u64 = 6;
+    const BASE: u64 = 7;
+    const ENTRY: u64 = 9;
+    const HWCAP: u64 = 16;
+    const RANDOM: u64 = 25;
+}
+// left unmapped right below the user stack so overflows fault
+const STACK_GUARD: usize = 4096;
+
+fn in_stack_guard(task: &Task, v: usize) -> bool {
+    v < task.spel0.vaddr && v >= task.spel0.vaddr - STACK_GUARD
+}
+
 // inplace
 pub fn execv_inner(path: &str, argv: &[&[u8]], envp: &[&[u8]], skipr: bool) -> Result<(), ()> {
-    let mut elf = Elf::new(path).map_err(|_| ())?;
+    let elf = Elf::new(path).map_err(|_| ())?;
+    load_elf(elf, argv, envp, skipr)
+}
 
+// the old image goes first, a failure after that has nothing to return to
+fn load_elf(mut elf: Elf, argv: &[&[u8]], envp: &[&[u8]], skipr: bool) -> Result<(), ()> {
     let task = mycpu().get_task().unwrap();
     let user_pt = task.user_pt.unwrap();
+    uaccess_done(task);
 
     let l0_pt = PmWrap::new(user_pt as usize, vm::PR_PW, false).unwrap();
     free_region(&task.mmap, l0_pt.as_slice_mut(), false);
     task.mmap.len = 0;
+    task.maps.clear();
     free_region(&task.brk, l0_pt.as_slice_mut(), false);
     task.brk.len = 0;
     free_regions(&mut task.program, l0_pt.as_slice_mut(), false).unwrap();
     task.program.clear();
+    flush_mm(task);
+
+    let bias = elf.bias();
+    let entry = elf.header.entry + bias;
+    let phoff = elf.header.phoff;
+    let phnum = elf.header.phnum as u64;
+    // runtime address of the program headers for AT_PHDR
+    let mut phdr = 0;
+    // (vaddr, filesz, memsz, align) of the tls template
+    let mut tls = None;
 
     let file = unsafe { (elf.file as *mut File).as_mut() }.unwrap();
     let mut phit = PhIter::new(&mut elf);
     let mut ph = Elf64Phdr::zeroed();
     while let Some(p) = phit.next((&mut ph) as *mut Elf64Phdr) {
+        if p.kind as u64 == elf::PT_PHDR {
+            phdr = p.vaddr + bias;
+        }
+        if p.kind as u64 == elf::PT_TLS {
+            tls = Some((p.vaddr + bias, p.filesz, p.memsz, p.align));
+        }
         if p.kind as u64 != elf::PT_LOAD {
             continue;
         }
+        if phdr == 0 && phoff >= p.offset && phoff < p.offset + p.filesz {
+            phdr = p.vaddr + (phoff - p.offset) + bias;
+        }
 
-        let len = align_f((p.vaddr as usize % 4096) + p.memsz as usize, 4096);
-        let vfrom = align_b(p.vaddr as usize, 4096);
+        let vaddr = (p.vaddr + bias) as usize;
+        let len = align_f((vaddr % 4096) + p.memsz as usize, 4096);
+        let vfrom = align_b(vaddr, 4096);
         let pages = len / 4096;
+        // the stack and its guard page stay out of reach
+        if vfrom < task.spel0.end() && vfrom + len > task.spel0.vaddr - STACK_GUARD {
+            return Err(());
+        }
         for i in 0..pages {
-            let pm = pm::alloc(4096).unwrap();
-            map(
+            let pm = pm::alloc(4096)?;
+            if map(
                 l0_pt.as_slice_mut(),
                 vfrom + i * 4096, //
                 pm,
                 1,
                 vm::PR_PW,
             )
-            .unwrap();
+            .is_err()
+            {
+                pm::free(pm, 4096);
+                return Err(());
+            }
         }
 
-        tlbi_aside1(task.pid as u64);
-        dsb!();
-        isb!();
+        flush_mm(task);
 
-        let slice = as_slice_mut(p.vaddr as *mut u8, p.memsz as usize);
+        let slice = as_slice_mut(vaddr as *mut u8, p.memsz as usize);
         file.seek_to(p.offset as usize);
-        file.read_all(&mut slice[0..p.filesz as usize]).unwrap();
+        file.read_all(&mut slice[0..p.filesz as usize])
+            .map_err(|_| ())?;
         (&mut slice[p.filesz as usize..]).fill(0);
         task.program.push_back(Region {
             vaddr: vfrom,
@@ -436,21 +693,25 @@ pub fn execv_inner(path: &str, argv: &[&[u8]], envp: &[&[u8]], skipr: bool) -> R
             ty: RegionType::Program,
         });
 
-        map_chg_perms(
-            l0_pt.as_slice_mut(),
-            vfrom,
-            pages,
-            if p.flags == elf::PF_R | elf::PF_X {
-                vm::PR_UR_UX
-            } else if p.flags == elf::PF_R | elf::PF_W {
-                vm::PR_PW_UR_UW1
-            } else if p.flags == elf::PF_R {
-                vm::PR_UR
-            } else {
-                panic!("unhandled flags combo")
-            },
-        )
-        .unwrap();
+        let perms = pte_perms(p.flags, p.flags & elf::PF_W != 0);
+        map_chg_perms(l0_pt.as_slice_mut(), vfrom, pages, perms).unwrap();
+    }
+
+    // aarch64 variant I, tp points at a 16 byte tcb and the block
+    // follows at the template's alignment. libc may set up its own
+    let mut tp = 0;
+    if let Some((vaddr, filesz, memsz, align)) = tls {
+        let align = (align as usize).max(16);
+        if align > 4096 || filesz > memsz {
+            return Err(());
+        }
+        let off = align_f(16, align);
+        let len = align_f(off + memsz as usize, 4096);
+        // the .tbss part comes in zeroed on first touch
+        let base = mmap_alloc(task, len, elf::PF_R | elf::PF_W, RegionType::Mmap).ok_or(())?;
+        let from = as_slice(vaddr as *const u8, filesz as usize);
+        as_slice_mut((base + off) as *mut u8, filesz as usize).copy_from_slice(from);
+        tp = base as u64;
     }
 
     let sp_el0 = as_slice_mut(task.spel0.vaddr as *mut u8, task.spel0.len);
@@ -464,14 +725,15 @@ pub fn execv_inner(path: &str, argv: &[&[u8]], envp: &[&[u8]], skipr: bool) -> R
     }
     w_idx -= 16; //AT_RANDOM
     let at_random = curptr!();
+    // canary and pointer guard seed, via a kernel buffer for the device
+    let mut seed = [0u8; 16];
+    rng::fill(&mut seed);
+    sp_el0[w_idx..w_idx + 16].copy_from_slice(&seed);
 
     let mut s = Vec::new();
     s.push(0); // envp null term
     for i in 0..envp.len() {
         let slice = envp[envp.len() - i - 1];
-        if slice.len() == 0 {
-            break;
-        }
         if slice.len() + 1 > w_idx {
             return Err(());
         }
@@ -486,9 +748,6 @@ pub fn execv_inner(path: &str, argv: &[&[u8]], envp: &[&[u8]], skipr: bool) -> R
 
     for i in 0..argv.len() {
         let slice = argv[argv.len() - i - 1];
-        if slice.len() == 0 {
-            break;
-        }
         if slice.len() + 1 > w_idx {
             return Err(());
         }
@@ -525,8 +784,18 @@ pub fn execv_inner(path: &str, argv: &[&[u8]], envp: &[&[u8]], skipr: bool) -> R
         }};
     }
 
-    auxv!(0, 0);
-    auxv!(25, at_random as u64);
+    auxv!(AT::NULL, 0);
+    auxv!(AT::RANDOM, at_random as u64);
+    if phdr != 0 {
+        auxv!(AT::PHDR, phdr);
+    }
+    auxv!(AT::PHENT, size_of::<Elf64Phdr>() as u64);
+    auxv!(AT::PHNUM, phnum);
+    auxv!(AT::PAGESZ, 4096);
+    // no interpreter is loaded
+    auxv!(AT::BASE, 0);
+    auxv!(AT::ENTRY, entry);
+    auxv!(AT::HWCAP, 0);
 
     let ptrs_len = 8 * (s.len() + 1);
     if w_idx < ptrs_len {
@@ -550,60 +819,226 @@ pub fn execv_inner(path: &str, argv: &[&[u8]], envp: &[&[u8]], skipr: bool) -> R
     let tf = unsafe { (task.trapframe as *mut trap::Frame).as_mut() }.unwrap();
     tf.zero();
 
-    tf.pc = elf.header.entry;
+    tf.pc = entry;
     tf.pstate = 0x0;
     tf.sp_el0 = sp_pos as u64;
 
-    restore_ttbr0(task.pid as usize, user_pt as usize);
-    w_tpidr_el0(0);
+    restore_ttbr0(task);
+    w_tpidr_el0(tp);
     Ok(())
 }
 
+// argv and envp with their pointers, all of it has to fit on the stack
+const ARG_MAX: usize = SPEL0_SIZE / 2;
+const PATH_MAX: usize = 4096;
+
+pub fn user_ok(task: &mut Task, v: usize) -> bool {
+    task.spel0.has(v) || find_region(task, v).is_some()
+}
+
+// every page of [v, v + len)
+pub fn user_range_ok(task: &mut Task, v: usize, len: usize) -> bool {
+    let end = match v.checked_add(len) {
+        Some(end) if len > 0 => end,
+        _ => return len == 0,
+    };
+    let mut p = v;
+    while p < end {
+        if !user_ok(task, p) {
+            return false;
+        }
+        p = (p & !4095) + 4096;
+    }
+    true
+}
+
+// physical address behind a user address, None if nothing backs it
+pub fn user_v2p(task: &mut Task, v: usize) -> Option<usize> {
+    let l0_pt = PmWrap::new(task.mm().user_pt.unwrap() as usize, vm::PR_PW, false).ok()?;
+    user_page(l0_pt.as_slice(), v)
+}
+
+// copied out, the old image is gone by the time execv_inner needs it
+fn user_cstr(task: &mut Task, ptr: u64, max: usize) -> Result<Vec<u8>, u64> {
+    let mut vec = Vec::new();
+    let mut p = ptr as usize;
+    loop {
+        if (p == ptr as usize || p % 4096 == 0) && !user_ok(task, p) {
+            return Err(err(EFAULT));
+        }
+        let c = unsafe { (p as *const u8).read() };
+        if c == 0 {
+            return Ok(vec);
+        }
+        if vec.len() == max {
+            return Err(err(E2BIG));
+        }
+        vec.push(c);
+        p += 1;
+    }
+}
+
+// a null array counts as empty
+fn user_strs(task: &mut Task, ptr: u64, left: &mut usize) -> Result<Vec<Vec<u8>>, u64> {
+    let mut strs = Vec::new();
+    if ptr == 0 {
+        return Ok(strs);
+    }
+    if ptr % 8 != 0 {
+        return Err(err(EFAULT));
+    }
+    loop {
+        let at = ptr as usize + 8 * strs.len();
+        if !user_ok(task, at) {
+            return Err(err(EFAULT));
+        }
+        let sptr = unsafe { (at as *const u64).read() };
+        if sptr == 0 {
+            return Ok(strs);
+        }
+        let s = user_cstr(task, sptr, *left)?;
+        if s.len() + 1 + 8 > *left {
+            return Err(err(E2BIG));
+        }
+        *left -= s.len() + 1 + 8;
+        strs.push(s);
+    }
+}
+
 pub fn execve() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
 
-    let path = cstr_as_slice(tf.regs[0] as *const u8);
-    let path = Vec::from(path);
+    if tf.regs[0] == 0 {
+        return err(EFAULT);
+    }
+    let path = match user_cstr(task, tf.regs[0], PATH_MAX) {
+        Ok(p) => p,
+        Err(e) if e == err(E2BIG) => return err(ENAMETOOLONG),
+        Err(e) => return e,
+    };
 
     let pathstr = match str::from_utf8(path.as_slice()) {
         Ok(p) => p,
-        _ => return !0,
+        _ => return err(ENOENT),
     };
 
-    let argv_ptr = cstr64_as_slice(tf.regs[1] as *const u64);
-    let envp_ptr = cstr64_as_slice(tf.regs[2] as *const u64);
+    let mut left = ARG_MAX;
+    let all_argv = match user_strs(task, tf.regs[1], &mut left) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+    let all_envp = match user_strs(task, tf.regs[2], &mut left) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
 
-    let mut argv = Vec::new();
-    let mut envp = Vec::new();
+    let elf = match Elf::new(pathstr) {
+        Ok(elf) => elf,
+        Err(e) => return err(e),
+    };
+    if let Err(e) = de_thread(task) {
+        return err(e);
+    }
+    let wait_lock = WAIT.acquire();
+    vfork_done(task);
+    drop(wait_lock);
 
-    let mut all = Vec::new();
+    let argv: Vec<&[u8]> = all_argv.iter().map(|v| v.as_slice()).collect();
+    let envp: Vec<&[u8]> = all_envp.iter().map(|v| v.as_slice()).collect();
 
-    for i in 0..argv_ptr.len() {
-        let cstr = cstr_as_slice(argv_ptr[i] as *const u8);
-        let vec = Vec::from(cstr);
-        argv.push(as_slice(vec.as_ptr(), vec.len()));
-        all.push(vec);
+    match load_elf(elf, argv.as_slice(), envp.as_slice(), true) {
+        Ok(_) => {
+            // handlers point into the old image, ignored stays ignored
+            for act in task.sigactions.iter_mut() {
+                if act.handler != SIG_IGN {
+                    *act = SigAction::zeroed();
+                }
+            }
+            task.sigrestart = 0;
+            // a registration was the old image's, like linux
+            task.mm().membarrier_private = false;
+            for fd in 0..task.files.len() {
+                if task.cloexec & (1 << fd) == 0 {
+                    continue;
+                }
+                if let Some(f) = task.files[fd].take() {
+                    let _ = f.close();
+                }
+            }
+            task.cloexec = 0;
+            0
+        }
+        // past the point of no return, the old image is gone
+        _ => {
+            kill_task(task, SIGSEGV);
+            0
+        }
     }
+}
 
-    for i in 0..envp_ptr.len() {
-        let cstr = cstr_as_slice(envp_ptr[i] as *const u8);
-        let vec = Vec::from(cstr);
-        envp.push(as_slice(vec.as_ptr(), vec.len()));
-        all.push(vec);
+// the other threads still run on the old image, they are killed and
+// waited for. a thread that isn't the leader takes over the leader's
+// parent. it and a CLONE_VM child get an address space and fd table of
+// their own, the old ones go with their last user. unlike linux it keeps
+// its own pid
+fn de_thread(task: &mut Task) -> Result<(), i32> {
+    let leader = &mut TASKS.as_mut()[task.tgid as usize];
+    if task.tgid != task.pid {
+        let wait_lock = WAIT.acquire();
+        task.parent = leader.parent;
+        task.autoreap = leader.autoreap;
+        // a zombie is its parent's to reap already
+        if !matches!(leader.state, State::Zombie) {
+            leader.autoreap = true;
+        }
+        drop(wait_lock);
     }
 
-    let ret = match execv_inner(pathstr, &argv.as_slice(), &envp.as_slice(), true) {
-        Ok(_) => 0,
-        _ => !0,
-    };
+    kill_group(task, 0);
+    while group_alive(task) {
+        if task.sigpending & (1 << (SIGKILL - 1)) != 0 {
+            return Err(EINTR);
+        }
+        yild();
+    }
 
-    ret
+    task.tgid = task.pid;
+
+    if task.fdt != task.pid as usize {
+        dup_files(task.fdt(), task);
+        put_files(task);
+        task.fdt = task.pid as usize;
+        task.fdt_users.store(1, Ordering::Release);
+    }
+
+    if task.mm == task.pid as usize {
+        // a CLONE_VM child still runs in it, the pages can't go
+        if task.mm_users.load(Ordering::Acquire) > 1 {
+            return Err(EBUSY);
+        }
+        return Ok(());
+    }
+
+    // alone in the old one, it is still good if this fails
+    if task.new_mm().is_err() {
+        return Err(ENOMEM);
+    }
+    let owner = task.mm();
+    task.mm = task.pid as usize;
+    task.mm_users.store(1, Ordering::Release);
+    restore_ttbr0(task);
+    if owner.mm_users.fetch_sub(1, Ordering::AcqRel) == 1 {
+        release_mm(owner);
+    }
+    Ok(())
 }
 
 pub fn brk() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
+    let task = task.mm();
+    let mm_lock = task.mm().mm_lock.acquire();
     let pos = task.brk.end() as u64;
 
     let new_pos = align_f(tf.regs[0] as usize, 4096) as u64;
@@ -625,33 +1060,34 @@ pub fn brk() -> u64 {
         return pos;
     }
 
-    let region = task.brk.alloc(incr).unwrap();
-
-    let pages = incr / 4096;
-    let l0_pt = PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false).unwrap();
-
-    for i in 0..pages {
-        let v = region + i * 4096;
-        let p = pm::alloc(4096).unwrap();
-        match map(l0_pt.as_slice_mut(), v, p, 1, vm::PR_PW_UR_UW1) {
-            Err(_) => {
-                todo!();
-            }
-            _ => {}
-        };
+    // pages come in on first touch, see dabt_handler
+    if task.brk.alloc(incr).is_none() {
+        return pos;
     }
 
-    let slice = as_slice_mut(pos as *mut u8, incr);
-    slice.fill(0x0);
-
     new_pos
 }
 
-pub fn settid() -> u64 {
+pub fn set_tid_address() -> u64 {
     let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    task.clear_child_tid = match tf.regs[0] {
+        0 => None,
+        p => Some(p),
+    };
     task.pid as u64
 }
 
+// only memory the task could write itself
+fn user_writable(task: &mut Task, v: usize) -> bool {
+    let mm = task.mm();
+    if mm.mmap.has(v) {
+        let mm_lock = mm.mm_lock.acquire();
+        return mm.maps.iter().any(|r| r.has(v) && r.flags & elf::PF_W > 0);
+    }
+    task.spel0.has(v) || find_region(task, v).is_some_and(|r| r.flags & elf::PF_W > 0)
+}
+
 pub fn set_robust_list() -> u64 {
     0
 }
@@ -660,173 +1096,672 @@ pub fn rseq() -> u64 {
     !0
 }
 
-pub fn prlimit64() -> u64 {
-    0
-}
-
-pub fn mprotect() -> u64 {
-    0
+pub struct MEMBARRIER;
+impl MEMBARRIER {
+    pub const QUERY: u64 = 0;
+    pub const GLOBAL: u64 = 1 << 0;
+    pub const GLOBAL_EXPEDITED: u64 = 1 << 1;
+    pub const REGISTER_GLOBAL_EXPEDITED: u64 = 1 << 2;
+    pub const PRIVATE_EXPEDITED: u64 = 1 << 3;
+    pub const REGISTER_PRIVATE_EXPEDITED: u64 = 1 << 4;
+    const SUPPORTED: u64 = MEMBARRIER::GLOBAL
+        | MEMBARRIER::GLOBAL_EXPEDITED
+        | MEMBARRIER::REGISTER_GLOBAL_EXPEDITED
+        | MEMBARRIER::PRIVATE_EXPEDITED
+        | MEMBARRIER::REGISTER_PRIVATE_EXPEDITED;
 }
 
-pub fn mmap() -> u64 {
+pub fn membarrier() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
-    let flags = tf.regs[3];
 
-    // TODO
-    if (flags & 0x20) == 0 {
-        return !0;
+    let cmd = tf.regs[0];
+    if tf.regs[1] != 0 {
+        return err(EINVAL);
     }
 
-    let len = align_f(tf.regs[1] as usize, 4096);
+    match cmd {
+        MEMBARRIER::QUERY => MEMBARRIER::SUPPORTED,
+        MEMBARRIER::REGISTER_GLOBAL_EXPEDITED => 0,
+        MEMBARRIER::REGISTER_PRIVATE_EXPEDITED => {
+            task.mm().membarrier_private = true;
+            0
+        }
+        // the address space has to be registered first
+        MEMBARRIER::PRIVATE_EXPEDITED if !task.mm().membarrier_private => err(EPERM),
+        MEMBARRIER::GLOBAL | MEMBARRIER::GLOBAL_EXPEDITED | MEMBARRIER::PRIVATE_EXPEDITED => {
+            smp::barrier_ipi();
+            0
+        }
+        _ => err(EINVAL),
+    }
+}
 
-    if len > 10 * MB {
-        return !0;
+// 0 is the caller
+fn task_by_pid(pid: usize) -> Option<&'static mut Task> {
+    if pid == 0 {
+        return mycpu().get_task();
+    }
+    let tasks = TASKS.as_mut();
+    if pid >= tasks.len() {
+        return None;
     }
+    match tasks[pid].state {
+        State::Free => None,
+        _ => Some(&mut tasks[pid]),
+    }
+}
 
-    let region = task.mmap.alloc(len).unwrap();
+// struct sigaction as the arm64 syscall sees it
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+impl SigAction {
+    const fn zeroed() -> SigAction {
+        SigAction {
+            handler: 0,
+            flags: 0,
+            restorer: 0,
+            mask: 0,
+        }
+    }
+}
 
-    let perms = if tf.regs[2] == 1 {
-        vm::PR_UR
-    } else if tf.regs[2] == 3 {
-        vm::PR_PW_UR_UW1
-    } else if tf.regs[2] == 5 {
-        vm::PR_UR_UX
-    } else {
-        panic!("mmap: unknown perms: {}\n", tf.regs[2]);
-    };
+pub struct SA;
+impl SA {
+    pub const RESTORER: u64 = 0x0400_0000;
+    pub const RESTART: u64 = 0x1000_0000;
+    pub const NODEFER: u64 = 0x4000_0000;
+    pub const RESETHAND: u64 = 0x8000_0000;
+}
 
-    let l0_pt = PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false);
-    if l0_pt.is_err() {
-        return !0;
-    }
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
 
-    let l0_pt = l0_pt.unwrap();
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rlimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+pub struct RLIMIT;
+impl RLIMIT {
+    pub const CPU: usize = 0;
+    pub const FSIZE: usize = 1;
+    pub const DATA: usize = 2;
+    pub const STACK: usize = 3;
+    pub const CORE: usize = 4;
+    pub const RSS: usize = 5;
+    pub const NPROC: usize = 6;
+    pub const NOFILE: usize = 7;
+    pub const MEMLOCK: usize = 8;
+    pub const AS: usize = 9;
+    pub const NLIMITS: usize = 16;
+    pub const INFINITY: u64 = !0;
+}
+
+const fn default_rlimits() -> [Rlimit; RLIMIT::NLIMITS] {
+    let mut r = [Rlimit {
+        cur: RLIMIT::INFINITY,
+        max: RLIMIT::INFINITY,
+    }; RLIMIT::NLIMITS];
+    // the fd table is fixed size
+    r[RLIMIT::NOFILE] = Rlimit {
+        cur: NFDS as u64,
+        max: NFDS as u64,
+    };
+    r[RLIMIT::STACK] = Rlimit {
+        cur: SPEL0_SIZE as u64,
+        max: SPEL0_SIZE as u64,
+    };
+    r[RLIMIT::AS] = Rlimit {
+        cur: GB as u64,
+        max: GB as u64,
+    };
+    r[RLIMIT::CORE] = Rlimit { cur: 0, max: 0 };
+    r
+}
 
-    let pages = len / 4096;
+pub fn prlimit64() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
 
-    for i in 0..pages {
-        let v = region + i * 4096;
-        let p = pm::alloc(4096).unwrap();
+    let pid = tf.regs[0] as usize;
+    let res = tf.regs[1] as usize;
+    let new = ptr2ref_op!(tf.regs[2], Rlimit);
+    let old = tf.regs[3] as *mut Rlimit;
 
-        match map(l0_pt.as_slice_mut(), v, p, 1, perms) {
-            Err(_) => {
-                todo!()
-            }
-            _ => {}
-        };
+    if res >= RLIMIT::NLIMITS {
+        return err(EINVAL);
     }
-    let slice = as_slice_mut(region as *mut u8, len);
-    slice.fill(0x0);
-    region as u64
-}
-
-pub fn munmap() -> u64 {
-    0
-}
 
-fn clone_region(region: &Region, from_pt: &mut [u64], to_pt: &mut [u64]) {
-    assert!(region.vaddr % 4096 == 0 && region.len % 4096 == 0);
-    assert!(region.len % region.blksize() == 0);
-    let flags = if region.flags == elf::PF_R | elf::PF_X {
-        // 0
-        vm::PR_UR_UX
-    } else if region.flags == elf::PF_R | elf::PF_W {
-        // 0
-        vm::PR_UR
-    } else if region.flags == elf::PF_R {
-        // 0
-        vm::PR_UR
+    let target = if let Some(t) = task_by_pid(pid) {
+        t
     } else {
-        panic!("unhandled flags combo")
+        return err(ESRCH);
     };
 
-    let n = region.len / region.blksize();
-    for i in 0..n {
-        let closure = |ent: *mut u64| unsafe {
-            *ent = (*ent & vm::PHY_MASK as u64) | flags | 0x403;
-        };
-        let vm = region.vaddr + (i * region.blksize());
-        let pm = v2p_pt(from_pt, vm, Some(closure)).unwrap();
-        crate::pm::dup(pm, region.blksize()).unwrap();
-        let pages = region.blksize() / 4096;
-        map(to_pt, vm, pm, pages, flags).unwrap();
-        for j in 1..pages {
-            v2p_pt(from_pt, vm + 4096 * j, Some(closure)).unwrap();
+    if let Some(new) = new {
+        if new.cur > new.max {
+            return err(EINVAL);
+        }
+        if res == RLIMIT::NOFILE && new.max > target.files.len() as u64 {
+            return err(EPERM);
         }
     }
-}
 
-fn clone_regions(
-    from: &RTree,
-    to: &mut RTree, //
-    from_pt: &mut [u64],
-    to_pt: &mut [u64],
-) -> Result<(), ()> {
-    let mut fit = from.iter();
-    while let Some(region) = fit.next() {
-        clone_region(region, from_pt, to_pt);
-        to.push_back(*region);
+    let lock = target.lock.acquire();
+    if !old.is_null() {
+        unsafe { old.write(target.rlimits[res]) };
     }
+    if let Some(new) = new {
+        target.rlimits[res] = *new;
+    }
+    drop(lock);
 
-    Ok(())
+    0
 }
 
-pub fn fork() -> u64 {
+// only the mmap region, the program's segments keep what the elf asked for
+pub fn mprotect() -> u64 {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
-    let flags64 = tf.regs[0];
-    let mut set_tid = false;
-    let mut clear_tid = false;
+    let addr = tf.regs[0] as usize;
+    let flags = match prot_flags(tf.regs[2]) {
+        Some(f) => f,
+        _ => return err(EINVAL),
+    };
+    let end = match addr.checked_add(align_f(tf.regs[1] as usize, 4096)) {
+        Some(end) if addr.is_multiple_of(4096) => end,
+        _ => return err(EINVAL),
+    };
 
-    if flags64 & SIGCHLD as u64 != 0 {
-        // todo!("flag SIGGCHLD is not implemented");
-    }
-    if flags64 & CLONE_VM as u64 != 0 {
-        todo!("flag CLONE_VM is not implemented");
-    }
-    if flags64 & CLONE_FS as u64 != 0 {
-        todo!("flag CLONE_FS is not implemented");
+    let task = task.mm();
+    if !task.mmap.has(addr) {
+        return 0;
     }
-    if flags64 & CLONE_FILES as u64 != 0 {
-        todo!("flag CLONE_FILES is not implemented");
-    }
-    if flags64 & CLONE_SIGHAND as u64 != 0 {
-        todo!("flag CLONE_SIGHAND is not implemented");
-    }
-    if flags64 & CLONE_PIDFD as u64 != 0 {
-        todo!("flag CLONE_PIDFD is not implemented");
+    let mm_lock = task.mm().mm_lock.acquire();
+
+    // every page of the range is mapped
+    let mut v = addr;
+    for r in task.maps.iter() {
+        if r.has(v) {
+            v = r.end();
+        }
     }
-    if flags64 & CLONE_PTRACE as u64 != 0 {
-        todo!("flag CLONE_PTRACE is not implemented");
+    if v < end {
+        return err(ENOMEM);
     }
-    if flags64 & CLONE_VFORK as u64 != 0 {
-        todo!("flag CLONE_VFORK is not implemented");
+    maps_protect(&mut task.maps, addr, end, flags);
+
+    let l0_pt = match PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false) {
+        Ok(pt) => pt,
+        _ => return err(ENOMEM),
+    };
+    for v in (addr..end).step_by(4096) {
+        let p = match user_page(l0_pt.as_slice(), v) {
+            Some(p) => p,
+            _ => continue,
+        };
+        // cow pages stay read only, the write fault copies them first
+        let shared = task.maps.iter().any(|r| r.has(v) && r.shared());
+        let write = shared || !pm::is_cow(p);
+        let _ = map_chg_perms(l0_pt.as_slice_mut(), v, 1, pte_perms(flags, write));
     }
-    if flags64 & CLONE_PARENT as u64 != 0 {
-        todo!("flag CLONE_PARENT is not implemented");
+    0
+}
+
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+
+// the PF_ flags a mapping records, None for bits that aren't prot bits
+fn prot_flags(prot: u64) -> Option<u32> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return None;
     }
-    if flags64 & CLONE_THREAD as u64 != 0 {
-        todo!("flag CLONE_THREAD is not implemented");
+    let mut flags = 0;
+    if prot & PROT_READ != 0 {
+        flags |= elf::PF_R;
     }
-    if flags64 & CLONE_NEWNS as u64 != 0 {
-        todo!("flag CLONE_NEWNS is not implemented");
+    if prot & PROT_WRITE != 0 {
+        flags |= elf::PF_W;
     }
-    if flags64 & CLONE_SYSVSEM as u64 != 0 {
-        todo!("flag CLONE_SYSVSEM is not implemented");
+    if prot & PROT_EXEC != 0 {
+        flags |= elf::PF_X;
     }
-    if flags64 & CLONE_SETTLS as u64 != 0 {
-        todo!("flag CLONE_SETTLS is not implemented");
+    Some(flags)
+}
+
+// user pte permissions for a region's flags. writable only when asked,
+// a private page waiting for cow is mapped read only. write and exec
+// both imply read, PROT_NONE pages are the kernel's only
+fn pte_perms(flags: u32, write: bool) -> u64 {
+    if flags == 0 {
+        return vm::PR;
     }
-    if flags64 & CLONE_PARENT_SETTID as u64 != 0 {
-        todo!("flag CLONE_PARENT_SETTID is not implemented");
+    match (write && flags & elf::PF_W != 0, flags & elf::PF_X != 0) {
+        (true, true) => vm::PR_PW_UR_UW_UX1,
+        (true, false) => vm::PR_PW_UR_UW1,
+        (false, true) => vm::PR_UR_UX,
+        (false, false) => vm::PR_UR,
     }
-    if flags64 & CLONE_CHILD_CLEARTID as u64 != 0 {
-        clear_tid = true;
-        // todo!("flag CLONE_CHILD_CLEARTID is not implemented");
+}
+
+// what an access needs of the flags: PF_R, PF_W or PF_X
+fn prot_allows(flags: u32, need: u32) -> bool {
+    flags & need != 0 || (need == elf::PF_R && flags != 0)
+}
+
+pub fn mmap() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let flags = tf.regs[3];
+
+    let len = align_f(tf.regs[1] as usize, 4096);
+    if len == 0 {
+        return err(EINVAL);
+    }
+
+    // TODO private file mappings
+    if (flags & MAP_ANONYMOUS) == 0 {
+        if (flags & MAP_SHARED) == 0 {
+            return !0;
+        }
+        return mmap_shared(
+            task,
+            tf.regs[4] as usize,
+            tf.regs[5] as usize,
+            len,
+            tf.regs[2],
+        );
+    }
+
+    let pflags = match prot_flags(tf.regs[2]) {
+        Some(f) => f,
+        _ => return err(EINVAL),
+    };
+
+    let task = task.mm();
+    let mm_lock = task.mm().mm_lock.acquire();
+
+    // nothing is backed yet, the fault handler fills pages on first
+    // touch and checks the access against the recorded protection
+    match mmap_alloc(task, len, pflags, RegionType::Mmap) {
+        Some(r) => r as u64,
+        _ => err(ENOMEM),
     }
-    if flags64 & CLONE_DETACHED as u64 != 0 {
-        todo!("flag CLONE_DETACHED is not implemented");
+}
+
+const MAP_SHARED: u64 = 0x01;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// map the pages of a shm object, every page gets its own reference so
+// the mapping outlives close/unlink and free_region drops it as usual
+fn mmap_shared(task: &mut Task, fd: usize, offt: usize, len: usize, prot: u64) -> u64 {
+    if offt % 4096 != 0 || len == 0 {
+        return err(EINVAL);
+    }
+
+    let file = match task.get_file(fd) {
+        Some(f) => f,
+        _ => return err(EBADF),
+    };
+
+    // the fd is the caller's, the mapping goes into the shared mm
+    let task = task.mm();
+    let mm_lock = task.mm().mm_lock.acquire();
+
+    let first = offt / 4096;
+    let pages = len / 4096;
+    if file.shm_page(first + pages - 1).is_none() {
+        return err(ENODEV);
+    }
+
+    let (perms, pflags) = if prot == 1 {
+        (vm::PR_UR, elf::PF_R)
+    } else if prot == 3 {
+        (vm::PR_PW_UR_UW1, elf::PF_R | elf::PF_W)
+    } else {
+        return err(EINVAL);
+    };
+
+    let l0_pt = match PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false) {
+        Ok(pt) => pt,
+        _ => return !0,
+    };
+
+    let region = match mmap_alloc(task, len, pflags, RegionType::Shared) {
+        Some(r) => r,
+        _ => return err(ENOMEM),
+    };
+
+    for i in 0..pages {
+        // truncated under us
+        let p = match file.shm_page(first + i) {
+            Some(p) if pm::share(p, 4096).is_ok() => p,
+            _ => {
+                unmap_range(task, l0_pt.as_slice_mut(), region, region + len);
+                return err(ENOMEM);
+            }
+        };
+        if map(l0_pt.as_slice_mut(), region + i * 4096, p, 1, perms).is_err() {
+            pm::free(p, 4096);
+            unmap_range(task, l0_pt.as_slice_mut(), region, region + len);
+            return err(ENOMEM);
+        }
+    }
+
+    region as u64
+}
+
+// only the mmap region can be unmapped. holes in the middle are handed
+// out again by mmap_alloc, the tail goes back to the bump allocator
+pub fn munmap() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let task = task.mm();
+    let addr = tf.regs[0] as usize;
+    let len = align_f(tf.regs[1] as usize, 4096);
+
+    if addr % 4096 != 0 || len == 0 {
+        return err(EINVAL);
+    }
+
+    let mm_lock = task.mm().mm_lock.acquire();
+
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        _ => return err(EINVAL),
+    };
+
+    // only mmap'd pages go, anything outside the region is left alone
+    // and, as on linux, a range with nothing mapped isn't an error
+    let addr = max(addr, task.mmap.vaddr);
+    let end = min(end, task.mmap.end());
+    if addr >= end {
+        return 0;
+    }
+
+    let l0_pt = match PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false) {
+        Ok(pt) => pt,
+        _ => return !0,
+    };
+
+    unmap_range(task, l0_pt.as_slice_mut(), addr, end);
+    0
+}
+
+// also what a failed mmap unwinds with, whatever got mapped is released
+fn unmap_range(task: &mut Task, l0_pt: &mut [u64], addr: usize, end: usize) {
+    for v in (addr..end).step_by(4096) {
+        if let Some(p) = user_page(l0_pt, v) {
+            unmap_4k_inner(l0_pt, v).unwrap();
+            pm::free(p, 4096);
+        }
+    }
+    flush_mm(task);
+    maps_cut(&mut task.maps, addr, end);
+
+    task.mmap.len = match task.maps.back() {
+        Some(r) => r.end() - task.mmap.vaddr,
+        _ => 0,
+    };
+}
+
+// first hole of len between the mappings, past the last one otherwise
+fn mmap_alloc(task: &mut Task, len: usize, flags: u32, ty: RegionType) -> Option<usize> {
+    let mut prev = task.mmap.vaddr;
+    let mut at = None;
+    for r in task.maps.iter() {
+        if r.vaddr - prev >= len {
+            at = Some(prev);
+            break;
+        }
+        prev = r.end();
+    }
+    let vaddr = match at {
+        Some(v) => v,
+        _ => {
+            task.mmap.len = prev - task.mmap.vaddr;
+            task.mmap.alloc(len)?
+        }
+    };
+
+    // keep the order
+    let mut tail = RTree::new();
+    while task.maps.back().is_some_and(|r| r.vaddr > vaddr) {
+        tail.push_front(task.maps.pop_back().unwrap());
+    }
+    task.maps.push_back(Region {
+        ty,
+        vaddr,
+        cap: len,
+        len,
+        flags,
+        granule: 0,
+    });
+    task.maps.append(&mut tail);
+    Some(vaddr)
+}
+
+// drop [from, to) from the mappings, splitting the ones it cuts into
+fn maps_cut(maps: &mut RTree, from: usize, to: usize) {
+    let mut left = RTree::new();
+    while let Some(r) = maps.pop_front() {
+        if r.end() <= from || r.vaddr >= to {
+            left.push_back(r);
+            continue;
+        }
+        if r.vaddr < from {
+            let len = from - r.vaddr;
+            left.push_back(Region { len, cap: len, ..r });
+        }
+        if r.end() > to {
+            let len = r.end() - to;
+            left.push_back(Region {
+                vaddr: to,
+                len,
+                cap: len,
+                ..r
+            });
+        }
+    }
+    *maps = left;
+}
+
+// new flags for [from, to), splitting the mappings at the edges
+fn maps_protect(maps: &mut RTree, from: usize, to: usize, flags: u32) {
+    let mut left = RTree::new();
+    while let Some(r) = maps.pop_front() {
+        if r.end() <= from || r.vaddr >= to {
+            left.push_back(r);
+            continue;
+        }
+        let (a, b) = (max(r.vaddr, from), min(r.end(), to));
+        if r.vaddr < a {
+            let len = a - r.vaddr;
+            left.push_back(Region { len, cap: len, ..r });
+        }
+        left.push_back(Region {
+            vaddr: a,
+            len: b - a,
+            cap: b - a,
+            flags,
+            ..r
+        });
+        if r.end() > b {
+            let len = r.end() - b;
+            left.push_back(Region {
+                vaddr: b,
+                len,
+                cap: len,
+                ..r
+            });
+        }
+    }
+    *maps = left;
+}
+
+// physical page behind v, None if nothing is mapped there
+fn user_page(l0_pt: &[u64], v: usize) -> Option<usize> {
+    let mut present = false;
+    let p = v2p_pt(
+        l0_pt,
+        v,
+        Some(|ptr: *mut u64| present = unsafe { *ptr } & 1 != 0),
+    )
+    .ok()?;
+    if present { Some(p) } else { None }
+}
+
+pub fn mincore() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let task = task.mm();
+    let addr = tf.regs[0] as usize;
+    let len = align_f(tf.regs[1] as usize, 4096);
+
+    if addr % 4096 != 0 {
+        return err(EINVAL);
+    }
+
+    let pages = len / 4096;
+    let l0_pt = match PmWrap::new(task.user_pt.unwrap() as usize, vm::PR, false) {
+        Ok(pt) => pt,
+        _ => return !0,
+    };
+
+    for i in 0..pages {
+        let v = addr + i * 4096;
+        if find_region(task, v).is_none() && !task.spel0.has(v) {
+            return err(ENOMEM);
+        }
+    }
+
+    let vec = as_slice_mut(tf.regs[2] as *mut u8, pages);
+    for i in 0..pages {
+        let mut present = false;
+        let _ = v2p_pt(
+            l0_pt.as_slice(),
+            addr + i * 4096,
+            Some(|ptr: *mut u64| present = unsafe { *ptr } & 1 != 0),
+        );
+        vec[i] = present as u8;
+    }
+    0
+}
+
+// on failure the blocks cloned so far are unmapped and released again
+fn clone_region(region: &Region, from_pt: &mut [u64], to_pt: &mut [u64]) -> Result<(), ()> {
+    assert!(region.vaddr % 4096 == 0 && region.len % 4096 == 0);
+    assert!(region.len % region.blksize() == 0);
+    // MAP_SHARED stays writable in both, the rest is read only till cow
+    let flags = pte_perms(region.flags, region.shared());
+
+    let n = region.len / region.blksize();
+    for i in 0..n {
+        let closure = |ent: *mut u64| unsafe {
+            *ent = (*ent & vm::PHY_MASK as u64) | flags | 0x403;
+        };
+        let vm = region.vaddr + (i * region.blksize());
+        let pm = match user_page(from_pt, vm) {
+            Some(pm) => pm,
+            _ => continue,
+        };
+        let pages = region.blksize() / 4096;
+        let mapped = map(to_pt, vm, pm, pages, flags).map_err(|_| ());
+        // a plain ref for shared blocks, the pages stay as they are in
+        // both tasks. otherwise the whole block is one allocation, a
+        // single ref covers every page
+        let mapped = mapped.and_then(|_| match region.shared() {
+            true => pm::share(pm, region.blksize()),
+            _ => pm::dup(pm, region.blksize()),
+        });
+        if mapped.is_err() {
+            let _ = unmap(to_pt, vm, pages);
+            for k in 0..i {
+                let v = region.vaddr + (k * region.blksize());
+                let p = match user_page(to_pt, v) {
+                    Some(p) => p,
+                    _ => continue,
+                };
+                unmap(to_pt, v, pages).unwrap();
+                pm::free(p, region.blksize());
+            }
+            return Err(());
+        }
+        if region.shared() {
+            continue;
+        }
+        // the parent only loses write access once it is shared
+        for j in 0..pages {
+            v2p_pt(from_pt, vm + 4096 * j, Some(closure)).unwrap();
+        }
+    }
+    Ok(())
+}
+
+fn clone_regions(
+    from: &RTree,
+    to: &mut RTree, //
+    from_pt: &mut [u64],
+    to_pt: &mut [u64],
+) -> Result<(), ()> {
+    let mut fit = from.iter();
+    while let Some(region) = fit.next() {
+        clone_region(region, from_pt, to_pt)?;
+        to.push_back(*region);
+    }
+
+    Ok(())
+}
+
+pub fn clone() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let flags64 = tf.regs[0];
+    let mut set_tid = false;
+    let mut clear_tid = false;
+
+    if flags64 & SIGCHLD as u64 != 0 {
+        // todo!("flag SIGGCHLD is not implemented");
+    }
+    // threads share the handlers, which needs the address space shared
+    let share_vm = flags64 & CLONE_VM as u64 != 0;
+    let thread = flags64 & CLONE_THREAD as u64 != 0;
+    let sighand = flags64 & CLONE_SIGHAND as u64 != 0;
+    let share_files = flags64 & CLONE_FILES as u64 != 0;
+    if (thread && !sighand) || (sighand && !share_vm) {
+        return err(EINVAL);
+    }
+    // CLONE_VM without CLONE_THREAD is a process of its own in the
+    // parent's address space, vfork and posix_spawn. execve moves it to
+    // one of its own. CLONE_FS and CLONE_SIGHAND copy for now,
+    // CLONE_SYSVSEM and CLONE_DETACHED have nothing to do
+    let vfork = flags64 & CLONE_VFORK as u64 != 0;
+    if flags64 & CLONE_PIDFD as u64 != 0 {
+        todo!("flag CLONE_PIDFD is not implemented");
+    }
+    if flags64 & CLONE_PTRACE as u64 != 0 {
+        todo!("flag CLONE_PTRACE is not implemented");
+    }
+    if flags64 & CLONE_PARENT as u64 != 0 {
+        todo!("flag CLONE_PARENT is not implemented");
+    }
+    if flags64 & CLONE_NEWNS as u64 != 0 {
+        todo!("flag CLONE_NEWNS is not implemented");
+    }
+    if flags64 & CLONE_CHILD_CLEARTID as u64 != 0 {
+        clear_tid = true;
+        // todo!("flag CLONE_CHILD_CLEARTID is not implemented");
     }
     if flags64 & CLONE_UNTRACED as u64 != 0 {
         todo!("flag CLONE_UNTRACED is not implemented");
@@ -877,72 +1812,146 @@ pub fn fork() -> u64 {
     if let Some(new_task) = alloc_task() {
         assert!(new_task.lock.holding());
 
-        new_task.cwd = Some(task.cwd.as_ref().unwrap().clone());
-
-        let from = PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false).unwrap();
-        let to = PmWrap::new(new_task.user_pt.unwrap() as usize, vm::PR_PW, false).unwrap();
-
-        clone_regions(
-            &task.program,
-            &mut new_task.program, //
-            from.as_slice_mut(),
-            to.as_slice_mut(),
-        )
-        .unwrap();
-
-        clone_region(&task.brk, from.as_slice_mut(), to.as_slice_mut());
-        clone_region(&task.mmap, from.as_slice_mut(), to.as_slice_mut());
-
-        new_task.brk = task.brk;
-        new_task.mmap = task.mmap;
-
-        copy_pm(
-            task.user_sp.unwrap() as usize,
-            new_task.user_sp.unwrap() as usize,
-            2,
-        )
-        .unwrap();
-
-        tlbi_aside1(task.pid as u64);
-        dsb!();
-        isb!();
-
-        for i in 0..task.files.len() {
-            if let Some(f) = &mut task.files[i] {
-                new_task.files[i] = f.dup();
+        new_task.cwd = Some(task.cwd.clone().unwrap_or("/".into()));
+        new_task.sigmask = task.sigmask;
+        new_task.affinity = task.affinity;
+        new_task.uid = task.uid;
+        new_task.euid = task.euid;
+        new_task.gid = task.gid;
+        new_task.egid = task.egid;
+        new_task.sigrestart = task.sigrestart;
+        new_task.sigactions = task.sigactions;
+        new_task.rlimits = task.rlimits;
+
+        if share_vm {
+            // drop the fresh address space alloc_task made
+            release_mm(new_task);
+            new_task.mm_users.store(0, Ordering::Release);
+            let owner = task.mm();
+            owner.mm_users.fetch_add(1, Ordering::AcqRel);
+            new_task.mm = owner.pid as usize;
+            new_task.user_pt = owner.user_pt;
+            new_task.user_sp = owner.user_sp;
+        } else {
+            // the parent's other threads stay out while its ptes go cow
+            let mm_lock = task.mm().mm_lock.acquire();
+            let res = clone_mm(task.mm(), new_task);
+            // parent ptes may be read-only now, on failure too. cow faults
+            // undo that
+            flush_mm(task);
+            drop(mm_lock);
+            if res.is_err() {
+                put_mm(new_task);
+                new_task.state = State::Free;
+                new_task.lock.release();
+                return err(ENOMEM);
             }
         }
 
+        if share_files {
+            let owner = task.fdt();
+            owner.fdt_users.fetch_add(1, Ordering::AcqRel);
+            new_task.fdt_users.store(0, Ordering::Release);
+            new_task.fdt = owner.pid as usize;
+        } else {
+            dup_files(task.fdt(), new_task);
+        }
+        if thread {
+            new_task.tgid = task.tgid;
+        }
+
         let nt = new_task.get_trap_frame().unwrap();
         *nt = *tf;
 
         nt.regs[0] = 0;
+        if tf.regs[1] != 0 {
+            nt.sp_el0 = tf.regs[1];
+        }
 
         new_task.ctx[14] = r_tpidr_el0();
         task.ctx[14] = r_tpidr_el0();
-
-        new_task.state = State::Ready;
+        if flags64 & CLONE_SETTLS as u64 != 0 {
+            new_task.ctx[14] = tf.regs[3];
+        }
 
         let wlock = WAIT.acquire();
         new_task.parent = Some(task as *mut Task);
+        // no zombie and no SIGCHLD for a thread
+        new_task.autoreap = thread;
+        new_task.vfork = vfork;
         drop(wlock);
 
+        new_task.state = State::Ready;
+
         let pid = new_task.pid as u64;
+        if flags64 & CLONE_PARENT_SETTID as u64 != 0 {
+            if let Some(ptid) = ptr2mut_op!(tf.regs[2], u32) {
+                *ptid = pid as u32;
+            }
+        }
         if set_tid {
             unsafe { *child_tidptr = new_task.pid as u32 }
         }
 
         if clear_tid {
-            new_task.tid = Some(tf.regs[4]);
+            new_task.clear_child_tid = Some(tf.regs[4]);
         }
         new_task.lock.release();
+
+        // the child runs on our memory and stack until it execs or exits
+        if vfork {
+            let wait_lock = WAIT.acquire();
+            while unsafe { core::ptr::read_volatile(&new_task.vfork) } {
+                sleep(&*new_task as *const Task as u64, wait_lock.get_lock());
+            }
+        }
         pid
     } else {
-        !0
-    }
+        err(ENOMEM)
+    }
+}
+
+// brk and mmap are only set once fully cloned, release_mm relies on it
+fn clone_mm(task: &mut Task, new_task: &mut Task) -> Result<(), ()> {
+    let from = PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false).map_err(|_| ())?;
+    let to = PmWrap::new(new_task.user_pt.unwrap() as usize, vm::PR_PW, false).map_err(|_| ())?;
+
+    clone_regions(
+        &task.program,
+        &mut new_task.program, //
+        from.as_slice_mut(),
+        to.as_slice_mut(),
+    )?;
+
+    clone_region(&task.brk, from.as_slice_mut(), to.as_slice_mut())?;
+    new_task.brk = task.brk;
+    // per mapping, MAP_SHARED ones keep their pages writable in both
+    clone_regions(
+        &task.maps,
+        &mut new_task.maps, //
+        from.as_slice_mut(),
+        to.as_slice_mut(),
+    )?;
+    new_task.mmap = task.mmap;
+
+    copy_pm(
+        task.user_sp.unwrap() as usize,
+        new_task.user_sp.unwrap() as usize,
+        2,
+    )
 }
 
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGBUS: u32 = 7;
+pub const SIGKILL: u32 = 9;
+pub const SIGSEGV: u32 = 11;
+pub const SIGPIPE: u32 = 13;
 pub const SIGCHLD: u32 = 17;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const NSIG: usize = 64;
 pub const CLONE_VM: u32 = 256;
 pub const CLONE_FS: u32 = 512;
 pub const CLONE_FILES: u32 = 1024;
@@ -979,8 +1988,13 @@ fn free_region(region: &Region, l0_pt: &mut [u64], skip: bool) {
     assert!(region.len % region.blksize() == 0);
     for i in 0..n {
         let v = region.vaddr + i * region.blksize();
-        let p = v2p_pt::<fn(*mut u64)>(l0_pt, v, None).unwrap();
+        // munmap leaves holes
+        let p = match user_page(l0_pt, v) {
+            Some(p) => p,
+            _ => continue,
+        };
         if !skip {
+            // only this task's ref, shared text outlives it in the others
             pm::free(p, region.blksize());
         }
         unmap(l0_pt, v, region.blksize() / 4096).unwrap();
@@ -995,18 +2009,8 @@ fn free_regions(regions: &mut RTree, l0_pt: &mut [u64], skip: bool) -> Result<()
     Ok(())
 }
 
-fn free_task(pid: usize) -> Result<(), vm::Error> {
-    let task: &mut Task = &mut TASKS.as_mut()[pid];
-
-    for i in 0..task.files.len() {
-        if let Some(f) = &mut task.files[i] {
-            print!("FREE FILE: {} dis: {}\n", i, mycpu().int_disables);
-            let c = f.close();
-            print!("FREE FILE: {} DONE dis: {}\n", i, mycpu().int_disables);
-            if c.is_err() {}
-        }
-    }
-
+// user address space and page tables, the kernel stack stays
+fn release_mm(task: &mut Task) {
     let l0_pt = PmWrap::new(
         task.user_pt.unwrap() as usize, //
         vm::PR_PW,
@@ -1025,20 +2029,102 @@ fn free_task(pid: usize) -> Result<(), vm::Error> {
     task.program.clear();
     task.brk.len = 0;
     task.mmap.len = 0;
+    task.maps.clear();
 
+    put_asid(task);
     free_pt(task.user_pt.unwrap() as u64);
+}
+
+// threads share the address space and with it the asid
+fn flush_mm(task: &Task) {
+    vm::tlb_shootdown(Some(task.mm().asid as u64), None);
+}
+
+fn dup_files(from: &mut Task, to: &mut Task) {
+    for i in 0..from.files.len() {
+        if let Some(f) = &mut from.files[i] {
+            to.files[i] = f.dup();
+        }
+    }
+    to.cloexec = from.cloexec;
+}
+
+// the last task out of an fd table closes it, the slot's next task
+// must not find them
+fn put_files(task: &mut Task) {
+    let owner = task.fdt();
+    if owner.fdt_users.fetch_sub(1, Ordering::AcqRel) != 1 {
+        return;
+    }
+    for i in 0..owner.files.len() {
+        if let Some(f) = owner.files[i].take() {
+            log!(Trace, "FREE FILE: {} dis: {}\n", i, mycpu().int_disables);
+            let c = f.close();
+            log!(
+                Trace,
+                "FREE FILE: {} DONE dis: {}\n",
+                i,
+                mycpu().int_disables
+            );
+            if c.is_err() {}
+        }
+    }
+    owner.cloexec = 0;
+}
+
+// the last task out of an address space frees it
+fn put_mm(task: &mut Task) {
+    let owner = task.mm();
+    if owner.mm_users.fetch_sub(1, Ordering::AcqRel) == 1 {
+        release_mm(owner);
+    }
+}
+
+fn free_task(pid: usize) -> Result<(), vm::Error> {
+    let task: &mut Task = &mut TASKS.as_mut()[pid];
+
+    put_files(task);
+
+    // the current task, its address space is still the live one
+    if let Some(ctid) = task.clear_child_tid.take() {
+        let v = ctid as usize;
+        if v % 4 == 0 && user_writable(task, v) {
+            unsafe { (v as *mut u32).write_volatile(0) };
+            // libcs wait on it private or shared
+            if futex::wake_addr(task, v, 1, true) == 0 {
+                futex::wake_addr(task, v, 1, false);
+            }
+        }
+    }
+
+    uaccess_done(task);
+    put_mm(task);
+    tty::clear_fg(task.pid as usize);
 
     let wait_lock = WAIT.acquire();
 
-    //TODO reparent
+    vfork_done(task);
+    if task.pid != 0 {
+        reparent(task);
+    }
 
-    if let Some(p) = task.parent {
+    if task.autoreap {
+        log!(Debug, "THREAD REAPED pid: {}\n", task.pid);
+    } else if let Some(p) = task.parent {
+        send_signal(unsafe { p.as_mut() }.unwrap(), SIGCHLD);
         wakeup(p as u64);
-        print!("TASK FREED pid: {}\n", task.pid);
+        log!(Debug, "TASK FREED pid: {}\n", task.pid);
     }
 
     let lock = task.lock.acquire();
-    task.state = State::Zombie;
+    if task.autoreap {
+        task.autoreap = false;
+        task.parent = None;
+        task.clear_child_tid = None;
+        task.state = State::Free;
+    } else {
+        task.state = State::Zombie;
+    }
     forget(lock);
 
     drop(wait_lock);
@@ -1048,9 +2134,42 @@ fn free_task(pid: usize) -> Result<(), vm::Error> {
 
 static WAIT: Lock<()> = Lock::new("wait", ());
 
+// a vfork parent gets its memory back. WAIT must be held
+fn vfork_done(task: &mut Task) {
+    assert!(WAIT.holding());
+    if task.vfork {
+        task.vfork = false;
+        wakeup(task as *const Task as u64);
+    }
+}
+
+// children go to init (pid 0) and are its to reap like any other. init
+// is busybox sh, it waits on -1 with WNOHANG before every prompt. WAIT
+// must be held
+fn reparent(task: &mut Task) {
+    assert!(WAIT.holding());
+    let me = task as *mut Task;
+    let tasks = TASKS.as_mut();
+    let init = &mut tasks[0] as *mut Task;
+    let mut zombies = false;
+    for i in 0..tasks.len() {
+        let c = &mut tasks[i];
+        let lock = c.lock.acquire();
+        if c.parent == Some(me) {
+            c.parent = Some(init);
+            zombies |= matches!(c.state, State::Zombie);
+        }
+        drop(lock);
+    }
+    if zombies {
+        send_signal(unsafe { init.as_mut() }.unwrap(), SIGCHLD);
+        wakeup(init as u64);
+    }
+}
+
 pub fn exit() -> u64 {
     let task = mycpu().get_task().unwrap();
-    task.exit_code = task.get_trap_frame().unwrap().regs[0];
+    task.exit_code = (task.get_trap_frame().unwrap().regs[0] & 0xff) << 8;
 
     if task.pid == 0 {
         panic!(
@@ -1061,61 +2180,137 @@ pub fn exit() -> u64 {
 
     free_task(task.pid as usize).unwrap();
 
-    print!("EXIT pid: {} status {}\n", task.pid, task.exit_code);
+    log!(Info, "EXIT pid: {} status {}\n", task.pid, task.exit_code);
     sched();
     0
 }
 
+// the others take code as their exit status instead of the SIGKILL
+// that brings them down, it is what the leader's parent gets to see
+fn kill_group(task: &mut Task, code: u64) {
+    let tasks = TASKS.as_mut();
+    for i in 0..tasks.len() {
+        let t = &mut tasks[i];
+        if i == task.pid as usize {
+            continue;
+        }
+        let lock = t.lock.acquire();
+        let live = matches!(t.state, State::Ready | State::Running | State::Sleeping);
+        let kill = live && t.tgid == task.tgid && !t.group_exit;
+        if kill {
+            t.group_exit = true;
+            t.exit_code = code;
+        }
+        drop(lock);
+        if kill {
+            send_signal(t, SIGKILL);
+        }
+    }
+}
+
+fn group_alive(task: &Task) -> bool {
+    let tasks = TASKS.as_mut();
+    (0..tasks.len()).any(|i| {
+        let t = &tasks[i];
+        let live = matches!(t.state, State::Ready | State::Running | State::Sleeping);
+        i != task.pid as usize && live && t.tgid == task.tgid
+    })
+}
+
 pub fn exit_group() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    kill_group(task, (task.get_trap_frame().unwrap().regs[0] & 0xff) << 8);
     exit()
 }
 
 pub fn getuid() -> u64 {
-    0
+    mycpu().get_task().unwrap().uid as u64
 }
 
 pub fn geteuid() -> u64 {
-    0
+    mycpu().get_task().unwrap().euid as u64
 }
 
+// no privilege checks yet, everyone may become anyone
 pub fn setuid() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let uid = tf.regs[0] as u32;
+    if uid == u32::MAX {
+        return err(EINVAL);
+    }
+    task.uid = uid;
+    task.euid = uid;
     0
 }
 
 pub fn getgid() -> u64 {
-    0
+    mycpu().get_task().unwrap().gid as u64
+}
+
+pub fn getegid() -> u64 {
+    mycpu().get_task().unwrap().egid as u64
 }
 
 pub fn setgid() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    let gid = tf.regs[0] as u32;
+    if gid == u32::MAX {
+        return err(EINVAL);
+    }
+    task.gid = gid;
+    task.egid = gid;
     0
 }
 
 pub fn gettid() -> u64 {
-    //TODO
-    getpid()
+    mycpu().get_task().unwrap().pid as u64
 }
 
+// struct new_utsname, every field nul padded
 #[repr(C)]
-pub struct OldUtsname {
-    pub name: [u8; 45],
+pub struct Utsname {
+    pub sysname: [u8; 65],
+    pub nodename: [u8; 65],
+    pub release: [u8; 65],
+    pub version: [u8; 65],
+    pub machine: [u8; 65],
+    pub domainname: [u8; 65],
+}
+
+fn uts_field(field: &mut [u8; 65], s: &str) {
+    field.fill(0);
+    // the last byte stays nul
+    let n = min(s.len(), field.len() - 1);
+    field[..n].copy_from_slice(&s.as_bytes()[..n]);
 }
 
 pub fn uname() -> u64 {
     let t = mycpu().get_task().unwrap();
     let tf = t.get_trap_frame().unwrap();
-    let ptr = unsafe { (tf.regs[0] as *mut OldUtsname).as_mut() }.unwrap();
+    let uts = match unsafe { (tf.regs[0] as *mut Utsname).as_mut() } {
+        Some(u) => u,
+        _ => return err(EFAULT),
+    };
 
-    (&mut ptr.name[0..2]).copy_from_slice("?\0".as_bytes());
-    (&mut ptr.name[7..7 + 6]).copy_from_slice("local\0".as_bytes());
-    (&mut ptr.name[7 + 6..7 + 6 + 6]).copy_from_slice("0.0.1\0".as_bytes());
-    (&mut ptr.name[7 + 6 + 6..7 + 6 + 6 + 2]).copy_from_slice("0\0".as_bytes());
-    (&mut ptr.name[7 + 6 + 6 + 2..7 + 6 + 6 + 2 + 8]).copy_from_slice("aarch64\0".as_bytes());
+    uts_field(&mut uts.sysname, "Linux");
+    uts_field(&mut uts.nodename, "local");
+    uts_field(&mut uts.release, "6.1.0-xyz");
+    uts_field(&mut uts.version, "#1 SMP");
+    uts_field(&mut uts.machine, "aarch64");
+    uts_field(&mut uts.domainname, "(none)");
     0
 }
 
-pub fn wait() -> u64 {
+pub struct W;
+impl W {
+    pub const NOHANG: u64 = 1;
+}
+
+// pid -1 is any child, no process groups so 0 and < -1 are too
+fn wait_pid(pid: i64, status: *mut u32, options: u64) -> u64 {
     let t = mycpu().get_task().unwrap();
-    let tf = t.get_trap_frame().unwrap();
     let ptr = t as *mut Task;
     let wait_lock = WAIT.acquire();
     let tasks = TASKS.as_mut();
@@ -1123,17 +2318,21 @@ pub fn wait() -> u64 {
         let mut has_child = false;
         for i in 0..tasks.len() {
             let task: &mut Task = &mut tasks[i];
+            if pid > 0 && task.pid as i64 != pid {
+                continue;
+            }
             let l = task.lock.acquire();
             if let Some(parent) = task.parent {
-                if parent == ptr {
+                // threads aren't waited for
+                if parent == ptr && !task.autoreap {
                     has_child = true;
                     if let State::Zombie = task.state {
-                        if let Some(tid) = task.tid {
-                            unsafe { *(tid as *mut u32) = 0 }
+                        if !status.is_null() {
+                            unsafe { *status = task.exit_code as u32 }
                         }
                         task.state = State::Free;
                         task.parent = None;
-                        task.tid = None;
+                        task.clear_child_tid = None;
                         return task.pid as u64;
                     }
                 }
@@ -1141,108 +2340,389 @@ pub fn wait() -> u64 {
             let _ = l;
         }
 
-        if !has_child {
-            return !0;
+        if !has_child {
+            return err(ECHILD);
+        }
+
+        if options & W::NOHANG != 0 {
+            return 0;
+        }
+
+        if sleep_intr(ptr as u64, wait_lock.get_lock()).is_err() {
+            return err(EINTR);
+        }
+    }
+}
+
+pub fn wait4() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let pid = tf.regs[0] as i32 as i64;
+    let status = tf.regs[1] as *mut u32;
+    let options = tf.regs[2];
+    let rusage = tf.regs[3] as *mut u8;
+
+    if options & !W::NOHANG != 0 {
+        return err(EINVAL);
+    }
+
+    let ret = wait_pid(pid, status, options);
+    // no accounting, struct rusage is all zeros
+    if (ret as i64) > 0 && !rusage.is_null() {
+        as_slice_mut(rusage, 144).fill(0);
+    }
+    ret
+}
+
+fn copy_pm(from_pm: usize, to_pm: usize, n: usize) -> Result<(), ()> {
+    for i in 0..n {
+        let to = PmWrap::new(to_pm + (4096 * i), vm::PR_PW, true).map_err(|_| ())?;
+        let from = PmWrap::new(from_pm + (4096 * i), vm::PR, false).map_err(|_| ())?;
+        to.as_slice_mut::<u8>().copy_from_slice(from.as_slice());
+    }
+    Ok(())
+}
+
+pub fn find_region(task: &mut Task, v: usize) -> Option<Region> {
+    let task = task.mm();
+    if task.brk.has(v) {
+        return Some(task.brk);
+    }
+    if task.mmap.has(v) {
+        // the other threads of the mm map and unmap under it
+        let mm_lock = (!task.mm_lock.holding()).then(|| task.mm().mm_lock.acquire());
+        return task.maps.iter().find(|r| r.has(v)).copied();
+    }
+    if let Some(r) = task.program.iter().find(|r| r.has(v)) {
+        return Some(*r);
+    }
+    None
+}
+
+pub const SEGV_MAPERR: u32 = 1;
+pub const SEGV_ACCERR: u32 = 2;
+
+pub fn dabt_handler() {
+    let task = mycpu().get_task().unwrap();
+    let vaddr = r_far_el1() as usize;
+    let esr = r_esr_el1();
+    let wnr = esr & (1 << 6) != 0;
+    // dfsc 0b0011xx is a permission fault, 0b0001xx translation
+    let perm = esr & 0x3c == 0x0c;
+    // from el0, not the kernel touching a user pointer
+    let user = esr >> 26 == 0x24;
+
+    // the other threads of the mm fault and mmap on other cores
+    let need = if wnr { elf::PF_W } else { elf::PF_R };
+    let mm_lock = task.mm().mm_lock.acquire();
+    let res = fault_in(task, vaddr, perm, need);
+    drop(mm_lock);
+    match res {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(_) => {
+            log!(Warn, "zero fill: out of memory pid {}\n", task.pid);
+            if user {
+                kill_task(task, SIGKILL);
+                return;
+            }
+        }
+    }
+
+    if user && in_stack_guard(task, vaddr) {
+        log!(Warn, "stack overflow: pid {} addr {:x}\n", task.pid, vaddr);
+        segv(task, vaddr, SEGV_MAPERR, user);
+        return;
+    }
+
+    let region = find_region(task, vaddr);
+
+    // a syscall or signal delivery with a bad user pointer, it may hold
+    // any lock so it has to run to the end
+    if !user && vaddr >> 48 == 0 && uaccess_fault(task, vaddr).is_ok() {
+        return;
+    }
+
+    let code = match region {
+        Some(r) if !prot_allows(r.flags, need) => SEGV_ACCERR,
+        Some(_) if perm => SEGV_ACCERR,
+        _ => SEGV_MAPERR,
+    };
+    segv(task, vaddr, code, user);
+}
+
+// zero fill a lazy page or break cow. Ok(false) when it's neither or
+// the protection doesn't allow need, Err when there was no page to fill
+// with. the mm lock is held
+fn fault_in(task: &mut Task, vaddr: usize, perm: bool, need: u32) -> Result<bool, ()> {
+    let mm = task.mm();
+    assert!(mm.mm_lock.holding());
+    let lazy = if mm.brk.has(vaddr) {
+        Some(mm.brk.flags)
+    } else {
+        mm.maps
+            .iter()
+            .find(|r| r.has(vaddr) && matches!(r.ty, RegionType::Mmap))
+            .map(|r| r.flags)
+    };
+    if let Some(flags) = lazy
+        && !perm
+    {
+        if !prot_allows(flags, need) {
+            return Ok(false);
+        }
+        return zero_fill(task, align_b(vaddr, 4096), pte_perms(flags, true)).map(|_| true);
+    }
+
+    let region = find_region(task, vaddr);
+    if let Some(region) = region {
+        if need == elf::PF_W && region.flags & elf::PF_W > 0 {
+            let block = align_b(vaddr, region.blksize());
+
+            let l0_pt = PmWrap::new(
+                mm.user_pt.unwrap() as usize, //
+                vm::PR_PW,
+                false,
+            )
+            .unwrap();
+
+            let mut good = false;
+            let _ = v2p_pt(
+                l0_pt.as_slice_mut(),
+                block,
+                Some(|ptr: *mut u64| {
+                    if unsafe { *ptr } & 1 == 0 {
+                        return;
+                    }
+                    let pm_ = unsafe { *ptr as usize & vm::PHY_MASK };
+                    let n = region.blksize() / 4096;
+
+                    let new_pm = match pm::cow_action(pm_, region.blksize()) {
+                        Some(pm::CowAction::Remap) => pm_,
+                        Some(pm::CowAction::Copy(new_pm)) => {
+                            copy_pm(pm_, new_pm, n).unwrap();
+                            new_pm
+                        }
+                        None => return,
+                    };
+
+                    // every page of the block, not just the faulting one
+                    map_ovwr(
+                        l0_pt.as_slice_mut(), //
+                        block,
+                        new_pm,
+                        n,
+                        pte_perms(region.flags, true),
+                    )
+                    .unwrap();
+
+                    if new_pm != pm_ {
+                        pm::free(pm_, region.blksize());
+                    }
+                    good = true;
+                }),
+            );
+
+            return Ok(good);
         }
-
-        sleep(ptr as u64, wait_lock.get_lock());
     }
+    Ok(false)
 }
 
-pub fn wait4() -> u64 {
-    wait()
+// map a zeroed scratch page over vaddr so the kernel's access completes,
+// the syscall then fails with EFAULT once everything is released
+fn uaccess_fault(task: &mut Task, vaddr: usize) -> Result<(), ()> {
+    let page = align_b(vaddr, 4096);
+    let l0_pt =
+        PmWrap::new(task.mm().user_pt.unwrap() as usize, vm::PR_PW, false).map_err(|_| ())?;
+    let mut old = 0;
+    let _ = v2p_pt(
+        l0_pt.as_slice(),
+        page,
+        Some(|ptr: *mut u64| old = unsafe { *ptr }),
+    );
+
+    let p = pm::alloc(4096)?;
+    map_ovwr(l0_pt.as_slice_mut(), page, p, 1, vm::PR_PW_UR_UW1).map_err(|_| pm::free(p, 4096))?;
+    vm::tlb_shootdown(None, Some(page));
+    as_slice_mut(page as *mut u8, 4096).fill(0);
+
+    log!(Debug, "uaccess fault: pid {} addr {:x}\n", task.pid, vaddr);
+    task.ufault.push((page, old));
+    Ok(())
 }
 
-fn copy_pm(from_pm: usize, to_pm: usize, n: usize) -> Result<(), ()> {
-    for i in 0..n {
-        let to = PmWrap::new(to_pm + (4096 * i), vm::PR_PW, true).map_err(|_| ())?;
-        let from = PmWrap::new(from_pm + (4096 * i), vm::PR, false).map_err(|_| ())?;
-        to.as_slice_mut::<u8>().copy_from_slice(from.as_slice());
+// put back what uaccess_fault replaced, true if there was anything
+pub fn uaccess_done(task: &mut Task) -> bool {
+    if task.ufault.is_empty() {
+        return false;
     }
-    Ok(())
+    let l0_pt = PmWrap::new(task.mm().user_pt.unwrap() as usize, vm::PR_PW, false).unwrap();
+    while let Some((page, old)) = task.ufault.pop() {
+        let mut scratch = 0;
+        let _ = v2p_pt(
+            l0_pt.as_slice(),
+            page,
+            Some(|ptr: *mut u64| unsafe {
+                scratch = *ptr as usize & vm::PHY_MASK;
+                *ptr = old;
+            }),
+        );
+        vm::tlb_shootdown(None, Some(page));
+        pm::free(scratch, 4096);
+    }
+    true
 }
 
-pub fn find_region(task: &mut Task, v: usize) -> Option<Region> {
-    if task.brk.has(v) {
-        return Some(task.brk);
+// first touch of a brk or anonymous mmap page, the mm lock is held
+fn zero_fill(task: &mut Task, v: usize, perms: u64) -> Result<(), ()> {
+    let task = task.mm();
+    let l0_pt = PmWrap::new(task.user_pt.unwrap() as usize, vm::PR_PW, false).map_err(|_| ())?;
+    if user_page(l0_pt.as_slice(), v).is_some() {
+        // already backed, the access just retries
+        return Ok(());
     }
-    if task.mmap.has(v) {
-        return Some(task.brk);
+    let p = pm::alloc(4096)?;
+    // zeroed through the kernel's window, perms may not let it write v
+    if PmWrap::new(p, vm::PR_PW, true).is_err() {
+        pm::free(p, 4096);
+        return Err(());
     }
-    if let Some(r) = task.program.iter().find(|r| r.has(v)) {
-        return Some(*r);
+    match map(l0_pt.as_slice_mut(), v, p, 1, perms) {
+        Ok(_) => Ok(()),
+        // mapped already, the spare page isn't needed
+        Err(vm::Error::Exists(_)) => {
+            pm::free(p, 4096);
+            Ok(())
+        }
+        Err(_) => {
+            pm::free(p, 4096);
+            Err(())
+        }
     }
-    None
 }
 
-pub fn dabt_handler() {
+pub fn iabt_handler() {
     let task = mycpu().get_task().unwrap();
     let vaddr = r_far_el1() as usize;
+    let esr = r_esr_el1();
+    let user = esr >> 26 == 0x20;
+    if !user {
+        panic!("kernel iabt: pid {} pc {:x}\n", task.pid, vaddr);
+    }
+    log!(Debug, "iabt: pid {} pc {:x}\n", task.pid, vaddr);
+
+    // first fetch from an untouched PROT_EXEC mapping
+    let perm = esr & 0x3c == 0x0c;
+    let mm_lock = task.mm().mm_lock.acquire();
+    let res = fault_in(task, vaddr, perm, elf::PF_X);
+    drop(mm_lock);
+    match res {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(_) => {
+            log!(Warn, "zero fill: out of memory pid {}\n", task.pid);
+            kill_task(task, SIGKILL);
+            return;
+        }
+    }
 
-    if let Some(region) = find_region(task, vaddr) {
-        if region.flags & elf::PF_W > 0 {
-            let block = align_b(vaddr, region.blksize());
-
+    let region = find_region(task, vaddr);
+    if let Some(region) = region {
+        if region.flags & elf::PF_X > 0 {
             let l0_pt = PmWrap::new(
-                task.user_pt.unwrap() as usize, //
+                task.mm().user_pt.unwrap() as usize, //
                 vm::PR_PW,
                 false,
             )
             .unwrap();
 
+            // text is mapped up front and never cow, so a fetch from a
+            // valid user executable entry only saw a stale tlb entry
             let mut good = false;
             let _ = v2p_pt(
                 l0_pt.as_slice_mut(),
-                block,
+                align_b(vaddr, 4096),
                 Some(|ptr: *mut u64| {
-                    let pm_ = unsafe { *ptr as usize & vm::PHY_MASK };
-
-                    pm::cow_action(pm_, region.blksize(), |a, al| {
-                        let n = region.blksize() / 4096;
-                        match a {
-                            pm::CowAction::Remap => {
-                                map_ovwr(
-                                    l0_pt.as_slice_mut(), //
-                                    block,
-                                    pm_,
-                                    n,
-                                    vm::PR_PW_UR_UW1,
-                                )
-                                .unwrap();
-                            }
-                            pm::CowAction::Alloc => {
-                                let new_pm = al.alloc(region.blksize()).unwrap();
-                                copy_pm(pm_, new_pm, n).unwrap();
-                                map_ovwr(
-                                    l0_pt.as_slice_mut(), //
-                                    block,
-                                    new_pm,
-                                    n,
-                                    vm::PR_PW_UR_UW1,
-                                )
-                                .unwrap();
-                            }
-                        }
-                        good = true;
-                    });
+                    let pte = unsafe { *ptr };
+                    // valid and UXN clear
+                    good = pte & 1 != 0 && pte & (1 << 54) == 0;
                 }),
-            )
-            .unwrap();
+            );
 
             if good {
+                vm::tlb_shootdown(None, Some(align_b(vaddr, 4096)));
                 return;
             }
         }
     }
 
-    //TODO segfaultonomy
+    let code = match region {
+        Some(_) => SEGV_ACCERR,
+        _ => SEGV_MAPERR,
+    };
+    segv(task, vaddr, code, user);
+}
+
+// undefined instructions and the like, fatal for the task only
+pub fn fatal_trap(sig: u32) {
+    let task = mycpu().get_task().unwrap();
+    if task.pid == 0 {
+        let tf = task.get_trap_frame().unwrap();
+        panic!("FATALITY: sig {} pid {} pc {:x}\n", sig, task.pid, tf.pc);
+    }
+    kill_task(task, sig);
+}
+
+fn segv(task: &mut Task, vaddr: usize, code: u32, user: bool) {
     let tf = task.get_trap_frame().unwrap();
-    let tls = r_tpidr_el0();
-    let x = r_tpidrro_el0();
-    panic!(
-        "FATALITY: dabt.. address {:x} pid {} pc {:x} tls {:x} {}\n",
-        vaddr, task.pid, tf.pc, tls, x
+    if !user {
+        panic!(
+            "kernel dabt: pid {} addr {:x} code {}\n",
+            task.pid, vaddr, code
+        );
+    }
+    log!(
+        Warn,
+        "SIGSEGV: pid {} addr {:x} pc {:x} code {}\n",
+        task.pid,
+        vaddr,
+        tf.pc,
+        code
     );
+
+    if task.pid == 0 {
+        let tls = r_tpidr_el0();
+        let x = r_tpidrro_el0();
+        panic!(
+            "FATALITY: dabt.. address {:x} pid {} pc {:x} tls {:x} {}\n",
+            vaddr, task.pid, tf.pc, tls, x
+        );
+    }
+
+    task.fault = Some((vaddr, code));
+
+    // a blocked or defaulted SIGSEGV can't do anything but terminate
+    let act = task.sigactions[SIGSEGV as usize - 1];
+    let blocked = task.sigmask & (1 << (SIGSEGV - 1)) != 0;
+    if act.handler > SIG_IGN && !blocked {
+        send_signal(task, SIGSEGV);
+        return;
+    }
+    kill_task(task, SIGSEGV);
+}
+
+// default action of a fatal signal, the parent sees WIFSIGNALED. the
+// rest of the thread group goes down with it
+fn kill_task(task: &mut Task, sig: u32) {
+    if !task.group_exit {
+        task.exit_code = sig as u64 & 0x7f;
+        kill_group(task, task.exit_code);
+    }
+    log!(Info, "KILLED pid: {} sig {}\n", task.pid, sig);
+    free_task(task.pid as usize).unwrap();
+    sched();
 }
 
 pub fn sleep<T>(chan: u64, lock: &Lock<T>) {
@@ -1253,22 +2733,228 @@ pub fn sleep<T>(chan: u64, lock: &Lock<T>) {
     task.chan = Some(chan);
     sched();
     task.chan = None;
-    let old = lock.acquire();
-    let _ = task_lock;
-    forget(old);
+    // wakeup holds lock and takes task locks, never the other way round
+    drop(task_lock);
+    forget(lock.acquire());
+}
+
+pub fn sleep_intr<T>(chan: u64, lock: &Lock<T>) -> Result<(), ()> {
+    let task = mycpu().get_task().unwrap();
+    let task_lock = task.lock.acquire();
+    if task.signal_pending() {
+        task.eintr = true;
+        return Err(());
+    }
+    lock.release();
+    task.state = State::Sleeping;
+    task.chan = Some(chan);
+    task.intr = true;
+    sched();
+    task.intr = false;
+    task.chan = None;
+    // wakeup holds lock and takes task locks, never the other way round
+    drop(task_lock);
+    forget(lock.acquire());
+    interrupted(task)
 }
 
-pub fn sleep_if<F: FnMut() -> bool>(cond: &mut F) {
+// interruptible, Err when woken by a signal
+pub fn sleep_if<F: FnMut() -> bool>(cond: &mut F) -> Result<(), ()> {
     let task = mycpu().get_task().unwrap();
     let task_lock = task.lock.acquire();
+    if task.signal_pending() {
+        task.eintr = true;
+        return Err(());
+    }
     if !cond() {
-        return;
+        return Ok(());
     }
     task.state = State::Sleeping;
     task.chan = None;
+    task.intr = true;
     sched();
+    task.intr = false;
     task.chan = None;
     drop(task_lock);
+    interrupted(task)
+}
+
+fn interrupted(task: &mut Task) -> Result<(), ()> {
+    if task.signal_pending() {
+        task.eintr = true;
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+// mark sig pending and kick the task out of an interruptible sleep
+pub fn send_signal(task: &mut Task, sig: u32) {
+    if sig == 0 || sig > 64 {
+        return;
+    }
+    let lock = task.lock.acquire();
+    // dropped like linux does, a SIGCHLD nobody wants must not cut a
+    // sleep short. blocked ones stay since the action may change
+    let act = task.sigactions[sig as usize - 1];
+    let blocked = task.sigmask & !UNBLOCKABLE & (1 << (sig - 1)) != 0;
+    let ignored = match act.handler {
+        SIG_IGN => sig != SIGKILL,
+        SIG_DFL => sig_dfl_ignored(sig),
+        _ => false,
+    };
+    if ignored && !blocked {
+        return;
+    }
+    task.sigpending |= 1 << (sig - 1);
+    if let State::Sleeping = task.state {
+        if task.intr && task.signal_pending() {
+            task.state = State::Ready;
+        }
+    }
+    drop(lock);
+}
+
+// what the handler finds on its stack, rt_sigreturn undoes it
+#[repr(C)]
+struct SigFrame {
+    info: SigInfo,
+    tf: trap::Frame,
+    mask: u64,
+}
+
+#[repr(C)]
+struct SigInfo {
+    signo: i32,
+    errno: i32,
+    code: i32,
+    _pad: i32,
+    addr: u64,
+    _rest: [u64; 13],
+}
+
+// can't be caught, blocked or ignored
+const UNBLOCKABLE: u64 = 1 << (SIGKILL - 1) | 1 << (SIGSTOP - 1);
+
+// default action is to do nothing. no job control, so stops are too
+fn sig_dfl_ignored(sig: u32) -> bool {
+    match sig {
+        17 | 18 | 23 | 28 => true, // CHLD CONT URG WINCH
+        19..=22 => true,           // STOP TSTP TTIN TTOU
+        _ => false,
+    }
+}
+
+// on the way back to el0. at most one handler frame per return, the
+// next signal goes out when the handler returns through rt_sigreturn
+pub fn do_signals() {
+    let task = match mycpu().get_task() {
+        Some(t) => t,
+        _ => return,
+    };
+
+    loop {
+        let lock = task.lock.acquire();
+        let sig = task.next_signal();
+        if sig == 0 {
+            drop(lock);
+            task.restore_sigmask();
+            return;
+        }
+        task.sigpending &= !(1 << (sig - 1));
+        let act = task.sigactions[sig as usize - 1];
+        if act.flags & SA::RESETHAND != 0 {
+            task.sigactions[sig as usize - 1] = SigAction::zeroed();
+            task.sigrestart &= !(1 << (sig - 1));
+        }
+        drop(lock);
+
+        match act.handler {
+            SIG_IGN if sig != SIGKILL => {}
+            SIG_DFL if sig_dfl_ignored(sig) => {}
+            SIG_DFL | SIG_IGN => kill_task(task, sig),
+            _ => {
+                // a frame half written to a scratch page is no frame
+                if push_sigframe(task, sig, &act).is_err() | uaccess_done(task) {
+                    kill_task(task, SIGSEGV);
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn push_sigframe(task: &mut Task, sig: u32, act: &SigAction) -> Result<(), ()> {
+    let tf = task.get_trap_frame().ok_or(())?;
+    let sp = align_b((tf.sp_el0 as usize).wrapping_sub(size_of::<SigFrame>()), 16);
+    if !task.spel0.has(sp) && find_region(task, sp).is_none() {
+        return Err(());
+    }
+
+    let frame = unsafe { (sp as *mut SigFrame).as_mut() }.ok_or(())?;
+    let (addr, code) = match task.fault {
+        Some((addr, code)) if sig == SIGSEGV => (addr as u64, code as i32),
+        _ => (0, 0),
+    };
+    frame.info = SigInfo {
+        signo: sig as i32,
+        errno: 0,
+        code,
+        _pad: 0,
+        addr,
+        _rest: [0; 13],
+    };
+    frame.tf = *tf;
+    frame.mask = task.saved_sigmask.take().unwrap_or(task.sigmask);
+
+    task.sigmask |= act.mask & !UNBLOCKABLE;
+    if act.flags & SA::NODEFER == 0 {
+        task.sigmask |= 1 << (sig - 1);
+    }
+
+    tf.sp_el0 = sp as u64;
+    tf.pc = act.handler;
+    tf.regs[0] = sig as u64;
+    tf.regs[1] = &frame.info as *const SigInfo as u64;
+    tf.regs[2] = 0;
+    tf.regs[30] = if act.flags & SA::RESTORER != 0 {
+        act.restorer
+    } else {
+        sigreturn_tramp as *const fn() as u64
+    };
+    Ok(())
+}
+
+// handlers return here unless libc brought its own restorer. .user is
+// a page of its own, read only and executable for el0
+#[cfg_attr(not(test), unsafe(no_mangle))]
+#[unsafe(naked)]
+#[unsafe(link_section = ".user")]
+pub extern "C" fn sigreturn_tramp() {
+    naked_asm!("mov x8, #139", "svc #0");
+}
+
+// sp is back where push_sigframe left it
+pub fn rt_sigreturn() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let sp = tf.sp_el0 as usize;
+    let frame = match ptr2ref_op!(sp, SigFrame) {
+        Some(f) if sp % 16 == 0 && (task.spel0.has(sp) || find_region(task, sp).is_some()) => f,
+        _ => {
+            kill_task(task, SIGSEGV);
+            return 0;
+        }
+    };
+
+    *tf = frame.tf;
+    // only the flags, a forged frame can't leave el0
+    tf.pstate &= 0xf000_0000;
+    task.sigmask = frame.mask & !UNBLOCKABLE;
+
+    // svc::handle stores the return value in x0
+    tf.regs[0]
 }
 
 pub fn wakeup(chan: u64) {
@@ -1299,11 +2985,10 @@ pub fn scheduler() {
             let task = &mut tasks[i];
             let lock = task.lock.acquire();
             match task.state {
-                State::Ready => {
+                State::Ready if task.affinity & (1 << cpuid()) != 0 => {
                     task.state = State::Running;
                     cpu.task_idx = Some(i);
                     switch(cpu.shed_ctx.as_mut_ptr(), task.ctx.as_ptr());
-                    restore_ttbr0(task.pid as usize, task.user_pt.unwrap() as usize);
                     cpu.task_idx = None;
                     found = true;
                 }
@@ -1313,7 +2998,9 @@ pub fn scheduler() {
         }
 
         if !found {
+            timer::idle_enter();
             wfi!();
+            timer::idle_exit();
         }
     }
 }
@@ -1326,10 +3013,12 @@ pub fn sched() {
     if let State::Running = task.state {
         panic!("running");
     }
+    // the task may come back on another core, int_enable goes with it
+    let int_enable = cpu.int_enable;
     // go back to sheduler()
     switch(task.ctx.as_mut_ptr(), cpu.shed_ctx.as_ptr());
-    restore_ttbr0(task.pid as usize, task.user_pt.unwrap() as usize);
-    mycpu().int_enable = cpu.int_enable;
+    restore_ttbr0(task);
+    mycpu().int_enable = int_enable;
 }
 
 pub fn yild() {
@@ -1337,28 +3026,116 @@ pub fn yild() {
     let lock = task.lock.acquire(); // re-acquire one released at fork ret
     task.state = State::Ready;
     sched();
-    print!("RESUME {}\n", task.pid);
+    log!(Trace, "RESUME {}\n", task.pid);
     drop(lock);
 }
 
 pub fn getpid() -> u64 {
     let task = mycpu().get_task().unwrap();
-    task.pid as u64
+    task.tgid as u64
 }
 
+// the leader's, a thread's parent is whoever created it. the leader
+// owns the address space, its slot outlives the group
 pub fn getppid() -> u64 {
-    let task = mycpu().get_task().unwrap();
+    let task = &TASKS.as_ref()[mycpu().get_task().unwrap().tgid as usize];
     match task.parent {
         Some(p) => unsafe { p.as_mut() }.unwrap().pid as u64,
         _ => task.pid as u64,
     }
 }
 
+// write to a pipe with no readers left: raise SIGPIPE, a blocked one
+// stays pending. the write itself fails with EPIPE either way
+pub fn sigpipe() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    send_signal(task, SIGPIPE);
+    err(EPIPE)
+}
+
+// no process groups yet, so only pid > 0. sig 0 just checks the pid
+pub fn kill_pid(pid: i64, sig: u64) -> u64 {
+    if pid <= 0 || sig > NSIG as u64 {
+        return err(EINVAL);
+    }
+    let tasks = TASKS.as_mut();
+    if pid as usize >= tasks.len() {
+        return err(ESRCH);
+    }
+    let target = &mut tasks[pid as usize];
+    match target.state {
+        State::Free => err(ESRCH),
+        State::Zombie => 0,
+        _ => {
+            send_signal(target, sig as u32);
+            0
+        }
+    }
+}
+
 pub fn kill() -> u64 {
-    0
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    kill_pid(tf.regs[0] as i32 as i64, tf.regs[1])
 }
 
+// one thread per process, the tid is the pid
 pub fn tgkill() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+    kill_pid(tf.regs[1] as i32 as i64, tf.regs[2])
+}
+
+pub fn sched_getaffinity() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let len = tf.regs[1] as usize;
+    let mask = tf.regs[2] as *mut u8;
+
+    // the kernel cpumask is one u64
+    if len < 8 || len % 8 != 0 || mask.is_null() {
+        return err(EINVAL);
+    }
+
+    let target = if let Some(t) = task_by_pid(tf.regs[0] as usize) {
+        t
+    } else {
+        return err(ESRCH);
+    };
+
+    let out = as_slice_mut(mask, 8);
+    out.copy_from_slice(&target.affinity.to_le_bytes());
+    8
+}
+
+pub fn sched_setaffinity() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let len = tf.regs[1] as usize;
+    let mask = tf.regs[2] as *const u8;
+
+    if len == 0 || mask.is_null() {
+        return err(EINVAL);
+    }
+
+    let target = if let Some(t) = task_by_pid(tf.regs[0] as usize) {
+        t
+    } else {
+        return err(ESRCH);
+    };
+
+    let mut bytes = [0u8; 8];
+    let n = min(len, 8);
+    bytes[0..n].copy_from_slice(as_slice(mask, n));
+    // only cores that came up, a task pinned elsewhere would never run
+    let set = u64::from_le_bytes(bytes) & ((1 << smp::online()) - 1);
+    if set == 0 {
+        return err(EINVAL);
+    }
+
+    target.affinity = set;
     0
 }
 
@@ -1375,11 +3152,41 @@ fn alloc_task() -> Option<&'static mut Task> {
     for i in 0..tasks.len() {
         let task = &mut tasks[i];
         let lock = task.lock.acquire();
-        if let State::Free = task.state {
+        let held = task.mm_users.load(Ordering::Acquire) != 0
+            || task.fdt_users.load(Ordering::Acquire) != 0;
+        if matches!(task.state, State::Free) && !held {
             task.state = State::Used;
             task.pid = i as u16;
+            task.tgid = i as u16;
+            task.group_exit = false;
+            task.vfork = false;
+            task.mm = i;
+            task.mm_users.store(1, Ordering::Release);
+            task.fdt = i;
+            task.fdt_users.store(1, Ordering::Release);
+            task.asid_gen = 0;
+            task.cloexec = 0;
+            task.sigpending = 0;
+            task.sigmask = 0;
+            task.saved_sigmask = None;
+            task.sigrestart = 0;
+            task.sigactions = [SigAction::zeroed(); NSIG];
+            task.autoreap = false;
+            task.eintr = false;
+            task.rlimits = default_rlimits();
+            task.affinity = ALL_CPUS;
+            task.uid = 0;
+            task.euid = 0;
+            task.gid = 0;
+            task.egid = 0;
             forget(lock);
-            task.init_1(0);
+            if task.init_1(0).is_err() {
+                task.mm_users.store(0, Ordering::Release);
+                task.fdt_users.store(0, Ordering::Release);
+                task.state = State::Free;
+                task.lock.release();
+                return None;
+            }
             return unsafe { (task as *const Task as *mut Task).as_mut() };
         }
         let _ = lock;
@@ -1387,19 +3194,89 @@ fn alloc_task() -> Option<&'static mut Task> {
     None
 }
 
-fn restore_ttbr0(task_idx: usize, pt: usize) {
-    let ttbr0 = (task_idx << 48) | pt as usize;
+// tcr_el1.AS is clear, 8 bit asids. 0 is never handed out
+const NASIDS: usize = 256;
+
+struct Asids {
+    generation: u64,
+    used: [u64; NASIDS / 64],
+}
+
+impl Asids {
+    fn alloc(&mut self) -> Option<u16> {
+        let i = (1..NASIDS).find(|&i| self.used[i / 64] & (1 << (i % 64)) == 0)?;
+        self.used[i / 64] |= 1 << (i % 64);
+        Some(i as u16)
+    }
+
+    // a new generation, every address space gets a new asid on its
+    // next switch except the ones running right now
+    fn rollover(&mut self) {
+        let old = self.generation;
+        self.generation += 1;
+        self.used = [0; NASIDS / 64];
+        for cpu in CPUS.as_ref().iter() {
+            if let Some(idx) = cpu.task_idx {
+                let mm = TASKS.as_ref()[idx].mm();
+                if mm.asid_gen == old {
+                    self.used[mm.asid as usize / 64] |= 1 << (mm.asid % 64);
+                    mm.asid_gen = self.generation;
+                }
+            }
+        }
+        vm::tlb_shootdown(None, None);
+    }
+}
+
+static ASIDS: Lock<Asids> = Lock::new(
+    "asid",
+    Asids {
+        generation: 1,
+        used: [0; NASIDS / 64],
+    },
+);
+
+// the address space keeps its asid until a rollover takes it away
+fn mm_asid(task: &Task) -> u16 {
+    let mm = task.mm();
+    let lock = ASIDS.acquire();
+    let asids = lock.as_mut();
+    if mm.asid_gen != asids.generation {
+        let asid = match asids.alloc() {
+            Some(a) => a,
+            _ => {
+                asids.rollover();
+                asids.alloc().unwrap()
+            }
+        };
+        mm.asid = asid;
+        mm.asid_gen = asids.generation;
+    }
+    mm.asid
+}
+
+// flushed before it can be handed out again, nothing stale is left
+// under it for the next address space
+fn put_asid(task: &mut Task) {
+    let lock = ASIDS.acquire();
+    let asids = lock.as_mut();
+    if task.asid_gen == asids.generation {
+        asids.used[task.asid as usize / 64] &= !(1 << (task.asid % 64));
+        vm::tlb_shootdown(Some(task.asid as u64), None);
+    }
+    task.asid_gen = 0;
+}
+
+fn restore_ttbr0(task: &Task) {
+    let asid = mm_asid(task) as usize;
+    let ttbr0 = (asid << 48) | task.user_pt.unwrap() as usize;
     w_ttbr0_el1(ttbr0 as u64);
     dsb!();
     isb!();
-    tlbi_aside1(task_idx as u64);
-    tlbi_vmalle1!();
-    dsb!();
-    isb!();
 }
 
 pub fn create_task(entry: u64) {
-    let task = alloc_task().unwrap();
+    let task = alloc_task().expect("no memory for the first task");
     task.files[0] = Some(fs::open_cons().unwrap());
     task.files[1] = Some(fs::open_cons().unwrap());
     task.files[2] = Some(fs::open_cons().unwrap());
@@ -1408,10 +3285,74 @@ pub fn create_task(entry: u64) {
 }
 
 pub fn rt_sigaction() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let sig = tf.regs[0] as usize;
+    let act = ptr2ref_op!(tf.regs[1], SigAction);
+    let old = tf.regs[2] as *mut SigAction;
+
+    if sig == 0 || sig > NSIG || tf.regs[3] != 8 {
+        return err(EINVAL);
+    }
+    if act.is_some() && (sig == SIGKILL as usize || sig == SIGSTOP as usize) {
+        return err(EINVAL);
+    }
+
+    let lock = task.lock.acquire();
+    if !old.is_null() {
+        unsafe { old.write(task.sigactions[sig - 1]) };
+    }
+    if let Some(act) = act {
+        task.sigactions[sig - 1] = *act;
+        if act.flags & SA::RESTART != 0 {
+            task.sigrestart |= 1 << (sig - 1);
+        } else {
+            task.sigrestart &= !(1 << (sig - 1));
+        }
+    }
+    drop(lock);
+
     0
 }
 
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+// SIGKILL and SIGSTOP are dropped from the new mask, whatever asked for them
 pub fn rt_sigprocmask() -> u64 {
+    let task = mycpu().get_task().unwrap();
+    let tf = task.get_trap_frame().unwrap();
+
+    let how = tf.regs[0];
+    let (set, old) = (tf.regs[1] as usize, tf.regs[2] as usize);
+    if tf.regs[3] != 8 {
+        return err(EINVAL);
+    }
+    if (set != 0 && !user_range_ok(task, set, 8)) || (old != 0 && !user_range_ok(task, old, 8)) {
+        return err(EFAULT);
+    }
+    let set = match set {
+        0 => None,
+        s => Some(*ptr2ref!(s, u64)),
+    };
+
+    let lock = task.lock.acquire();
+    let cur = task.sigmask;
+    if let Some(set) = set {
+        task.sigmask = match how {
+            SIG_BLOCK => cur | set,
+            SIG_UNBLOCK => cur & !set,
+            SIG_SETMASK => set,
+            _ => return err(EINVAL),
+        } & !UNBLOCKABLE;
+    }
+    drop(lock);
+
+    if old != 0 {
+        unsafe { (old as *mut u64).write(cur) };
+    }
     0
 }
 
@@ -1424,7 +3365,7 @@ const TEST_ENV: [&[u8]; 4] = [
     "PS1=\\w \\$ ".as_bytes(),
 ];
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[allow(unused)]
 pub extern "C" fn forkret() {
     let cpu = mycpu();
@@ -1432,10 +3373,10 @@ pub extern "C" fn forkret() {
     // was held in scheduler()
     task.lock.release();
 
-    restore_ttbr0(task.pid as usize, task.user_pt.unwrap() as usize);
+    restore_ttbr0(task);
 
     if FIRST.swap(false, Ordering::Release) {
-        print!("launching init..\n");
+        log!(Info, "launching init..\n");
         execv_inner(
             "busybox",
             &[
@@ -1456,6 +3397,8 @@ pub extern "C" fn forkret() {
         task.cwd = Some("/".into());
     }
 
+    do_signals();
+
     unsafe {
         asm!(
             "mov sp, {}",
@@ -1486,7 +3429,7 @@ pub extern "C" fn forkret() {
 }
 
 #[unsafe(naked)]
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 extern "C" fn switch(save: *mut u64, load: *const u64) {
     naked_asm!(
         "stp x19, x18, [x0], #16",
@@ -1548,3 +3491,118 @@ static TASKS: SyncUnsafeCell<[Task; NTASKS]> = SyncUnsafeCell::new([
     Task::zeroed(),
     Task::zeroed(),
 ]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(maps: &RTree) -> Vec<(usize, usize)> {
+        maps.iter().map(|r| (r.vaddr, r.end())).collect()
+    }
+
+    #[test]
+    fn mmap_alloc_reuses_holes() {
+        let mut t = Task::zeroed();
+        let rw = elf::PF_R | elf::PF_W;
+        let a = mmap_alloc(&mut t, 0x1000, rw, RegionType::Mmap).unwrap();
+        let b = mmap_alloc(&mut t, 0x2000, rw, RegionType::Mmap).unwrap();
+        let c = mmap_alloc(&mut t, 0x1000, rw, RegionType::Mmap).unwrap();
+        assert_eq!((a, b, c), (GB, GB + 0x1000, GB + 0x3000));
+
+        maps_cut(&mut t.maps, b, b + 0x2000);
+        assert_eq!(spans(&t.maps), [(a, a + 0x1000), (c, c + 0x1000)]);
+        // first fit, and the list stays sorted
+        assert_eq!(mmap_alloc(&mut t, 0x1000, rw, RegionType::Mmap), Some(b));
+        assert_eq!(
+            mmap_alloc(&mut t, 0x2000, rw, RegionType::Mmap),
+            Some(GB + 0x4000)
+        );
+        assert_eq!(
+            mmap_alloc(&mut t, 0x1000, rw, RegionType::Mmap),
+            Some(b + 0x1000)
+        );
+        assert_eq!(
+            spans(&t.maps),
+            [
+                (a, a + 0x1000),
+                (b, b + 0x1000),
+                (b + 0x1000, b + 0x2000),
+                (c, c + 0x1000),
+                (GB + 0x4000, GB + 0x6000)
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_cut_splits() {
+        let mut t = Task::zeroed();
+        let rw = elf::PF_R | elf::PF_W;
+        let a = mmap_alloc(&mut t, 0x4000, rw, RegionType::Mmap).unwrap();
+        let b = mmap_alloc(&mut t, 0x2000, elf::PF_R, RegionType::Shared).unwrap();
+
+        // a hole in the middle of one, the flags go with both halves
+        maps_cut(&mut t.maps, a + 0x1000, a + 0x2000);
+        assert_eq!(
+            spans(&t.maps),
+            [(a, a + 0x1000), (a + 0x2000, a + 0x4000), (b, b + 0x2000)]
+        );
+        assert!(
+            t.maps
+                .iter()
+                .take(2)
+                .all(|r| r.flags == rw && r.len == r.cap)
+        );
+
+        // across two, trimming the end of one and the start of the next
+        maps_cut(&mut t.maps, a + 0x3000, b + 0x1000);
+        assert_eq!(
+            spans(&t.maps),
+            [
+                (a, a + 0x1000),
+                (a + 0x2000, a + 0x3000),
+                (b + 0x1000, b + 0x2000)
+            ]
+        );
+        assert!(matches!(t.maps.back().unwrap().ty, RegionType::Shared));
+
+        // nothing there
+        maps_cut(&mut t.maps, a + 0x1000, a + 0x2000);
+        assert_eq!(t.maps.len(), 3);
+        maps_cut(&mut t.maps, 0, usize::MAX);
+        assert!(t.maps.is_empty());
+    }
+
+    #[test]
+    fn maps_protect_splits() {
+        let mut t = Task::zeroed();
+        let a = mmap_alloc(&mut t, 0x3000, 0, RegionType::Mmap).unwrap();
+        let b = mmap_alloc(&mut t, 0x1000, elf::PF_R, RegionType::Mmap).unwrap();
+        let rw = elf::PF_R | elf::PF_W;
+
+        // the guard page stays PROT_NONE, like a thread stack
+        maps_protect(&mut t.maps, a + 0x1000, b + 0x1000, rw);
+        let got: Vec<_> = t.maps.iter().map(|r| (r.vaddr, r.end(), r.flags)).collect();
+        assert_eq!(
+            got,
+            [
+                (a, a + 0x1000, 0),
+                (a + 0x1000, a + 0x3000, rw),
+                (b, b + 0x1000, rw)
+            ]
+        );
+    }
+
+    #[test]
+    fn prot_to_ptes() {
+        assert_eq!(prot_flags(0), Some(0));
+        assert_eq!(prot_flags(7), Some(elf::PF_R | elf::PF_W | elf::PF_X));
+        assert_eq!(prot_flags(8), None);
+        assert_eq!(pte_perms(0, true), vm::PR);
+        assert_eq!(pte_perms(elf::PF_R | elf::PF_W, false), vm::PR_UR);
+        assert_eq!(pte_perms(elf::PF_W, true), vm::PR_PW_UR_UW1);
+        assert_eq!(pte_perms(elf::PF_X, true), vm::PR_UR_UX);
+        assert!(prot_allows(elf::PF_W, elf::PF_R));
+        assert!(!prot_allows(elf::PF_R, elf::PF_W));
+        assert!(!prot_allows(0, elf::PF_R));
+    }
+}
@@ -0,0 +1,202 @@
+use core::cmp::min;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    fs::{self, O},
+    log, pm,
+    spin::Lock,
+    vm::{self, PmWrap},
+};
+
+// glibc's shm_open is a plain open() under this prefix
+pub const PREFIX: &str = "/dev/shm/";
+
+const NSHM: usize = 16;
+
+pub struct File {
+    name: Option<String>,
+    pages: Vec<usize>,
+    refs: usize,
+}
+
+impl File {
+    const fn zeroed() -> File {
+        File {
+            name: None,
+            pages: Vec::new(),
+            refs: 0,
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8], offt: usize) -> Result<usize, ()> {
+        let lock = SHM.acquire();
+        let size = self.get_size() as usize;
+        if offt >= size {
+            return Ok(0);
+        }
+        let len = min(buf.len(), size - offt);
+        let mut done = 0;
+        while done < len {
+            let pos = offt + done;
+            let page = PmWrap::new(self.pages[pos / 4096], vm::PR, false).map_err(|_| ())?;
+            let from = pos % 4096;
+            let n = min(4096 - from, len - done);
+            buf[done..done + n].copy_from_slice(&page.as_slice::<u8>()[from..from + n]);
+            done += n;
+        }
+        Ok(done)
+    }
+
+    pub fn write(&mut self, buf: &[u8], offt: usize) -> Result<usize, ()> {
+        let lock = SHM.acquire();
+        if offt + buf.len() > self.get_size() as usize {
+            self.resize((offt + buf.len()) as u64)?;
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offt + done;
+            let page = PmWrap::new(self.pages[pos / 4096], vm::PR_PW, false).map_err(|_| ())?;
+            let to = pos % 4096;
+            let n = min(4096 - to, buf.len() - done);
+            page.as_slice_mut::<u8>()[to..to + n].copy_from_slice(&buf[done..done + n]);
+            done += n;
+        }
+        Ok(done)
+    }
+
+    pub fn get_size(&self) -> u64 {
+        (self.pages.len() * 4096) as u64
+    }
+
+    pub fn truncate(&mut self, size: u64) -> Result<(), ()> {
+        let lock = SHM.acquire();
+        self.resize(size)
+    }
+
+    // size is kept page granular, the caller holds SHM
+    fn resize(&mut self, size: u64) -> Result<(), ()> {
+        let n = pm::align_f(size as usize, 4096) / 4096;
+        while self.pages.len() > n {
+            pm::free(self.pages.pop().unwrap(), 4096);
+        }
+        while self.pages.len() < n {
+            let p = pm::alloc(4096)?;
+            if PmWrap::new(p, vm::PR_PW, true).is_err() {
+                pm::free(p, 4096);
+                return Err(());
+            }
+            self.pages.push(p);
+        }
+        Ok(())
+    }
+
+    pub fn page(&self, idx: usize) -> Option<usize> {
+        let lock = SHM.acquire();
+        self.pages.get(idx).copied()
+    }
+
+    pub fn stat(&self, stat: &mut fs::Stat) -> Result<(), ()> {
+        stat.st_ino = 0;
+        stat.st_size = self.get_size() as i64;
+        stat.st_nlink = 1;
+        stat.st_mode = 0o100666;
+        stat.st_blksize = 4096;
+        stat.st_blocks = (self.get_size() / 512) as i64;
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<(), ()> {
+        let lock = SHM.acquire();
+        assert!(self.refs > 0);
+        self.refs -= 1;
+        if self.refs == 0 && self.name.is_none() {
+            self.resize(0)?;
+        }
+        drop(lock);
+        Ok(())
+    }
+}
+
+pub fn is_shm(path: &str) -> bool {
+    path.starts_with(PREFIX) && path.len() > PREFIX.len()
+}
+
+pub fn open(path: &str, flags: u32) -> Result<&'static mut File, ()> {
+    let name = &path[PREFIX.len()..];
+    let lock = SHM.acquire();
+    let files = lock.as_mut();
+
+    for i in 0..files.len() {
+        let file = &mut files[i];
+        if file.name.as_deref() == Some(name) {
+            if flags & O::CREAT != 0 && flags & O::EXCL != 0 {
+                return Err(());
+            }
+            file.refs += 1;
+            if flags & O::TRUNC != 0 {
+                file.resize(0)?;
+            }
+            log!(Debug, "SHM OPEN: {} refs {}\n", name, file.refs);
+            return Ok(unsafe { (file as *mut File).as_mut() }.unwrap());
+        }
+    }
+
+    if flags & O::CREAT == 0 {
+        return Err(());
+    }
+
+    for i in 0..files.len() {
+        let file = &mut files[i];
+        if file.name.is_none() && file.refs == 0 {
+            file.name = Some(String::from(name));
+            file.refs = 1;
+            log!(Debug, "SHM CREATE: {}\n", name);
+            return Ok(unsafe { (file as *mut File).as_mut() }.unwrap());
+        }
+    }
+
+    Err(())
+}
+
+// pages stay alive until the last fd is closed, mappings hold their own refs
+pub fn unlink(path: &str) -> Result<(), ()> {
+    let name = &path[PREFIX.len()..];
+    let lock = SHM.acquire();
+    let files = lock.as_mut();
+
+    for i in 0..files.len() {
+        let file = &mut files[i];
+        if file.name.as_deref() == Some(name) {
+            file.name = None;
+            if file.refs == 0 {
+                file.resize(0)?;
+            }
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+static SHM: Lock<[File; NSHM]> = Lock::new(
+    "shm",
+    [
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+        File::zeroed(),
+    ],
+);
@@ -0,0 +1,199 @@
+use core::{
+    arch::{asm, naked_asm},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    _trap_vec,
+    arch::{self, enable_fp},
+    dsb, dt,
+    heap::SyncUnsafeCell,
+    isb, log,
+    sched::{self, NCPU, cpuid},
+    timer, tlbi_vmalle1, trap, wfi,
+};
+
+// secondary cores are started with psci CPU_ON, mmu off, at the
+// physical address of secondary_entry with their index in x0
+
+const VOFFT: usize = 0xffff_0000_0000_0000;
+
+struct PSCI;
+impl PSCI {
+    const CPU_ON: u64 = 0xc400_0003;
+    const SUCCESS: i64 = 0;
+    const ALREADY_ON: i64 = -4;
+}
+
+const STACK_SIZE: usize = 4096 * 4;
+
+#[repr(C, align(4096))]
+struct Stacks([[u8; STACK_SIZE]; NCPU]);
+
+// slot 0 is unused, the boot core keeps _boot_stack
+static STACKS: SyncUnsafeCell<Stacks> = SyncUnsafeCell::new(Stacks([[0; STACK_SIZE]; NCPU]));
+
+// the boot tables are gone by now. the entry needs the page it runs from
+// identity mapped to turn the mmu on, one 1gb block over the kernel does
+#[repr(C, align(4096))]
+struct IdMap {
+    l0: [u64; 512],
+    l1: [u64; 512],
+}
+
+static IDMAP: SyncUnsafeCell<IdMap> = SyncUnsafeCell::new(IdMap {
+    l0: [0; 512],
+    l1: [0; 512],
+});
+
+// read with the mmu off
+static TTBR1: SyncUnsafeCell<u64> = SyncUnsafeCell::new(0);
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+// sgi the other cores answer with a dsb, what membarrier waits on
+pub const IPI_BARRIER: u32 = 1;
+
+static BARRIER_SEQ: AtomicUsize = AtomicUsize::new(0);
+// last barrier each core went through
+static BARRIER_ACK: [AtomicUsize; NCPU] = [const { AtomicUsize::new(0) }; NCPU];
+
+// returns once every online core has fenced after the caller did
+pub fn barrier_ipi() {
+    dsb!();
+    let seq = BARRIER_SEQ.fetch_add(1, Ordering::AcqRel) + 1;
+    trap::gic_sgi_others(IPI_BARRIER);
+    let me = cpuid();
+    for id in 0..online() {
+        if id == me {
+            continue;
+        }
+        while BARRIER_ACK[id].load(Ordering::Acquire) < seq {
+            // another core may be waiting here with interrupts masked,
+            // answer its barrier too or neither gets anywhere
+            barrier_ack();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// from the irq handler, or a core spinning in barrier_ipi
+pub fn barrier_ack() {
+    let seq = BARRIER_SEQ.load(Ordering::Acquire);
+    dsb!();
+    BARRIER_ACK[cpuid()].fetch_max(seq, Ordering::AcqRel);
+}
+
+fn psci_call(fid: u64, a1: u64, a2: u64, a3: u64) -> i64 {
+    let mut ret = fid;
+    // the fixed registers only exist on aarch64, host test builds skip it
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        if dt::psci_hvc() {
+            asm!("hvc #0", inout("x0") ret, in("x1") a1, in("x2") a2, in("x3") a3, clobber_abi("C"));
+        } else {
+            asm!("smc #0", inout("x0") ret, in("x1") a1, in("x2") a2, in("x3") a3, clobber_abi("C"));
+        }
+    }
+    ret as i64
+}
+
+// to the point of coherency, the secondary reads it uncached
+fn clean(v: usize, len: usize) {
+    let mut a = v & !63;
+    while a < v + len {
+        unsafe { asm!("dc cvac, {}", in(reg) a) };
+        a += 64;
+    }
+    dsb!();
+}
+
+// after the boot core is done with vm, right before its scheduler
+pub fn init() {
+    let map = IDMAP.as_mut();
+    let l1 = map.l1.as_ptr() as usize - VOFFT;
+    let entry = secondary_entry as *const () as usize - VOFFT;
+    map.l0[0] = l1 as u64 | 3;
+    map.l1[entry >> 30] = ((entry >> 30) << 30) as u64 | 0x401;
+    *TTBR1.as_mut() = arch::r_ttbr1_el1();
+    clean(map as *const IdMap as usize, size_of::<IdMap>());
+    clean(TTBR1.0.get() as usize, 8);
+
+    for id in 1..NCPU {
+        match psci_call(PSCI::CPU_ON, id as u64, entry as u64, id as u64) {
+            PSCI::SUCCESS | PSCI::ALREADY_ON => {}
+            // no such core, the rest are missing too
+            _ => break,
+        }
+    }
+}
+
+#[unsafe(naked)]
+extern "C" fn secondary_entry() {
+    naked_asm!(
+        "mov x19, x0",
+        "ldr x0, =0x5b0103210",
+        "msr tcr_el1, x0",
+        "mov x0, #0xff",
+        "msr mair_el1, x0",
+        // still at the physical address, adrp gives physical ones
+        "adrp x0, {idmap}",
+        "msr ttbr0_el1, x0",
+        "adrp x0, {ttbr1}",
+        "ldr x0, [x0, #:lo12:{ttbr1}]",
+        "msr ttbr1_el1, x0",
+        "dsb sy",
+        "isb sy",
+        "tlbi vmalle1",
+        "dsb sy",
+        "isb sy",
+        "mrs x0, sctlr_el1",
+        "orr x0, x0, #1",
+        "msr sctlr_el1, x0",
+        "isb sy",
+        // stacks + (id + 1) * size, the top of this core's stack
+        "ldr x0, ={stacks}",
+        "add x1, x19, #1",
+        "mov x2, #{size}",
+        "madd x0, x1, x2, x0",
+        "mov sp, x0",
+        "ldr x0, ={trap}",
+        "msr vbar_el1, x0",
+        "mov x0, x19",
+        "ldr x1, ={main}",
+        "blr x1",
+        "1:",
+        "wfi",
+        "b 1b",
+        idmap = sym IDMAP,
+        ttbr1 = sym TTBR1,
+        stacks = sym STACKS,
+        size = const STACK_SIZE,
+        trap = sym _trap_vec,
+        main = sym secondary_main,
+    );
+}
+
+extern "C" fn secondary_main(id: usize) {
+    // the identity map was only for the jump into the kernel half
+    arch::w_ttbr0_el1(0);
+    dsb!();
+    isb!();
+    tlbi_vmalle1!();
+    dsb!();
+    isb!();
+
+    enable_fp();
+    trap::gic_enable();
+    timer::init();
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    log!(Info, "cpu {} up\n", id);
+    sched::scheduler();
+    loop {
+        wfi!();
+    }
+}
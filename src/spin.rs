@@ -1,7 +1,7 @@
 use core::{
     cell::UnsafeCell,
     hint::spin_loop,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicU32, Ordering},
 };
 
 use crate::sched::{Cpu, mycpu};
@@ -10,7 +10,9 @@ pub struct Lock<T> {
     data: UnsafeCell<T>,
     pub name: &'static str,
     cpu: UnsafeCell<*mut Cpu>,
-    pub locked: AtomicBool,
+    // ticket lock, waiters get in in the order they took a ticket
+    next: AtomicU32,
+    owner: AtomicU32,
 }
 
 unsafe impl<T> Sync for Lock<T> {}
@@ -21,32 +23,61 @@ impl<T> Lock<T> {
             data: UnsafeCell::new(data),
             name,
             cpu: UnsafeCell::new(0 as *mut Cpu),
-            locked: AtomicBool::new(false),
+            next: AtomicU32::new(0),
+            owner: AtomicU32::new(0),
         }
     }
 
     pub fn acquire(&self) -> LockGuard<'_, T> {
+        // masked first, mycpu() can't change under us after that
+        mycpu().disable_intr();
         let cpu = mycpu();
 
         if self.holding() {
             panic!("another lock {}", self.name);
         }
 
-        cpu.disable_intr();
-        while let Err(_) =
-            self.locked
-                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
-        {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.owner.load(Ordering::Acquire) != ticket {
             spin_loop();
         }
         unsafe { self.cpu.get().write(cpu as *mut Cpu) };
         LockGuard(self)
     }
 
+    // one attempt, None when taken. also None and no panic when this core
+    // is the holder, an irq handler can back off either way
+    pub fn try_acquire(&self) -> Option<LockGuard<'_, T>> {
+        mycpu().disable_intr();
+        let cpu = mycpu();
+
+        let owner = self.owner.load(Ordering::Relaxed);
+        if self.holding()
+            || self
+                .next
+                .compare_exchange(
+                    owner,
+                    owner.wrapping_add(1),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            cpu.enable_intr();
+            return None;
+        }
+        unsafe { self.cpu.get().write(cpu as *mut Cpu) };
+        Some(LockGuard(self))
+    }
+
     pub fn holding(&self) -> bool {
         let cur = unsafe { self.cpu.get().read() };
         let cpu = mycpu();
-        cpu as *mut Cpu == cur && self.locked.load(Ordering::Relaxed)
+        cpu as *mut Cpu == cur && self.locked()
+    }
+
+    pub fn locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != self.next.load(Ordering::Relaxed)
     }
 
     pub fn release(&self) {
@@ -54,8 +85,10 @@ impl<T> Lock<T> {
         // let cur = unsafe { self.cpu.get().read() };
         let cpu = mycpu();
         // assert!(cpu as *mut Cpu == cur);
-        self.locked.store(false, Ordering::Release);
         unsafe { self.cpu.get().write(0 as *mut Cpu) }
+        // only the holder writes owner
+        let owner = self.owner.load(Ordering::Relaxed);
+        self.owner.store(owner.wrapping_add(1), Ordering::Release);
         cpu.enable_intr();
     }
 }
@@ -6,78 +6,79 @@ use core::{
 
 use crate::print;
 
-pub struct BitSet128 {
-    pub back: u128,
-    len: u8,
-}
-
-impl BitSet128 {
-    pub const fn new(len: u8) -> Self {
-        assert!(len <= 128);
-        Self {
-            back: if len == 128 { 0 } else { (!0u128) << len },
-            len,
+// bits past len are kept set, they are never handed out
+pub struct BitSet<const WORDS: usize> {
+    pub back: [u64; WORDS],
+    len: usize,
+}
+
+pub type BitSet128 = BitSet<2>;
+
+impl<const WORDS: usize> BitSet<WORDS> {
+    pub const fn new(len: usize) -> Self {
+        assert!(len <= WORDS * 64);
+        let mut back = [0; WORDS];
+        let mut i = len;
+        while i < WORDS * 64 {
+            back[i / 64] |= 1 << (i % 64);
+            i += 1;
         }
+        Self { back, len }
     }
 
-    pub fn len(&self) -> u8 {
+    pub fn len(&self) -> usize {
         self.len
     }
 
     #[inline]
-    pub fn set(&mut self, bit: u8) {
+    pub fn set(&mut self, bit: usize) {
         assert!(bit < self.len);
-        self.back |= 1u128 << bit
+        self.back[bit / 64] |= 1 << (bit % 64)
     }
 
     #[inline]
-    pub fn clr(&mut self, bit: u8) {
+    pub fn clr(&mut self, bit: usize) {
         assert!(bit < self.len);
-        self.back &= !(1u128 << bit)
+        self.back[bit / 64] &= !(1 << (bit % 64))
     }
 
     #[inline]
-    pub fn tst(&self, bit: u8) -> bool {
+    pub fn tst(&self, bit: usize) -> bool {
         assert!(bit < self.len);
-        (self.back & 1u128 << bit) != 0
+        (self.back[bit / 64] & 1 << (bit % 64)) != 0
     }
 
     #[inline]
     pub fn full(&self) -> bool {
-        self.back == !0u128
+        self.back.iter().all(|&w| w == !0)
     }
 
-    #[inline]
-    pub fn first_clr(&self) -> Option<u8> {
-        if self.full() {
-            return None;
-        }
-
-        for i in 0..self.len {
-            if !self.tst(i) {
-                return Some(i);
-            }
-        }
+    pub fn n_clr(&self) -> usize {
+        self.back.iter().map(|w| (!w).count_ones() as usize).sum()
+    }
 
-        None
+    #[inline]
+    pub fn first_clr(&self) -> Option<usize> {
+        let (i, w) = self.back.iter().enumerate().find(|(_, w)| **w != !0)?;
+        Some(i * 64 + (!w).trailing_zeros() as usize)
     }
 
+    // n clear bits in a row, all set. the first one
     #[inline]
-    pub fn set_nclr(&mut self, n: u8) -> Option<u8> {
+    pub fn set_nclr(&mut self, n: usize) -> Option<usize> {
         let mut f = 0;
-        let mut b = self.back;
-        for i in 0..self.len() {
-            if !self.tst(i) {
-                b |= 1u128 << i;
-                f += 1;
-            } else {
+        for i in 0..self.len {
+            if self.tst(i) {
                 f = 0;
-                b = self.back;
+                continue;
             }
+            f += 1;
             if f == n {
-                self.back = b;
-                let idx = i - (n - 1);
-                return Some(idx as u8);
+                let idx = i + 1 - n;
+                for j in idx..=i {
+                    self.set(j);
+                }
+                return Some(idx);
             }
         }
         None
@@ -175,3 +176,51 @@ pub fn print_slice_chars(s: &[u8]) {
         print!("{}", s[i] as char);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_clr_crosses_words() {
+        let mut b = BitSet::<2>::new(128);
+        for i in 0..64 {
+            b.set(i);
+        }
+        assert_eq!(b.first_clr(), Some(64));
+        b.set(64);
+        assert_eq!(b.first_clr(), Some(65));
+        b.clr(3);
+        assert_eq!(b.first_clr(), Some(3));
+    }
+
+    #[test]
+    fn past_len_is_never_handed_out() {
+        let mut b = BitSet::<2>::new(70);
+        assert_eq!(b.n_clr(), 70);
+        for i in 0..70 {
+            assert_eq!(b.first_clr(), Some(i));
+            b.set(i);
+        }
+        assert!(b.full());
+        assert_eq!(b.first_clr(), None);
+        assert_eq!(b.set_nclr(1), None);
+    }
+
+    #[test]
+    fn set_nclr_takes_a_contiguous_run() {
+        let mut b = BitSet::<2>::new(128);
+        b.set(1);
+        b.set(62);
+        // 2..62 is the first hole of 60, 63.. spans the word boundary
+        assert_eq!(b.set_nclr(60), Some(2));
+        assert_eq!(b.set_nclr(4), Some(63));
+        for i in 63..67 {
+            assert!(b.tst(i));
+        }
+        assert_eq!(b.first_clr(), Some(0));
+        assert_eq!(b.set_nclr(62), None);
+        assert_eq!(b.set_nclr(61), Some(67));
+        assert_eq!(b.n_clr(), 1);
+    }
+}
@@ -1,13 +1,16 @@
 use crate::{
-    fs, print, rtc,
+    errno::{EFAULT, EINTR, err},
+    fs, futex, log, rtc,
     sched::{self, mycpu},
 };
 
 pub fn handle() {
     let task = mycpu().get_task().unwrap();
     let tf = task.get_trap_frame().unwrap();
-    print!("++ pid: {} svc: {}\n", task.pid, tf.regs[8]);
-    tf.regs[0] = match tf.regs[8] {
+    log!(Trace, "++ pid: {} svc: {}\n", task.pid, tf.regs[8]);
+    let x0 = tf.regs[0];
+    let ret = match tf.regs[8] {
+        // numbers from asm-generic/unistd.h, which aarch64 uses
         17 => fs::getcwd(),
         24 => fs::dup3(),
         25 => fs::fcntl(),
@@ -17,55 +20,100 @@ pub fn handle() {
         36 => fs::symlinkat(),
         37 => fs::linkat(),
         38 => fs::renameat(),
+        43 => fs::statfs(),
+        44 => fs::fstatfs(),
         46 => fs::ftruncate(),
         48 => fs::faccessat(),
         49 => fs::chdir(),
         56 => fs::openat(),
         57 => fs::close(),
+        59 => fs::pipe2(),
         61 => fs::getdents64(),
         62 => fs::lseek(),
-        63 => fs::sys_read(),
-        64 => fs::sys_write(),
-        66 => fs::sys_writev(),
-        71 => fs::sendfile64(),
+        63 => fs::sys_read(),   // read
+        64 => fs::sys_write(),  // write
+        66 => fs::sys_writev(), // writev
+        71 => fs::sendfile64(), // sendfile
         73 => fs::ppoll(),
         78 => fs::readlinkat(),
-        79 => fs::newfsstatat(),
-        80 => fs::newfstat(),
+        79 => fs::newfsstatat(), // newfstatat
+        80 => fs::newfstat(),    // fstat
+        82 => fs::fsync(),
+        83 => fs::fdatasync(),
         88 => fs::utimensat(),
         94 => sched::exit_group(),
-        96 => sched::settid(),
+        98 => futex::futex(),
+        96 => sched::set_tid_address(),
         99 => sched::set_robust_list(),
+        101 => rtc::nanosleep(),
         113 => rtc::clock_gettime(),
         115 => rtc::clock_nanosleep(),
+        122 => sched::sched_setaffinity(),
+        123 => sched::sched_getaffinity(),
         129 => sched::kill(),
         131 => sched::tgkill(),
         134 => sched::rt_sigaction(),
         135 => sched::rt_sigprocmask(),
+        139 => sched::rt_sigreturn(),
         144 => sched::setgid(),
         146 => sched::setuid(),
         154 => sched::setpgid(),
-        155 => sched::getgid(),
+        155 => sched::getpgid(),
         160 => sched::uname(),
         166 => fs::umask(),
-        174 => sched::getuid(),
+        169 => rtc::gettimeofday(),
+        170 => rtc::settimeofday(),
         172 => sched::getpid(),
         173 => sched::getppid(),
+        174 => sched::getuid(),
         175 => sched::geteuid(),
         176 => sched::getgid(),
+        177 => sched::getegid(),
         178 => sched::gettid(),
         214 => sched::brk(),
         215 => sched::munmap(),
-        220 => sched::fork(),
+        220 => sched::clone(),
         221 => sched::execve(),
         222 => sched::mmap(),
         226 => sched::mprotect(),
+        232 => sched::mincore(),
         260 => sched::wait4(),
         261 => sched::prlimit64(),
+        276 => fs::renameat2(),
         278 => fs::getrandom(),
+        283 => sched::membarrier(),
         293 => sched::rseq(),
         // 93 => sched::exit(),
         // 95 => sched::wait(),
         _ => panic!("unimplemented syscall {}\n", tf.regs[8]),
+    };
+
+    // touched a bad user pointer, see sched::uaccess_fault
+    if sched::uaccess_done(task) {
+        task.take_eintr();
+        tf.regs[0] = err(EFAULT);
+        return;
+    }
+
+    // failed because a signal cut a sleep short
+    if task.take_eintr() && (ret as i64) < 0 {
+        let sig = task.next_signal();
+        if restartable(tf.regs[8]) && sig != 0 && task.sigrestart & (1 << (sig - 1)) != 0 {
+            // SA_RESTART, run the svc again once the handler returns
+            tf.regs[0] = x0;
+            tf.pc -= 4;
+            return;
+        }
+        tf.regs[0] = err(EINTR);
+        return;
+    }
+
+    tf.regs[0] = ret;
+}
+
+fn restartable(nr: u64) -> bool {
+    match nr {
+        63 | 64 | 66 | 260 => true,
+        _ => false,
     }
 }
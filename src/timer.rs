@@ -1,9 +1,13 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    cmp::min,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use crate::{
     heap::SyncUnsafeCell,
     print,
-    sched::{self, Task, Wq, cpuid, mycpu, wakeup},
+    sched::{self, NCPU, Task, Wq, cpuid, mycpu, sleep_intr, wakeup},
     spin::Lock,
     trap,
 };
@@ -77,30 +81,106 @@ pub fn init() {
     w_pctl_el0(1);
 }
 
-pub fn handle_tik(el: u8) {
-    let freq = r_freq();
+pub const DEFAULT_HZ: u64 = 100;
+// longest we stay asleep in tickless idle
+const IDLE_MAX_TICKS: u64 = 100;
+
+static HZ: AtomicU64 = AtomicU64::new(DEFAULT_HZ);
+// per core, each has its own timer
+static IDLE_FROM: [AtomicU64; NCPU] = [const { AtomicU64::new(0) }; NCPU];
+
+pub fn hz() -> u64 {
+    HZ.load(Ordering::Relaxed)
+}
+
+pub fn set_hz(hz: u64) -> Result<(), ()> {
+    if hz == 0 || hz > 1000 {
+        return Err(());
+    }
+    HZ.store(hz, Ordering::Relaxed);
+    w_ptval_el0(period());
+    Ok(())
+}
 
-    // freq = ticks/s
-    // freq/100 = ticks/(s/100)
+// freq = ticks/s
+// freq/hz = ticks/(s/hz)
+fn period() -> u64 {
+    r_freq() / hz()
+}
 
+pub fn handle_tik(el: u8) {
     if cpuid() == 0 {
-        let lock = TICKLOCK.acquire();
-        // print!("T {} {} {}\n", lock.as_ref().0, lock.as_ref().1.count, el);
-        lock.as_mut().0 += 1;
-        // wakeup(lock.as_ref() as *const u64 as u64);
-        lock.as_mut().1.wake_all();
-        drop(lock);
+        account(1);
     }
 
-    w_ptval_el0(freq / 100);
+    w_ptval_el0(period());
+    expire();
 
     if (el == 1 && mycpu().get_task().is_some()) || el == 0 {
         sched::yild();
     }
 }
 
+// ticks since boot, bumped by cpu 0 only
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_ns() -> u64 {
+    let freq = r_freq();
+    if freq == 0 {
+        return 0;
+    }
+    ((r_pct_el0() as u128 * 1_000_000_000) / freq as u128) as u64
+}
+
+// raw CNTPCT, see cnt2ns
+pub fn counter() -> u64 {
+    r_pct_el0()
+}
+
 static TICKLOCK: Lock<(u64, Wq)> = Lock::new("TICK", (0, Wq::new("ticks")));
 
+fn account(n: u64) {
+    TICKS.fetch_add(n, Ordering::Relaxed);
+    let lock = TICKLOCK.acquire();
+    // print!("T {} {}\n", lock.as_ref().0, lock.as_ref().1.count);
+    lock.as_mut().0 += n;
+    // wakeup(lock.as_ref() as *const u64 as u64);
+    lock.as_mut().1.wake_all();
+    drop(lock);
+}
+
+// called by the idle loop right before wfi. sleepers on TICKLOCK need
+// every tick, otherwise nothing is due soon and the tick is pushed out
+pub fn idle_enter() {
+    let lock = TICKLOCK.acquire();
+    let busy = lock.as_ref().1.count > 0;
+    drop(lock);
+    if busy {
+        return;
+    }
+    let now = r_pct_el0();
+    IDLE_FROM[cpuid()].store(now, Ordering::Relaxed);
+    let cval = min(now + period() * IDLE_MAX_TICKS, next_deadline());
+    w_pcval_el0(cval);
+}
+
+// re-arm the periodic tick and catch up on the ticks skipped while idle
+pub fn idle_exit() {
+    let from = IDLE_FROM[cpuid()].swap(0, Ordering::Relaxed);
+    if from == 0 {
+        return;
+    }
+    let missed = (r_pct_el0() - from) / period();
+    w_ptval_el0(period());
+    if cpuid() == 0 && missed > 0 {
+        account(missed);
+    }
+}
+
 pub fn sleep(millis: u64) {
     let lock = TICKLOCK.acquire();
     let mut start = lock.as_ref().0;
@@ -111,13 +191,111 @@ pub fn sleep(millis: u64) {
     }
 }
 
-pub fn read_tick() -> u64 {
-    let lock = TICKLOCK.acquire();
-    lock.as_ref().0
+// one slot per task, a task is in at most one timed sleep
+const NSLEEPERS: usize = sched::NTASKS;
+
+#[derive(Clone, Copy)]
+struct Sleeper {
+    // in CNTPCT units
+    deadline: u64,
+    chan: u64,
+    used: bool,
+}
+
+static SLEEPERS: Lock<[Sleeper; NSLEEPERS]> = Lock::new(
+    "sleepers",
+    [Sleeper {
+        deadline: 0,
+        chan: 0,
+        used: false,
+    }; NSLEEPERS],
+);
+
+fn ns2cnt(ns: u64) -> u64 {
+    ((ns as u128 * r_freq() as u128) / 1_000_000_000) as u64
+}
+
+pub fn cnt2ns(cnt: u64) -> u64 {
+    let freq = r_freq();
+    if freq == 0 {
+        return 0;
+    }
+    ((cnt as u128 * 1_000_000_000) / freq as u128) as u64
+}
+
+// keeps waking the chan every tick until the sleeper disarms
+fn expire() {
+    let now = r_pct_el0();
+    let lock = SLEEPERS.acquire();
+    for s in lock.as_ref().iter() {
+        if s.used && s.deadline <= now {
+            wakeup(s.chan);
+        }
+    }
+}
+
+// counter value ns from now
+pub fn deadline(ns: u64) -> u64 {
+    r_pct_el0().saturating_add(ns2cnt(ns))
+}
+
+pub fn passed(deadline: u64) -> bool {
+    r_pct_el0() >= deadline
+}
+
+// for sleepers on their own chan and lock, the slot goes to disarm
+pub fn arm(deadline: u64, chan: u64) -> Result<usize, ()> {
+    let lock = SLEEPERS.acquire();
+    let slot = lock.as_ref().iter().position(|s| !s.used).ok_or(())?;
+    lock.as_mut()[slot] = Sleeper {
+        deadline,
+        chan,
+        used: true,
+    };
+    Ok(slot)
+}
+
+pub fn disarm(slot: usize) {
+    let lock = SLEEPERS.acquire();
+    lock.as_mut()[slot].used = false;
 }
 
-pub fn add2wait() {
+fn next_deadline() -> u64 {
+    let lock = SLEEPERS.acquire();
+    lock.as_ref()
+        .iter()
+        .filter(|s| s.used)
+        .map(|s| s.deadline)
+        .min()
+        .unwrap_or(u64::MAX)
+}
+
+// interruptible, Err holds the ns left when a signal came in and is
+// None when there was no free slot
+pub fn sleep_ns(ns: u64) -> Result<(), Option<u64>> {
+    let deadline = deadline(ns);
+    let lock = SLEEPERS.acquire();
+    let slot = lock.as_ref().iter().position(|s| !s.used).ok_or(None)?;
+    // sleeps on the address of its slot
+    let chan = &lock.as_ref()[slot] as *const Sleeper as u64;
+    lock.as_mut()[slot] = Sleeper {
+        deadline,
+        chan,
+        used: true,
+    };
+
+    let mut res = Ok(());
+    while r_pct_el0() < deadline {
+        if sleep_intr(chan, lock.get_lock()).is_err() {
+            res = Err(Some(cnt2ns(deadline.saturating_sub(r_pct_el0()))));
+            break;
+        }
+    }
+    lock.as_mut()[slot].used = false;
+    res
+}
+
+pub fn read_tick() -> u64 {
     let lock = TICKLOCK.acquire();
-    let task = mycpu().get_task().unwrap();
-    lock.as_mut().1.add(task as *mut Task);
+    lock.as_ref().0
 }
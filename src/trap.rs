@@ -1,9 +1,9 @@
 use crate::{
-    _boot_stack, _boot_stack_btm, arch,
+    _boot_stack, _boot_stack_btm, arch, blk, dt,
     heap::SyncUnsafeCell,
-    p9, print,
+    log, p9,
     sched::{self, mycpu},
-    svc, timer, uart,
+    smp, svc, timer, uart, virtconsole, virtinput,
     vm::{self},
     wfi,
 };
@@ -53,55 +53,103 @@ impl LowerHex for Frame {
     }
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub extern "C" fn irq_handler(frame: &Frame) {
     let idx = gic_ack();
     gic_eoi(idx);
-    match idx {
+    // sgis carry the sending core in bits 10..12
+    match idx & 0x3ff {
+        smp::IPI_BARRIER => smp::barrier_ack(),
         30 => timer::handle_tik(frame.el()),
         33 => uart::handle_rx(),
-        78 => p9::irq_handle(),
+        x if x == p9::irq() && p9::present() => p9::irq_handle(),
+        x if x == blk::irq() && blk::present() => blk::irq_handle(),
+        x if x == virtconsole::irq() && virtconsole::present() => virtconsole::irq_handle(),
+        x if x == virtinput::irq() && virtinput::present() => virtinput::irq_handle(),
         _ => {
-            print!("unhandled irq: {}\n", idx);
+            log!(Error, "unhandled irq: {}\n", idx);
             loop {
                 wfi!();
             }
         }
     };
+    if frame.el() == 0 {
+        let task = mycpu().get_task().unwrap();
+        task.trapframe = frame as *const Frame as u64;
+        sched::do_signals();
+    }
+}
+
+// ESR_EL1.EC
+struct EC;
+impl EC {
+    const UNKNOWN: u64 = 0b000000;
+    const SVC64: u64 = 0b010101;
+    const IABT_LOW: u64 = 0b100000;
+    const IABT_CUR: u64 = 0b100001;
+    const PC_ALIGN: u64 = 0b100010;
+    const DABT_LOW: u64 = 0b100100;
+    const DABT_CUR: u64 = 0b100101;
+    const SP_ALIGN: u64 = 0b100110;
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub extern "C" fn sync_handler(frame: &Frame) {
     let task = mycpu().get_task().unwrap();
-    task.trapframe = frame as *const Frame as u64;
-    let esr = arch::r_esr_el1();
-    if esr >> 26 == 0b010101 {
-        return svc::handle();
+    // a fault inside a syscall must not lose the user frame
+    if frame.el() == 0 {
+        task.trapframe = frame as *const Frame as u64;
     }
-    if esr >> 26 == 0x24 || esr >> 26 == 0x25 {
-        return sched::dabt_handler();
+    let esr = arch::r_esr_el1();
+    match esr >> 26 {
+        EC::SVC64 => {
+            svc::handle();
+            return sched::do_signals();
+        }
+        EC::DABT_LOW | EC::DABT_CUR => sched::dabt_handler(),
+        EC::IABT_LOW | EC::IABT_CUR => sched::iabt_handler(),
+        ec => {
+            let far = arch::r_far_el1();
+            let elr = arch::r_elr_el1();
+            log!(
+                Error,
+                "sync... pid {} far = 0x{:x} erl: 0x{:x} ret pc: 0x{:x} esr: {:x}\n",
+                task.pid,
+                far,
+                elr,
+                frame.pc,
+                ec
+            );
+            if frame.el() == 0 {
+                // the task's own fault, the rest of the system goes on
+                let sig = match ec {
+                    EC::PC_ALIGN | EC::SP_ALIGN => sched::SIGBUS,
+                    _ => sched::SIGILL,
+                };
+                sched::fatal_trap(sig);
+                return;
+            }
+            let sp = arch::r_sp();
+            let btm = unsafe { (&_boot_stack_btm) as *const u64 as u64 };
+            let depth = sp.wrapping_sub(btm);
+            log!(
+                Error,
+                "kernel stack overflow =  {} depth: {}\n",
+                sp <= btm,
+                depth
+            );
+            log!(Error, "{:?}\n", frame);
+            loop {
+                wfi!();
+            }
+        }
     }
-    let far = arch::r_far_el1();
-    let elr = arch::r_elr_el1();
-    print!(
-        "sync... pid {} far = 0x{:x} erl: 0x{:x} ret pc: 0x{:x} esr: {:x}\n",
-        task.pid,
-        far,
-        elr,
-        frame.pc,
-        esr >> 26
-    );
-    let sp = arch::r_sp();
-    let btm = unsafe { (&_boot_stack_btm) as *const u64 as u64 };
-    let depth = sp.wrapping_sub(btm);
-    print!("kernel stack overflow =  {} depth: {}\n", sp <= btm, depth);
-    print!("{:?}\n", frame);
-    loop {
-        wfi!();
+    if frame.el() == 0 {
+        sched::do_signals();
     }
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[unsafe(naked)]
 #[allow(unused)]
 pub extern "C" fn _sync_handler() {
@@ -152,7 +200,7 @@ pub extern "C" fn _sync_handler() {
     );
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[unsafe(naked)]
 #[allow(unused)]
 pub extern "C" fn _irq_handler() {
@@ -203,14 +251,14 @@ pub extern "C" fn _irq_handler() {
     );
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[unsafe(naked)]
 #[allow(unused)]
 pub extern "C" fn _other_handler() {
     naked_asm!("1:", "wfi", "b 1b");
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 #[unsafe(naked)]
 #[allow(unused)]
 #[unsafe(link_section = ".text.vector")]
@@ -260,7 +308,7 @@ fn gic_dist() -> usize {
 }
 
 #[allow(unused)]
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub fn gic_enable() {
     unsafe {
         let x = (gic_cpu() + 4) as *mut u32;
@@ -272,6 +320,8 @@ pub fn gic_enable() {
         let x = gic_cpu() as *mut u32;
         x.write_volatile(1);
     };
+    // banked, every core enables its own
+    gic_enable_intr(smp::IPI_BARRIER as usize);
 }
 
 #[allow(unused)]
@@ -295,12 +345,24 @@ pub fn gic_enable_intr(idx: usize) {
     let back = idx / 32;
     let bit = idx % 32;
     let back_ptr = (gic_dist() + 0x100) as *mut u32;
+    // spis go nowhere until targeted, the boot core takes them all.
+    // sgis and ppis are banked per core
+    if idx >= 32 {
+        let target = (gic_dist() + 0x800 + idx) as *mut u8;
+        unsafe { target.write_volatile(1) };
+    }
     unsafe {
         let v = back_ptr.add(back).read_volatile() | (1u32 << bit);
         back_ptr.add(back).write_volatile(v);
     }
 }
 
+// GICD_SGIR target list filter 1, every core but this one
+pub fn gic_sgi_others(id: u32) {
+    let ptr = (gic_dist() + 0xf00) as *mut u32;
+    unsafe { ptr.write_volatile(1 << 24 | id) };
+}
+
 #[allow(unused)]
 pub fn gic_disable_intr(idx: usize) {
     let back = idx / 32;
@@ -313,9 +375,10 @@ pub fn gic_disable_intr(idx: usize) {
 }
 
 pub fn init() {
-    let map = vm::map(0x8000000, 1, vm::PR_PW).unwrap();
+    let (dist, cpu) = dt::gic();
+    let map = vm::map(dist, 1, vm::PR_PW).unwrap();
     unsafe { GIC_DIST.0.get().write(map) };
-    let map = vm::map(0x8010000, 1, vm::PR_PW).unwrap();
+    let map = vm::map(cpu, 1, vm::PR_PW).unwrap();
     unsafe { GIC_CPU.0.get().write(map) };
     gic_enable();
 }
@@ -1,4 +1,10 @@
-use crate::{heap::SyncUnsafeCell, print};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    heap::SyncUnsafeCell,
+    print,
+    sched::{SIGINT, SIGQUIT, SIGTSTP},
+};
 
 #[allow(non_camel_case_types)]
 pub struct TC_IFLAGS;
@@ -72,14 +78,18 @@ impl V {
     pub const EOL2: usize = 16;
 }
 
-pub const NCCS: usize = 32;
+// the kernel's, the libc struct termios is bigger and translated
+pub const NCCS: usize = 19;
 #[allow(non_camel_case_types)]
 pub type cc_t = u8;
 #[allow(non_camel_case_types)]
 pub type speed_t = u32;
 
+// struct termios of asm-generic/termbits.h, 36 bytes and no speeds. the
+// old one was libc's with 32 ccs and the speeds, copying its cc array out
+// ran past a kernel sized buffer
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Termios {
     i: u32,
     o: u32,
@@ -87,8 +97,15 @@ pub struct Termios {
     l: u32,
     line: cc_t,
     cc: [cc_t; NCCS],
-    ispeed: u32,
-    ospeed: u32,
+}
+
+// struct termios2, TCGETS2 and TCSETS2
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Termios2 {
+    t: Termios,
+    ispeed: speed_t,
+    ospeed: speed_t,
 }
 
 #[repr(C)]
@@ -104,52 +121,73 @@ pub struct Winsize {
 // c_oflag = OPOST | ONLCR
 // c_lflag = ICANON | ECHO | ISIG
 
-const fn make_cc() -> [u8; 32] {
-    let mut arr = [0; 32];
+const fn make_cc() -> [cc_t; NCCS] {
+    let mut arr = [0; NCCS];
     arr[V::INTR] = 3;
+    arr[V::QUIT] = 28;
     arr[V::ERASE] = 127;
     arr[V::KILL] = 21;
     arr[V::EOF] = 4;
+    arr[V::MIN] = 1;
+    arr[V::SUSP] = 26;
     arr
 }
 
-static TERMIOS: SyncUnsafeCell<Termios> = SyncUnsafeCell::new(Termios {
-    i: TC_IFLAGS::ICRNL,
-    o: TC_OFLAGS::OPOST | TC_OFLAGS::ONLCR,
-    c: 0,
-    l: TC_LFLAGS::ICANON | TC_LFLAGS::ECHO | TC_LFLAGS::ISIG,
-    line: 0,
-    cc: make_cc(),
+static TERMIOS: SyncUnsafeCell<Termios2> = SyncUnsafeCell::new(Termios2 {
+    t: Termios {
+        i: TC_IFLAGS::ICRNL,
+        o: TC_OFLAGS::OPOST | TC_OFLAGS::ONLCR,
+        c: 0,
+        l: TC_LFLAGS::ICANON | TC_LFLAGS::ECHO | TC_LFLAGS::ISIG,
+        line: 0,
+        cc: make_cc(),
+    },
     ispeed: 9600,
     ospeed: 9600,
 });
 
-pub fn get_termios(ptr: *mut Termios) -> u64 {
-    let from = TERMIOS.as_ref();
-    let to = unsafe { ptr.as_mut().unwrap() };
+// no process groups, the foreground is one pid: the last one set with
+// TIOCSPGRP, or the first to read the console while nobody is
+static FG: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+pub fn fg() -> Option<usize> {
+    match FG.load(Ordering::Acquire) {
+        usize::MAX => None,
+        pid => Some(pid),
+    }
+}
+
+pub fn set_fg(pid: usize) {
+    FG.store(pid, Ordering::Release);
+}
+
+pub fn claim_fg(pid: usize) {
+    let _ = FG.compare_exchange(usize::MAX, pid, Ordering::AcqRel, Ordering::Acquire);
+}
 
-    to.i = from.i;
-    to.o = from.o;
-    to.c = from.c;
-    to.l = from.l;
+// on exit, the slot may go to someone else
+pub fn clear_fg(pid: usize) {
+    let _ = FG.compare_exchange(pid, usize::MAX, Ordering::AcqRel, Ordering::Acquire);
+}
 
-    to.line = from.line;
-    // TODO stack smash
-    // to.cc = from.cc;
+// the caller checks the user range, alignment isn't
+pub fn get_termios(ptr: *mut Termios) -> u64 {
+    unsafe { ptr.write_unaligned(TERMIOS.as_ref().t) };
     0
 }
 
 pub fn set_termios(ptr: *const Termios) -> u64 {
-    let to = TERMIOS.as_mut();
-    let from = unsafe { ptr.as_ref().unwrap() };
+    TERMIOS.as_mut().t = unsafe { ptr.read_unaligned() };
+    0
+}
 
-    to.i = from.i;
-    to.o = from.o;
-    to.c = from.c;
-    to.l = from.l;
-    to.line = from.line;
-    to.cc = from.cc;
+pub fn get_termios2(ptr: *mut Termios2) -> u64 {
+    unsafe { ptr.write_unaligned(*TERMIOS.as_ref()) };
+    0
+}
 
+pub fn set_termios2(ptr: *const Termios2) -> u64 {
+    *TERMIOS.as_mut() = unsafe { ptr.read_unaligned() };
     0
 }
 
@@ -163,21 +201,48 @@ pub fn get_winsz(ptr: *mut Winsize) -> u64 {
 }
 
 pub fn echo() -> bool {
-    let t = TERMIOS.as_ref();
+    let t = &TERMIOS.as_ref().t;
     t.l & TC_LFLAGS::ECHO != 0
 }
 
 pub fn icanon() -> bool {
-    let t = TERMIOS.as_ref();
+    let t = &TERMIOS.as_ref().t;
     t.l & TC_LFLAGS::ICANON != 0
 }
 
 pub fn opost() -> bool {
-    let t = TERMIOS.as_ref();
+    let t = &TERMIOS.as_ref().t;
     t.o & TC_OFLAGS::OPOST != 0
 }
 
 pub fn onlcr() -> bool {
-    let t = TERMIOS.as_ref();
+    let t = &TERMIOS.as_ref().t;
     t.o & TC_OFLAGS::ONLCR != 0
 }
+
+// the signal c stands for under ISIG, a zero cc is disabled
+pub fn sig_char(c: u8) -> Option<u32> {
+    let t = &TERMIOS.as_ref().t;
+    if t.l & TC_LFLAGS::ISIG == 0 || c == 0 {
+        return None;
+    }
+    if c == t.cc[V::INTR] {
+        Some(SIGINT)
+    } else if c == t.cc[V::QUIT] {
+        Some(SIGQUIT)
+    } else if c == t.cc[V::SUSP] {
+        Some(SIGTSTP)
+    } else {
+        None
+    }
+}
+
+pub fn noflsh() -> bool {
+    let t = &TERMIOS.as_ref().t;
+    t.l & TC_LFLAGS::NOFLSH != 0
+}
+
+pub fn cc(idx: usize) -> cc_t {
+    let t = &TERMIOS.as_ref().t;
+    t.cc[idx]
+}
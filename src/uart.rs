@@ -1,10 +1,41 @@
 use core::cell::UnsafeCell;
 
-use crate::{cons, heap::SyncUnsafeCell, ptr2ref, trap::gic_enable_intr, vm};
+use crate::{
+    cons,
+    heap::SyncUnsafeCell,
+    ptr2ref,
+    spin::{Lock, LockGuard},
+    trap::gic_enable_intr,
+    vm,
+};
+
+// interrupt mask, status and clear bits
+struct INT;
+impl INT {
+    const RX: u32 = 1 << 4;
+    const RT: u32 = 1 << 6;
+}
+
+struct FL;
+impl FL {
+    const RXFE: u32 = 1 << 4;
+}
 
 static MAP: SyncUnsafeCell<usize> = SyncUnsafeCell(UnsafeCell::new(0));
 
-// static LOCK: spin::Lock<()> = spin::Lock::new("uart", ());
+// whole print!s and console writes go out in one piece
+static LOCK: Lock<()> = Lock::new("uart", ());
+
+// None when this cpu already holds it: a print nested in a print, or a
+// panic in the middle of one. interrupts stay off while it is held so
+// irq handlers can't spin on it
+pub fn lock() -> Option<LockGuard<'static, ()>> {
+    if LOCK.holding() {
+        None
+    } else {
+        Some(LOCK.acquire())
+    }
+}
 
 #[inline]
 fn write_char(c: u8, map: usize) {
@@ -12,8 +43,10 @@ fn write_char(c: u8, map: usize) {
     unsafe { dr.write_volatile(c) };
 }
 
-pub fn putc(c: u8) {
-    write_char(c, unsafe { MAP.0.get().read() });
+pub fn write(buf: &[u8]) {
+    let lock = lock();
+    write_bytes(buf, unsafe { MAP.0.get().read() });
+    drop(lock);
 }
 
 fn write_bytes(b: &[u8], map: usize) {
@@ -32,17 +65,13 @@ impl core::fmt::Write for Writer {
     }
 }
 
-impl Writer {
-    pub fn write_bytes(buf: &[u8]) {
-        write_bytes(buf, unsafe { MAP.0.get().read() });
-    }
-}
-
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {{
+        let lock = $crate::uart::lock();
         let mut stdout = $crate::uart::Writer;
         core::fmt::write(&mut stdout, format_args!($($arg)*)).unwrap();
+        drop(lock);
     }};
 }
 
@@ -66,8 +95,10 @@ pub fn enable_rx(map: usize) {
         let cr = base.read_volatile() | 1u32 << 4;
         base.write_volatile(cr);
 
+        // with the fifo on, rx only fires at the trigger level, whatever
+        // is left below it comes in through the receive timeout
         let base = (map + 0x38) as *mut u32;
-        let cr = base.read_volatile() | 1u32 << 4;
+        let cr = base.read_volatile() | INT::RX | INT::RT;
         base.write_volatile(cr);
     }
     gic_enable_intr(33);
@@ -84,14 +115,13 @@ pub fn enable_tx(map: usize) {
 fn clr_rx() {
     let base = (unsafe { MAP.0.get().read() } + 0x44) as *mut u32;
     unsafe {
-        let cr = 1u32 << 4;
-        base.write_volatile(cr);
+        base.write_volatile(INT::RX | INT::RT);
     }
 }
 
 fn read() -> u8 {
     let dr = (unsafe { MAP.0.get().read() }) as *const u8;
-    unsafe { *dr }
+    unsafe { dr.read_volatile() }
 }
 
 #[inline]
@@ -99,11 +129,10 @@ fn read_fl() -> u32 {
     unsafe { ((MAP.0.get().read() + 0x18) as *const u32).read_volatile() }
 }
 
+// cleared before the drain, a byte landing during it raises it again
 pub fn handle_rx() {
-    while read_fl() & 0b10000 == 0 {
-        let c = read();
-        cons::push_char(c);
-        // print!("uart... {}\n", c);
-    }
     clr_rx();
+    while read_fl() & FL::RXFE == 0 {
+        cons::push_char(read());
+    }
 }
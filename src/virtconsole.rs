@@ -0,0 +1,165 @@
+use crate::{
+    cons, dsb,
+    spin::Lock,
+    trap::gic_enable_intr,
+    virtio::{self, Q, Regs, Status, init_dev_common},
+};
+use core::{
+    arch::asm,
+    cmp::min,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+const QSIZE: usize = 8;
+const BUFSZ: usize = 64;
+
+// port 0 only, no VIRTIO_CONSOLE_F_MULTIPORT
+const RXQ: u32 = 0;
+const TXQ: u32 = 1;
+
+struct VirtioCons {
+    regs: Option<NonNull<Regs>>,
+    rx: Q<QSIZE>,
+    tx: Q<QSIZE>,
+    rx_bufs: [[u8; BUFSZ]; QSIZE],
+    tx_buf: [u8; BUFSZ],
+}
+
+static VCONS: Lock<VirtioCons> = Lock::new(
+    "virtio-cons",
+    VirtioCons {
+        regs: None,
+        rx: Q::new(),
+        tx: Q::new(),
+        rx_bufs: [[0; BUFSZ]; QSIZE],
+        tx_buf: [0; BUFSZ],
+    },
+);
+
+// 0 when no device was found
+static IRQ: AtomicU32 = AtomicU32::new(0);
+
+pub fn present() -> bool {
+    IRQ.load(Ordering::Relaxed) != 0
+}
+
+pub fn irq() -> u32 {
+    IRQ.load(Ordering::Relaxed)
+}
+
+fn post_rx(vc: &mut VirtioCons, buf_idx: usize) {
+    let d = vc.rx.alloc_desc().unwrap();
+    let ptr = vc.rx_bufs[buf_idx].as_ptr();
+    vc.rx
+        .get_desc_mut(d as usize)
+        .set_writable()
+        .set_data(ptr as u64)
+        .set_len(BUFSZ as u32);
+    vc.rx.set_desc_data(d as usize, buf_idx as u64);
+    vc.rx.add_avail(d);
+}
+
+pub fn init(regs: &mut Regs, irq: u32) {
+    let lock = VCONS.acquire();
+    let vc = lock.as_mut();
+
+    if vc.regs.is_some() {
+        // TODO
+        return;
+    }
+
+    vc.regs = NonNull::new(regs as *mut Regs);
+
+    init_dev_common(regs, 0, 0);
+
+    virtio::set_q_len(regs, RXQ, vc.rx.len());
+    virtio::set_used_area(regs, vc.rx.used_area_paddr());
+    virtio::set_avail_area(regs, vc.rx.avail_area_paddr());
+    virtio::set_desc_area(regs, vc.rx.desc_area_paddr());
+
+    virtio::set_q_len(regs, TXQ, vc.tx.len());
+    virtio::set_used_area(regs, vc.tx.used_area_paddr());
+    virtio::set_avail_area(regs, vc.tx.avail_area_paddr());
+    virtio::set_desc_area(regs, vc.tx.desc_area_paddr());
+    dsb!();
+
+    for i in 0..QSIZE {
+        post_rx(vc, i);
+    }
+
+    virtio::set_ready(regs, RXQ);
+    virtio::set_ready(regs, TXQ);
+
+    let status: u32 = regs.read(Regs::STATUS);
+    regs.write(Regs::STATUS, status | Status::DRIVER_OK);
+    dsb!();
+
+    virtio::notify_q(regs, RXQ);
+
+    IRQ.store(irq, Ordering::Relaxed);
+    gic_enable_intr(irq as usize);
+}
+
+// synchronous, the transmit queue is drained before returning
+pub fn write(buf: &[u8]) -> Result<usize, ()> {
+    let lock = VCONS.acquire();
+    let vc = lock.as_mut();
+    if vc.regs.is_none() {
+        return Err(());
+    }
+    let regs = unsafe { vc.regs.unwrap().as_mut() };
+
+    let mut done = 0;
+    while done < buf.len() {
+        let n = min(BUFSZ, buf.len() - done);
+        vc.tx_buf[0..n].copy_from_slice(&buf[done..done + n]);
+
+        let d = vc.tx.alloc_desc().ok_or(())?;
+        let ptr = vc.tx_buf.as_ptr();
+        vc.tx
+            .get_desc_mut(d as usize)
+            .set_data(ptr as u64)
+            .set_len(n as u32);
+
+        let old = vc.tx.add_avail(d);
+        virtio::notify_q(regs, TXQ);
+        vc.tx.wait_use(old);
+        vc.tx.pop_used();
+        done += n;
+    }
+
+    Ok(done)
+}
+
+pub fn irq_handle() {
+    let mut input = [0u8; BUFSZ * QSIZE];
+    let mut n = 0;
+
+    let lock = VCONS.acquire();
+    let vc = lock.as_mut();
+    assert!(vc.regs.is_some());
+    let regs = unsafe { vc.regs.unwrap().as_mut() };
+    let irq_status = virtio::get_irq_status(regs);
+
+    while let Some((_, buf_idx)) = vc.rx.peek_used() {
+        let used = vc.rx.used.ring[vc.rx.used_pos as usize % QSIZE].read();
+        let len = min(used.len as usize, BUFSZ);
+        let buf_idx = buf_idx as usize;
+        if n + len <= input.len() {
+            input[n..n + len].copy_from_slice(&vc.rx_bufs[buf_idx][0..len]);
+            n += len;
+        }
+        vc.rx.pop_used();
+        post_rx(vc, buf_idx);
+    }
+
+    virtio::notify_q(regs, RXQ);
+    virtio::irq_ack(regs, irq_status);
+    drop(lock);
+
+    // push_char may echo back through write()
+    for i in 0..n {
+        cons::push_char(input[i]);
+    }
+}
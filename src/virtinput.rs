@@ -0,0 +1,262 @@
+use crate::{
+    cons, dsb,
+    spin::Lock,
+    trap::gic_enable_intr,
+    virtio::{self, Q, Regs, Status, init_dev_common},
+};
+use core::{
+    arch::asm,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+const QSIZE: usize = 16;
+
+const EVENTQ: u32 = 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    kind: u16,
+    code: u16,
+    value: u32,
+}
+
+impl Event {
+    const fn zeroed() -> Event {
+        Event {
+            kind: 0,
+            code: 0,
+            value: 0,
+        }
+    }
+}
+
+struct Ev;
+impl Ev {
+    const SYN: u16 = 0x00;
+    const KEY: u16 = 0x01;
+}
+
+struct Key;
+impl Key {
+    const LEFTCTRL: u16 = 29;
+    const LEFTSHIFT: u16 = 42;
+    const RIGHTSHIFT: u16 = 54;
+    const RIGHTCTRL: u16 = 97;
+}
+
+// linux input keycodes 0..58 to ascii, unshifted and shifted
+const KEYMAP: [(u8, u8); 58] = [
+    (0, 0),
+    (27, 27),
+    (b'1', b'!'),
+    (b'2', b'@'),
+    (b'3', b'#'),
+    (b'4', b'$'),
+    (b'5', b'%'),
+    (b'6', b'^'),
+    (b'7', b'&'),
+    (b'8', b'*'),
+    (b'9', b'('),
+    (b'0', b')'),
+    (b'-', b'_'),
+    (b'=', b'+'),
+    (127, 127),
+    (b'\t', b'\t'),
+    (b'q', b'Q'),
+    (b'w', b'W'),
+    (b'e', b'E'),
+    (b'r', b'R'),
+    (b't', b'T'),
+    (b'y', b'Y'),
+    (b'u', b'U'),
+    (b'i', b'I'),
+    (b'o', b'O'),
+    (b'p', b'P'),
+    (b'[', b'{'),
+    (b']', b'}'),
+    (b'\r', b'\r'),
+    (0, 0),
+    (b'a', b'A'),
+    (b's', b'S'),
+    (b'd', b'D'),
+    (b'f', b'F'),
+    (b'g', b'G'),
+    (b'h', b'H'),
+    (b'j', b'J'),
+    (b'k', b'K'),
+    (b'l', b'L'),
+    (b';', b':'),
+    (b'\'', b'"'),
+    (b'`', b'~'),
+    (0, 0),
+    (b'\\', b'|'),
+    (b'z', b'Z'),
+    (b'x', b'X'),
+    (b'c', b'C'),
+    (b'v', b'V'),
+    (b'b', b'B'),
+    (b'n', b'N'),
+    (b'm', b'M'),
+    (b',', b'<'),
+    (b'.', b'>'),
+    (b'/', b'?'),
+    (0, 0),
+    (b'*', b'*'),
+    (0, 0),
+    (b' ', b' '),
+];
+
+struct VirtioInput {
+    regs: Option<NonNull<Regs>>,
+    vq: Q<QSIZE>,
+    events: [Event; QSIZE],
+    shift: bool,
+    ctrl: bool,
+}
+
+static INPUT: Lock<VirtioInput> = Lock::new(
+    "virtio-input",
+    VirtioInput {
+        regs: None,
+        vq: Q::new(),
+        events: [Event::zeroed(); QSIZE],
+        shift: false,
+        ctrl: false,
+    },
+);
+
+// 0 when no device was found
+static IRQ: AtomicU32 = AtomicU32::new(0);
+
+pub fn present() -> bool {
+    IRQ.load(Ordering::Relaxed) != 0
+}
+
+pub fn irq() -> u32 {
+    IRQ.load(Ordering::Relaxed)
+}
+
+fn post_event(vi: &mut VirtioInput, idx: usize) {
+    let d = vi.vq.alloc_desc().unwrap();
+    let ptr = &vi.events[idx] as *const Event;
+    vi.vq
+        .get_desc_mut(d as usize)
+        .set_writable()
+        .set_data(ptr as u64)
+        .set_len(size_of::<Event>() as u32);
+    vi.vq.set_desc_data(d as usize, idx as u64);
+    vi.vq.add_avail(d);
+}
+
+pub fn init(regs: &mut Regs, irq: u32) {
+    let lock = INPUT.acquire();
+    let vi = lock.as_mut();
+
+    if vi.regs.is_some() {
+        // TODO more than one keyboard
+        return;
+    }
+
+    vi.regs = NonNull::new(regs as *mut Regs);
+
+    init_dev_common(regs, 0, 0);
+
+    virtio::set_q_len(regs, EVENTQ, vi.vq.len());
+    virtio::set_used_area(regs, vi.vq.used_area_paddr());
+    virtio::set_avail_area(regs, vi.vq.avail_area_paddr());
+    virtio::set_desc_area(regs, vi.vq.desc_area_paddr());
+    dsb!();
+
+    for i in 0..QSIZE {
+        post_event(vi, i);
+    }
+
+    virtio::set_ready(regs, EVENTQ);
+
+    let status: u32 = regs.read(Regs::STATUS);
+    regs.write(Regs::STATUS, status | Status::DRIVER_OK);
+    dsb!();
+
+    virtio::notify_q(regs, EVENTQ);
+
+    IRQ.store(irq, Ordering::Relaxed);
+    gic_enable_intr(irq as usize);
+}
+
+// value: 0 release, 1 press, 2 autorepeat
+fn translate(vi: &mut VirtioInput, ev: &Event) -> Option<u8> {
+    if ev.kind != Ev::KEY {
+        return None;
+    }
+
+    match ev.code {
+        Key::LEFTSHIFT | Key::RIGHTSHIFT => {
+            vi.shift = ev.value != 0;
+            return None;
+        }
+        Key::LEFTCTRL | Key::RIGHTCTRL => {
+            vi.ctrl = ev.value != 0;
+            return None;
+        }
+        _ => {}
+    }
+
+    if ev.value == 0 || ev.code as usize >= KEYMAP.len() {
+        return None;
+    }
+
+    let (lo, hi) = KEYMAP[ev.code as usize];
+    let c = if vi.shift { hi } else { lo };
+    if c == 0 {
+        return None;
+    }
+
+    if vi.ctrl && c.is_ascii_alphabetic() {
+        return Some(c.to_ascii_lowercase() - b'a' + 1);
+    }
+    Some(c)
+}
+
+// reposted buffers can fill up again while the ring is drained, a full
+// batch goes to the console and the rest waits for the next round
+pub fn irq_handle() {
+    loop {
+        let mut input = [0u8; QSIZE];
+        let mut n = 0;
+        let mut full = false;
+
+        let lock = INPUT.acquire();
+        let vi = lock.as_mut();
+        assert!(vi.regs.is_some());
+        let regs = unsafe { vi.regs.unwrap().as_mut() };
+        let irq_status = virtio::get_irq_status(regs);
+
+        while let Some((_, idx)) = vi.vq.peek_used() {
+            if n == input.len() {
+                full = true;
+                break;
+            }
+            let idx = idx as usize;
+            let ev = vi.events[idx];
+            if let Some(c) = translate(vi, &ev) {
+                input[n] = c;
+                n += 1;
+            }
+            vi.vq.pop_used();
+            post_event(vi, idx);
+        }
+
+        virtio::notify_q(regs, EVENTQ);
+        virtio::irq_ack(regs, irq_status);
+        drop(lock);
+
+        for i in 0..n {
+            cons::push_char(input[i]);
+        }
+        if !full {
+            break;
+        }
+    }
+}
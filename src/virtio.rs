@@ -1,10 +1,13 @@
-use core::{arch::asm, hint::spin_loop, ptr::slice_from_raw_parts_mut};
+use core::{arch::asm, hint::spin_loop};
 
 use alloc::vec::Vec;
 
 use crate::{
-    blk, dsb, p9, print, rng,
-    stuff::BitSet128,
+    blk, dsb, dt, log, p9,
+    pm::{KB, align_b},
+    rng,
+    stuff::BitSet,
+    virtconsole, virtinput,
     vm::{self, map, map2},
 };
 
@@ -222,6 +225,13 @@ impl Regs {
     pub const CONFIG: usize = 0x0100;
 }
 
+// feature bits common to all devices, word 0
+pub struct Feature;
+impl Feature {
+    pub const INDIRECT_DESC: u64 = 1 << 28;
+    pub const EVENT_IDX: u64 = 1 << 29;
+}
+
 pub struct Status;
 impl Status {
     pub const ACKNOWLEDGE: u32 = 1;
@@ -296,6 +306,11 @@ impl VqDesc {
         self
     }
 
+    pub fn set_indirect(&mut self) -> &mut Self {
+        self.flags.write(self.flags.read() | Self::F_INDIRECT);
+        self
+    }
+
     pub fn set_len(&mut self, len: u32) -> &mut Self {
         self.len.write(len);
         self
@@ -371,14 +386,21 @@ impl<const N: usize> VqUsed<N> {
     }
 }
 
+// largest queue a Q can be made for
+pub const MAX_QSIZE: usize = 256;
+
 pub struct Q<const N: usize> {
     desc: [VqDesc; N],
     avail: VqAvail<N>,
     pub used: VqUsed<N>,
     // data
-    desc_bs: BitSet128,
+    desc_bs: BitSet<{ MAX_QSIZE / 64 }>,
     pub desc_data: [u64; N],
     pub used_pos: u16,
+    // VIRTIO_F_EVENT_IDX, avail_event/used_event replace the flags
+    event_idx: bool,
+    // avail.idx at the last notify
+    kicked: u16,
 }
 
 impl<const N: usize> Q<N> {
@@ -387,32 +409,46 @@ impl<const N: usize> Q<N> {
             desc: [VqDesc::zeroed(); N],
             avail: VqAvail::zeroed(),
             used: VqUsed::zeroed(),
-            desc_bs: BitSet128::new(N as u8),
+            desc_bs: BitSet::new(N),
             desc_data: [0; N],
             used_pos: 0,
+            event_idx: false,
+            kicked: 0,
         }
     }
 
+    // only once negotiated, otherwise the device never fills avail_event
+    pub fn set_event_idx(&mut self, on: bool) {
+        self.event_idx = on;
+    }
+
     pub fn alloc_desc(&mut self) -> Option<u16> {
         // return None;
         match self.desc_bs.first_clr() {
             Some(f) => {
                 self.desc_bs.set(f);
                 self.desc_data[f as usize] = 0;
+                // don't inherit NEXT/WRITE from the last chain
+                self.desc[f as usize] = VqDesc::zeroed();
                 Some(f as u16)
             }
             _ => None,
         }
     }
 
+    pub fn n_free(&self) -> usize {
+        // bits past N are kept set
+        self.desc_bs.n_clr()
+    }
+
     pub fn free_desc(&mut self, hidx: usize) {
-        self.desc_bs.clr(hidx as u8);
+        self.desc_bs.clr(hidx);
         let mut d = self.get_desc(hidx);
         let mut i = 1;
         while let Some(nidx) = d.get_next() {
             assert!(i < N);
-            assert!(self.desc_bs.tst(nidx as u8));
-            self.desc_bs.clr(nidx as u8);
+            assert!(self.desc_bs.tst(nidx as usize));
+            self.desc_bs.clr(nidx as usize);
             d = self.get_desc(nidx as usize);
             i += 1;
         }
@@ -422,6 +458,10 @@ impl<const N: usize> Q<N> {
         let used = (&self.used.ring[self.used_pos as usize % N]).read();
         self.free_desc(used.id as usize);
         self.used_pos = self.used_pos.wrapping_add(1);
+        // interrupt again for the next one we haven't seen, ignored
+        // without EVENT_IDX
+        self.avail.used_event.write(self.used_pos);
+        dsb!();
     }
 
     pub fn peek_used(&self) -> Option<(&VqDesc, u64)> {
@@ -446,6 +486,20 @@ impl<const N: usize> Q<N> {
         tail
     }
 
+    // one ring slot for a whole chain, table must stay put until the
+    // device hands the head back
+    pub fn set_indirect(&mut self, table: &mut [VqDesc]) -> Option<u16> {
+        for i in 1..table.len() {
+            table[i - 1].set_next(i as u16);
+        }
+        let d = self.alloc_desc()?;
+        self.desc[d as usize]
+            .set_data(table.as_ptr() as u64)
+            .set_len((table.len() * size_of::<VqDesc>()) as u32)
+            .set_indirect();
+        Some(d)
+    }
+
     pub fn get_desc_mut(&mut self, idx: usize) -> &mut VqDesc {
         &mut self.desc[idx]
     }
@@ -481,6 +535,17 @@ impl<const N: usize> Q<N> {
         used_idx
     }
 
+    // QUEUENOTIFY only if the device asked for it since the last kick
+    pub fn notify(&mut self, regs: &mut Regs, qpos: u32) {
+        let new = self.avail.idx.read();
+        let old = self.kicked;
+        self.kicked = new;
+        dsb!();
+        if !self.event_idx || need_event(self.used.avail_event.read(), new, old) {
+            notify_q(regs, qpos);
+        }
+    }
+
     pub fn len(&self) -> u32 {
         N as u32
     }
@@ -492,6 +557,44 @@ impl<const N: usize> Q<N> {
     }
 }
 
+// vring_need_event from the spec: did idx move past event going from
+// old to new
+#[inline]
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+// how much of ptr..ptr+len is physically contiguous from ptr on, the
+// heap only promises that within a page
+pub fn phys_run(ptr: u64, len: usize) -> usize {
+    let start = vm::v2p(ptr as usize).unwrap();
+    let mut n = core::cmp::min(len, 4096 - (ptr as usize % 4096));
+    while n < len && vm::v2p(ptr as usize + n).unwrap() == start + n {
+        n += core::cmp::min(len - n, 4096);
+    }
+    n
+}
+
+pub fn is_contiguous(ptr: u64, len: usize) -> bool {
+    len == 0 || phys_run(ptr, len) == len
+}
+
+// ptr..ptr+len cut where it stops being physically contiguous
+pub fn push_segs(table: &mut Vec<VqDesc>, ptr: u64, len: usize, writable: bool) {
+    let mut off = 0;
+    while off < len {
+        let addr = ptr + off as u64;
+        let n = phys_run(addr, len - off);
+        let mut d = VqDesc::zeroed();
+        d.set_data(addr).set_len(n as u32);
+        if writable {
+            d.set_writable();
+        }
+        table.push(d);
+        off += n;
+    }
+}
+
 #[inline]
 pub fn select_q(regs: &mut Regs, pos: u32) {
     regs.write(Regs::QUEUESEL, pos);
@@ -567,46 +670,67 @@ pub fn set_q_len(regs: &mut Regs, qpos: u32, len: u32) {
 
 pub fn init() {
     let perm = vm::PR_PW;
-    let maps = [
-        map(0xa000000, 1, perm).unwrap(),
-        map(0xa000000 + 4096, 1, perm).unwrap(),
-        map(0xa000000 + 4096 * 2, 1, perm).unwrap(),
-        map(0xa000000 + 4096 * 3, 1, perm).unwrap(),
-    ];
-    let mut irq_n = 0x10 + 32;
-    for m in 0..4 {
-        let regs = unsafe { slice_from_raw_parts_mut(maps[m] as *mut Regs, 8).as_mut() }.unwrap();
-        for i in 0..8 {
-            let reg = &mut regs[i];
-
-            assert!(reg.read::<u32>(Regs::MAGICVALUE) == 0x74726976);
-            assert!(reg.read::<u32>(Regs::VERSION) == 2);
-            let id: u32 = reg.read(Regs::DEVICEID);
-
-            match id {
-                2 => {
-                    // virtio-blk
-                    print!("virtio-blk found.\n");
-                    // blk::init(reg);
-                }
-                4 => {
-                    // virtio-rng
-                    print!("virtio-rng found.\n");
-                    // rng::init(reg);
-                }
-                9 => {
-                    // virtio-9p
-                    print!("virtio-9p found.\n");
-                    p9::init(reg, irq_n);
-                }
-                _ => {}
+    // slots are 0x200 apart, neighbours share a page
+    let mut page = (usize::MAX, 0);
+    let mut n = 0;
+    while let Some(dev) = dt::virtio_mmio(n) {
+        n += 1;
+        let pa = align_b(dev.base, 4 * KB);
+        if page.0 != pa {
+            page = (pa, map(pa, 1, perm).unwrap());
+        }
+        let reg = unsafe { ((page.1 + dev.base - pa) as *mut Regs).as_mut() }.unwrap();
+        let irq_n = dev.irq;
+
+        assert!(reg.read::<u32>(Regs::MAGICVALUE) == 0x74726976);
+        assert!(reg.read::<u32>(Regs::VERSION) == 2);
+        let id: u32 = reg.read(Regs::DEVICEID);
+
+        match id {
+            2 => {
+                // virtio-blk
+                log!(Info, "virtio-blk found.\n");
+                blk::init(reg, irq_n);
+            }
+            3 => {
+                // virtio-console
+                log!(Info, "virtio-console found.\n");
+                virtconsole::init(reg, irq_n);
+            }
+            4 => {
+                // virtio-rng
+                log!(Info, "virtio-rng found.\n");
+                rng::init(reg);
             }
-            irq_n += 1;
+            9 => {
+                // virtio-9p
+                log!(Info, "virtio-9p found.\n");
+                p9::init(reg, irq_n);
+            }
+            18 => {
+                // virtio-input
+                log!(Info, "virtio-input found.\n");
+                virtinput::init(reg, irq_n);
+            }
+            _ => {}
         }
     }
 }
 
-pub fn init_dev_common(reg: &mut Regs, features: u32) {
+fn device_features(reg: &mut Regs) -> u64 {
+    let mut features = 0;
+    for sel in 0..2u32 {
+        reg.write(Regs::DEVICEFEATURESSEL, sel);
+        dsb!();
+        let word: u32 = reg.read(Regs::DEVICEFEATURES);
+        features |= (word as u64) << (32 * sel);
+    }
+    features
+}
+
+// the device only gets what it offers out of wanted, returns that set.
+// anything in required it doesn't offer fails the device
+pub fn init_dev_common(reg: &mut Regs, wanted: u64, required: u64) -> u64 {
     reg.write::<u32>(Regs::STATUS, 0);
     dsb!();
     let mut status: u32 = reg.read(Regs::STATUS);
@@ -614,11 +738,20 @@ pub fn init_dev_common(reg: &mut Regs, features: u32) {
     dsb!();
     reg.write(Regs::STATUS, status | Status::DRIVER);
     dsb!();
-    reg.write(Regs::DEVICEFEATURESSEL, 0u32);
-    reg.write(Regs::DRIVERFEATURESSEL, 0u32);
-    dsb!();
-    // let device_features: u32 = reg.read(Regs::DEVICEFEATURES);
-    reg.write(Regs::DRIVERFEATURES, features);
+
+    let offered = device_features(reg);
+    if offered & required != required {
+        reg.write(Regs::STATUS, status | Status::FAILED);
+        dsb!();
+        panic!("virt missing features: {:x}\n", required & !offered);
+    }
+
+    let features = offered & (wanted | required);
+    for sel in 0..2u32 {
+        reg.write(Regs::DRIVERFEATURESSEL, sel);
+        dsb!();
+        reg.write(Regs::DRIVERFEATURES, (features >> (32 * sel)) as u32);
+    }
     status = reg.read(Regs::STATUS);
     dsb!();
     reg.write(Regs::STATUS, status | Status::FEATURES_OK);
@@ -627,4 +760,27 @@ pub fn init_dev_common(reg: &mut Regs, features: u32) {
     if (status & Status::FEATURES_OK) == 0 {
         panic!("virt feature not ok.");
     }
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn need_event_window() {
+        // the device asked to hear about index 5
+        assert!(need_event(5, 6, 5));
+        assert!(need_event(5, 8, 2));
+        assert!(!need_event(5, 5, 2));
+        assert!(!need_event(5, 9, 6));
+        assert!(!need_event(5, 5, 5));
+    }
+
+    #[test]
+    fn need_event_wraps() {
+        assert!(need_event(0xffff, 2, 0xfffe));
+        assert!(need_event(1, 3, 0xfff0));
+        assert!(!need_event(0xfff0, 3, 0xfff8));
+    }
 }
@@ -2,13 +2,13 @@ use alloc::collections::linked_list::LinkedList;
 
 use crate::{
     _bss_end, _data_end, _rodata_end, _text_end, _user_end,
-    arch::{self, tlbi_vaee1},
-    dsb, isb,
+    arch::{self, tlbi_aside1, tlbi_aside1is, tlbi_vaee1, tlbi_vaee1is},
+    dsb, dsb_ish, dsb_ishst, isb,
     pm::{GB, KB, MB},
     print,
     sched::{self},
-    stuff::{BitSet128, as_slice, as_slice_mut, defer},
-    tlbi_vmalle1,
+    stuff::{BitSet, as_slice, as_slice_mut, defer},
+    tlbi_vmalle1, tlbi_vmalle1is,
 };
 use core::{arch::asm, cell::UnsafeCell, mem};
 use core::{fmt::Display, ptr::NonNull};
@@ -107,19 +107,15 @@ fn zero_pt_slice(ptr: &mut [u64]) {
     }
 }
 
-fn pt_alloc_if_0(idx: usize, pt: &mut [u64]) -> &mut [u64] {
+fn pt_alloc_if_0(idx: usize, pt: &mut [u64]) -> Result<&mut [u64], Error> {
     assert!(PT.holding());
 
     assert!(pt.len() == 512);
     let mut nxt_pt = pt[idx];
     if nxt_pt == 0 {
-        nxt_pt = match pm::alloc(4096) {
-            Ok(ptr) => {
-                zero_pt((ptr as u64 + VOFFT) as *mut u64);
-                ptr as u64 | 3
-            }
-            _ => unreachable!(), /*TODO handle error*/
-        };
+        let ptr = pm::alloc(4096).map_err(|_| Error::Alloc)?;
+        zero_pt((ptr as u64 + VOFFT) as *mut u64);
+        nxt_pt = ptr as u64 | 3;
         pt[idx] = nxt_pt;
     }
 
@@ -127,7 +123,7 @@ fn pt_alloc_if_0(idx: usize, pt: &mut [u64]) -> &mut [u64] {
         ((nxt_pt as *mut u64 as usize & PHY_MASK) + VOFFT as usize) as *mut u64,
         512,
     );
-    nxt_pt
+    Ok(nxt_pt)
 }
 
 pub struct PmWrap {
@@ -146,9 +142,7 @@ impl PmWrap {
             unsafe { FIXED_L3.inner.get().as_mut() } //
                 .unwrap()
                 .data[vaddr.l3() as usize] = pm as u64 | perms | 0x403;
-            tlbi_vaee1(v as u64);
-            dsb!();
-            isb!();
+            tlb_shootdown(None, Some(v));
             if zero {
                 zero_pt(v as *mut u64);
             }
@@ -191,14 +185,10 @@ pub fn pt_alloc_if_0_2<F: FnMut(&mut [u64])>(
     let mut nxt_pt = pt[idx];
     let mut new = false;
     if nxt_pt == 0 {
-        match pm::alloc(4096) {
-            Ok(ptr) => {
-                pt[idx] = ptr as u64 | 3;
-                nxt_pt = ptr as u64;
-                new = true;
-            }
-            _ => unreachable!(), /*TODO handle error*/
-        };
+        let ptr = pm::alloc(4096).map_err(|_| Error::Alloc)?;
+        pt[idx] = ptr as u64 | 3;
+        nxt_pt = ptr as u64;
+        new = true;
     }
 
     let nxt_pt = PmWrap::new(nxt_pt as usize, PR_PW, new);
@@ -210,8 +200,8 @@ pub fn pt_alloc_if_0_2<F: FnMut(&mut [u64])>(
     nxt_pt
 }
 
-#[unsafe(no_mangle)]
-fn use_gb_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) {
+#[cfg_attr(not(test), unsafe(no_mangle))]
+fn use_gb_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) -> Result<(), Error> {
     assert!(l0_pt.len() == 512);
     k_begin = align_b(k_begin, GB);
     k_end = align_f(k_end, GB);
@@ -220,10 +210,11 @@ fn use_gb_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) {
 
     while i < k_end {
         let vaddr = Vaddr::new(i + VOFFT as usize);
-        let l1_pt = pt_alloc_if_0(vaddr.l0() as usize, l0_pt);
+        let l1_pt = pt_alloc_if_0(vaddr.l0() as usize, l0_pt)?;
         l1_pt[vaddr.l1() as usize] = (i | 0x401) as u64;
         i += GB;
     }
+    Ok(())
 }
 
 fn region_perms(vaddr: u64) -> u64 {
@@ -242,13 +233,17 @@ fn region_perms(vaddr: u64) -> u64 {
     } else if vaddr >= rodata_end && vaddr < bss_end {
         return PR_PW;
     } else if vaddr >= bss_end && vaddr < user_end {
-        return PR_PW_UR_UW_UX2;
+        // the sigreturn trampoline, every process sees it
+        return PR_UR_UX;
+    } else if vaddr >= user_end {
+        // pm's page array
+        return PR_PW;
     }
 
     0
 }
 
-fn use_2mb_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) {
+fn use_2mb_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) -> Result<(), Error> {
     assert!(l0_pt.len() == 512);
     k_begin = align_b(k_begin, 2 * MB);
     k_end = align_f(k_end, 2 * MB);
@@ -257,14 +252,15 @@ fn use_2mb_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) {
 
     while i < k_end {
         let vaddr = Vaddr::new(i + VOFFT as usize);
-        let l1_pt = pt_alloc_if_0(vaddr.l0() as usize, l0_pt);
-        let l2_pt = pt_alloc_if_0(vaddr.l1() as usize, l1_pt);
+        let l1_pt = pt_alloc_if_0(vaddr.l0() as usize, l0_pt)?;
+        let l2_pt = pt_alloc_if_0(vaddr.l1() as usize, l1_pt)?;
         l2_pt[vaddr.l2() as usize] = (i | 0x401) as u64;
         i += 2 * MB;
     }
+    Ok(())
 }
 
-fn use_4k_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) {
+fn use_4k_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) -> Result<(), Error> {
     assert!(l0_pt.len() == 512);
     k_begin = align_b(k_begin, 4 * KB);
     k_end = align_f(k_end, 4 * KB);
@@ -273,12 +269,35 @@ fn use_4k_blocks(l0_pt: &mut [u64], mut k_begin: usize, mut k_end: usize) {
 
     while i < k_end {
         let vaddr = Vaddr::new(i + VOFFT as usize);
-        let l1_pt = pt_alloc_if_0(vaddr.l0() as usize, l0_pt);
-        let l2_pt = pt_alloc_if_0(vaddr.l1() as usize, l1_pt);
-        let l3_pt = pt_alloc_if_0(vaddr.l2() as usize, l2_pt);
+        let l1_pt = pt_alloc_if_0(vaddr.l0() as usize, l0_pt)?;
+        let l2_pt = pt_alloc_if_0(vaddr.l1() as usize, l1_pt)?;
+        let l3_pt = pt_alloc_if_0(vaddr.l2() as usize, l2_pt)?;
         l3_pt[vaddr.l3() as usize] = (i as u64 | region_perms(i as u64 + VOFFT) | 0x403) as u64;
         i += 4 * KB;
     }
+    Ok(())
+}
+
+// invalidate on this core and broadcast to the inner shareable domain.
+// vaddr wins over asid (vaae1 hits every asid), neither flushes everything
+pub fn tlb_shootdown(asid: Option<u64>, vaddr: Option<usize>) {
+    dsb_ishst!();
+    match (asid, vaddr) {
+        (_, Some(v)) => {
+            tlbi_vaee1(v as u64);
+            tlbi_vaee1is(v as u64);
+        }
+        (Some(asid), None) => {
+            tlbi_aside1(asid);
+            tlbi_aside1is(asid);
+        }
+        _ => {
+            tlbi_vmalle1!();
+            tlbi_vmalle1is!();
+        }
+    }
+    dsb_ish!();
+    isb!();
 }
 
 #[derive(Debug)]
@@ -305,7 +324,8 @@ pub fn map_v2p_4k_inner<F: FnMut(&mut [u64])>(
         .map_err(|_| Error::Alloc)?;
 
     let mut overwritten = false;
-    if l3_pt.as_slice::<u64>()[vaddr.l3() as usize] != 0 {
+    let old = l3_pt.as_slice::<u64>()[vaddr.l3() as usize];
+    if old != 0 {
         if !overw {
             return Err(Error::Exists(v));
         } else {
@@ -313,8 +333,14 @@ pub fn map_v2p_4k_inner<F: FnMut(&mut [u64])>(
         }
     }
 
+    // break before make, a live entry is invalidated and flushed
+    // everywhere before the new one goes in
+    if old & 1 != 0 {
+        l3_pt.as_slice_mut()[vaddr.l3() as usize] = 0;
+        tlb_shootdown(None, Some(v));
+    }
     l3_pt.as_slice_mut()[vaddr.l3() as usize] = (p as u64 | perms | 0x403) as u64;
-    tlbi_vaee1(v as u64);
+    tlb_shootdown(None, Some(v));
 
     if overwritten {
         return Err(Error::Exists(v));
@@ -322,7 +348,7 @@ pub fn map_v2p_4k_inner<F: FnMut(&mut [u64])>(
     Ok(v)
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 fn map_v2p_4k(v: usize, p: usize, perms: u64) -> Result<usize, Error> {
     let pt_lock = PT.acquire();
     let l0_pt = &mut pt_lock.as_mut().data;
@@ -348,7 +374,7 @@ fn map_v2p_4k(v: usize, p: usize, perms: u64) -> Result<usize, Error> {
     // Ok(v)
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 fn map_v2p_4k2(v: usize, p: usize, perms: u64) -> Result<usize, Error> {
     let pt_lock = PT.acquire();
     let l0_pt = &mut pt_lock.as_mut().data;
@@ -404,7 +430,7 @@ pub fn unmap_4k_inner(l0_pt: &mut [u64], v: usize) -> Result<(), Error> {
     let l3_pt = walk_to_l3(l0_pt, v).map_err(|e| e)?;
     l3_pt.as_slice_mut::<u64>()[vaddr.l3() as usize] = 0;
 
-    tlbi_vaee1(v as u64);
+    tlb_shootdown(None, Some(v));
     Ok(())
 }
 
@@ -450,7 +476,8 @@ pub fn init(k_begin: usize, k_end: usize) {
     let pt_lock = PT.acquire();
     let l0_pt = &mut pt_lock.as_mut().data;
 
-    use_4k_blocks(l0_pt, k_begin, k_end);
+    // nothing to fall back to this early
+    use_4k_blocks(l0_pt, k_begin, k_end).unwrap();
     let v = Vaddr::new(!0usize);
 
     l0_pt[v.l0() as usize] = ((FIXED_L1.inner.get() as u64) - VOFFT) | 3;
@@ -515,7 +542,7 @@ impl Vaddr {
 
 pub struct Region {
     start: usize,
-    bs: BitSet128,
+    bs: BitSet<2>,
     nxt: Option<NonNull<Region>>,
 }
 
@@ -525,7 +552,7 @@ impl Region {
     const fn new(start: usize) -> Region {
         Region {
             start,
-            bs: BitSet128::new(128),
+            bs: BitSet::new(128),
             nxt: None,
         }
     }
@@ -555,7 +582,7 @@ impl Region {
             return None;
         }
 
-        match self.bs.set_nclr(n as u8) {
+        match self.bs.set_nclr(n) {
             Some(i) => {
                 // self.bs.set(i);
                 Some(i as usize * 4096 + self.start)
@@ -569,12 +596,12 @@ impl Region {
             let local = addr - self.start;
             let bit = local / (4096);
 
-            if bit >= 128 {
+            if bit >= self.bs.len() {
                 return None;
             }
 
-            assert!(self.bs.tst(bit as u8));
-            self.bs.clr(bit as u8);
+            assert!(self.bs.tst(bit));
+            self.bs.clr(bit);
             Some(())
         } else {
             None
@@ -632,6 +659,19 @@ pub fn map(p: usize, n: usize, perms: u64) -> Result<usize, Error> {
     }
 }
 
+// caller owns the range, nothing is reserved in REGIONS
+pub fn map_at(v: usize, p: usize, n: usize, perms: u64) -> Result<usize, Error> {
+    for i in 0..n {
+        if let Err(e) = map_v2p_4k(v + (i * 4 * KB), p + (i * 4 * KB), perms) {
+            for j in 0..i {
+                unmap_4k(v + (j * 4 * KB)).unwrap();
+            }
+            return Err(e);
+        }
+    }
+    Ok(v)
+}
+
 //dbg
 pub fn map2(p: usize, n: usize, perms: u64) -> Result<usize, Error> {
     match alloc(n) {
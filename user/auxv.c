@@ -0,0 +1,25 @@
+// built as a static pie: the load bias, the auxv and tls from PT_TLS
+#include <elf.h>
+#include <sys/auxv.h>
+#include <unistd.h>
+
+#include "t.h"
+
+static __thread int tls_init = 0x1234;
+static __thread int tls_bss;
+
+int main(int argc, char **argv)
+{
+	CHECK(argc >= 1 && argv[0] != NULL);
+	CHECK(getauxval(AT_PAGESZ) == 4096);
+	CHECK(getauxval(AT_PHENT) == sizeof(Elf64_Phdr));
+	CHECK(getauxval(AT_PHNUM) > 0 && getauxval(AT_PHDR) != 0);
+	// the entry is somewhere in the image, wherever it was loaded
+	unsigned long entry = getauxval(AT_ENTRY);
+	unsigned long phdr = getauxval(AT_PHDR);
+	CHECK(entry > phdr - 4096 && entry - phdr < (1 << 24));
+	CHECK(tls_init == 0x1234 && tls_bss == 0);
+	tls_bss = 7;
+	CHECK(tls_bss == 7);
+	DONE();
+}
@@ -0,0 +1,60 @@
+// copy on write across forks, nothing stays aliased once it resolves
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "t.h"
+
+#define PAGES 64
+
+static char region[PAGES * 4096];
+
+static int scribble(int k)
+{
+	// every child writes its own pages, then checks all of them
+	for (int i = k; i < PAGES; i += 4)
+		region[i * 4096 + k] = k + 1;
+	for (int i = 0; i < PAGES; i++)
+		for (int j = 0; j < 8; j++) {
+			char want = (i % 4 == k && j == k) ? k + 1 : 0;
+			if (region[i * 4096 + j] != want)
+				return 1;
+		}
+	return 0;
+}
+
+int main(void)
+{
+	for (int round = 0; round < 20; round++) {
+		pid_t kids[4];
+		for (int k = 0; k < 4; k++)
+			if ((kids[k] = fork()) == 0)
+				_exit(scribble(k));
+		for (int k = 0; k < 4; k++) {
+			int st;
+			CHECK(waitpid(kids[k], &st, 0) == kids[k]);
+			CHECK(WIFEXITED(st) && WEXITSTATUS(st) == 0);
+		}
+		for (int i = 0; i < PAGES * 4096; i += 4096)
+			CHECK(region[i] == 0);
+		if (fails)
+			break;
+	}
+
+	// the parent writing after the fork doesn't show in the child
+	region[0] = 'p';
+	int p[2];
+	pipe(p);
+	pid_t pid = fork();
+	if (pid == 0) {
+		char c;
+		read(p[0], &c, 1);
+		_exit(region[0] != 'p' || region[4096] != 0);
+	}
+	region[0] = 'q';
+	region[4096] = 'q';
+	write(p[1], "x", 1);
+	int st;
+	waitpid(pid, &st, 0);
+	CHECK(WIFEXITED(st) && WEXITSTATUS(st) == 0);
+	DONE();
+}
@@ -0,0 +1,15 @@
+// what exec runs. prints argv[1], or with -c n exits 0 if fd n is closed
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+int main(int argc, char **argv)
+{
+	if (argc > 2 && !strcmp(argv[1], "-c"))
+		return fcntl(atoi(argv[2]), F_GETFD) < 0 ? 0 : 1;
+	if (argc > 1)
+		printf("%s", argv[1]);
+	return 0;
+}
@@ -0,0 +1,70 @@
+// execve argument copying and close on exec, runs ./echo
+#include <fcntl.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "t.h"
+
+static int run(char *const argv[], char *out, size_t n)
+{
+	int p[2], st;
+	pipe(p);
+	pid_t pid = fork();
+	if (pid == 0) {
+		dup2(p[1], 1);
+		close(p[0]);
+		char *envp[] = {"A=1", NULL};
+		execve(argv[0], argv, envp);
+		_exit(127);
+	}
+	close(p[1]);
+	ssize_t got = 0, r;
+	while (out && (r = read(p[0], out + got, n - 1 - got)) > 0)
+		got += r;
+	if (out)
+		out[got] = 0;
+	close(p[0]);
+	waitpid(pid, &st, 0);
+	return st;
+}
+
+int main(void)
+{
+	char out[256];
+	int st = run((char *[]){"./echo", "hello exec", NULL}, out, sizeof(out));
+	CHECK(WIFEXITED(st) && WEXITSTATUS(st) == 0);
+	CHECK(!strcmp(out, "hello exec"));
+
+	// a long argument crosses a page
+	char big[6000];
+	memset(big, 'a', sizeof(big) - 1);
+	big[sizeof(big) - 1] = 0;
+	char *bout = malloc(sizeof(big) + 1);
+	st = run((char *[]){"./echo", big, NULL}, bout, sizeof(big) + 1);
+	CHECK(WIFEXITED(st) && !strcmp(bout, big));
+	free(bout);
+
+	int keep = open("/dev/null", O_RDONLY);
+	int gone = open("/dev/null", O_RDONLY | O_CLOEXEC);
+	char k[8], g[8];
+	snprintf(k, sizeof(k), "%d", keep);
+	snprintf(g, sizeof(g), "%d", gone);
+	st = run((char *[]){"./echo", "-c", g, NULL}, NULL, 0);
+	CHECK(WIFEXITED(st) && WEXITSTATUS(st) == 0);
+	st = run((char *[]){"./echo", "-c", k, NULL}, NULL, 0);
+	CHECK(WIFEXITED(st) && WEXITSTATUS(st) == 1);
+
+	// nothing there, the caller is still around to see it
+	errno = 0;
+	CHECK(execve("./nope", (char *[]){"nope", NULL}, NULL) < 0 && errno == ENOENT);
+	// not an elf, still there
+	int fd = open("junk", O_WRONLY | O_CREAT | O_TRUNC, 0755);
+	char junk[128];
+	memset(junk, 'j', sizeof(junk));
+	CHECK(fd >= 0 && write(fd, junk, sizeof(junk)) == sizeof(junk));
+	close(fd);
+	errno = 0;
+	CHECK(execve("./junk", (char *[]){"junk", NULL}, NULL) < 0 && errno == ENOEXEC);
+	unlink("junk");
+	DONE();
+}
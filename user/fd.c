@@ -0,0 +1,161 @@
+// fd table, pipes, devices and poll
+#include <fcntl.h>
+#include <poll.h>
+#include <signal.h>
+#include <sys/random.h>
+#include <sys/resource.h>
+#include <sys/uio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "t.h"
+
+// blocked, SIGPIPE waits for the unblock and then kills
+static int masked_sigpipe(void)
+{
+	int p[2];
+	sigset_t set;
+	sigemptyset(&set);
+	sigaddset(&set, SIGPIPE);
+	signal(SIGPIPE, SIG_DFL);
+	sigprocmask(SIG_BLOCK, &set, NULL);
+	pipe(p);
+	close(p[0]);
+	if (write(p[1], "x", 1) >= 0 || errno != EPIPE)
+		return 1;
+	sigprocmask(SIG_UNBLOCK, &set, NULL);
+	return 2;
+}
+
+static void pipes(void)
+{
+	int p[2];
+	char buf[16];
+	CHECK(pipe(p) == 0);
+	pid_t pid = fork();
+	if (pid == 0) {
+		close(p[0]);
+		usleep(20000);
+		_exit(write(p[1], "from child", 10) != 10);
+	}
+	close(p[1]);
+	// blocks until the child writes, then eof once it is gone
+	CHECK(read(p[0], buf, sizeof(buf)) == 10 && !memcmp(buf, "from child", 10));
+	CHECK(read(p[0], buf, sizeof(buf)) == 0);
+	int st;
+	CHECK(waitpid(pid, &st, 0) == pid && WIFEXITED(st) && !WEXITSTATUS(st));
+	close(p[0]);
+
+	// no readers left
+	signal(SIGPIPE, SIG_IGN);
+	CHECK(pipe(p) == 0);
+	close(p[0]);
+	errno = 0;
+	CHECK(write(p[1], "x", 1) < 0 && errno == EPIPE);
+	close(p[1]);
+	st = FORKED(masked_sigpipe);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGPIPE);
+}
+
+static void devices(void)
+{
+	char buf[64];
+	memset(buf, 1, sizeof(buf));
+	int z = open("/dev/zero", O_RDONLY);
+	CHECK(z >= 0 && read(z, buf, sizeof(buf)) == sizeof(buf));
+	for (int i = 0; i < 64; i++)
+		CHECK(buf[i] == 0);
+	close(z);
+	int n = open("/dev/null", O_RDWR);
+	CHECK(n >= 0 && write(n, buf, sizeof(buf)) == sizeof(buf));
+	CHECK(read(n, buf, sizeof(buf)) == 0);
+	close(n);
+
+	char a[64], b[64];
+	CHECK(getrandom(a, 64, 0) == 64 && getrandom(b, 64, 0) == 64);
+	CHECK(memcmp(a, b, 64) != 0);
+}
+
+static void many_fds(void)
+{
+	struct rlimit rl = {64, 64};
+	setrlimit(RLIMIT_NOFILE, &rl);
+	int fds[32];
+	for (int i = 0; i < 32; i++) {
+		fds[i] = open("/dev/null", O_RDONLY);
+		CHECK(fds[i] >= 0);
+	}
+	for (int i = 0; i < 32; i++)
+		close(fds[i]);
+}
+
+static void dupfd(void)
+{
+	char buf[4];
+	int p[2];
+	CHECK(pipe(p) == 0);
+	CHECK(write(p[1], "abcd", 4) == 4);
+	int d = fcntl(p[0], F_DUPFD, 10);
+	CHECK(d >= 10);
+	// one open file, the offset moves for both
+	CHECK(read(p[0], buf, 2) == 2 && !memcmp(buf, "ab", 2));
+	CHECK(read(d, buf, 2) == 2 && !memcmp(buf, "cd", 2));
+	CHECK(fcntl(d, F_GETFD) == 0);
+	int c = fcntl(p[0], F_DUPFD_CLOEXEC, 0);
+	CHECK(c >= 0 && fcntl(c, F_GETFD) == FD_CLOEXEC);
+
+	CHECK(fcntl(p[0], F_SETFL, O_NONBLOCK) == 0);
+	CHECK(fcntl(d, F_GETFL) & O_NONBLOCK);
+	errno = 0;
+	CHECK(read(d, buf, 1) < 0 && errno == EAGAIN);
+	close(c);
+	close(d);
+	close(p[0]);
+	close(p[1]);
+}
+
+static void bad_fds(void)
+{
+	char buf[4] = "abc";
+	struct iovec iov = {buf, 3};
+	int fds[] = {-1, 63, 1000};
+	for (int i = 0; i < 3; i++) {
+		errno = 0;
+		CHECK(read(fds[i], buf, 3) < 0 && errno == EBADF);
+		errno = 0;
+		CHECK(write(fds[i], buf, 3) < 0 && errno == EBADF);
+		errno = 0;
+		CHECK(writev(fds[i], &iov, 1) < 0 && errno == EBADF);
+	}
+}
+
+static void polling(void)
+{
+	int p[2];
+	CHECK(pipe(p) == 0);
+	struct pollfd pf = {.fd = p[0], .events = POLLIN};
+	CHECK(poll(&pf, 1, 0) == 0);
+	pid_t pid = fork();
+	if (pid == 0) {
+		usleep(30000);
+		_exit(write(p[1], "x", 1) != 1);
+	}
+	CHECK(poll(&pf, 1, 2000) == 1 && (pf.revents & POLLIN));
+	waitpid(pid, NULL, 0);
+	close(p[1]);
+	char c;
+	CHECK(read(p[0], &c, 1) == 1);
+	CHECK(poll(&pf, 1, 0) == 1 && (pf.revents & POLLHUP));
+	close(p[0]);
+}
+
+int main(void)
+{
+	pipes();
+	devices();
+	many_fds();
+	dupfd();
+	bad_fds();
+	polling();
+	DONE();
+}
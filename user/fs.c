@@ -0,0 +1,259 @@
+// the 9p backed file ops, run from a writable directory of the share
+#include <dirent.h>
+#include <fcntl.h>
+#include <sys/stat.h>
+#include <sys/statfs.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "t.h"
+
+#define D "fs.tmp"
+
+static void put(const char *path, const char *s, size_t n)
+{
+	int fd = open(path, O_WRONLY | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	CHECK(write(fd, s, n) == (ssize_t)n);
+	CHECK(fsync(fd) == 0);
+	close(fd);
+}
+
+static void errno_enoent(void)
+{
+	errno = 0;
+	CHECK(open(D "/nope", O_RDONLY) < 0 && errno == ENOENT);
+	// a failed open gives back its fid, more than there are fids
+	for (int i = 0; i < 1000; i++)
+		CHECK(open(D "/nope", O_RDONLY) < 0);
+	int fd = open(D, O_RDONLY);
+	CHECK(fd == 3);
+	close(fd);
+}
+
+static void creat_excl_trunc(void)
+{
+	int fd = open(D "/c", O_WRONLY | O_CREAT | O_EXCL, 0600);
+	CHECK(fd >= 0);
+	CHECK(write(fd, "hello", 5) == 5);
+	close(fd);
+	errno = 0;
+	CHECK(open(D "/c", O_WRONLY | O_CREAT | O_EXCL, 0600) < 0 &&
+	      errno == EEXIST);
+
+	struct stat st;
+	fd = open(D "/c", O_RDWR | O_TRUNC);
+	CHECK(fd >= 0);
+	CHECK(fstat(fd, &st) == 0 && st.st_size == 0);
+	CHECK(S_ISREG(st.st_mode) && (st.st_mode & 0777) == 0600);
+	close(fd);
+	// what the server said, not EIO
+	errno = 0;
+	CHECK(open(D, O_WRONLY | O_TRUNC) < 0 && errno == EISDIR);
+}
+
+static void seek_and_stat(void)
+{
+	char buf[8] = {0};
+	put(D "/s", "0123456789", 10);
+	int fd = open(D "/s", O_RDONLY);
+	CHECK(lseek(fd, 3, SEEK_SET) == 3);
+	CHECK(read(fd, buf, 2) == 2 && !memcmp(buf, "34", 2));
+	CHECK(lseek(fd, 2, SEEK_CUR) == 7);
+	CHECK(read(fd, buf, 1) == 1 && buf[0] == '7');
+	CHECK(lseek(fd, -1, SEEK_END) == 9);
+	CHECK(read(fd, buf, 8) == 1 && buf[0] == '9');
+	errno = 0;
+	CHECK(lseek(fd, -20, SEEK_CUR) < 0 && errno == EINVAL);
+
+	struct stat st;
+	CHECK(fstat(fd, &st) == 0 && st.st_size == 10 && S_ISREG(st.st_mode));
+	close(fd);
+
+	struct statfs sf;
+	CHECK(statfs("/", &sf) == 0);
+	CHECK(sf.f_bsize > 0 && (sf.f_bsize & (sf.f_bsize - 1)) == 0);
+
+	// the files that aren't on the share have one too
+	int p[2];
+	CHECK(pipe(p) == 0);
+	CHECK(fstatfs(p[0], &sf) == 0 && sf.f_type == 0x50495045);
+	close(p[0]);
+	close(p[1]);
+	fd = open("/dev/null", O_RDONLY);
+	CHECK(fstatfs(fd, &sf) == 0 && sf.f_type == 0x01021994);
+	close(fd);
+}
+
+// small writes may be held back, nothing looking by path can tell
+static void held_writes(void)
+{
+	char buf[8];
+	struct stat st;
+	int fd = open(D "/w", O_WRONLY | O_CREAT | O_TRUNC, 0644);
+	CHECK(write(fd, "abc", 3) == 3);
+	CHECK(stat(D "/w", &st) == 0 && st.st_size == 3);
+	CHECK(write(fd, "de", 2) == 2);
+	int r = open(D "/w", O_RDONLY);
+	CHECK(read(r, buf, sizeof(buf)) == 5 && !memcmp(buf, "abcde", 5));
+	close(r);
+	CHECK(write(fd, "f", 1) == 1);
+	CHECK(truncate(D "/w", 2) == 0);
+	CHECK(stat(D "/w", &st) == 0 && st.st_size == 2);
+	CHECK(fsync(fd) == 0);
+	close(fd);
+}
+
+// bigger than any iounit, the chunks have to line up
+static void big_io(void)
+{
+	size_t n = 1 << 20;
+	char *a = malloc(n), *b = malloc(n);
+	for (size_t i = 0; i < n; i++)
+		a[i] = i * 7 + (i >> 12);
+	put(D "/big", a, n);
+	int fd = open(D "/big", O_RDONLY);
+	size_t got = 0;
+	ssize_t r;
+	while ((r = read(fd, b + got, n - got)) > 0)
+		got += r;
+	CHECK(got == n && !memcmp(a, b, n));
+	close(fd);
+	free(a);
+	free(b);
+}
+
+static void deep_walk(void)
+{
+	char path[512] = D;
+	for (int i = 0; i < 20; i++) {
+		strcat(path, "/d");
+		CHECK(mkdir(path, 0755) == 0);
+	}
+	strcat(path, "/leaf");
+	put(path, "x", 1);
+	struct stat st;
+	CHECK(stat(path, &st) == 0 && st.st_size == 1);
+}
+
+static void links(void)
+{
+	char buf[64];
+	put(D "/target", "t", 1);
+	CHECK(symlink("target", D "/sym") == 0);
+	ssize_t n = readlink(D "/sym", buf, sizeof(buf));
+	CHECK(n == 6 && !memcmp(buf, "target", 6));
+	struct stat st;
+	CHECK(lstat(D "/sym", &st) == 0 && S_ISLNK(st.st_mode));
+	CHECK(stat(D "/sym", &st) == 0 && S_ISREG(st.st_mode));
+
+	CHECK(rename(D "/target", D "/moved") == 0);
+	CHECK(access(D "/target", F_OK) < 0 && errno == ENOENT);
+	CHECK(access(D "/moved", F_OK) == 0);
+
+	CHECK(mkdir(D "/sub", 0755) == 0);
+	CHECK(link(D "/moved", D "/sub/hard") == 0);
+	CHECK(stat(D "/sub/hard", &st) == 0 && st.st_nlink == 2);
+	errno = 0;
+	CHECK(link(D "/moved", D "/sub/hard") < 0 && errno == EEXIST);
+}
+
+static void locks(void)
+{
+	int fd = open(D "/lk", O_RDWR | O_CREAT, 0644);
+	struct flock fl = {.l_type = F_WRLCK, .l_whence = SEEK_SET};
+	CHECK(fcntl(fd, F_SETLK, &fl) == 0);
+	// another process sees it
+	if (fork() == 0) {
+		struct flock q = {.l_type = F_WRLCK, .l_whence = SEEK_SET};
+		int cfd = open(D "/lk", O_RDWR);
+		_exit(fcntl(cfd, F_GETLK, &q) != 0 || q.l_type == F_UNLCK);
+	}
+	int st;
+	wait(&st);
+	CHECK(WIFEXITED(st) && WEXITSTATUS(st) == 0);
+	fl.l_type = F_UNLCK;
+	CHECK(fcntl(fd, F_SETLK, &fl) == 0);
+	close(fd);
+}
+
+static void readdir_all(void)
+{
+	const char *names[] = {"e0", "e1", "e2", "e3", "e4"};
+	int seen = 0, n = 0;
+	CHECK(mkdir(D "/ls", 0755) == 0);
+	for (int i = 0; i < 5; i++) {
+		char p[64];
+		snprintf(p, sizeof(p), D "/ls/%s", names[i]);
+		put(p, "", 0);
+	}
+	DIR *d = opendir(D "/ls");
+	CHECK(d != NULL);
+	struct dirent *e;
+	while (d && (e = readdir(d))) {
+		n++;
+		for (int i = 0; i < 5; i++)
+			if (!strcmp(e->d_name, names[i]))
+				seen |= 1 << i;
+	}
+	CHECK(seen == 0x1f && n == 7);
+	if (d)
+		closedir(d);
+}
+
+// not 9p, shm_open lands here
+static void shm_truncate(void)
+{
+	struct stat st;
+	int fd = open("/dev/shm/fs.tmp", O_RDWR | O_CREAT, 0600);
+	CHECK(fd >= 0);
+	CHECK(ftruncate(fd, 3 * 4096) == 0);
+	CHECK(fstat(fd, &st) == 0 && st.st_size == 3 * 4096);
+	CHECK(ftruncate(fd, 4096) == 0);
+	CHECK(fstat(fd, &st) == 0 && st.st_size == 4096);
+	errno = 0;
+	CHECK(ftruncate(fd, -1) < 0 && errno == EINVAL);
+	close(fd);
+	unlink("/dev/shm/fs.tmp");
+}
+
+// more readers than virtqueue descriptors, they queue up
+static void concurrent(void)
+{
+	put(D "/shared", "abcdefgh", 8);
+	for (int i = 0; i < 16; i++) {
+		if (fork() == 0) {
+			int bad = 0;
+			char b[8];
+			for (int j = 0; j < 50; j++) {
+				int fd = open(D "/shared", O_RDONLY);
+				bad |= read(fd, b, 8) != 8 || memcmp(b, "abcdefgh", 8);
+				close(fd);
+			}
+			_exit(bad);
+		}
+	}
+	int st, ok = 0;
+	while (wait(&st) > 0)
+		ok += WIFEXITED(st) && WEXITSTATUS(st) == 0;
+	CHECK(ok == 16);
+}
+
+int main(void)
+{
+	system("rm -rf " D);
+	CHECK(mkdir(D, 0755) == 0);
+	errno_enoent();
+	creat_excl_trunc();
+	seek_and_stat();
+	held_writes();
+	big_io();
+	deep_walk();
+	links();
+	locks();
+	readdir_all();
+	shm_truncate();
+	concurrent();
+	system("rm -rf " D);
+	DONE();
+}
@@ -0,0 +1,89 @@
+// futex wait/wake between threads, the bitset variants, and the clear
+// tid wake on exit
+#include <linux/futex.h>
+#include <pthread.h>
+#include <stdatomic.h>
+#include <sys/syscall.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "t.h"
+
+#define ROUNDS 1000
+
+static atomic_int word;
+
+static long futex(atomic_int *uaddr, int op, int val)
+{
+	return syscall(SYS_futex, uaddr, op, val, NULL, NULL, 0);
+}
+
+static long futex_bitset(atomic_int *uaddr, int op, int val,
+			 struct timespec *ts, unsigned mask)
+{
+	return syscall(SYS_futex, uaddr, op, val, ts, NULL, mask);
+}
+
+// the match any mask is what libc passes, the timeout is absolute
+static void bitset(void)
+{
+	atomic_int w = 1;
+	struct timespec a, b, dl;
+	errno = 0;
+	CHECK(futex_bitset(&w, FUTEX_WAIT_BITSET_PRIVATE, 0, NULL, ~0u) < 0 && errno == EAGAIN);
+	CHECK(futex_bitset(&w, FUTEX_WAKE_BITSET_PRIVATE, 1, NULL, ~0u) == 0);
+	errno = 0;
+	CHECK(futex_bitset(&w, FUTEX_WAKE_BITSET_PRIVATE, 1, NULL, 0) < 0 && errno == EINVAL);
+
+	clock_gettime(CLOCK_MONOTONIC, &a);
+	dl = a;
+	dl.tv_nsec += 50000000;
+	if (dl.tv_nsec >= 1000000000) {
+		dl.tv_sec++;
+		dl.tv_nsec -= 1000000000;
+	}
+	errno = 0;
+	CHECK(futex_bitset(&w, FUTEX_WAIT_BITSET_PRIVATE, 1, &dl, ~0u) < 0 && errno == ETIMEDOUT);
+	clock_gettime(CLOCK_MONOTONIC, &b);
+	long ms = (b.tv_sec - a.tv_sec) * 1000 + (b.tv_nsec - a.tv_nsec) / 1000000;
+	CHECK(ms >= 40 && ms < 1000);
+	// already past
+	errno = 0;
+	CHECK(futex_bitset(&w, FUTEX_WAIT_BITSET_PRIVATE, 1, &a, ~0u) < 0 && errno == ETIMEDOUT);
+}
+
+// wait for the word to be mine, then hand it to the other side
+static void pingpong(int me)
+{
+	for (int i = 0; i < ROUNDS; i++) {
+		int v;
+		while ((v = atomic_load(&word)) != me)
+			futex(&word, FUTEX_WAIT_PRIVATE, v);
+		atomic_store(&word, !me);
+		futex(&word, FUTEX_WAKE_PRIVATE, 1);
+	}
+}
+
+static void *pong(void *arg)
+{
+	(void)arg;
+	pingpong(1);
+	return (void *)42;
+}
+
+int main(void)
+{
+	pthread_t t;
+	void *ret;
+	CHECK(pthread_create(&t, NULL, pong, NULL) == 0);
+	pingpong(0);
+	// joins on the clear tid futex
+	CHECK(pthread_join(t, &ret) == 0 && ret == (void *)42);
+
+	atomic_int w = 1;
+	errno = 0;
+	CHECK(futex(&w, FUTEX_WAIT_PRIVATE, 0) < 0 && errno == EAGAIN);
+	CHECK(futex(&w, FUTEX_WAKE_PRIVATE, 1) == 0);
+	bitset();
+	DONE();
+}
@@ -0,0 +1,152 @@
+// anonymous mappings: lazy backing, protection, unmap, reuse of the
+// freed range, brk
+#include <signal.h>
+#include <sys/mman.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "t.h"
+
+#define RW (PROT_READ | PROT_WRITE)
+#define ANON (MAP_PRIVATE | MAP_ANONYMOUS)
+
+static char *gone;
+
+static int touch_gone(void)
+{
+	return *(volatile char *)gone;
+}
+
+static void unmap(void)
+{
+	char *p = mmap(NULL, 3 * 4096, RW, ANON, -1, 0);
+	CHECK(p != MAP_FAILED);
+	memset(p, 'x', 3 * 4096);
+	// a hole in the middle, the ends stay
+	CHECK(munmap(p + 4096, 4096) == 0);
+	CHECK(p[0] == 'x' && p[2 * 4096] == 'x');
+	gone = p + 4096;
+	int st = FORKED(touch_gone);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+	CHECK(munmap(p, 3 * 4096) == 0);
+}
+
+static void reuse(void)
+{
+	char *a = mmap(NULL, 4096, RW, ANON, -1, 0);
+	char *b = mmap(NULL, 4 * 4096, RW, ANON, -1, 0);
+	char *c = mmap(NULL, 4096, RW, ANON, -1, 0);
+	CHECK(a != MAP_FAILED && b != MAP_FAILED && c != MAP_FAILED);
+	memset(b, 'b', 4 * 4096);
+	CHECK(munmap(b, 4 * 4096) == 0);
+	// first fit, the gap b left is a candidate
+	char *d = mmap(NULL, 2 * 4096, RW, ANON, -1, 0);
+	CHECK(d != MAP_FAILED && d <= b);
+	// fresh pages, not what b left behind
+	CHECK(d[0] == 0 && d[4096] == 0);
+	munmap(a, 4096);
+	munmap(c, 4096);
+	munmap(d, 2 * 4096);
+}
+
+static void too_big(void)
+{
+	errno = 0;
+	CHECK(mmap(NULL, 1UL << 40, RW, ANON, -1, 0) == MAP_FAILED && errno == ENOMEM);
+	errno = 0;
+	CHECK(mmap(NULL, 0, RW, ANON, -1, 0) == MAP_FAILED && errno == EINVAL);
+	// still fine afterwards
+	char *p = mmap(NULL, 4096, RW, ANON, -1, 0);
+	CHECK(p != MAP_FAILED);
+	p[0] = 1;
+	munmap(p, 4096);
+}
+
+static void heap(void)
+{
+	char *b = sbrk(0);
+	CHECK(sbrk(8 * 4096) == b);
+	b[0] = 1;
+	b[8 * 4096 - 1] = 2;
+	CHECK(sbrk(-8 * 4096) != (void *)-1 && sbrk(0) == b);
+}
+
+// nothing is backed until it's touched
+static void lazy(void)
+{
+	size_t n = 16 << 20;
+	unsigned char *vec = malloc(n / 4096);
+	char *p = mmap(NULL, n, RW, ANON, -1, 0);
+	CHECK(p != MAP_FAILED);
+	p[4096] = 1;
+	p[n - 1] = 2;
+	CHECK(mincore(p, n, vec) == 0);
+	int resident = 0;
+	for (size_t i = 0; i < n / 4096; i++)
+		resident += vec[i] & 1;
+	CHECK(resident == 2 && vec[1] && vec[n / 4096 - 1]);
+	munmap(p, n);
+	free(vec);
+}
+
+static char *probe;
+
+static int read_probe(void)
+{
+	return *(volatile char *)probe;
+}
+
+static int write_probe(void)
+{
+	*(volatile char *)probe = 1;
+	return 0;
+}
+
+static void prot(void)
+{
+	int st;
+	char *ro = mmap(NULL, 16 << 20, PROT_READ, ANON, -1, 0);
+	CHECK(ro != MAP_FAILED && ro[12345] == 0);
+	probe = ro;
+	st = FORKED(write_probe);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+
+	char *none = mmap(NULL, 2 * 4096, PROT_NONE, ANON, -1, 0);
+	CHECK(none != MAP_FAILED);
+	probe = none;
+	st = FORKED(read_probe);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+	// what a thread stack's guard looks like
+	CHECK(mprotect(none + 4096, 4096, RW) == 0);
+	none[4096] = 3;
+	CHECK(none[4096] == 3);
+	st = FORKED(read_probe);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+
+	char *rwx = mmap(NULL, 4096, RW | PROT_EXEC, ANON, -1, 0);
+	CHECK(rwx != MAP_FAILED);
+	rwx[0] = 1;
+	errno = 0;
+	CHECK(mmap(NULL, 4096, 0x40, ANON, -1, 0) == MAP_FAILED && errno == EINVAL);
+
+	// read only after it was written, and still cow across a fork
+	CHECK(mprotect(none + 4096, 4096, PROT_READ) == 0);
+	probe = none + 4096;
+	st = FORKED(write_probe);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+	CHECK(none[4096] == 3);
+	munmap(ro, 16 << 20);
+	munmap(none, 2 * 4096);
+	munmap(rwx, 4096);
+}
+
+int main(void)
+{
+	lazy();
+	prot();
+	unmap();
+	reuse();
+	too_big();
+	heap();
+	DONE();
+}
@@ -0,0 +1,163 @@
+// processes: wait, kill, orphans, uname, cpus, asids and membarrier
+#include <sched.h>
+#include <linux/membarrier.h>
+#include <signal.h>
+#include <sys/syscall.h>
+#include <sys/utsname.h>
+#include <sys/wait.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "t.h"
+
+static void killed_is_zombie(void)
+{
+	pid_t pid = fork();
+	if (pid == 0)
+		for (;;)
+			pause();
+	CHECK(kill(pid, SIGKILL) == 0);
+	int st;
+	CHECK(waitpid(pid, &st, 0) == pid);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGKILL);
+	errno = 0;
+	CHECK(kill(pid, 0) < 0 && errno == ESRCH);
+}
+
+static void wait_for_one(void)
+{
+	pid_t live = fork();
+	if (live == 0)
+		for (;;)
+			pause();
+	int st;
+	CHECK(waitpid(live, &st, WNOHANG) == 0);
+
+	pid_t kids[3];
+	for (int i = 0; i < 3; i++)
+		if ((kids[i] = fork()) == 0)
+			_exit(10 + i);
+	// the middle one, whatever order they finish in
+	CHECK(waitpid(kids[1], &st, 0) == kids[1] && WEXITSTATUS(st) == 11);
+	CHECK(waitpid(kids[0], &st, 0) == kids[0] && WEXITSTATUS(st) == 10);
+	CHECK(waitpid(kids[2], &st, 0) == kids[2] && WEXITSTATUS(st) == 12);
+
+	kill(live, SIGKILL);
+	CHECK(waitpid(live, &st, 0) == live);
+	errno = 0;
+	CHECK(waitpid(-1, &st, WNOHANG) < 0 && errno == ECHILD);
+}
+
+// the grandchild outlives its parent and goes to init
+static void orphan(void)
+{
+	int p[2];
+	pipe(p);
+	pid_t mid = fork();
+	if (mid == 0) {
+		if (fork() == 0) {
+			char c;
+			read(p[0], &c, 1);
+			pid_t pp = getppid();
+			write(p[1], &pp, sizeof(pp));
+			_exit(0);
+		}
+		_exit(0);
+	}
+	waitpid(mid, NULL, 0);
+	write(p[1], "x", 1);
+	usleep(50000);
+	pid_t pp = 0;
+	CHECK(read(p[0], &pp, sizeof(pp)) == sizeof(pp));
+	CHECK(pp != mid && pp != getpid());
+	close(p[0]);
+	close(p[1]);
+}
+
+static void names(void)
+{
+	struct utsname u;
+	CHECK(uname(&u) == 0);
+#ifdef __aarch64__
+	CHECK(!strcmp(u.machine, "aarch64"));
+#endif
+}
+
+static long now_ms(void)
+{
+	struct timespec t;
+	clock_gettime(CLOCK_MONOTONIC, &t);
+	return t.tv_sec * 1000 + t.tv_nsec / 1000000;
+}
+
+static long busy(int n)
+{
+	long t = now_ms();
+	for (int i = 0; i < n; i++)
+		if (fork() == 0) {
+			for (volatile long j = 0; j < 50000000; j++)
+				;
+			_exit(0);
+		}
+	while (wait(NULL) > 0)
+		;
+	return now_ms() - t;
+}
+
+// two busy children on two cores finish in about the time of one
+static void concurrent(void)
+{
+	if (sysconf(_SC_NPROCESSORS_ONLN) < 2)
+		return;
+	long one = busy(1);
+	CHECK(busy(2) < one * 3 / 2);
+}
+
+// more address spaces than asids, each still sees its own memory
+static void asids(void)
+{
+	static volatile int v;
+	for (int i = 0; i < 600; i++) {
+		pid_t pid = fork();
+		if (pid == 0) {
+			v = i;
+			sched_yield();
+			_exit(v != i);
+		}
+		int st;
+		waitpid(pid, &st, 0);
+		CHECK(WIFEXITED(st) && WEXITSTATUS(st) == 0);
+		if (fails)
+			break;
+	}
+	CHECK(v == 0);
+}
+
+static long membarrier(int cmd)
+{
+	return syscall(SYS_membarrier, cmd, 0, 0);
+}
+
+// the private barrier wants the address space registered first
+static void barriers(void)
+{
+	long q = membarrier(MEMBARRIER_CMD_QUERY);
+	CHECK(q > 0 && (q & MEMBARRIER_CMD_PRIVATE_EXPEDITED));
+	CHECK(membarrier(MEMBARRIER_CMD_GLOBAL) == 0);
+	errno = 0;
+	CHECK(membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED) < 0 && errno == EPERM);
+	CHECK(membarrier(MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED) == 0);
+	CHECK(membarrier(MEMBARRIER_CMD_PRIVATE_EXPEDITED) == 0);
+}
+
+int main(void)
+{
+	killed_is_zombie();
+	wait_for_one();
+	orphan();
+	names();
+	concurrent();
+	asids();
+	barriers();
+	DONE();
+}
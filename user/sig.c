@@ -0,0 +1,161 @@
+// signal actions, handlers, the signal mask and the faults that turn into
+// signals
+#include <signal.h>
+#include <sys/mman.h>
+#include <sys/syscall.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "t.h"
+
+static void handler(int sig)
+{
+	(void)sig;
+}
+
+static volatile int caught;
+static volatile int caught_signo;
+
+static void counter(int sig, siginfo_t *si, void *uc)
+{
+	(void)uc;
+	caught++;
+	caught_signo = si->si_signo;
+}
+
+static void handled(void)
+{
+	struct sigaction sa = {.sa_sigaction = counter, .sa_flags = SA_SIGINFO};
+	sigemptyset(&sa.sa_mask);
+	CHECK(sigaction(SIGUSR1, &sa, NULL) == 0);
+	caught = 0;
+	CHECK(kill(getpid(), SIGUSR1) == 0);
+	CHECK(caught == 1 && caught_signo == SIGUSR1);
+	CHECK(raise(SIGUSR1) == 0 && caught == 2);
+#ifdef __aarch64__
+	// no restorer, the handler returns through the kernel's trampoline
+	struct {
+		void *handler;
+		unsigned long flags;
+		void *restorer;
+		unsigned long mask;
+	} raw = {(void *)counter, SA_SIGINFO, NULL, 0};
+	CHECK(syscall(SYS_rt_sigaction, SIGUSR1, &raw, NULL, 8) == 0);
+	CHECK(kill(getpid(), SIGUSR1) == 0 && caught == 3);
+#endif
+	sa.sa_handler = SIG_DFL;
+	sa.sa_flags = 0;
+	sigaction(SIGUSR1, &sa, NULL);
+}
+
+static int block_all(void)
+{
+	sigset_t all;
+	sigfillset(&all);
+	sigprocmask(SIG_BLOCK, &all, NULL);
+	kill(getpid(), SIGKILL);
+	return 0;
+}
+
+static void masks(void)
+{
+	struct sigaction sa = {.sa_sigaction = counter, .sa_flags = SA_SIGINFO};
+	sigemptyset(&sa.sa_mask);
+	CHECK(sigaction(SIGUSR1, &sa, NULL) == 0);
+	caught = 0;
+
+	sigset_t set, old, cur;
+	sigemptyset(&set);
+	sigaddset(&set, SIGUSR1);
+	CHECK(sigprocmask(SIG_BLOCK, &set, &old) == 0 && !sigismember(&old, SIGUSR1));
+	CHECK(sigprocmask(SIG_SETMASK, NULL, &cur) == 0 && sigismember(&cur, SIGUSR1));
+	// held until it's unblocked, then delivered before sigprocmask returns
+	CHECK(kill(getpid(), SIGUSR1) == 0 && caught == 0);
+	CHECK(sigprocmask(SIG_UNBLOCK, &set, NULL) == 0 && caught == 1);
+	CHECK(sigprocmask(SIG_SETMASK, NULL, &cur) == 0 && !sigismember(&cur, SIGUSR1));
+
+	sigaddset(&set, SIGKILL);
+	sigaddset(&set, SIGSTOP);
+	CHECK(sigprocmask(SIG_SETMASK, &set, NULL) == 0);
+	CHECK(sigprocmask(SIG_SETMASK, &old, &cur) == 0 && sigismember(&cur, SIGUSR1));
+	CHECK(!sigismember(&cur, SIGKILL) && !sigismember(&cur, SIGSTOP));
+	errno = 0;
+	CHECK(sigprocmask(7, &set, NULL) < 0 && errno == EINVAL);
+
+	int st = FORKED(block_all);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGKILL);
+
+	sa.sa_handler = SIG_DFL;
+	sa.sa_flags = 0;
+	sigaction(SIGUSR1, &sa, NULL);
+}
+
+static void actions(void)
+{
+	struct sigaction sa = {.sa_handler = handler, .sa_flags = SA_RESTART};
+	struct sigaction old;
+	sigemptyset(&sa.sa_mask);
+	sigaddset(&sa.sa_mask, SIGUSR2);
+	CHECK(sigaction(SIGUSR1, &sa, NULL) == 0);
+	CHECK(sigaction(SIGUSR1, NULL, &old) == 0);
+	CHECK(old.sa_handler == handler && (old.sa_flags & SA_RESTART));
+	CHECK(sigismember(&old.sa_mask, SIGUSR2) == 1);
+	sa.sa_handler = SIG_DFL;
+	CHECK(sigaction(SIGUSR1, &sa, &old) == 0 && old.sa_handler == handler);
+	errno = 0;
+	CHECK(sigaction(SIGKILL, &sa, NULL) < 0 && errno == EINVAL);
+}
+
+static int null_deref(void)
+{
+	return *(volatile int *)0;
+}
+
+static int bad_pc(void)
+{
+	void (*f)(void) = (void (*)(void))0xdead0000;
+	f();
+	return 0;
+}
+
+static int write_ro(void)
+{
+	char *p = mmap(NULL, 4096, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+	p[0] = 1;
+	return 0;
+}
+
+static int depth(int n)
+{
+	volatile char pad[1024];
+	pad[0] = n;
+	return n ? depth(n - 1) + pad[0] : 0;
+}
+
+// runs off the bottom of the stack into the guard page
+static int recurse(void)
+{
+	return depth(1 << 30);
+}
+
+static void faults(void)
+{
+	int st;
+	st = FORKED(null_deref);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+	st = FORKED(bad_pc);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+	st = FORKED(write_ro);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+	st = FORKED(recurse);
+	CHECK(WIFSIGNALED(st) && WTERMSIG(st) == SIGSEGV);
+}
+
+int main(void)
+{
+	actions();
+	handled();
+	masks();
+	faults();
+	DONE();
+}
@@ -0,0 +1,34 @@
+// tiny harness for the userspace tests, one binary per area. a failed
+// CHECK prints and carries on, the exit status is the number of failures
+#include <errno.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static int fails;
+
+#define CHECK(c)                                                              \
+	do {                                                                  \
+		if (!(c)) {                                                   \
+			printf("FAIL %s:%d: %s (errno %d)\n", __FILE__,       \
+			       __LINE__, #c, errno);                          \
+			fails++;                                              \
+		}                                                             \
+	} while (0)
+
+#define DONE()                                                                \
+	do {                                                                  \
+		printf("%s %s\n", fails ? "FAIL" : "ok", __FILE__);           \
+		return fails;                                                 \
+	} while (0)
+
+// run f in a child, its wait status
+#define FORKED(f)                                                             \
+	({                                                                    \
+		int _st = -1;                                                 \
+		pid_t _p = fork();                                            \
+		if (_p == 0)                                                  \
+			_exit(f());                                           \
+		waitpid(_p, &_st, 0);                                         \
+		_st;                                                          \
+	})
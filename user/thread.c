@@ -0,0 +1,66 @@
+// shared address spaces: threads, CLONE_VM processes and vfork
+#define _GNU_SOURCE
+#include <pthread.h>
+#include <sched.h>
+#include <signal.h>
+#include <spawn.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include "t.h"
+
+static volatile int shared;
+
+static void *writer(void *arg)
+{
+	shared = (int)(long)arg;
+	return NULL;
+}
+
+static int vm_child(void *arg)
+{
+	shared = (int)(long)arg;
+	return getpid() == getppid();
+}
+
+extern char **environ;
+
+int main(void)
+{
+	pthread_t t;
+	CHECK(pthread_create(&t, NULL, writer, (void *)7) == 0);
+	CHECK(pthread_join(t, NULL) == 0);
+	CHECK(shared == 7);
+
+	// a process of its own in our memory, waited for like any child
+	static char stack[16384] __attribute__((aligned(16)));
+	pid_t pid = clone(vm_child, stack + sizeof(stack), CLONE_VM | SIGCHLD, (void *)8);
+	CHECK(pid > 0 && pid != getpid());
+	int st;
+	CHECK(waitpid(pid, &st, 0) == pid && WIFEXITED(st) && WEXITSTATUS(st) == 0);
+	CHECK(shared == 8);
+
+	// the parent sleeps until the child is done with its memory
+	pid = vfork();
+	if (pid == 0) {
+		shared = 9;
+		_exit(3);
+	}
+	CHECK(shared == 9);
+	CHECK(waitpid(pid, &st, 0) == pid && WEXITSTATUS(st) == 3);
+
+	pid = vfork();
+	if (pid == 0) {
+		execl("./echo", "echo", "-c", "100", (char *)NULL);
+		_exit(127);
+	}
+	CHECK(waitpid(pid, &st, 0) == pid && WIFEXITED(st) && WEXITSTATUS(st) == 0);
+
+	// musl's is CLONE_VM | CLONE_VFORK
+	char *argv[] = {"echo", "-c", "100", NULL};
+	CHECK(posix_spawn(&pid, "./echo", NULL, NULL, argv, environ) == 0);
+	CHECK(waitpid(pid, &st, 0) == pid && WIFEXITED(st) && WEXITSTATUS(st) == 0);
+	errno = 0;
+	CHECK(posix_spawn(&pid, "./nope", NULL, NULL, argv, environ) == ENOENT);
+	DONE();
+}
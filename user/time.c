@@ -0,0 +1,48 @@
+// clocks and sleeping
+#include <sys/time.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "t.h"
+
+static long ns(struct timespec *t)
+{
+	return t->tv_sec * 1000000000L + t->tv_nsec;
+}
+
+int main(void)
+{
+	struct timespec a, b;
+	CHECK(clock_gettime(CLOCK_MONOTONIC, &a) == 0);
+	CHECK(clock_gettime(CLOCK_MONOTONIC, &b) == 0);
+	CHECK(ns(&b) >= ns(&a));
+
+	struct timespec d = {0, 50000000};
+	clock_gettime(CLOCK_MONOTONIC, &a);
+	CHECK(nanosleep(&d, NULL) == 0);
+	clock_gettime(CLOCK_MONOTONIC, &b);
+	CHECK(ns(&b) - ns(&a) >= 50000000);
+
+	struct timeval tv1, tv2;
+	CHECK(gettimeofday(&tv1, NULL) == 0);
+	CHECK(gettimeofday(&tv2, NULL) == 0);
+	CHECK(tv2.tv_sec > tv1.tv_sec ||
+	      (tv2.tv_sec == tv1.tv_sec && tv2.tv_usec >= tv1.tv_usec));
+	CHECK(tv1.tv_sec > 1600000000);
+
+	// finer than the rtc's seconds, and in step with gettimeofday
+	int sub = 0;
+	struct timespec r0, r1, step = {0, 30000000};
+	CHECK(clock_gettime(CLOCK_REALTIME, &r0) == 0);
+	for (int i = 0; i < 5; i++) {
+		nanosleep(&step, NULL);
+		CHECK(clock_gettime(CLOCK_REALTIME, &r1) == 0);
+		CHECK(ns(&r1) > ns(&r0));
+		sub |= r1.tv_nsec != 0;
+		r0 = r1;
+	}
+	CHECK(sub);
+	CHECK(gettimeofday(&tv1, NULL) == 0);
+	CHECK(tv1.tv_sec - r1.tv_sec <= 1);
+	DONE();
+}
@@ -0,0 +1,38 @@
+// termios on the console, run from the interactive shell
+#include <termios.h>
+#include <time.h>
+#include <unistd.h>
+
+#include "t.h"
+
+int main(void)
+{
+	struct termios old, t, back;
+	if (!isatty(0)) {
+		printf("skip %s: stdin is not a tty\n", __FILE__);
+		return 0;
+	}
+	CHECK(tcgetattr(0, &old) == 0);
+	t = old;
+	t.c_cc[VINTR] = 0x18;
+	CHECK(tcsetattr(0, TCSANOW, &t) == 0);
+	CHECK(tcgetattr(0, &back) == 0 && back.c_cc[VINTR] == 0x18);
+
+	// raw, and nothing typed: VTIME runs out and read returns 0
+	t.c_lflag &= ~(ICANON | ECHO);
+	t.c_cc[VMIN] = 0;
+	t.c_cc[VTIME] = 2;
+	CHECK(tcsetattr(0, TCSANOW, &t) == 0);
+	CHECK(tcgetattr(0, &back) == 0 && !(back.c_lflag & ICANON));
+	CHECK(back.c_cc[VMIN] == 0 && back.c_cc[VTIME] == 2);
+	struct timespec a, b;
+	char c;
+	clock_gettime(CLOCK_MONOTONIC, &a);
+	CHECK(read(0, &c, 1) == 0);
+	clock_gettime(CLOCK_MONOTONIC, &b);
+	long ms = (b.tv_sec - a.tv_sec) * 1000 + (b.tv_nsec - a.tv_nsec) / 1000000;
+	CHECK(ms >= 150);
+
+	CHECK(tcsetattr(0, TCSANOW, &old) == 0);
+	DONE();
+}